@@ -0,0 +1,967 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    sync::{Mutex, PoisonError},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bitcoin::{
+    Address, Amount, Block, BlockHash, Network, OutPoint, Transaction, Txid,
+    bip158::BlockFilter,
+    consensus::encode::{FromHexError, deserialize_hex},
+    hex::{FromHex, HexToBytesError},
+};
+use esplora_client::{Tx, TxStatus, Utxo, UtxoStatus};
+use log::{debug, info};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use thiserror::Error;
+
+use crate::REDACTED;
+use crate::chain::{ChainError, ChainSource, page_after, tx_with_prevouts};
+use crate::descriptor::descriptor_checksum;
+use crate::proxy;
+
+/// `RPC_INVALID_ADDRESS_OR_KEY`: unknown transaction, block or address.
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+/// `RPC_WALLET_NOT_FOUND`: the wallet does not exist or is not loaded.
+const RPC_WALLET_NOT_FOUND: i64 = -18;
+/// `RPC_WALLET_ALREADY_LOADED`: the wallet is already loaded.
+const RPC_WALLET_ALREADY_LOADED: i64 = -35;
+
+/// Errors that happen while talking to bitcoind.
+#[derive(Debug, Error)]
+pub enum BitcoindError {
+    /// Neither a cookie file nor a username and password are configured.
+    #[error("either `cookie_file` or `rpc_user` and `rpc_password` must be set")]
+    MissingCredentials,
+
+    /// Error reading the cookie file.
+    #[error("failed to read cookie file `{0}`: {1}")]
+    Cookie(String, #[source] io::Error),
+
+    /// Error making the HTTP request.
+    #[error(transparent)]
+    Http(#[from] minreq::Error),
+
+    /// bitcoind answered with an HTTP error and no JSON-RPC error.
+    #[error("bitcoind responded with HTTP {0}: {1}")]
+    Status(i32, String),
+
+    /// bitcoind answered with a JSON-RPC error.
+    #[error("`{method}` failed with code {code}: {message}")]
+    Rpc { method: String, code: i64, message: String },
+
+    /// Error parsing a JSON-RPC result.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// Error decoding a raw transaction.
+    #[error(transparent)]
+    Decode(#[from] FromHexError),
+
+    /// Error decoding a block filter.
+    #[error(transparent)]
+    Hex(#[from] HexToBytesError),
+
+    /// bitcoind runs on a different chain than the configured network.
+    #[error("bitcoind runs on chain `{0}`, expected `{1}`")]
+    NetworkMismatch(String, &'static str),
+
+    /// bitcoind refused to import an address into the wallet.
+    #[error("failed to import address {0}: {1}")]
+    Import(Address, String),
+}
+
+/// bitcoind JSON-RPC connection parameters.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct BitcoindConfig {
+    /// The JSON-RPC URL.
+    /// The default RPC port of the network on localhost is used, if left empty.
+    #[serde(default)]
+    pub(crate) url: Option<String>,
+    /// The `.cookie` file to authenticate with.
+    #[serde(default)]
+    pub(crate) cookie_file: Option<String>,
+    /// The RPC username, if not authenticating with a cookie file.
+    #[serde(default)]
+    pub(crate) rpc_user: Option<String>,
+    /// The RPC password, if not authenticating with a cookie file.
+    #[serde(default)]
+    pub(crate) rpc_password: Option<String>,
+    /// The watch-only descriptor wallet the watched addresses are imported into.
+    /// It is created if it does not exist.
+    #[serde(default = "default_bitcoind_wallet")]
+    pub(crate) wallet: String,
+    /// The UNIX timestamp to rescan the chain from when importing addresses.
+    /// The whole chain is rescanned, if left empty.
+    #[serde(default)]
+    pub(crate) rescan_since: u64,
+    /// How long to wait for bitcoind to respond, in seconds. Imports wait for their rescan to finish.
+    #[serde(default = "default_bitcoind_timeout_sec")]
+    pub(crate) timeout_sec: u64,
+}
+
+fn default_bitcoind_wallet() -> String {
+    String::from("smaug")
+}
+
+fn default_bitcoind_timeout_sec() -> u64 {
+    30
+}
+
+impl fmt::Debug for BitcoindConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BitcoindConfig")
+            .field("url", &self.url)
+            .field("cookie_file", &self.cookie_file)
+            .field("rpc_user", &self.rpc_user)
+            .field("rpc_password", &self.rpc_password.as_ref().map(|_| REDACTED))
+            .field("wallet", &self.wallet)
+            .field("rescan_since", &self.rescan_since)
+            .field("timeout_sec", &self.timeout_sec)
+            .finish()
+    }
+}
+
+/// The default bitcoind RPC URL of a [`Network`].
+fn default_bitcoind_url(network: Network) -> String {
+    let port = match network {
+        Network::Bitcoin => 8332,
+        Network::Testnet => 18332,
+        Network::Testnet4 => 48332,
+        Network::Signet => 38332,
+        Network::Regtest => 18443,
+    };
+
+    format!("http://127.0.0.1:{port}")
+}
+
+/// How to authenticate to bitcoind.
+#[derive(Clone)]
+enum Auth {
+    /// The path of a `.cookie` file, re-read on every request since bitcoind rewrites it on restart.
+    Cookie(String),
+    /// A username and password.
+    UserPass(String, String),
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The cookie file is only shown by its path, which is no secret.
+        match self {
+            Auth::Cookie(path) => f.debug_tuple("Cookie").field(path).finish(),
+            Auth::UserPass(user, _) => f.debug_tuple("UserPass").field(user).field(&REDACTED).finish(),
+        }
+    }
+}
+
+impl Auth {
+    /// The value of the `Authorization` header.
+    fn header(&self) -> Result<String, BitcoindError> {
+        let credentials = match self {
+            Auth::Cookie(path) => fs::read_to_string(path)
+                .map_err(|e| BitcoindError::Cookie(path.clone(), e))?
+                .trim()
+                .to_string(),
+            Auth::UserPass(user, password) => format!("{user}:{password}"),
+        };
+
+        Ok(format!("Basic {}", BASE64.encode(credentials)))
+    }
+}
+
+/// A JSON-RPC error object.
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// A JSON-RPC response.
+#[derive(Debug, Deserialize)]
+struct RpcResponse {
+    #[serde(default)]
+    result: Value,
+    error: Option<RpcError>,
+}
+
+/// The part of `getblockchaininfo` smaug uses.
+#[derive(Debug, Deserialize)]
+struct BlockchainInfo {
+    chain: String,
+}
+
+/// The part of `getaddressinfo` smaug uses.
+#[derive(Debug, Deserialize)]
+struct AddressInfo {
+    ismine: bool,
+    #[serde(default)]
+    iswatchonly: bool,
+}
+
+/// The result of importing one descriptor.
+#[derive(Debug, Deserialize)]
+struct ImportResult {
+    success: bool,
+    error: Option<RpcError>,
+}
+
+/// An entry of `listunspent`.
+#[derive(Debug, Deserialize)]
+struct Unspent {
+    txid: Txid,
+    vout: u32,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    amount: Amount,
+}
+
+/// A wallet transaction, as returned by `gettransaction` and `listsinceblock`.
+#[derive(Debug, Deserialize)]
+struct WalletTx {
+    txid: Txid,
+    /// Negative if the transaction conflicts with a confirmed one.
+    confirmations: i64,
+    blockhash: Option<BlockHash>,
+    blockheight: Option<u32>,
+    blocktime: Option<u64>,
+    /// Set if the transaction was replaced in the mempool.
+    replaced_by_txid: Option<Txid>,
+    /// Only returned by `gettransaction`.
+    hex: Option<String>,
+}
+
+impl WalletTx {
+    fn status(&self) -> TxStatus {
+        TxStatus {
+            confirmed: self.confirmations > 0,
+            block_height: self.blockheight,
+            block_hash: self.blockhash,
+            block_time: self.blocktime,
+        }
+    }
+}
+
+/// The part of `listsinceblock` smaug uses.
+#[derive(Debug, Deserialize)]
+struct SinceBlock {
+    transactions: Vec<WalletTx>,
+}
+
+/// A non-wallet transaction, as returned by a verbose `getrawtransaction`.
+#[derive(Debug, Deserialize)]
+struct RawTx {
+    hex: String,
+    blockhash: Option<BlockHash>,
+    blocktime: Option<u64>,
+}
+
+/// The part of `getblockfilter` smaug uses.
+#[derive(Debug, Deserialize)]
+struct RawBlockFilter {
+    filter: String,
+}
+
+/// The part of `getblockheader` smaug uses.
+#[derive(Debug, Deserialize)]
+struct BlockHeader {
+    height: u32,
+}
+
+/// Whether `result` failed with the JSON-RPC error `code`.
+fn is_rpc_error<T>(result: &Result<T, BitcoindError>, code: i64) -> bool {
+    matches!(result, Err(BitcoindError::Rpc { code: error_code, .. }) if *error_code == code)
+}
+
+/// A [`ChainSource`] backed by bitcoind's JSON-RPC and a watch-only descriptor wallet.
+///
+/// Watched addresses are imported into the wallet as `addr()` descriptors, so UTXOs and transactions are served by
+/// the wallet without revealing the addresses to anyone, nor requiring `txindex`.
+#[derive(Debug)]
+pub(crate) struct BitcoindClient {
+    network: Network,
+    url: String,
+    wallet: String,
+    auth: Auth,
+    rescan_since: u64,
+    timeout_sec: u64,
+    /// Whether the chain was checked and the wallet loaded.
+    ready: Mutex<bool>,
+    /// The addresses known to be imported into the wallet.
+    imported: Mutex<HashSet<Address>>,
+    /// The transactions fetched so far, which never change.
+    transactions: Mutex<HashMap<Txid, Transaction>>,
+    /// Whether bitcoind serves compact block filters (`-blockfilterindex`), once checked.
+    filter_index: Mutex<Option<bool>>,
+}
+
+impl BitcoindClient {
+    /// Build a client from the `[bitcoind]` section of the configuration.
+    pub(crate) fn new(network: Network, config: &BitcoindConfig) -> Result<BitcoindClient, BitcoindError> {
+        let auth = match (&config.cookie_file, &config.rpc_user, &config.rpc_password) {
+            (Some(cookie_file), _, _) => Auth::Cookie(cookie_file.clone()),
+            (None, Some(user), Some(password)) => Auth::UserPass(user.clone(), password.clone()),
+            _ => return Err(BitcoindError::MissingCredentials),
+        };
+
+        Ok(BitcoindClient {
+            network,
+            url: config
+                .url
+                .clone()
+                .unwrap_or_else(|| default_bitcoind_url(network))
+                .trim_end_matches('/')
+                .to_string(),
+            wallet: config.wallet.clone(),
+            auth,
+            rescan_since: config.rescan_since,
+            timeout_sec: config.timeout_sec,
+            ready: Mutex::new(false),
+            imported: Mutex::new(HashSet::new()),
+            transactions: Mutex::new(HashMap::new()),
+            filter_index: Mutex::new(None),
+        })
+    }
+
+    /// The JSON-RPC URL.
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Make a JSON-RPC request to `url`, waiting `timeout_sec` for a response, or indefinitely if `None`.
+    fn request(
+        &self,
+        url: &str,
+        method: &str,
+        params: Value,
+        timeout_sec: Option<u64>,
+    ) -> Result<Value, BitcoindError> {
+        let body = json!({ "jsonrpc": "1.0", "id": "smaug", "method": method, "params": params });
+        let mut request = proxy::proxied(minreq::post(url))
+            .with_header("Authorization", self.auth.header()?)
+            .with_header("Content-Type", "application/json")
+            .with_body(body.to_string());
+        if let Some(timeout_sec) = timeout_sec {
+            request = request.with_timeout(timeout_sec);
+        }
+
+        let response = request.send()?;
+        match serde_json::from_slice::<RpcResponse>(response.as_bytes()) {
+            Ok(RpcResponse { error: Some(error), .. }) => Err(BitcoindError::Rpc {
+                method: method.to_string(),
+                code: error.code,
+                message: error.message,
+            }),
+            Ok(RpcResponse { result, .. }) if response.status_code == 200 => Ok(result),
+            _ => Err(BitcoindError::Status(
+                response.status_code,
+                String::from_utf8_lossy(response.as_bytes()).into_owned(),
+            )),
+        }
+    }
+
+    /// Call a node RPC.
+    fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, BitcoindError> {
+        let result = self.request(&self.url, method, params, Some(self.timeout_sec))?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Whether bitcoind serves compact block filters, checked once with `getindexinfo`.
+    fn filter_index(&self) -> Result<bool, BitcoindError> {
+        let mut filter_index = self.filter_index.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(filter_index) = *filter_index {
+            return Ok(filter_index);
+        }
+
+        let indexes: HashMap<String, Value> = self.call("getindexinfo", json!(["basic block filter index"]))?;
+        let enabled = !indexes.is_empty();
+        match enabled {
+            true => info!("bitcoind serves compact block filters, only blocks matching them are downloaded"),
+            false => debug!("bitcoind does not serve compact block filters, every block is downloaded"),
+        }
+        *filter_index = Some(enabled);
+
+        Ok(enabled)
+    }
+
+    /// Call a wallet RPC, loading the wallet first if needed.
+    fn wallet_call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+        timeout_sec: Option<u64>,
+    ) -> Result<T, BitcoindError> {
+        self.setup()?;
+
+        let wallet_url = format!("{}/wallet/{}", self.url, self.wallet);
+        let result = self.request(&wallet_url, method, params, timeout_sec);
+        if is_rpc_error(&result, RPC_WALLET_NOT_FOUND) {
+            // bitcoind restarted without loading the wallet, load it again on the next call.
+            *self.ready.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        }
+
+        Ok(serde_json::from_value(result?)?)
+    }
+
+    /// Check that bitcoind runs on the configured network, and load or create the watch-only wallet.
+    fn setup(&self) -> Result<(), BitcoindError> {
+        let mut ready = self.ready.lock().unwrap_or_else(PoisonError::into_inner);
+        if *ready {
+            return Ok(());
+        }
+
+        let info: BlockchainInfo = self.call("getblockchaininfo", json!([]))?;
+        if info.chain != self.network.to_core_arg() {
+            return Err(BitcoindError::NetworkMismatch(info.chain, self.network.to_core_arg()));
+        }
+
+        let loaded = self.call::<Value>("loadwallet", json!([self.wallet]));
+        if is_rpc_error(&loaded, RPC_WALLET_NOT_FOUND) {
+            self.call::<Value>(
+                "createwallet",
+                json!({
+                    "wallet_name": self.wallet,
+                    "disable_private_keys": true,
+                    "blank": true,
+                    "descriptors": true,
+                    "load_on_startup": true,
+                }),
+            )?;
+            info!("Created watch-only wallet `{}`", self.wallet);
+        } else if !is_rpc_error(&loaded, RPC_WALLET_ALREADY_LOADED) {
+            loaded?;
+            info!("Loaded wallet `{}`", self.wallet);
+        }
+
+        *ready = true;
+        Ok(())
+    }
+
+    /// Import the addresses that are not in the wallet yet, rescanning from `rescan_since`.
+    fn import(&self, addresses: &[Address]) -> Result<(), BitcoindError> {
+        let mut imported = self.imported.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut new_addresses = Vec::new();
+        for address in addresses {
+            if imported.contains(address) {
+                continue;
+            }
+
+            let info: AddressInfo = self.wallet_call("getaddressinfo", json!([address]), Some(self.timeout_sec))?;
+            match info.ismine || info.iswatchonly {
+                true => {
+                    imported.insert(address.clone());
+                }
+                false => new_addresses.push(address.clone()),
+            }
+        }
+        if new_addresses.is_empty() {
+            return Ok(());
+        }
+
+        let requests: Vec<Value> = new_addresses
+            .iter()
+            .map(|address| {
+                let descriptor = format!("addr({address})");
+                let descriptor = match descriptor_checksum(&descriptor) {
+                    Some(checksum) => format!("{descriptor}#{checksum}"),
+                    None => descriptor,
+                };
+                json!({ "desc": descriptor, "timestamp": self.rescan_since, "label": "smaug" })
+            })
+            .collect();
+
+        info!(
+            "Importing {} addresses into wallet `{}`, rescanning from {}...",
+            new_addresses.len(),
+            self.wallet,
+            self.rescan_since
+        );
+        let results: Vec<ImportResult> = self.wallet_call("importdescriptors", json!([requests]), None)?;
+        for (address, result) in new_addresses.into_iter().zip(results) {
+            if !result.success {
+                let message = result.error.map(|error| error.message).unwrap_or_default();
+                return Err(BitcoindError::Import(address, message));
+            }
+            imported.insert(address);
+        }
+
+        Ok(())
+    }
+
+    /// A wallet transaction, if the wallet knows it.
+    fn wallet_tx(&self, txid: &Txid) -> Result<Option<WalletTx>, BitcoindError> {
+        let result = self.wallet_call::<WalletTx>("gettransaction", json!([txid, true, false]), Some(self.timeout_sec));
+        if is_rpc_error(&result, RPC_INVALID_ADDRESS_OR_KEY) {
+            return Ok(None);
+        }
+
+        let wallet_tx = result?;
+        if let Some(hex) = &wallet_tx.hex {
+            let transaction: Transaction = deserialize_hex(hex)?;
+            self.cache(transaction);
+        }
+
+        Ok(Some(wallet_tx))
+    }
+
+    /// Every wallet transaction that is confirmed or in the mempool: unconfirmed first, then newest first.
+    fn wallet_txs(&self) -> Result<Vec<WalletTx>, BitcoindError> {
+        let since_block: SinceBlock = self.wallet_call("listsinceblock", json!([]), Some(self.timeout_sec))?;
+
+        // `listsinceblock` lists a transaction once per output it sends or receives.
+        let mut seen = HashSet::new();
+        let mut wallet_txs: Vec<WalletTx> = since_block
+            .transactions
+            .into_iter()
+            .filter(|wallet_tx| wallet_tx.confirmations >= 0 && wallet_tx.replaced_by_txid.is_none())
+            .filter(|wallet_tx| seen.insert(wallet_tx.txid))
+            .collect();
+        wallet_txs.sort_by_key(|wallet_tx| (wallet_tx.confirmations > 0, Reverse(wallet_tx.blockheight)));
+
+        Ok(wallet_txs)
+    }
+
+    fn cache(&self, transaction: Transaction) {
+        self.transactions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(transaction.compute_txid(), transaction);
+    }
+
+    /// A transaction, from the cache, the wallet or the mempool.
+    ///
+    /// Confirmed transactions that are not in the wallet are only found if bitcoind runs with `txindex`.
+    fn transaction(&self, txid: &Txid) -> Result<Option<Transaction>, BitcoindError> {
+        if let Some(transaction) = self
+            .transactions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(txid)
+        {
+            return Ok(Some(transaction.clone()));
+        }
+
+        if self.wallet_tx(txid)?.is_some() {
+            return Ok(self
+                .transactions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(txid)
+                .cloned());
+        }
+
+        let result = self.call::<String>("getrawtransaction", json!([txid]));
+        if is_rpc_error(&result, RPC_INVALID_ADDRESS_OR_KEY) {
+            return Ok(None);
+        }
+        let transaction: Transaction = deserialize_hex(&result?)?;
+        self.cache(transaction.clone());
+
+        Ok(Some(transaction))
+    }
+
+    /// Convert a [`Transaction`] to a [`Tx`], looking up its prevouts.
+    fn esplora_tx(&self, transaction: &Transaction, status: TxStatus) -> Result<Tx, BitcoindError> {
+        tx_with_prevouts(transaction, status, |txid| self.transaction(txid))
+    }
+
+    /// The status of a transaction confirmed in `block_hash`, or of an unconfirmed one.
+    fn block_status(&self, block_hash: Option<BlockHash>, block_time: Option<u64>) -> Result<TxStatus, BitcoindError> {
+        let block_height = match block_hash {
+            Some(block_hash) => Some(self.call::<BlockHeader>("getblockheader", json!([block_hash]))?.height),
+            None => None,
+        };
+
+        Ok(TxStatus {
+            confirmed: block_hash.is_some(),
+            block_height,
+            block_hash,
+            block_time,
+        })
+    }
+}
+
+impl ChainSource for BitcoindClient {
+    fn watch(&self, addresses: &[Address]) -> Result<(), ChainError> {
+        Ok(self.import(addresses)?)
+    }
+
+    fn height(&self) -> Result<u32, ChainError> {
+        Ok(self.call("getblockcount", json!([]))?)
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+        Ok(self.call("getblockhash", json!([height]))?)
+    }
+
+    fn block(&self, height: u32) -> Result<Block, ChainError> {
+        let block_hash: BlockHash = self.call("getblockhash", json!([height]))?;
+        let hex: String = self.call("getblock", json!([block_hash, 0]))?;
+
+        Ok(deserialize_hex(&hex).map_err(BitcoindError::from)?)
+    }
+
+    fn block_filter(&self, height: u32) -> Result<Option<BlockFilter>, ChainError> {
+        if !self.filter_index()? {
+            return Ok(None);
+        }
+
+        let block_hash: BlockHash = self.call("getblockhash", json!([height]))?;
+        let filter: RawBlockFilter = self.call("getblockfilter", json!([block_hash, "basic"]))?;
+        let content = Vec::<u8>::from_hex(&filter.filter).map_err(BitcoindError::from)?;
+
+        Ok(Some(BlockFilter::new(&content)))
+    }
+
+    fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        let unspents: Vec<Unspent> = self.wallet_call(
+            "listunspent",
+            json!([0, 9_999_999, [address], true]),
+            Some(self.timeout_sec),
+        )?;
+
+        let mut statuses: HashMap<Txid, TxStatus> = HashMap::new();
+        let mut utxos = Vec::new();
+        for unspent in unspents {
+            let status = match statuses.get(&unspent.txid) {
+                Some(status) => status.clone(),
+                None => {
+                    let status = match self.wallet_tx(&unspent.txid)? {
+                        Some(wallet_tx) => wallet_tx.status(),
+                        None => self.block_status(None, None)?,
+                    };
+                    statuses.insert(unspent.txid, status.clone());
+                    status
+                }
+            };
+
+            utxos.push(Utxo {
+                txid: unspent.txid,
+                vout: unspent.vout,
+                status: UtxoStatus {
+                    confirmed: status.confirmed,
+                    block_height: status.block_height,
+                    block_hash: status.block_hash,
+                    block_time: status.block_time,
+                },
+                value: unspent.amount,
+            });
+        }
+
+        Ok(utxos)
+    }
+
+    fn addresses_used(&self, addresses: &[&Address]) -> Result<Vec<bool>, ChainError> {
+        // Every used address received coins at some point, even if they were spent since.
+        addresses
+            .iter()
+            .map(|address| {
+                let received: f64 =
+                    self.wallet_call("getreceivedbyaddress", json!([address, 0]), Some(self.timeout_sec))?;
+                Ok(received > 0.0)
+            })
+            .collect()
+    }
+
+    fn address_txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        let script_pubkey = address.script_pubkey();
+
+        let mut txs = Vec::new();
+        for wallet_tx in self.wallet_txs()? {
+            let Some(transaction) = self.transaction(&wallet_tx.txid)? else {
+                continue;
+            };
+            let tx = self.esplora_tx(&transaction, wallet_tx.status())?;
+
+            let deposits = tx.vout.iter().any(|vout| vout.scriptpubkey == script_pubkey);
+            let withdraws = tx.vin.iter().any(|vin| {
+                vin.prevout
+                    .as_ref()
+                    .is_some_and(|prevout| prevout.scriptpubkey == script_pubkey)
+            });
+            if deposits || withdraws {
+                txs.push(tx);
+            }
+        }
+
+        let txs = page_after(txs, last_seen);
+        debug!("{} wallet transactions touch address {address}", txs.len());
+
+        Ok(txs)
+    }
+
+    fn mempool_address_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError> {
+        let mut txs = self.address_txs(address, None)?;
+        txs.retain(|tx| !tx.status.confirmed);
+
+        Ok(txs)
+    }
+
+    fn spending_txid(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, ChainError> {
+        // `gettxout` only returns unspent outputs, mempool spends included.
+        let unspent: Value = self.call("gettxout", json!([txid, vout, true]))?;
+        if !unspent.is_null() {
+            return Ok(None);
+        }
+
+        // Spends of wallet outputs are wallet transactions. Prefer a confirmed one over a conflicting unconfirmed one.
+        let outpoint = OutPoint::new(*txid, vout);
+        let mut spending_txid = None;
+        for wallet_tx in self.wallet_txs()? {
+            let Some(transaction) = self.transaction(&wallet_tx.txid)? else {
+                continue;
+            };
+            if transaction.input.iter().any(|input| input.previous_output == outpoint)
+                && (spending_txid.is_none() || wallet_tx.confirmations > 0)
+            {
+                spending_txid = Some(wallet_tx.txid);
+            }
+        }
+
+        Ok(spending_txid)
+    }
+
+    fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError> {
+        if let Some(wallet_tx) = self.wallet_tx(txid)?
+            && let Some(transaction) = self.transaction(txid)?
+        {
+            return Ok(Some(self.esplora_tx(&transaction, wallet_tx.status())?));
+        }
+
+        let result = self.call::<RawTx>("getrawtransaction", json!([txid, true]));
+        if is_rpc_error(&result, RPC_INVALID_ADDRESS_OR_KEY) {
+            return Ok(None);
+        }
+        let raw_tx = result?;
+        let transaction: Transaction = deserialize_hex(&raw_tx.hex).map_err(BitcoindError::from)?;
+        let status = self.block_status(raw_tx.blockhash, raw_tx.blocktime)?;
+        self.cache(transaction.clone());
+
+        Ok(Some(self.esplora_tx(&transaction, status)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        ScriptBuf, Sequence, TxIn, TxOut, Witness, absolute::LockTime, consensus::encode::serialize_hex,
+        transaction::Version,
+    };
+
+    use super::*;
+    use crate::stand_in::{Request, Requests, http_stand_in};
+
+    /// How the RPC stand-in answers a method call: a result, or an error code and message.
+    type Answer = fn(&str, &Value) -> Result<Value, (i64, &'static str)>;
+
+    /// A JSON-RPC stand-in answering every request with `answer(method, params)`.
+    ///
+    /// Requests without the `Authorization` header of the `__cookie__:secret` cookie are refused.
+    fn rpc_stand_in(answer: Answer) -> (String, Requests) {
+        http_stand_in(move |request| {
+            if request.header("authorization") != Some("Basic X19jb29raWVfXzpzZWNyZXQ=") {
+                return (String::from("401 Unauthorized"), String::new());
+            }
+
+            let call = request.json();
+            match answer(call["method"].as_str().unwrap(), &call["params"]) {
+                Ok(result) => (
+                    String::from("200 OK"),
+                    json!({ "result": result, "error": null, "id": "smaug" }).to_string(),
+                ),
+                Err((code, message)) => (
+                    String::from("500 Internal Server Error"),
+                    json!({ "result": null, "error": { "code": code, "message": message }, "id": "smaug" }).to_string(),
+                ),
+            }
+        })
+    }
+
+    fn client(url: String) -> BitcoindClient {
+        let cookie_file = std::env::temp_dir().join(format!("smaug-test-{}.cookie", std::process::id()));
+        fs::write(&cookie_file, "__cookie__:secret\n").unwrap();
+
+        let config = BitcoindConfig {
+            url: Some(url),
+            cookie_file: Some(cookie_file.display().to_string()),
+            rpc_user: None,
+            rpc_password: None,
+            wallet: default_bitcoind_wallet(),
+            rescan_since: 1757566235,
+            timeout_sec: 5,
+        };
+        BitcoindClient::new(Network::Testnet4, &config).unwrap()
+    }
+
+    fn address() -> Address {
+        Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap()
+    }
+
+    fn transaction(previous_output: OutPoint, output: TxOut) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![output],
+        }
+    }
+
+    /// A confirmed deposit of 100,000 sats to [`address`], from an input the node does not know.
+    fn deposit() -> Transaction {
+        let previous_output = OutPoint::new(
+            Txid::from_str("9e8b5c7b468dab43ae55f66d977819102c3bb129825cc6bddb54f45fafb7ae33").unwrap(),
+            3,
+        );
+        transaction(
+            previous_output,
+            TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: address().script_pubkey(),
+            },
+        )
+    }
+
+    /// An unconfirmed withdrawal sweeping [`deposit`] with a 1,000 sats fee.
+    fn withdrawal() -> Transaction {
+        transaction(
+            OutPoint::new(deposit().compute_txid(), 0),
+            TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: ScriptBuf::new_op_return([0u8; 4]),
+            },
+        )
+    }
+
+    fn wallet_tx(transaction: &Transaction) -> Value {
+        match *transaction == deposit() {
+            true => json!({
+                "txid": transaction.compute_txid(),
+                "confirmations": 3,
+                "blockhash": "000000000000000a0c62ba7e0a1ad5b6a8f2a1bb7cbb34e0bae8cc0dd0d4b05a",
+                "blockheight": 101595,
+                "blocktime": 1757566235,
+                "hex": serialize_hex(transaction),
+            }),
+            false => json!({
+                "txid": transaction.compute_txid(),
+                "confirmations": 0,
+                "hex": serialize_hex(transaction),
+            }),
+        }
+    }
+
+    /// A node on testnet4 without the `smaug` wallet, whose wallet then holds [`deposit`] and [`withdrawal`].
+    fn node(method: &str, params: &Value) -> Result<Value, (i64, &'static str)> {
+        match method {
+            "getblockchaininfo" => Ok(json!({ "chain": "testnet4" })),
+            "loadwallet" => Err((RPC_WALLET_NOT_FOUND, "Wallet file not found")),
+            "createwallet" => Ok(json!({ "name": "smaug" })),
+            "getaddressinfo" => Ok(json!({ "ismine": false, "iswatchonly": false })),
+            "importdescriptors" => Ok(json!([{ "success": true }])),
+            "getblockcount" => Ok(json!(101597)),
+            "listunspent" => Ok(json!([])),
+            "listsinceblock" => Ok(json!({
+                "transactions": [wallet_tx(&deposit()), wallet_tx(&withdrawal()), wallet_tx(&deposit())],
+            })),
+            "gettransaction" => [deposit(), withdrawal()]
+                .iter()
+                .find(|transaction| json!(transaction.compute_txid()) == params[0])
+                .map(wallet_tx)
+                .ok_or((RPC_INVALID_ADDRESS_OR_KEY, "Invalid or non-wallet transaction id")),
+            "getrawtransaction" => Err((RPC_INVALID_ADDRESS_OR_KEY, "No such mempool transaction")),
+            "gettxout" => Ok(Value::Null),
+            _ => Err((-32601, "Method not found")),
+        }
+    }
+
+    #[test]
+    fn import_into_watch_only_wallet() {
+        let (url, requests) = rpc_stand_in(node);
+        let client = client(url);
+
+        client.watch(&[address()]).unwrap();
+        // Imported addresses are not looked up again.
+        client.watch(&[address()]).unwrap();
+        assert_eq!(client.height().unwrap(), 101597);
+
+        let requests = requests.lock().unwrap();
+        let calls: Vec<Value> = requests.iter().map(Request::json).collect();
+        let methods: Vec<&str> = calls.iter().map(|call| call["method"].as_str().unwrap()).collect();
+        assert_eq!(
+            methods,
+            [
+                "getblockchaininfo",
+                "loadwallet",
+                "createwallet",
+                "getaddressinfo",
+                "importdescriptors",
+                "getblockcount"
+            ]
+        );
+        assert_eq!(calls[2]["params"]["disable_private_keys"], json!(true));
+        assert_eq!(calls[2]["params"]["descriptors"], json!(true));
+
+        assert_eq!(requests[4].path, "/wallet/smaug");
+        let params = &calls[4]["params"];
+        let descriptor = format!("addr({})", address());
+        let checksum = descriptor_checksum(&descriptor).unwrap();
+        assert_eq!(
+            params[0],
+            json!([{ "desc": format!("{descriptor}#{checksum}"), "timestamp": 1757566235, "label": "smaug" }])
+        );
+    }
+
+    #[test]
+    fn transactions_from_wallet() {
+        let (url, _) = rpc_stand_in(node);
+        let client = client(url);
+
+        let txs = client.address_txs(&address(), None).unwrap();
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid).collect();
+        assert_eq!(txids, [withdrawal().compute_txid(), deposit().compute_txid()]);
+        assert!(client.address_txs(&address(), Some(txids[1])).unwrap().is_empty());
+
+        let sweep = &txs[0];
+        assert!(!sweep.status.confirmed);
+        assert_eq!(sweep.fee, 1_000);
+        assert_eq!(sweep.vin[0].prevout.as_ref().unwrap().value, 100_000);
+
+        let deposit = &txs[1];
+        assert_eq!(deposit.status.block_height, Some(101595));
+        assert!(deposit.vin[0].prevout.is_none());
+        assert_eq!(deposit.fee, 0);
+
+        let mempool_txs = client.mempool_address_txs(&address()).unwrap();
+        assert_eq!(mempool_txs.len(), 1);
+        assert_eq!(
+            client.spending_txid(&txids[1], 0).unwrap(),
+            Some(withdrawal().compute_txid())
+        );
+        assert_eq!(client.tx(&txids[0]).unwrap().unwrap().fee, 1_000);
+    }
+
+    #[test]
+    fn network_mismatch() {
+        let (url, _) = rpc_stand_in(|method, _| match method {
+            "getblockchaininfo" => Ok(json!({ "chain": "main" })),
+            _ => Err((-32601, "Method not found")),
+        });
+
+        assert!(matches!(
+            client(url).import(&[address()]),
+            Err(BitcoindError::NetworkMismatch(chain, "testnet4")) if chain == "main"
+        ));
+    }
+}
@@ -0,0 +1,859 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::{
+    Address, Block, BlockHash, Network, Txid,
+    bip158::{BlockFilter, FilterHash, FilterHeader},
+    block::Header,
+    consensus::encode::{self, deserialize, serialize},
+    constants::genesis_block,
+    hashes::Hash,
+    p2p::{
+        Address as PeerAddress, Magic, ServiceFlags,
+        message::{NetworkMessage, RawNetworkMessage},
+        message_blockdata::{GetHeadersMessage, Inventory},
+        message_filter::{GetCFHeaders, GetCFilters},
+        message_network::VersionMessage,
+    },
+};
+use esplora_client::{Tx, Utxo};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::chain::{ChainError, ChainSource};
+use crate::proxy;
+use crate::state::write_atomically;
+
+/// The protocol version announced to peers.
+const PROTOCOL_VERSION: u32 = 70015;
+/// The BIP158 basic filter type.
+const BASIC_FILTER: u8 = 0;
+/// The most filters a peer serves per `getcfilters` request.
+const MAX_FILTERS_PER_REQUEST: u32 = 1000;
+/// The most headers a peer serves per `getheaders` request.
+const MAX_HEADERS_PER_REQUEST: usize = 2000;
+/// The largest message accepted from a peer.
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+/// How many headers below the last processed filter are kept, to follow reorgs and build locators.
+const REORG_WINDOW: u32 = 144;
+
+/// Errors that happen while talking to P2P peers or handling the header store.
+#[derive(Debug, Error)]
+pub enum CbfError {
+    /// No peer is configured.
+    #[error("at least one peer must be configured in `[cbf]`")]
+    NoPeers,
+
+    /// None of the configured peers could be reached.
+    #[error("no peer serving compact block filters could be reached")]
+    Unreachable,
+
+    /// A peer address could not be resolved.
+    #[error("peer `{0}` could not be resolved")]
+    InvalidPeer(String),
+
+    /// The peer does not advertise `NODE_COMPACT_FILTERS`.
+    #[error("peer `{0}` does not serve compact block filters")]
+    NoFilters(String),
+
+    /// Error reading from or writing to a peer.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// Error decoding a message from a peer.
+    #[error(transparent)]
+    Decode(#[from] encode::Error),
+
+    /// A peer sent something that does not check out.
+    #[error("peer sent an invalid {0}")]
+    Invalid(&'static str),
+
+    /// A peer did not answer in time.
+    #[error("timed out waiting for `{0}`")]
+    Timeout(&'static str),
+
+    /// Error reading or writing the header store.
+    #[error("header store `{0}`: {1}")]
+    StoreIo(PathBuf, #[source] io::Error),
+
+    /// Error (de)serializing the header store.
+    #[error("header store `{0}`: {1}")]
+    StoreJson(PathBuf, #[source] serde_json::Error),
+
+    /// The header store was written for a different network.
+    #[error("header store `{0}` was written for network `{1}`")]
+    StoreNetwork(PathBuf, Network),
+}
+
+/// Compact block filter light client parameters.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct CbfConfig {
+    /// The `host:port` of the peers to download headers, filters and blocks from, tried in order.
+    pub(crate) peers: Vec<String>,
+    /// Where to keep the recent headers and the last processed filter across restarts.
+    #[serde(default = "default_header_store")]
+    pub(crate) header_store: String,
+    /// How long to wait for a peer to respond, in seconds.
+    #[serde(default = "default_cbf_timeout_sec")]
+    pub(crate) timeout_sec: u64,
+}
+
+fn default_header_store() -> String {
+    String::from("smaug-headers.json")
+}
+
+fn default_cbf_timeout_sec() -> u64 {
+    30
+}
+
+/// A connection to a P2P peer, past the `version` handshake.
+#[derive(Debug)]
+struct Peer {
+    address: String,
+    stream: BufReader<TcpStream>,
+    magic: Magic,
+    timeout: Duration,
+}
+
+impl Peer {
+    /// Connect to `address` and perform the `version` handshake, making sure the peer serves compact block filters.
+    fn connect(address: &str, network: Network, timeout: Duration) -> Result<Peer, CbfError> {
+        let stream = proxy::connect_address(address, timeout).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => CbfError::InvalidPeer(address.to_string()),
+            _ => e.into(),
+        })?;
+        // Through a proxy, this is the proxy's address, which reveals nothing about us.
+        let socket_address = stream.peer_addr()?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut peer = Peer {
+            address: address.to_string(),
+            stream: BufReader::new(stream),
+            magic: network.magic(),
+            timeout,
+        };
+        peer.handshake(socket_address)?;
+
+        Ok(peer)
+    }
+
+    fn handshake(&mut self, socket_address: SocketAddr) -> Result<(), CbfError> {
+        let mut nonce = [0u8; 8];
+        getrandom::getrandom(&mut nonce).map_err(io::Error::from)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp as i64,
+            PeerAddress::new(&socket_address, ServiceFlags::NONE),
+            PeerAddress::new(&unspecified, ServiceFlags::NONE),
+            u64::from_le_bytes(nonce),
+            format!("/smaug:{}/", env!("CARGO_PKG_VERSION")),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        self.send(NetworkMessage::Version(version))?;
+
+        let (mut version_received, mut verack_received) = (false, false);
+        self.receive_until("verack", |peer, message| {
+            match message {
+                NetworkMessage::Version(version) => {
+                    if !version
+                        .services
+                        .has(ServiceFlags::COMPACT_FILTERS | ServiceFlags::WITNESS)
+                    {
+                        return Err(CbfError::NoFilters(peer.address.clone()));
+                    }
+                    version_received = true;
+                    peer.send(NetworkMessage::Verack)?;
+                }
+                NetworkMessage::Verack => verack_received = true,
+                _ => {}
+            }
+            Ok((version_received && verack_received).then_some(()))
+        })
+    }
+
+    fn send(&mut self, message: NetworkMessage) -> Result<(), CbfError> {
+        let message = RawNetworkMessage::new(self.magic, message);
+        self.stream.get_mut().write_all(&serialize(&message))?;
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<NetworkMessage, CbfError> {
+        // The magic, the command, the payload length and the payload checksum.
+        let mut message = vec![0u8; 24];
+        self.stream.read_exact(&mut message)?;
+        let length = u32::from_le_bytes([message[16], message[17], message[18], message[19]]) as usize;
+        if length > MAX_MESSAGE_SIZE {
+            return Err(CbfError::Invalid("message"));
+        }
+        message.resize(24 + length, 0);
+        self.stream.read_exact(&mut message[24..])?;
+
+        let message: RawNetworkMessage = deserialize(&message)?;
+        if *message.magic() != self.magic {
+            return Err(CbfError::Invalid("network magic"));
+        }
+
+        Ok(message.into_payload())
+    }
+
+    /// Feed every message received to `handle` until it returns a result, answering pings in the meantime.
+    fn receive_until<T>(
+        &mut self,
+        command: &'static str,
+        mut handle: impl FnMut(&mut Peer, NetworkMessage) -> Result<Option<T>, CbfError>,
+    ) -> Result<T, CbfError> {
+        let deadline = Instant::now() + self.timeout;
+        while Instant::now() < deadline {
+            match self.receive()? {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                message => {
+                    if let Some(result) = handle(self, message)? {
+                        return Ok(result);
+                    }
+                }
+            }
+        }
+
+        Err(CbfError::Timeout(command))
+    }
+}
+
+/// The on-disk representation of [`Headers`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredHeaders {
+    network: Network,
+    base_height: u32,
+    block_hashes: Vec<BlockHash>,
+    filter_height: Option<u32>,
+    filter_headers: BTreeMap<u32, FilterHeader>,
+}
+
+/// The best header chain known, from `base_height` up, and the filter headers checked so far.
+#[derive(Debug)]
+struct Headers {
+    /// The height of the first block hash.
+    base_height: u32,
+    block_hashes: Vec<BlockHash>,
+    /// The height of the last block whose filter was processed.
+    filter_height: Option<u32>,
+    filter_headers: BTreeMap<u32, FilterHeader>,
+}
+
+impl Headers {
+    /// Load the headers from `path`, or start from the genesis block if it does not exist.
+    fn load(path: &Path, network: Network) -> Result<Headers, CbfError> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Headers {
+                    base_height: 0,
+                    block_hashes: vec![genesis_block(network).block_hash()],
+                    filter_height: None,
+                    filter_headers: BTreeMap::new(),
+                });
+            }
+            Err(e) => return Err(CbfError::StoreIo(path.to_path_buf(), e)),
+        };
+        let stored: StoredHeaders =
+            serde_json::from_str(&json).map_err(|e| CbfError::StoreJson(path.to_path_buf(), e))?;
+        if stored.network != network || stored.block_hashes.is_empty() {
+            return Err(CbfError::StoreNetwork(path.to_path_buf(), stored.network));
+        }
+
+        Ok(Headers {
+            base_height: stored.base_height,
+            block_hashes: stored.block_hashes,
+            filter_height: stored.filter_height,
+            filter_headers: stored.filter_headers,
+        })
+    }
+
+    /// Save the headers to `path`, through a temporary file.
+    fn save(&self, path: &Path, network: Network) -> Result<(), CbfError> {
+        let stored = StoredHeaders {
+            network,
+            base_height: self.base_height,
+            block_hashes: self.block_hashes.clone(),
+            filter_height: self.filter_height,
+            filter_headers: self.filter_headers.clone(),
+        };
+        let json = serde_json::to_string(&stored).map_err(|e| CbfError::StoreJson(path.to_path_buf(), e))?;
+
+        write_atomically(path, json.as_bytes()).map_err(|e| CbfError::StoreIo(path.to_path_buf(), e))
+    }
+
+    fn tip_height(&self) -> u32 {
+        self.base_height + self.block_hashes.len() as u32 - 1
+    }
+
+    fn block_hash(&self, height: u32) -> Option<BlockHash> {
+        let index = height.checked_sub(self.base_height)?;
+        self.block_hashes.get(index as usize).copied()
+    }
+
+    /// The hashes to locate the fork point with a peer: the last 10, then exponentially further apart.
+    fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = Vec::new();
+        let mut index = self.block_hashes.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.block_hashes[index]);
+            if index == 0 {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+    }
+
+    /// Connect `headers` to the chain, replacing the blocks they reorganize away.
+    fn connect(&mut self, headers: &[Header]) -> Result<(), CbfError> {
+        let Some(first) = headers.first() else {
+            return Ok(());
+        };
+        let fork_index = self
+            .block_hashes
+            .iter()
+            .rposition(|block_hash| *block_hash == first.prev_blockhash)
+            .ok_or(CbfError::Invalid("header chain"))?;
+
+        let mut prev_blockhash = first.prev_blockhash;
+        for header in headers {
+            if header.prev_blockhash != prev_blockhash || header.validate_pow(header.target()).is_err() {
+                return Err(CbfError::Invalid("header chain"));
+            }
+            prev_blockhash = header.block_hash();
+        }
+
+        if fork_index + 1 < self.block_hashes.len() {
+            let fork_height = self.base_height + fork_index as u32;
+            info!(
+                "Reorg of {} blocks above height {fork_height}",
+                self.tip_height() - fork_height
+            );
+            self.block_hashes.truncate(fork_index + 1);
+            self.filter_headers.split_off(&(fork_height + 1));
+        }
+        self.block_hashes.extend(headers.iter().map(Header::block_hash));
+
+        Ok(())
+    }
+
+    /// Record that the filter of the block at `height` was processed, dropping what is no longer needed below it.
+    fn processed(&mut self, height: u32) {
+        self.filter_height = Some(height);
+
+        let keep_from = height.saturating_sub(REORG_WINDOW).max(self.base_height);
+        self.block_hashes.drain(..(keep_from - self.base_height) as usize);
+        self.base_height = keep_from;
+        self.filter_headers = self.filter_headers.split_off(&keep_from);
+    }
+}
+
+/// What [`CbfClient`] keeps across requests.
+#[derive(Debug)]
+struct CbfState {
+    peer: Option<Peer>,
+    headers: Headers,
+    /// Filters downloaded ahead of being requested, by height.
+    filters: BTreeMap<u32, BlockFilter>,
+}
+
+/// A [`ChainSource`] downloading headers, BIP158 compact block filters and blocks from P2P peers (BIP157).
+///
+/// Peers never learn which scripts are watched: filters are matched locally, and only the blocks they match are
+/// downloaded. Addresses cannot be queried, so it only works with `detection = "scan"`.
+#[derive(Debug)]
+pub(crate) struct CbfClient {
+    network: Network,
+    peers: Vec<String>,
+    header_store: PathBuf,
+    timeout: Duration,
+    state: Mutex<CbfState>,
+}
+
+impl CbfClient {
+    /// Build a client from the `[cbf]` section of the configuration, resuming from the header store if it exists.
+    pub(crate) fn new(network: Network, config: &CbfConfig) -> Result<CbfClient, CbfError> {
+        if config.peers.is_empty() {
+            return Err(CbfError::NoPeers);
+        }
+
+        let header_store = PathBuf::from(&config.header_store);
+        let headers = Headers::load(&header_store, network)?;
+        if let Some(filter_height) = headers.filter_height {
+            info!(
+                "Loaded headers from `{}`, resuming after the filter at height {filter_height}",
+                header_store.display()
+            );
+        }
+
+        Ok(CbfClient {
+            network,
+            peers: config.peers.clone(),
+            header_store,
+            timeout: Duration::from_secs(config.timeout_sec),
+            state: Mutex::new(CbfState {
+                peer: None,
+                headers,
+                filters: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Run `request` against the connected peer, connecting to the first reachable one if needed.
+    ///
+    /// The connection is dropped if the request fails, so the next one starts afresh.
+    fn with_peer<T>(
+        &self,
+        state: &mut CbfState,
+        request: impl FnOnce(&mut Peer, &mut Headers) -> Result<T, CbfError>,
+    ) -> Result<T, CbfError> {
+        let mut peer = match state.peer.take() {
+            Some(peer) => peer,
+            None => self.connect()?,
+        };
+
+        let result = request(&mut peer, &mut state.headers);
+        match &result {
+            Ok(_) => state.peer = Some(peer),
+            Err(e) => warn!("Disconnecting from peer `{}`: {e}", peer.address),
+        }
+
+        result
+    }
+
+    fn connect(&self) -> Result<Peer, CbfError> {
+        for address in &self.peers {
+            match Peer::connect(address, self.network, self.timeout) {
+                Ok(peer) => {
+                    info!("Connected to peer `{address}`");
+                    return Ok(peer);
+                }
+                Err(e) => warn!("Failed to connect to peer `{address}`: {e}"),
+            }
+        }
+
+        Err(CbfError::Unreachable)
+    }
+
+    fn save(&self, headers: &Headers) {
+        if let Err(e) = headers.save(&self.header_store, self.network) {
+            warn!("Failed to save headers: {e}");
+        }
+    }
+}
+
+/// Download headers from `peer` until it has no more to send.
+fn sync_headers(peer: &mut Peer, headers: &mut Headers) -> Result<(), CbfError> {
+    loop {
+        let locator = headers.locator();
+        peer.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+            locator,
+            BlockHash::all_zeros(),
+        )))?;
+        let new_headers = peer.receive_until("headers", |_, message| match message {
+            NetworkMessage::Headers(new_headers) => Ok(Some(new_headers)),
+            _ => Ok(None),
+        })?;
+
+        headers.connect(&new_headers)?;
+        if new_headers.len() < MAX_HEADERS_PER_REQUEST {
+            return Ok(());
+        }
+        debug!("Synced headers up to height {}", headers.tip_height());
+    }
+}
+
+/// Download the filters of the blocks from `start_height` up to `stop_height` from `peer`, checking them against the
+/// filter headers the peer commits to.
+fn fetch_filters(
+    peer: &mut Peer,
+    headers: &mut Headers,
+    start_height: u32,
+    stop_height: u32,
+) -> Result<Vec<(u32, BlockFilter)>, CbfError> {
+    let stop_hash = headers
+        .block_hash(stop_height)
+        .ok_or(CbfError::Invalid("header chain"))?;
+
+    peer.send(NetworkMessage::GetCFHeaders(GetCFHeaders {
+        filter_type: BASIC_FILTER,
+        start_height,
+        stop_hash,
+    }))?;
+    let cfheaders = peer.receive_until("cfheaders", |_, message| match message {
+        NetworkMessage::CFHeaders(cfheaders) if cfheaders.stop_hash == stop_hash => Ok(Some(cfheaders)),
+        _ => Ok(None),
+    })?;
+    if cfheaders.filter_hashes.len() != (stop_height - start_height + 1) as usize {
+        return Err(CbfError::Invalid("filter headers"));
+    }
+    // The filter headers must extend the ones already checked.
+    if let Some(previous) = start_height
+        .checked_sub(1)
+        .and_then(|height| headers.filter_headers.get(&height))
+        && *previous != cfheaders.previous_filter_header
+    {
+        return Err(CbfError::Invalid("filter headers"));
+    }
+
+    peer.send(NetworkMessage::GetCFilters(GetCFilters {
+        filter_type: BASIC_FILTER,
+        start_height,
+        stop_hash,
+    }))?;
+    let mut filters = Vec::new();
+    let mut previous_filter_header = cfheaders.previous_filter_header;
+    peer.receive_until("cfilter", |_, message| {
+        let NetworkMessage::CFilter(cfilter) = message else {
+            return Ok(None);
+        };
+
+        let height = start_height + filters.len() as u32;
+        let filter_hash = FilterHash::hash(&cfilter.filter);
+        if headers.block_hash(height) != Some(cfilter.block_hash)
+            || filter_hash != cfheaders.filter_hashes[filters.len()]
+        {
+            return Err(CbfError::Invalid("filter"));
+        }
+        previous_filter_header = filter_hash.filter_header(&previous_filter_header);
+        headers.filter_headers.insert(height, previous_filter_header);
+        filters.push((height, BlockFilter::new(&cfilter.filter)));
+
+        Ok((height == stop_height).then_some(()))
+    })?;
+
+    Ok(filters)
+}
+
+impl ChainSource for CbfClient {
+    fn height(&self) -> Result<u32, ChainError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.with_peer(&mut state, sync_headers)?;
+        // The filters downloaded ahead may belong to blocks that were just reorganized away.
+        state.filters.clear();
+
+        // Before the first filter is processed, the whole chain may be held, which is not worth saving.
+        if state.headers.filter_height.is_some() {
+            self.save(&state.headers);
+        }
+
+        Ok(state.headers.tip_height())
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        state.headers.block_hash(height).ok_or(ChainError::MissingBlock(height))
+    }
+
+    fn block(&self, height: u32) -> Result<Block, ChainError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let block_hash = state
+            .headers
+            .block_hash(height)
+            .ok_or(ChainError::MissingBlock(height))?;
+
+        let block = self.with_peer(&mut state, |peer, _| {
+            peer.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)]))?;
+            peer.receive_until("block", |_, message| match message {
+                NetworkMessage::Block(block) if block.block_hash() == block_hash => Ok(Some(Some(block))),
+                NetworkMessage::NotFound(_) => Ok(Some(None)),
+                _ => Ok(None),
+            })
+        })?;
+
+        let block = block.ok_or(ChainError::MissingBlock(height))?;
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return Err(CbfError::Invalid("block").into());
+        }
+
+        Ok(block)
+    }
+
+    fn block_filter(&self, height: u32) -> Result<Option<BlockFilter>, ChainError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if !state.filters.contains_key(&height) {
+            // Download the filters ahead, so catching up does not take a round trip per block.
+            state.filters.clear();
+            let tip_height = state.headers.tip_height();
+            if height > tip_height {
+                return Err(ChainError::MissingBlock(height));
+            }
+            let stop_height = tip_height.min(height + MAX_FILTERS_PER_REQUEST - 1);
+            let filters = self.with_peer(&mut state, |peer, headers| {
+                fetch_filters(peer, headers, height, stop_height)
+            })?;
+            state.filters.extend(filters);
+        }
+        let filter = state.filters.remove(&height).ok_or(ChainError::MissingBlock(height))?;
+
+        // Every block below `height` was processed by now.
+        if let Some(processed) = height.checked_sub(1) {
+            state.headers.processed(processed);
+            self.save(&state.headers);
+        }
+
+        Ok(Some(filter))
+    }
+
+    fn address_utxos(&self, _address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        Err(ChainError::Unsupported("address queries"))
+    }
+
+    fn address_txs(&self, _address: &Address, _last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        Err(ChainError::Unsupported("address queries"))
+    }
+
+    fn mempool_address_txs(&self, _address: &Address) -> Result<Vec<Tx>, ChainError> {
+        Err(ChainError::Unsupported("address queries"))
+    }
+
+    fn spending_txid(&self, _txid: &Txid, _vout: u32) -> Result<Option<Txid>, ChainError> {
+        Err(ChainError::Unsupported("transactions"))
+    }
+
+    fn tx(&self, _txid: &Txid) -> Result<Option<Tx>, ChainError> {
+        Err(ChainError::Unsupported("transactions"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, iter, net::TcpListener, thread};
+
+    use bitcoin::{
+        Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness, absolute::LockTime,
+        bip158, block::Version, p2p::message_filter::CFHeaders, p2p::message_filter::CFilter, transaction,
+    };
+
+    use super::*;
+
+    fn mine(prev: &Header, script_pubkey: ScriptBuf, tag: u8) -> Block {
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![0x01, tag]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50_0000_0000),
+                script_pubkey,
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: prev.time + 600,
+                bits: prev.bits,
+                nonce: 0,
+            },
+            txdata: vec![coinbase],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        block
+    }
+
+    /// A regtest chain of `scripts.len()` blocks on top of the genesis block, each paying to one of `scripts`.
+    fn chain(scripts: &[ScriptBuf], tag: u8) -> Vec<Block> {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        for script_pubkey in scripts {
+            let block = mine(&blocks.last().unwrap().header, script_pubkey.clone(), tag);
+            blocks.push(block);
+        }
+
+        blocks
+    }
+
+    fn filter(block: &Block) -> BlockFilter {
+        BlockFilter::new_script_filter(block, |outpoint| {
+            Err::<ScriptBuf, _>(bip158::Error::UtxoMissing(*outpoint))
+        })
+        .unwrap()
+    }
+
+    /// Serve `blocks` and their filters to every peer connecting to the returned address.
+    fn peer_stand_in(blocks: Vec<Block>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let filters: Vec<BlockFilter> = blocks.iter().map(filter).collect();
+        let mut filter_headers = Vec::new();
+        for filter in &filters {
+            let previous = filter_headers.last().copied().unwrap_or(FilterHeader::all_zeros());
+            filter_headers.push(filter.filter_header(&previous));
+        }
+        let height_of = move |blocks: &[Block], block_hash: BlockHash| {
+            blocks
+                .iter()
+                .position(|block| block.block_hash() == block_hash)
+                .unwrap()
+        };
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut peer = Peer {
+                    address: String::from("smaug"),
+                    stream: BufReader::new(stream.unwrap()),
+                    magic: Network::Regtest.magic(),
+                    timeout: Duration::from_secs(5),
+                };
+                while let Ok(message) = peer.receive() {
+                    let replies = match message {
+                        NetworkMessage::Version(version) => {
+                            let mut version = version.clone();
+                            version.services =
+                                ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+                            vec![NetworkMessage::Version(version), NetworkMessage::Verack]
+                        }
+                        NetworkMessage::GetHeaders(request) => {
+                            let start = request
+                                .locator_hashes
+                                .iter()
+                                .find_map(|block_hash| {
+                                    blocks.iter().position(|block| block.block_hash() == *block_hash)
+                                })
+                                .unwrap();
+                            let headers = blocks[start + 1..].iter().map(|block| block.header).collect();
+                            vec![NetworkMessage::Headers(headers)]
+                        }
+                        NetworkMessage::GetCFHeaders(request) => {
+                            let (start, stop) = (request.start_height as usize, height_of(&blocks, request.stop_hash));
+                            vec![NetworkMessage::CFHeaders(CFHeaders {
+                                filter_type: BASIC_FILTER,
+                                stop_hash: request.stop_hash,
+                                previous_filter_header: match start {
+                                    0 => FilterHeader::all_zeros(),
+                                    _ => filter_headers[start - 1],
+                                },
+                                filter_hashes: filters[start..=stop]
+                                    .iter()
+                                    .map(|filter| FilterHash::hash(&filter.content))
+                                    .collect(),
+                            })]
+                        }
+                        NetworkMessage::GetCFilters(request) => {
+                            let (start, stop) = (request.start_height as usize, height_of(&blocks, request.stop_hash));
+                            (start..=stop)
+                                .map(|height| {
+                                    NetworkMessage::CFilter(CFilter {
+                                        filter_type: BASIC_FILTER,
+                                        block_hash: blocks[height].block_hash(),
+                                        filter: filters[height].content.clone(),
+                                    })
+                                })
+                                .collect()
+                        }
+                        NetworkMessage::GetData(inventory) => inventory
+                            .iter()
+                            .filter_map(|inventory| match inventory {
+                                Inventory::WitnessBlock(block_hash) => {
+                                    Some(NetworkMessage::Block(blocks[height_of(&blocks, *block_hash)].clone()))
+                                }
+                                _ => None,
+                            })
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    for reply in replies {
+                        peer.send(reply).unwrap();
+                    }
+                }
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn filters_match_and_resume_from_header_store() {
+        let watched = ScriptBuf::from_bytes([[0x00, 0x14].as_slice(), &[0x13; 20]].concat());
+        let elsewhere = ScriptBuf::from_bytes([[0x00, 0x14].as_slice(), &[0x37; 20]].concat());
+        let blocks = chain(&[elsewhere.clone(), watched.clone(), elsewhere], 0);
+        let header_store = env::temp_dir().join(format!("smaug-headers-{}.json", std::process::id()));
+        let config = CbfConfig {
+            peers: vec![String::from("127.0.0.1:1"), peer_stand_in(blocks.clone())],
+            header_store: header_store.to_string_lossy().into_owned(),
+            timeout_sec: 5,
+        };
+
+        let client = CbfClient::new(Network::Regtest, &config).unwrap();
+        assert_eq!(client.height().unwrap(), 3);
+        for height in 1..=3 {
+            let filter = client.block_filter(height).unwrap().unwrap();
+            let block_hash = client.block_hash(height).unwrap();
+            assert_eq!(block_hash, blocks[height as usize].block_hash());
+            assert_eq!(
+                filter.match_any(&block_hash, iter::once(watched.as_bytes())).unwrap(),
+                height == 2
+            );
+        }
+        assert_eq!(client.block(2).unwrap(), blocks[2]);
+
+        // A restart resumes from the header store, without connecting to a peer.
+        let client = CbfClient::new(Network::Regtest, &config).unwrap();
+        let state = client.state.lock().unwrap();
+        assert_eq!(state.headers.filter_height, Some(2));
+        assert_eq!(state.headers.block_hash(3), Some(blocks[3].block_hash()));
+        assert!(state.headers.filter_headers.contains_key(&3));
+        drop(state);
+
+        fs::remove_file(&header_store).unwrap();
+    }
+
+    #[test]
+    fn reorg_replaces_blocks_above_fork() {
+        let script_pubkey = ScriptBuf::new_op_return([0x13, 0x37]);
+        let stale = chain(&[script_pubkey.clone(), script_pubkey.clone()], 0);
+        let best = chain(&[script_pubkey.clone(), script_pubkey.clone(), script_pubkey], 1);
+
+        let mut headers = Headers::load(Path::new("/nonexistent/smaug-headers.json"), Network::Regtest).unwrap();
+        headers
+            .connect(&stale[1..].iter().map(|block| block.header).collect::<Vec<_>>())
+            .unwrap();
+        headers.filter_headers.insert(2, FilterHeader::all_zeros());
+        assert_eq!(headers.tip_height(), 2);
+
+        headers
+            .connect(&best[1..].iter().map(|block| block.header).collect::<Vec<_>>())
+            .unwrap();
+        assert_eq!(headers.tip_height(), 3);
+        assert_eq!(headers.block_hash(1), Some(best[1].block_hash()));
+        assert!(headers.filter_headers.is_empty());
+
+        // Headers that do not connect to the chain are rejected.
+        let orphan = mine(&best[3].header, ScriptBuf::new(), 2);
+        let orphan = mine(&orphan.header, ScriptBuf::new(), 2);
+        assert!(headers.connect(&[orphan.header]).is_err());
+    }
+}
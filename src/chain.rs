@@ -0,0 +1,448 @@
+use std::{process, thread, time::Duration};
+
+use bitcoin::{
+    Address, Amount, Block, BlockHash, Network, Transaction, TxOut, Txid, bip158::BlockFilter, block::Header,
+};
+use esplora_client::{AddressStats, MerkleProof, PrevOut, Tx, TxStatus, Utxo, Vin, Vout};
+use log::{error, info, warn};
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+use crate::bitcoind::{BitcoindClient, BitcoindError};
+use crate::cbf::{CbfClient, CbfError};
+use crate::electrum::{ElectrumClient, ElectrumError};
+use crate::proxy;
+use crate::quorum::EsploraBackends;
+use crate::smaug::{BITCOIN_ESPLORA, Event, SIGNET_ESPLORA, TESTNET4_ESPLORA};
+use crate::spv::{SpvError, SpvVerifier};
+use crate::{Config, Detection, EsploraStrategy};
+
+/// Errors that happen while querying a [`ChainSource`].
+#[derive(Debug, Error)]
+pub enum ChainError {
+    /// Error querying the Esplora API.
+    #[error(transparent)]
+    Esplora(#[from] esplora_client::Error),
+
+    /// The Esplora API rate limits `smaug` for longer than it is willing to wait.
+    #[error(
+        "Esplora API `{0}` is rate limiting requests for the next {1} seconds: lower `esplora_concurrency` or use \
+         your own Esplora API"
+    )]
+    RateLimited(String, u64),
+
+    /// The Esplora API refuses to serve the history of an address with that many transactions.
+    #[error(
+        "Esplora API `{0}` refuses to serve an address with that many transactions: watch it through bitcoind or an \
+         Electrum server, or use your own Esplora API with higher limits"
+    )]
+    Oversized(String),
+
+    /// Error querying bitcoind over JSON-RPC.
+    #[error(transparent)]
+    Bitcoind(#[from] BitcoindError),
+
+    /// Error querying an Electrum server.
+    #[error(transparent)]
+    Electrum(#[from] ElectrumError),
+
+    /// Error querying P2P peers for compact block filters.
+    #[error(transparent)]
+    Cbf(#[from] CbfError),
+
+    /// Error verifying the answers of the chain source against the header chain.
+    #[error(transparent)]
+    Spv(#[from] SpvError),
+
+    /// Error matching a compact block filter.
+    #[error(transparent)]
+    Filter(#[from] bitcoin::bip158::Error),
+
+    /// The chain source does not have a block.
+    #[error("block {0} is not available")]
+    MissingBlock(u32),
+
+    /// The chain source cannot serve something `smaug` needs.
+    #[error("the chain source does not serve {0}")]
+    Unsupported(&'static str),
+}
+
+/// A cheap fingerprint of the activity on an address, which changes whenever a transaction touching it is seen or
+/// confirmed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AddressStatus {
+    /// The confirmed and mempool stats of the address, from every Esplora API queried.
+    Esplora(Vec<AddressStats>),
+    /// The Electrum status of the script of the address, `None` if it has no history.
+    Electrum(Option<String>),
+}
+
+impl AddressStatus {
+    /// Whether `utxos` are consistent with the status, so it can stand for them until it changes.
+    ///
+    /// The UTXOs may have been fetched from another server than the status, behind a load balancer or after a
+    /// failover, which may not have seen the same transactions yet.
+    pub(crate) fn matches(&self, utxos: &[Utxo]) -> bool {
+        match self {
+            AddressStatus::Esplora(stats) => {
+                let balance = utxos.iter().map(|utxo| utxo.value).sum::<Amount>().to_sat();
+                stats.iter().all(|stats| {
+                    let funded = stats.chain_stats.funded_txo_sum + stats.mempool_stats.funded_txo_sum;
+                    let spent = stats.chain_stats.spent_txo_sum + stats.mempool_stats.spent_txo_sum;
+                    funded.checked_sub(spent) == Some(balance)
+                })
+            }
+            // Electrum clients cache the UTXOs of a script by its status already.
+            AddressStatus::Electrum(_) => true,
+        }
+    }
+
+    /// Whether the address has any history, confirmed or not.
+    pub(crate) fn is_used(&self) -> bool {
+        match self {
+            AddressStatus::Esplora(stats) => stats
+                .iter()
+                .any(|stats| stats.chain_stats.tx_count + stats.mempool_stats.tx_count > 0),
+            AddressStatus::Electrum(status) => status.is_some(),
+        }
+    }
+}
+
+/// Where `smaug` gets the state of the chain and of the watched addresses from.
+///
+/// Transactions and UTXOs are returned as Esplora types, whatever the backend.
+pub(crate) trait ChainSource {
+    /// Start watching `addresses`, for backends that must be told about them before they can be queried.
+    fn watch(&self, _addresses: &[Address]) -> Result<(), ChainError> {
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the chain source to push a change: a new block, or new activity on a watched address.
+    ///
+    /// Returns whether a change was pushed. Sources that cannot push changes just sleep.
+    fn wait_for_changes(&self, timeout: Duration) -> bool {
+        thread::sleep(timeout);
+        false
+    }
+
+    /// The height of the chain tip.
+    fn height(&self) -> Result<u32, ChainError>;
+
+    /// The hash of the block at `height`.
+    fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError>;
+
+    /// The block at `height`.
+    fn block(&self, _height: u32) -> Result<Block, ChainError> {
+        Err(ChainError::Unsupported("blocks"))
+    }
+
+    /// The header of the block `block_hash`.
+    fn header(&self, _block_hash: &BlockHash) -> Result<Header, ChainError> {
+        Err(ChainError::Unsupported("block headers"))
+    }
+
+    /// The BIP158 basic filter of the block at `height`, if the chain source serves them.
+    ///
+    /// Blocks are downloaded without consulting a filter first, if it does not.
+    fn block_filter(&self, _height: u32) -> Result<Option<BlockFilter>, ChainError> {
+        Ok(None)
+    }
+
+    /// The UTXOs locked to `address`, including unconfirmed ones.
+    fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError>;
+
+    /// The UTXOs locked to each of `addresses`, in order.
+    ///
+    /// Sources that can query addresses concurrently override this: the others query them one after the other, waiting
+    /// for their jitter in between.
+    fn addresses_utxos(&self, addresses: &[&Address]) -> Result<Vec<Vec<Utxo>>, ChainError> {
+        addresses
+            .iter()
+            .map(|address| {
+                proxy::jitter();
+                self.address_utxos(address)
+            })
+            .collect()
+    }
+
+    /// The [`AddressStatus`] of each of `addresses`, in order, or `None` for the addresses whose status the chain
+    /// source cannot tell cheaply.
+    ///
+    /// The UTXOs of an address are only fetched again once its status changed, or when it is `None`.
+    fn addresses_status(&self, addresses: &[&Address]) -> Result<Vec<Option<AddressStatus>>, ChainError> {
+        Ok(vec![None; addresses.len()])
+    }
+
+    /// Whether each of `addresses`, in order, has any history, even if it holds no UTXO anymore.
+    ///
+    /// Told from the [`AddressStatus`] of the addresses, or else from the first page of their history.
+    fn addresses_used(&self, addresses: &[&Address]) -> Result<Vec<bool>, ChainError> {
+        let statuses = self.addresses_status(addresses)?;
+        addresses
+            .iter()
+            .zip(statuses)
+            .map(|(address, status)| match status {
+                Some(status) => Ok(status.is_used()),
+                None => {
+                    proxy::jitter();
+                    Ok(!self.address_txs(address, None)?.is_empty())
+                }
+            })
+            .collect()
+    }
+
+    /// The transactions touching `address`, newest first, mempool transactions included.
+    ///
+    /// Only confirmed transactions older than `last_seen` are returned when it is set, for paging.
+    fn address_txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError>;
+
+    /// The first page of the transactions touching each of `addresses`, in order, like
+    /// [`addresses_utxos`](ChainSource::addresses_utxos).
+    fn addresses_txs(&self, addresses: &[&Address]) -> Result<Vec<Vec<Tx>>, ChainError> {
+        addresses
+            .iter()
+            .map(|address| {
+                proxy::jitter();
+                self.address_txs(address, None)
+            })
+            .collect()
+    }
+
+    /// The mempool transactions touching `address`.
+    fn mempool_address_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError>;
+
+    /// The mempool transactions touching each of `addresses`, in order, like
+    /// [`addresses_utxos`](ChainSource::addresses_utxos).
+    fn addresses_mempool_txs(&self, addresses: &[&Address]) -> Result<Vec<Vec<Tx>>, ChainError> {
+        addresses
+            .iter()
+            .map(|address| {
+                proxy::jitter();
+                self.mempool_address_txs(address)
+            })
+            .collect()
+    }
+
+    /// The txid of the transaction spending an output, if it is spent.
+    fn spending_txid(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, ChainError>;
+
+    /// A transaction, with its prevouts and confirmation status.
+    fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError>;
+
+    /// The proof that the confirmed transaction `txid` is included in its block, if it is confirmed.
+    fn merkle_proof(&self, _txid: &Txid) -> Result<Option<MerkleProof>, ChainError> {
+        Err(ChainError::Unsupported("merkle proofs"))
+    }
+
+    /// The [`Event`]s about the chain source itself raised since the last call: disagreements between its backends,
+    /// or answers that failed verification.
+    fn take_events(&self) -> Vec<Event> {
+        Vec::new()
+    }
+}
+
+/// Convert a [`Transaction`] to a [`Tx`], given the prevout of every input (`None` if unknown).
+///
+/// The fee is only known if every prevout is, and is 0 otherwise.
+pub(crate) fn tx_from_transaction(transaction: &Transaction, prevouts: Vec<Option<TxOut>>, status: TxStatus) -> Tx {
+    let vin: Vec<Vin> = transaction
+        .input
+        .iter()
+        .zip(prevouts)
+        .map(|(input, prevout)| Vin {
+            txid: input.previous_output.txid,
+            vout: input.previous_output.vout,
+            prevout: prevout.map(|output| PrevOut {
+                value: output.value.to_sat(),
+                scriptpubkey: output.script_pubkey,
+            }),
+            scriptsig: input.script_sig.clone(),
+            witness: input.witness.to_vec(),
+            sequence: input.sequence.0,
+            is_coinbase: input.previous_output.is_null(),
+        })
+        .collect();
+
+    let vout: Vec<Vout> = transaction
+        .output
+        .iter()
+        .map(|output| Vout {
+            value: output.value.to_sat(),
+            scriptpubkey: output.script_pubkey.clone(),
+        })
+        .collect();
+
+    let fee = match vin.iter().map(|vin| vin.prevout.as_ref()).collect::<Option<Vec<_>>>() {
+        Some(prevouts) if !transaction.is_coinbase() => {
+            let input_value: u64 = prevouts.iter().map(|prevout| prevout.value).sum();
+            let output_value: u64 = vout.iter().map(|vout| vout.value).sum();
+            input_value.saturating_sub(output_value)
+        }
+        _ => 0,
+    };
+
+    Tx {
+        txid: transaction.compute_txid(),
+        version: transaction.version.0,
+        locktime: transaction.lock_time.to_consensus_u32(),
+        vin,
+        vout,
+        size: transaction.total_size(),
+        weight: transaction.weight().to_wu(),
+        status,
+        fee,
+    }
+}
+
+/// Convert a [`Transaction`] to a [`Tx`], looking up the prevout of every input in the transaction `fetch` returns
+/// for its txid (`None` if unknown).
+pub(crate) fn tx_with_prevouts<E>(
+    transaction: &Transaction,
+    status: TxStatus,
+    mut fetch: impl FnMut(&Txid) -> Result<Option<Transaction>, E>,
+) -> Result<Tx, E> {
+    let mut prevouts = Vec::new();
+    for input in &transaction.input {
+        let outpoint = input.previous_output;
+        prevouts.push(match outpoint.is_null() {
+            true => None,
+            false => fetch(&outpoint.txid)?.and_then(|previous| previous.output.get(outpoint.vout as usize).cloned()),
+        });
+    }
+
+    Ok(tx_from_transaction(transaction, prevouts, status))
+}
+
+/// The page of an address history, newest first, that follows `last_seen`.
+///
+/// For backends that return the whole history at once: only the confirmed transactions older than `last_seen` are
+/// left for its next page.
+pub(crate) fn page_after(mut txs: Vec<Tx>, last_seen: Option<Txid>) -> Vec<Tx> {
+    let Some(last_seen) = last_seen else {
+        return txs;
+    };
+
+    let mut page = match txs.iter().position(|tx| tx.txid == last_seen) {
+        Some(position) => txs.split_off(position + 1),
+        None => Vec::new(),
+    };
+    page.retain(|tx| tx.status.confirmed);
+
+    page
+}
+
+/// Build the [`ChainSource`] selected in the configuration.
+///
+/// bitcoind is used if configured, else an Electrum server if configured, else P2P peers serving compact block filters
+/// if configured, else the configured or default Esplora APIs, verified against a header chain if `[spv]` is set.
+///
+/// The requests to the Esplora APIs run on `runtime`.
+pub(crate) fn chain_source_from_config(config: &Config, runtime: &Handle) -> Result<Box<dyn ChainSource>, ChainError> {
+    if config.spv.is_some() && (config.bitcoind.is_some() || config.electrum.is_some() || config.cbf.is_some()) {
+        warn!("`[spv]` only verifies Esplora APIs, ignoring it");
+    }
+
+    if let Some(bitcoind) = &config.bitcoind {
+        if !config.esplora_url.is_empty() || config.electrum.is_some() || config.cbf.is_some() {
+            warn!("`[bitcoind]` is set, ignoring `esplora_url`, `[electrum]` and `[cbf]`");
+        }
+
+        let client = BitcoindClient::new(config.network, bitcoind)?;
+        info!(
+            "Using bitcoind JSON-RPC: {} (wallet `{}`)",
+            client.url(),
+            bitcoind.wallet
+        );
+        return Ok(Box::new(client));
+    }
+
+    if let Some(electrum) = &config.electrum {
+        if !config.esplora_url.is_empty() || config.cbf.is_some() {
+            warn!("`[electrum]` is set, ignoring `esplora_url` and `[cbf]`");
+        }
+
+        if config.detection == Detection::Scan {
+            return Err(ChainError::Unsupported("blocks"));
+        }
+
+        let client = ElectrumClient::new(electrum)?;
+        info!("Using Electrum server: {}", client.url());
+        return Ok(Box::new(client));
+    }
+
+    if let Some(cbf) = &config.cbf {
+        if !config.esplora_url.is_empty() {
+            warn!("`[cbf]` is set, ignoring `esplora_url`");
+        }
+
+        // Peers only serve blocks and filters, so addresses can only be watched by scanning blocks.
+        if config.detection != Detection::Scan {
+            return Err(ChainError::Unsupported("address queries"));
+        }
+
+        let client = CbfClient::new(config.network, cbf)?;
+        info!("Using compact block filters from P2P peers: {}", cbf.peers.join(", "));
+        return Ok(Box::new(client));
+    }
+
+    if config.esplora_strategy == EsploraStrategy::Quorum && config.esplora_url.len() < 2 {
+        warn!("`esplora_strategy` is `quorum`, but there is nothing to compare with less than 2 `esplora_url`");
+    }
+
+    let urls = match config.esplora_url.as_slice() {
+        [url] => {
+            info!("Using configured Esplora API: {url}");
+            vec![url.clone()]
+        }
+        [] => {
+            let base_url = match &config.network {
+                Network::Bitcoin => {
+                    info!("Using default Bitcoin Esplora API: {BITCOIN_ESPLORA}");
+                    BITCOIN_ESPLORA
+                }
+                Network::Signet => {
+                    info!("Using default Signet Esplora API: {SIGNET_ESPLORA}");
+                    SIGNET_ESPLORA
+                }
+                Network::Testnet4 => {
+                    info!("Using default Testnet4 Esplora API: {TESTNET4_ESPLORA}");
+                    TESTNET4_ESPLORA
+                }
+                _ => {
+                    error!("Other networks are not supported");
+                    process::exit(1);
+                }
+            };
+            vec![base_url.to_string()]
+        }
+        urls => {
+            info!(
+                "Using {} Esplora APIs ({:?}): {}",
+                urls.len(),
+                config.esplora_strategy,
+                urls.join(", ")
+            );
+            urls.to_vec()
+        }
+    };
+    let esplora: Box<dyn ChainSource> = Box::new(EsploraBackends::new(
+        &urls,
+        config.esplora_strategy,
+        config.quorum_tolerance,
+        config.esplora_concurrency,
+        runtime.clone(),
+    )?);
+
+    // Check the answers of the Esplora APIs against a header chain, if configured.
+    match &config.spv {
+        Some(spv) => {
+            let verifier = SpvVerifier::new(esplora, config.network, spv)?;
+            info!(
+                "Verifying Esplora answers against headers from height {}",
+                verifier.checkpoint_height()
+            );
+            Ok(Box::new(verifier))
+        }
+        None => Ok(esplora),
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+
+use bitcoin::{
+    Network,
+    address::{Address, NetworkUnchecked},
+};
+use esplora_client::Utxo;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::chain::ChainSource;
+use crate::smaug::{Event, EventParams, MilestoneParams, Movement, SmaugError, Spend, UtxoDB, fetch_spend};
+use crate::state::StoredUtxo;
+use crate::watchlist::WatchList;
+
+/// How many confirmations deposits and withdrawals are notified at.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ConfirmationsConfig {
+    /// The confirmation depths to notify deposits at. The first one notifies the deposit itself, the later ones
+    /// follow up on it.
+    #[serde(default = "default_milestones")]
+    pub(crate) deposits: Vec<u32>,
+    /// The confirmation depths to notify withdrawals at, like `deposits`.
+    #[serde(default = "default_milestones")]
+    pub(crate) withdrawals: Vec<u32>,
+    /// Depths for specific addresses, overriding `deposits` and `withdrawals`.
+    #[serde(default)]
+    pub(crate) addresses: Vec<AddressConfirmations>,
+}
+
+/// The confirmation depths to notify the movements of one address at.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AddressConfirmations {
+    /// The address these depths apply to.
+    pub(crate) address: Address<NetworkUnchecked>,
+    /// The depths to notify deposits at, instead of the default ones.
+    #[serde(default)]
+    pub(crate) deposits: Option<Vec<u32>>,
+    /// The depths to notify withdrawals at, instead of the default ones.
+    #[serde(default)]
+    pub(crate) withdrawals: Option<Vec<u32>>,
+}
+
+fn default_milestones() -> Vec<u32> {
+    vec![0]
+}
+
+/// The confirmation depths to notify the deposits and withdrawals of an address at.
+#[derive(Clone, Debug)]
+struct Milestones {
+    deposits: Vec<u32>,
+    withdrawals: Vec<u32>,
+}
+
+impl Milestones {
+    fn of(&self, movement: Movement) -> &[u32] {
+        match movement {
+            Movement::Deposit => &self.deposits,
+            Movement::Withdrawal => &self.withdrawals,
+        }
+    }
+}
+
+/// Sort the configured depths, dropping duplicates.
+fn sorted(mut milestones: Vec<u32>) -> Vec<u32> {
+    milestones.sort_unstable();
+    milestones.dedup();
+    milestones
+}
+
+/// A deposit or withdrawal with milestones left to notify.
+#[derive(Debug)]
+struct Tracked {
+    movement: Movement,
+    params: EventParams,
+    /// The depths left to notify at, in ascending order.
+    milestones: Vec<u32>,
+    /// Whether the deposit or withdrawal itself was notified already.
+    notified: bool,
+}
+
+impl Tracked {
+    fn is(&self, movement: Movement, utxo: &Utxo) -> bool {
+        self.movement == movement && self.params.utxo.txid == utxo.txid && self.params.utxo.vout == utxo.vout
+    }
+
+    /// How many confirmations the deposit or withdrawal has at `height`.
+    ///
+    /// A withdrawal whose spending transaction is unknown is assumed to have confirmed when it was detected.
+    fn confirmations(&self, height: u32) -> u32 {
+        let block_height = match (self.movement, &self.params.spend) {
+            (Movement::Deposit, _) => self.params.utxo.status.block_height,
+            (Movement::Withdrawal, Some(spend)) => spend.status.block_height,
+            (Movement::Withdrawal, None) => Some(self.params.height),
+        };
+
+        block_height.map_or(0, |block_height| (height + 1).saturating_sub(block_height))
+    }
+}
+
+/// A [`Tracked`] movement as stored in the state file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StoredTracked {
+    movement: Movement,
+    address: Address<NetworkUnchecked>,
+    utxo: StoredUtxo,
+    height: u32,
+    after_restart: bool,
+    spend: Option<Spend>,
+    milestones: Vec<u32>,
+    notified: bool,
+}
+
+impl From<&Tracked> for StoredTracked {
+    fn from(tracked: &Tracked) -> Self {
+        StoredTracked {
+            movement: tracked.movement,
+            address: tracked.params.address.as_unchecked().clone(),
+            utxo: StoredUtxo::from(&tracked.params.utxo),
+            height: tracked.params.height,
+            after_restart: tracked.params.after_restart,
+            spend: tracked.params.spend.clone(),
+            milestones: tracked.milestones.clone(),
+            notified: tracked.notified,
+        }
+    }
+}
+
+/// Holds back deposits and withdrawals until they reach their first confirmation milestone, and follows them up at
+/// every later one.
+///
+/// Without a `[confirmations]` section, every [`Event`] is passed through as is.
+#[derive(Debug, Default)]
+pub(crate) struct ConfirmationWatch {
+    /// The default milestones, if configured.
+    defaults: Option<Milestones>,
+    /// The milestones of specific addresses.
+    overrides: HashMap<Address, Milestones>,
+    /// The movements with milestones left to notify.
+    tracked: Vec<Tracked>,
+}
+
+impl ConfirmationWatch {
+    /// Resolve the configured milestones, validating the addresses against `network`.
+    pub(crate) fn new(config: Option<&ConfirmationsConfig>, network: Network) -> Result<ConfirmationWatch, SmaugError> {
+        let Some(config) = config else {
+            return Ok(ConfirmationWatch::default());
+        };
+
+        let defaults = Milestones {
+            deposits: sorted(config.deposits.clone()),
+            withdrawals: sorted(config.withdrawals.clone()),
+        };
+        let mut overrides = HashMap::new();
+        for address_config in &config.addresses {
+            let milestones = Milestones {
+                deposits: sorted(address_config.deposits.clone().unwrap_or(defaults.deposits.clone())),
+                withdrawals: sorted(
+                    address_config
+                        .withdrawals
+                        .clone()
+                        .unwrap_or(defaults.withdrawals.clone()),
+                ),
+            };
+            overrides.insert(address_config.address.clone().require_network(network)?, milestones);
+        }
+
+        Ok(ConfirmationWatch {
+            defaults: Some(defaults),
+            overrides,
+            tracked: Vec::new(),
+        })
+    }
+
+    /// The movements with milestones left to notify, to persist them with the state.
+    pub(crate) fn stored(&self) -> Vec<StoredTracked> {
+        self.tracked.iter().map(StoredTracked::from).collect()
+    }
+
+    /// Resume tracking the movements persisted with the state by the last run, validating their addresses against
+    /// `network` and looking up where they come from in `watchlist`.
+    pub(crate) fn restore(
+        &mut self,
+        stored: &[StoredTracked],
+        watchlist: &WatchList,
+        network: Network,
+    ) -> Result<(), SmaugError> {
+        if self.defaults.is_none() {
+            if !stored.is_empty() {
+                warn!(
+                    "Confirmation milestones are no longer configured, dropping the {} deposits and withdrawals held \
+                     back by the last run",
+                    stored.len()
+                );
+            }
+            return Ok(());
+        }
+
+        for stored in stored {
+            let address = stored.address.clone().require_network(network)?;
+            self.tracked.push(Tracked {
+                movement: stored.movement,
+                params: EventParams {
+                    origin: watchlist.origin(&address).cloned(),
+                    address,
+                    utxo: Utxo::from(stored.utxo.clone()),
+                    height: stored.height,
+                    after_restart: stored.after_restart,
+                    spend: stored.spend.clone(),
+                },
+                milestones: stored.milestones.clone(),
+                notified: stored.notified,
+            });
+        }
+        if !self.tracked.is_empty() {
+            info!(
+                "Following the confirmations of {} deposits and withdrawals from the last run",
+                self.tracked.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Track the deposits and withdrawals among `events`, and release the ones that reached a milestone at `height`.
+    ///
+    /// Deposits are followed through their UTXO in `current_state`, withdrawals through their spending transaction.
+    /// The follow-ups the detection modes raise about a tracked movement, like [`Event::Confirmed`], are folded into
+    /// its milestones.
+    pub(crate) fn update(
+        &mut self,
+        chain: &dyn ChainSource,
+        current_state: &UtxoDB,
+        height: u32,
+        events: Vec<Event>,
+    ) -> Vec<Event> {
+        if self.defaults.is_none() {
+            return events;
+        }
+
+        let mut released = Vec::new();
+        for event in events {
+            match event {
+                Event::Deposit(event_params) => released.extend(self.track(Movement::Deposit, event_params)),
+                Event::Withdrawal(event_params) => released.extend(self.track(Movement::Withdrawal, event_params)),
+                Event::Confirmed(event_params) => {
+                    match self
+                        .tracked
+                        .iter_mut()
+                        .find(|tracked| tracked.is(Movement::Deposit, &event_params.utxo))
+                    {
+                        Some(tracked) => tracked.params.utxo = event_params.utxo,
+                        None => released.push(Event::Confirmed(event_params)),
+                    }
+                }
+                Event::DepositReorged(ref event_params) => {
+                    self.tracked
+                        .retain(|tracked| !tracked.is(Movement::Deposit, &event_params.utxo));
+                    released.push(event);
+                }
+                Event::WithdrawalReorged(ref event_params) => {
+                    self.tracked
+                        .retain(|tracked| !tracked.is(Movement::Withdrawal, &event_params.utxo));
+                    released.push(event);
+                }
+                event => released.push(event),
+            }
+        }
+
+        released.extend(self.reached(chain, current_state, height));
+        released
+    }
+
+    /// Start tracking a deposit or withdrawal, or update the one already tracked.
+    ///
+    /// Returns the [`Event`] to pass through, for a notified withdrawal whose spending transaction was replaced.
+    fn track(&mut self, movement: Movement, event_params: EventParams) -> Option<Event> {
+        if let Some(tracked) = self
+            .tracked
+            .iter_mut()
+            .find(|tracked| tracked.is(movement, &event_params.utxo))
+        {
+            if movement == Movement::Deposit {
+                tracked.params.utxo = event_params.utxo;
+                return None;
+            }
+
+            let previous_spend = tracked.params.spend.as_ref().map(|spend| spend.txid);
+            let spend = event_params.spend.as_ref().map(|spend| spend.txid);
+            let replaced = previous_spend.is_some() && spend.is_some() && previous_spend != spend;
+            if event_params.spend.is_some() {
+                tracked.params.spend = event_params.spend.clone();
+            }
+            return (replaced && tracked.notified).then_some(Event::Withdrawal(event_params));
+        }
+
+        let milestones = self
+            .overrides
+            .get(&event_params.address)
+            .or(self.defaults.as_ref())
+            .map(|milestones| milestones.of(movement).to_vec())
+            .unwrap_or_default();
+        if milestones.is_empty() {
+            debug!(
+                "No confirmation milestones for {:?}s of address {}, not notifying {}:{}",
+                movement, event_params.address, event_params.utxo.txid, event_params.utxo.vout
+            );
+            return None;
+        }
+
+        self.tracked.push(Tracked {
+            movement,
+            params: event_params,
+            milestones,
+            notified: false,
+        });
+        None
+    }
+
+    /// Refresh the confirmation status of every tracked movement, and generate the [`Event`]s of the milestones
+    /// reached at `height`.
+    fn reached(&mut self, chain: &dyn ChainSource, current_state: &UtxoDB, height: u32) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        self.tracked.retain_mut(|tracked| {
+            let (txid, vout) = (tracked.params.utxo.txid, tracked.params.utxo.vout);
+            match tracked.movement {
+                Movement::Deposit => {
+                    let utxo = current_state
+                        .get(&tracked.params.address)
+                        .and_then(|utxos| utxos.iter().find(|utxo| utxo.txid == txid && utxo.vout == vout));
+                    match utxo {
+                        Some(utxo) => tracked.params.utxo = *utxo,
+                        // A deposit spent before its first milestone would otherwise never be notified at all.
+                        None if !tracked.notified => {
+                            warn!(
+                                "The deposit {txid}:{vout} was spent before reaching its first confirmation \
+                                 milestone, notifying it now"
+                            );
+                            events.push(Event::Deposit(tracked.params.clone()));
+                            return false;
+                        }
+                        None => {
+                            info!(
+                                "The deposit {txid}:{vout} is no longer unspent, no longer following its confirmations"
+                            );
+                            return false;
+                        }
+                    }
+                }
+                // Only look the spend up again while it is unknown or unconfirmed, and a milestone is waiting on it.
+                Movement::Withdrawal => {
+                    let confirmed = tracked
+                        .params
+                        .spend
+                        .as_ref()
+                        .is_some_and(|spend| spend.status.confirmed);
+                    if !confirmed && tracked.milestones[0] > tracked.confirmations(height) {
+                        match fetch_spend(chain, &txid, vout) {
+                            Ok(Some(spend)) => tracked.params.spend = Some(spend),
+                            Ok(None) => debug!("No spending transaction found for {txid}:{vout}"),
+                            Err(e) => warn!("Failed to look up the spending transaction of {txid}:{vout}: {e}"),
+                        }
+                    }
+                }
+            }
+
+            let confirmations = tracked.confirmations(height);
+            let reached = tracked
+                .milestones
+                .iter()
+                .take_while(|milestone| **milestone <= confirmations)
+                .count();
+            if reached == 0 {
+                return true;
+            }
+            tracked.milestones.drain(..reached);
+            let last = tracked.milestones.is_empty();
+
+            // The first milestone notifies the movement itself, and only the deepest one reached meanwhile is
+            // followed up on.
+            if !tracked.notified {
+                tracked.notified = true;
+                events.push(match tracked.movement {
+                    Movement::Deposit => Event::Deposit(tracked.params.clone()),
+                    Movement::Withdrawal => Event::Withdrawal(tracked.params.clone()),
+                });
+                if reached == 1 {
+                    return !last;
+                }
+            }
+            events.push(Event::Milestone(MilestoneParams {
+                movement: tracked.movement,
+                confirmations,
+                last,
+                params: tracked.params.clone(),
+            }));
+
+            !last
+        });
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{Amount, BlockHash, Txid, Weight, hashes::Hash};
+    use esplora_client::{TxStatus, UtxoStatus};
+
+    use super::*;
+    use crate::Config;
+    use crate::chain::ChainError;
+    use crate::smaug::Spend;
+
+    const WATCHED: &str = "tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd";
+    const COLD: &str = "tb1pp0aea5wv49f43t30hex2x5avlxelxlac7uwrjr0u57k7xnld3qzqnulr5q";
+
+    /// A chain source that knows of no spending transaction.
+    struct FakeChain;
+
+    impl ChainSource for FakeChain {
+        fn height(&self) -> Result<u32, ChainError> {
+            Ok(0)
+        }
+
+        fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+            Err(ChainError::MissingBlock(height))
+        }
+
+        fn address_utxos(&self, _: &Address) -> Result<Vec<Utxo>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn address_txs(&self, _: &Address, _: Option<Txid>) -> Result<Vec<esplora_client::Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn mempool_address_txs(&self, _: &Address) -> Result<Vec<esplora_client::Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn spending_txid(&self, _: &Txid, _: u32) -> Result<Option<Txid>, ChainError> {
+            Ok(None)
+        }
+
+        fn tx(&self, _: &Txid) -> Result<Option<esplora_client::Tx>, ChainError> {
+            Ok(None)
+        }
+    }
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address)
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap()
+    }
+
+    fn utxo(txid_byte: u8, block_height: Option<u32>) -> Utxo {
+        Utxo {
+            txid: Txid::from_byte_array([txid_byte; 32]),
+            vout: 0,
+            status: UtxoStatus {
+                confirmed: block_height.is_some(),
+                block_height,
+                block_hash: None,
+                block_time: None,
+            },
+            value: Amount::from_sat(1337),
+        }
+    }
+
+    fn event_params(address: &Address, utxo: Utxo, spend_height: Option<u32>) -> EventParams {
+        EventParams {
+            address: address.clone(),
+            origin: None,
+            utxo,
+            height: 100,
+            after_restart: false,
+            spend: spend_height.map(|block_height| Spend {
+                txid: Txid::from_byte_array([0xff; 32]),
+                status: TxStatus {
+                    confirmed: true,
+                    block_height: Some(block_height),
+                    block_hash: None,
+                    block_time: None,
+                },
+                outputs: Vec::new(),
+                fee: Some(Amount::from_sat(1000)),
+                weight: Weight::from_wu(800),
+                rbf: false,
+            }),
+        }
+    }
+
+    fn watch(config: &str) -> ConfirmationWatch {
+        let config: ConfirmationsConfig = toml::from_str(config).unwrap();
+        ConfirmationWatch::new(Some(&config), Network::Testnet4).unwrap()
+    }
+
+    #[test]
+    fn movements_are_notified_at_their_milestones() {
+        let mut watch = watch("deposits = [3]\nwithdrawals = [6, 0]");
+        let watched = address(WATCHED);
+
+        // An unconfirmed deposit and a withdrawal confirmed at height 100.
+        let mut state = UtxoDB::from([(watched.clone(), vec![utxo(1, None)])]);
+        let events = vec![
+            Event::Deposit(event_params(&watched, utxo(1, None), None)),
+            Event::Withdrawal(event_params(&watched, utxo(2, Some(90)), Some(100))),
+        ];
+        let events = watch.update(&FakeChain, &state, 100, events);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Event::Withdrawal(_)));
+
+        // The deposit confirms at height 101: its confirmation is not notified on its own.
+        state.insert(watched.clone(), vec![utxo(1, Some(101))]);
+        let confirmed = Event::Confirmed(event_params(&watched, utxo(1, Some(101)), None));
+        assert!(watch.update(&FakeChain, &state, 101, vec![confirmed]).is_empty());
+
+        let events = watch.update(&FakeChain, &state, 103, Vec::new());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Event::Deposit(params) if params.utxo.status.block_height == Some(101)));
+
+        // The withdrawal is 6 deep at height 105, and the all-clear is the last of its milestones.
+        let events = watch.update(&FakeChain, &state, 105, Vec::new());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            Event::Milestone(milestone)
+                if milestone.movement == Movement::Withdrawal && milestone.confirmations == 6 && milestone.last
+        ));
+        assert!(watch.tracked.is_empty());
+    }
+
+    #[test]
+    fn address_milestones_override_the_defaults() {
+        let mut watch = watch(&format!(
+            "deposits = [1]\n[[addresses]]\naddress = \"{COLD}\"\ndeposits = [0, 2]"
+        ));
+        let (watched, cold) = (address(WATCHED), address(COLD));
+        let state = UtxoDB::from([
+            (watched.clone(), vec![utxo(1, Some(200))]),
+            (cold.clone(), vec![utxo(2, Some(198))]),
+        ]);
+
+        // Both deposits are past their first milestone, and the one to the cold address is past its last one too.
+        let events = vec![
+            Event::Deposit(event_params(&watched, utxo(1, Some(200)), None)),
+            Event::Deposit(event_params(&cold, utxo(2, Some(198)), None)),
+        ];
+        let events = watch.update(&FakeChain, &state, 200, events);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Event::Deposit(params) if params.address == watched));
+        assert!(matches!(&events[1], Event::Deposit(params) if params.address == cold));
+        assert!(matches!(&events[2], Event::Milestone(milestone) if milestone.confirmations == 3 && milestone.last));
+
+        // Without a `[confirmations]` section, events are passed through as is.
+        let mut watch = ConfirmationWatch::new(None, Network::Testnet4).unwrap();
+        let events = vec![Event::Deposit(event_params(&watched, utxo(1, None), None))];
+        assert_eq!(watch.update(&FakeChain, &UtxoDB::new(), 200, events).len(), 1);
+    }
+
+    #[test]
+    fn held_back_movements_survive_a_restart() {
+        let mut watch = watch("deposits = [3]\nwithdrawals = [6]");
+        let watched = address(WATCHED);
+        let state = UtxoDB::from([(watched.clone(), vec![utxo(1, Some(100))])]);
+        let events = vec![
+            Event::Deposit(event_params(&watched, utxo(1, Some(100)), None)),
+            Event::Withdrawal(event_params(&watched, utxo(2, Some(90)), Some(100))),
+        ];
+        assert!(watch.update(&FakeChain, &state, 100, events).is_empty());
+
+        // Round trip through the state file format into the watch of the next run.
+        let stored: Vec<StoredTracked> =
+            serde_json::from_str(&serde_json::to_string(&watch.stored()).unwrap()).unwrap();
+        let config: Config = toml::from_str(&format!(
+            "network = \"testnet4\"\naddresses = [\"{WATCHED}\"]\nnotify_subscriptions = false\nnotify_deposits = true"
+        ))
+        .unwrap();
+        let mut restarted = self::watch("deposits = [3]\nwithdrawals = [6]");
+        restarted
+            .restore(&stored, &WatchList::from_config(&config).unwrap(), Network::Testnet4)
+            .unwrap();
+        assert!(
+            restarted
+                .restore(&stored, &WatchList::from_config(&config).unwrap(), Network::Bitcoin)
+                .is_err()
+        );
+
+        let events = restarted.update(&FakeChain, &state, 102, Vec::new());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Event::Deposit(params) if params.utxo.txid == utxo(1, None).txid));
+        let events = restarted.update(&FakeChain, &state, 105, Vec::new());
+        assert_eq!(events.len(), 1);
+        let Event::Withdrawal(params) = &events[0] else {
+            panic!("expected a withdrawal, got {:?}", events[0]);
+        };
+        assert_eq!(params.spend.as_ref().unwrap().fee, Some(Amount::from_sat(1000)));
+    }
+
+    #[test]
+    fn deposits_spent_before_their_first_milestone_are_notified() {
+        let mut watch = watch("deposits = [6]");
+        let watched = address(WATCHED);
+        let state = UtxoDB::from([(watched.clone(), vec![utxo(1, Some(100))])]);
+        let events = vec![Event::Deposit(event_params(&watched, utxo(1, Some(100)), None))];
+        assert!(watch.update(&FakeChain, &state, 100, events).is_empty());
+
+        // The deposit is spent at height 101, long before it is 6 deep.
+        let events = watch.update(&FakeChain, &UtxoDB::new(), 101, Vec::new());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Event::Deposit(params) if params.address == watched));
+        assert!(watch.tracked.is_empty());
+    }
+}
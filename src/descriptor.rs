@@ -0,0 +1,754 @@
+use std::{collections::HashMap, fmt};
+
+use bitcoin::{
+    Address, Network, NetworkKind, ScriptBuf, base58,
+    bip32::{self, ChildNumber, Xpub},
+    opcodes::all::OP_CHECKMULTISIG,
+    script::Builder,
+    secp256k1::{Secp256k1, VerifyOnly},
+};
+use serde::Serialize;
+use thiserror::Error;
+
+/// The default amount of unused addresses to derive past the last used one.
+pub(crate) const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// The character set used to compute descriptor checksums (BIP380).
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+
+/// The character set used to encode descriptor checksums (BIP380).
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// SLIP-132 version bytes for `ypub` and `upub` (P2SH-P2WPKH).
+const YPUB: [u8; 4] = [0x04, 0x9d, 0x7c, 0xb2];
+const UPUB: [u8; 4] = [0x04, 0x4a, 0x52, 0x62];
+/// SLIP-132 version bytes for `zpub` and `vpub` (P2WPKH).
+const ZPUB: [u8; 4] = [0x04, 0xb2, 0x47, 0x46];
+const VPUB: [u8; 4] = [0x04, 0x5f, 0x1c, 0xf6];
+/// BIP32 version bytes for `xpub` and `tpub`.
+const XPUB: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+/// Errors that happen while parsing or deriving from a descriptor.
+#[derive(Debug, Error)]
+pub enum DescriptorError {
+    /// The descriptor has no `#checksum` suffix.
+    #[error("descriptor `{0}` is missing its checksum")]
+    MissingChecksum(String),
+
+    /// The descriptor checksum does not match its content.
+    #[error("invalid checksum for descriptor `{descriptor}`: expected `{expected}`")]
+    InvalidChecksum { descriptor: String, expected: String },
+
+    /// The descriptor uses a script type or syntax smaug does not understand.
+    #[error("unsupported descriptor `{0}`")]
+    Unsupported(String),
+
+    /// A derivation path step could not be parsed or is hardened after an xpub.
+    #[error("invalid derivation step `{0}`")]
+    InvalidPath(String),
+
+    /// The multipath `<a;b>` expressions of a descriptor have different lengths.
+    #[error("mismatched multipath lengths in descriptor `{0}`")]
+    MultipathMismatch(String),
+
+    /// The extended key belongs to a different network than the configured one.
+    #[error("extended key `{0}` does not belong to network {1}")]
+    NetworkMismatch(String, Network),
+
+    /// Error decoding an extended public key.
+    #[error(transparent)]
+    Base58(#[from] base58::Error),
+
+    /// Error parsing or deriving an extended public key.
+    #[error(transparent)]
+    Bip32(#[from] bip32::Error),
+}
+
+/// A step in the derivation path that follows an extended key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PathStep {
+    /// A single unhardened child.
+    Fixed(ChildNumber),
+    /// A BIP389 multipath step, e.g. `<0;1>`.
+    Multi(Vec<ChildNumber>),
+}
+
+/// An extended public key with the derivation steps that follow it.
+#[derive(Clone, Debug)]
+struct DescriptorKey {
+    xpub: Xpub,
+    path: Vec<PathStep>,
+    wildcard: bool,
+}
+
+/// The script template of a descriptor.
+#[derive(Clone, Debug)]
+enum ScriptKind {
+    Pkh(DescriptorKey),
+    Wpkh(DescriptorKey),
+    ShWpkh(DescriptorKey),
+    Tr(DescriptorKey),
+    Wsh(Multisig),
+    ShWsh(Multisig),
+}
+
+/// A `multi` or `sortedmulti` fragment.
+#[derive(Clone, Debug)]
+struct Multisig {
+    threshold: usize,
+    keys: Vec<DescriptorKey>,
+    sorted: bool,
+}
+
+/// The keychain a derived address belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Keychain {
+    /// Receive addresses.
+    External,
+    /// Change addresses.
+    Internal,
+    /// Any other branch, by the child index it derives through.
+    Other(u32),
+}
+
+impl fmt::Display for Keychain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Keychain::External => write!(f, "receive"),
+            Keychain::Internal => write!(f, "change"),
+            Keychain::Other(index) => write!(f, "branch {index}"),
+        }
+    }
+}
+
+/// Where a derived address comes from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct AddressOrigin {
+    /// The descriptor (or extended key) as written in the configuration.
+    pub(crate) descriptor: String,
+    /// The keychain the address was derived on.
+    pub(crate) keychain: Keychain,
+    /// The derivation index of the address.
+    pub(crate) index: u32,
+}
+
+impl fmt::Display for AddressOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} index {} of {}", self.keychain, self.index, self.descriptor)
+    }
+}
+
+/// A parsed output descriptor or SLIP-132 extended public key.
+#[derive(Clone, Debug)]
+pub(crate) struct Descriptor {
+    /// The descriptor as written in the configuration.
+    original: String,
+    kind: ScriptKind,
+    /// The number of single-path descriptors this one expands into.
+    branches: usize,
+}
+
+impl Descriptor {
+    /// Parse a checksummed output descriptor or a bare `xpub`/`ypub`/`zpub` (and their testnet counterparts).
+    ///
+    /// Bare extended keys are watched on both the `<0;1>/*` receive and change keychains,
+    /// as `pkh` for `xpub`, `sh(wpkh)` for `ypub` and `wpkh` for `zpub`.
+    pub(crate) fn parse(s: &str, network: Network) -> Result<Descriptor, DescriptorError> {
+        let s = s.trim();
+        let kind = if s.contains('(') {
+            let (body, checksum) = s
+                .rsplit_once('#')
+                .ok_or_else(|| DescriptorError::MissingChecksum(s.to_string()))?;
+            let expected = descriptor_checksum(body).ok_or_else(|| DescriptorError::Unsupported(s.to_string()))?;
+            if expected != checksum {
+                return Err(DescriptorError::InvalidChecksum {
+                    descriptor: s.to_string(),
+                    expected,
+                });
+            }
+            parse_script(body, network)?
+        } else {
+            parse_slip132(s, network)?
+        };
+
+        let branches = kind
+            .keys()
+            .iter()
+            .map(|key| key.branches())
+            .try_fold(1, |acc, n| match (acc, n) {
+                (1, n) | (n, 1) => Ok(n),
+                (a, b) if a == b => Ok(a),
+                _ => Err(DescriptorError::MultipathMismatch(s.to_string())),
+            })?;
+
+        Ok(Descriptor {
+            original: s.to_string(),
+            kind,
+            branches,
+        })
+    }
+
+    /// The number of single-path descriptors this one expands into.
+    pub(crate) fn branches(&self) -> usize {
+        self.branches
+    }
+
+    /// Whether this descriptor derives more than one address per branch.
+    pub(crate) fn is_ranged(&self) -> bool {
+        self.kind.keys().iter().any(|key| key.wildcard)
+    }
+
+    /// The keychain that addresses of `branch` belong to, told by the child index the branch derives through: the
+    /// one picked from the multipath step, or else the last fixed step.
+    ///
+    /// By convention, `0` is the receive keychain and `1` the change keychain. Any other index is named by itself, and
+    /// keys deriving straight from the extended key are considered receive.
+    pub(crate) fn keychain(&self, branch: usize) -> Keychain {
+        let keys = self.kind.keys();
+        let key = keys.iter().find(|key| key.branches() > 1).or(keys.first());
+        match key.and_then(|key| key.branch_child(branch)).map(u32::from) {
+            None | Some(0) => Keychain::External,
+            Some(1) => Keychain::Internal,
+            Some(index) => Keychain::Other(index),
+        }
+    }
+
+    /// Derive the address at `index` of `branch`.
+    pub(crate) fn derive(
+        &self,
+        secp: &Secp256k1<VerifyOnly>,
+        branch: usize,
+        index: u32,
+        network: Network,
+    ) -> Result<Address, DescriptorError> {
+        let address = match &self.kind {
+            ScriptKind::Pkh(key) => Address::p2pkh(key.derive(secp, branch, index)?.to_pub(), network),
+            ScriptKind::Wpkh(key) => Address::p2wpkh(&key.derive(secp, branch, index)?.to_pub(), network),
+            ScriptKind::ShWpkh(key) => Address::p2shwpkh(&key.derive(secp, branch, index)?.to_pub(), network),
+            ScriptKind::Tr(key) => {
+                let internal_key = key.derive(secp, branch, index)?.to_x_only_pub();
+                Address::p2tr(secp, internal_key, None, network)
+            }
+            ScriptKind::Wsh(multi) => Address::p2wsh(&multi.witness_script(secp, branch, index)?, network),
+            ScriptKind::ShWsh(multi) => Address::p2shwsh(&multi.witness_script(secp, branch, index)?, network),
+        };
+
+        Ok(address)
+    }
+
+    /// The origin of the address at `index` of `branch`.
+    pub(crate) fn origin(&self, branch: usize, index: u32) -> AddressOrigin {
+        AddressOrigin {
+            descriptor: self.original.clone(),
+            keychain: self.keychain(branch),
+            index,
+        }
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.original)
+    }
+}
+
+impl ScriptKind {
+    fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            ScriptKind::Pkh(key) | ScriptKind::Wpkh(key) | ScriptKind::ShWpkh(key) | ScriptKind::Tr(key) => vec![key],
+            ScriptKind::Wsh(multi) | ScriptKind::ShWsh(multi) => multi.keys.iter().collect(),
+        }
+    }
+}
+
+impl DescriptorKey {
+    fn branches(&self) -> usize {
+        self.path
+            .iter()
+            .find_map(|step| match step {
+                PathStep::Multi(children) => Some(children.len()),
+                PathStep::Fixed(_) => None,
+            })
+            .unwrap_or(1)
+    }
+
+    /// The child `branch` derives through in the multipath step, or else in the last fixed step.
+    fn branch_child(&self, branch: usize) -> Option<ChildNumber> {
+        let multi = self.path.iter().find_map(|step| match step {
+            PathStep::Multi(children) => Some(children[branch.min(children.len() - 1)]),
+            PathStep::Fixed(_) => None,
+        });
+
+        multi.or_else(|| match self.path.last() {
+            Some(PathStep::Fixed(child)) => Some(*child),
+            _ => None,
+        })
+    }
+
+    fn derive(&self, secp: &Secp256k1<VerifyOnly>, branch: usize, index: u32) -> Result<Xpub, DescriptorError> {
+        let mut path: Vec<ChildNumber> = self
+            .path
+            .iter()
+            .map(|step| match step {
+                PathStep::Fixed(child) => *child,
+                PathStep::Multi(children) => children[branch.min(children.len() - 1)],
+            })
+            .collect();
+        if self.wildcard {
+            path.push(ChildNumber::from_normal_idx(index)?);
+        }
+
+        Ok(self.xpub.derive_pub(secp, &path)?)
+    }
+}
+
+impl Multisig {
+    fn witness_script(
+        &self,
+        secp: &Secp256k1<VerifyOnly>,
+        branch: usize,
+        index: u32,
+    ) -> Result<ScriptBuf, DescriptorError> {
+        let mut pubkeys = self
+            .keys
+            .iter()
+            .map(|key| Ok(key.derive(secp, branch, index)?.public_key.serialize()))
+            .collect::<Result<Vec<_>, DescriptorError>>()?;
+        if self.sorted {
+            pubkeys.sort();
+        }
+
+        let mut builder = Builder::new().push_int(self.threshold as i64);
+        for pubkey in &pubkeys {
+            builder = builder.push_slice(pubkey);
+        }
+
+        Ok(builder
+            .push_int(pubkeys.len() as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script())
+    }
+}
+
+/// Compute the BIP380 checksum of a descriptor (without the `#checksum` suffix).
+pub(crate) fn descriptor_checksum(descriptor: &str) -> Option<String> {
+    fn polymod(c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+        if c0 & 1 != 0 {
+            c ^= 0xf5dee51989;
+        }
+        if c0 & 2 != 0 {
+            c ^= 0xa9fdca3312;
+        }
+        if c0 & 4 != 0 {
+            c ^= 0x1bab10e32d;
+        }
+        if c0 & 8 != 0 {
+            c ^= 0x3706b1677a;
+        }
+        if c0 & 16 != 0 {
+            c ^= 0x644d626ffd;
+        }
+        c
+    }
+
+    let mut c = 1u64;
+    let mut class = 0u64;
+    let mut class_count = 0;
+    for ch in descriptor.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = polymod(c, pos & 31);
+        class = class * 3 + (pos >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    Some(
+        (0..8)
+            .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+/// Parse the script expression of a descriptor.
+fn parse_script(s: &str, network: Network) -> Result<ScriptKind, DescriptorError> {
+    let unsupported = || DescriptorError::Unsupported(s.to_string());
+
+    let (function, inner) = split_function(s).ok_or_else(unsupported)?;
+    match function {
+        "pkh" => Ok(ScriptKind::Pkh(parse_key(inner, network)?)),
+        "wpkh" => Ok(ScriptKind::Wpkh(parse_key(inner, network)?)),
+        "tr" if !inner.contains(',') => Ok(ScriptKind::Tr(parse_key(inner, network)?)),
+        "wsh" => Ok(ScriptKind::Wsh(parse_multisig(inner, network)?)),
+        "sh" => {
+            let (function, inner) = split_function(inner).ok_or_else(unsupported)?;
+            match function {
+                "wpkh" => Ok(ScriptKind::ShWpkh(parse_key(inner, network)?)),
+                "wsh" => Ok(ScriptKind::ShWsh(parse_multisig(inner, network)?)),
+                _ => Err(unsupported()),
+            }
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+/// Split `function(inner)` into `function` and `inner`.
+fn split_function(s: &str) -> Option<(&str, &str)> {
+    let (function, rest) = s.split_once('(')?;
+    let inner = rest.strip_suffix(')')?;
+
+    Some((function, inner))
+}
+
+/// Parse a `multi(k,KEY,...)` or `sortedmulti(k,KEY,...)` fragment.
+fn parse_multisig(s: &str, network: Network) -> Result<Multisig, DescriptorError> {
+    let unsupported = || DescriptorError::Unsupported(s.to_string());
+
+    let (function, inner) = split_function(s).ok_or_else(unsupported)?;
+    let sorted = match function {
+        "multi" => false,
+        "sortedmulti" => true,
+        _ => return Err(unsupported()),
+    };
+
+    let mut args = inner.split(',');
+    let threshold: usize = args.next().and_then(|k| k.parse().ok()).ok_or_else(unsupported)?;
+    let keys = args.map(|key| parse_key(key, network)).collect::<Result<Vec<_>, _>>()?;
+    if threshold == 0 || threshold > keys.len() || keys.len() > 20 {
+        return Err(unsupported());
+    }
+
+    Ok(Multisig {
+        threshold,
+        keys,
+        sorted,
+    })
+}
+
+/// Parse a key expression: `[fingerprint/origin/path]xpub/path/*`.
+///
+/// The key origin is informational and is skipped.
+fn parse_key(s: &str, network: Network) -> Result<DescriptorKey, DescriptorError> {
+    let s = match s.strip_prefix('[') {
+        Some(rest) => rest
+            .split_once(']')
+            .map(|(_, key)| key)
+            .ok_or_else(|| DescriptorError::Unsupported(s.to_string()))?,
+        None => s,
+    };
+
+    let mut parts = s.split('/');
+    let xpub = parse_xpub(parts.next().unwrap_or_default(), network)?;
+
+    let mut path = Vec::new();
+    let mut wildcard = false;
+    for step in parts {
+        if wildcard {
+            return Err(DescriptorError::InvalidPath(step.to_string()));
+        }
+        match step {
+            "*" => wildcard = true,
+            _ => match step.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+                Some(children) => {
+                    let children = children.split(';').map(parse_child).collect::<Result<Vec<_>, _>>()?;
+                    if children.len() < 2 || path.iter().any(|step| matches!(step, PathStep::Multi(_))) {
+                        return Err(DescriptorError::InvalidPath(step.to_string()));
+                    }
+                    path.push(PathStep::Multi(children));
+                }
+                None => path.push(PathStep::Fixed(parse_child(step)?)),
+            },
+        }
+    }
+
+    Ok(DescriptorKey { xpub, path, wildcard })
+}
+
+/// Parse an unhardened derivation step.
+fn parse_child(step: &str) -> Result<ChildNumber, DescriptorError> {
+    step.parse::<u32>()
+        .ok()
+        .and_then(|index| ChildNumber::from_normal_idx(index).ok())
+        .ok_or_else(|| DescriptorError::InvalidPath(step.to_string()))
+}
+
+/// Parse an `xpub`/`tpub`, checking it against the configured [`Network`].
+fn parse_xpub(s: &str, network: Network) -> Result<Xpub, DescriptorError> {
+    let xpub: Xpub = s.parse()?;
+    if xpub.network != NetworkKind::from(network) {
+        return Err(DescriptorError::NetworkMismatch(s.to_string(), network));
+    }
+
+    Ok(xpub)
+}
+
+/// Parse a bare SLIP-132 extended public key into a `<0;1>/*` descriptor.
+fn parse_slip132(s: &str, network: Network) -> Result<ScriptKind, DescriptorError> {
+    let mut data = base58::decode_check(s)?;
+    if data.len() < 4 {
+        return Err(DescriptorError::Unsupported(s.to_string()));
+    }
+
+    let version: [u8; 4] = [data[0], data[1], data[2], data[3]];
+    let (xpub_version, wrap): (_, fn(DescriptorKey) -> ScriptKind) = match version {
+        XPUB => (XPUB, ScriptKind::Pkh),
+        TPUB => (TPUB, ScriptKind::Pkh),
+        YPUB => (XPUB, ScriptKind::ShWpkh),
+        UPUB => (TPUB, ScriptKind::ShWpkh),
+        ZPUB => (XPUB, ScriptKind::Wpkh),
+        VPUB => (TPUB, ScriptKind::Wpkh),
+        _ => return Err(DescriptorError::Unsupported(s.to_string())),
+    };
+    data[..4].copy_from_slice(&xpub_version);
+
+    let xpub = Xpub::decode(&data)?;
+    if xpub.network != NetworkKind::from(network) {
+        return Err(DescriptorError::NetworkMismatch(s.to_string(), network));
+    }
+
+    Ok(wrap(DescriptorKey {
+        xpub,
+        path: vec![PathStep::Multi(vec![
+            ChildNumber::Normal { index: 0 },
+            ChildNumber::Normal { index: 1 },
+        ])],
+        wildcard: true,
+    }))
+}
+
+/// The derivation state of one branch of a watched descriptor.
+#[derive(Clone, Debug, Default)]
+struct Branch {
+    /// The addresses derived so far, by index.
+    derived: Vec<Address>,
+    /// The highest index known to have been used.
+    last_used: Option<u32>,
+}
+
+/// A [`Descriptor`] being watched, with the addresses derived from it so far.
+///
+/// An address counts as used once it has any history, even if it holds no UTXO anymore. Every branch keeps `gap_limit`
+/// unused addresses derived past its last used one.
+#[derive(Clone, Debug)]
+pub(crate) struct DescriptorWatch {
+    descriptor: Descriptor,
+    gap_limit: u32,
+    network: Network,
+    branches: Vec<Branch>,
+    /// The branch and index of every derived address.
+    indices: HashMap<Address, (usize, u32)>,
+}
+
+impl DescriptorWatch {
+    /// Start watching a [`Descriptor`].
+    pub(crate) fn new(descriptor: Descriptor, gap_limit: u32, network: Network) -> DescriptorWatch {
+        let branches = vec![Branch::default(); descriptor.branches()];
+
+        DescriptorWatch {
+            descriptor,
+            gap_limit,
+            network,
+            branches,
+            indices: HashMap::new(),
+        }
+    }
+
+    /// The watched [`Descriptor`].
+    pub(crate) fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Mark `address` as used, if it was derived from this descriptor.
+    pub(crate) fn mark_used(&mut self, address: &Address) {
+        if let Some(&(branch, index)) = self.indices.get(address) {
+            let branch = &mut self.branches[branch];
+            branch.last_used = Some(branch.last_used.map_or(index, |last| last.max(index)));
+        }
+    }
+
+    /// Whether `address` is at or below the last used index of its branch, so its use changes nothing.
+    pub(crate) fn is_used(&self, address: &Address) -> bool {
+        self.indices
+            .get(address)
+            .is_some_and(|&(branch, index)| self.branches[branch].last_used.is_some_and(|last| index <= last))
+    }
+
+    /// The last used address of every branch, enough to restore the derivation state with
+    /// [`mark_used`](DescriptorWatch::mark_used).
+    pub(crate) fn last_used(&self) -> impl Iterator<Item = &Address> {
+        self.branches
+            .iter()
+            .filter_map(|branch| branch.last_used.map(|last_used| &branch.derived[last_used as usize]))
+    }
+
+    /// Derive addresses until every branch has `gap_limit` unused addresses past its last used one.
+    ///
+    /// Returns the newly derived addresses and their origins.
+    pub(crate) fn extend(
+        &mut self,
+        secp: &Secp256k1<VerifyOnly>,
+    ) -> Result<Vec<(Address, AddressOrigin)>, DescriptorError> {
+        let mut new_addresses = Vec::new();
+
+        for (i, branch) in self.branches.iter_mut().enumerate() {
+            let target = match self.descriptor.is_ranged() {
+                true => branch.last_used.map_or(0, |last| last + 1) + self.gap_limit.max(1),
+                false => 1,
+            };
+
+            while (branch.derived.len() as u32) < target {
+                let index = branch.derived.len() as u32;
+                let address = self.descriptor.derive(secp, i, index, self.network)?;
+                branch.derived.push(address.clone());
+                self.indices.entry(address.clone()).or_insert((i, index));
+                new_addresses.push((address, self.descriptor.origin(i, index)));
+            }
+        }
+
+        Ok(new_addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIP84_ZPUB: &str = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    const BIP86_XPUB: &str = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+
+    fn with_checksum(descriptor: &str) -> String {
+        format!("{descriptor}#{}", descriptor_checksum(descriptor).unwrap())
+    }
+
+    #[test]
+    fn checksum_matches_bip380() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert!(Descriptor::parse("raw(deadbeef)#89f8spxm", Network::Bitcoin).is_err());
+
+        let descriptor = format!("tr({BIP86_XPUB}/0/*)");
+        assert!(matches!(
+            Descriptor::parse(&descriptor, Network::Bitcoin),
+            Err(DescriptorError::MissingChecksum(_))
+        ));
+        assert!(matches!(
+            Descriptor::parse(&format!("{descriptor}#qqqqqqqq"), Network::Bitcoin),
+            Err(DescriptorError::InvalidChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn derive_bip84_zpub() {
+        let secp = Secp256k1::verification_only();
+        let descriptor = Descriptor::parse(BIP84_ZPUB, Network::Bitcoin).unwrap();
+
+        assert_eq!(descriptor.branches(), 2);
+        assert_eq!(
+            descriptor.derive(&secp, 0, 0, Network::Bitcoin).unwrap().to_string(),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        assert_eq!(
+            descriptor.derive(&secp, 0, 1, Network::Bitcoin).unwrap().to_string(),
+            "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g"
+        );
+        assert_eq!(
+            descriptor.derive(&secp, 1, 0, Network::Bitcoin).unwrap().to_string(),
+            "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"
+        );
+        assert_eq!(descriptor.keychain(1), Keychain::Internal);
+    }
+
+    #[test]
+    fn derive_bip86_multipath_descriptor() {
+        let secp = Secp256k1::verification_only();
+        let descriptor = with_checksum(&format!("tr([73c5da0a/86h/0h/0h]{BIP86_XPUB}/<0;1>/*)"));
+        let descriptor = Descriptor::parse(&descriptor, Network::Bitcoin).unwrap();
+
+        assert_eq!(
+            descriptor.derive(&secp, 0, 0, Network::Bitcoin).unwrap().to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        assert_eq!(
+            descriptor.derive(&secp, 1, 0, Network::Bitcoin).unwrap().to_string(),
+            "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7"
+        );
+    }
+
+    #[test]
+    fn keychains_follow_the_derived_child() {
+        let parse = |descriptor: &str| Descriptor::parse(&with_checksum(descriptor), Network::Bitcoin).unwrap();
+
+        let reversed = parse(&format!("tr({BIP86_XPUB}/<1;0>/*)"));
+        assert_eq!(reversed.keychain(0), Keychain::Internal);
+        assert_eq!(reversed.keychain(1), Keychain::External);
+
+        let single = parse(&format!("tr({BIP86_XPUB}/7/*)"));
+        assert_eq!(single.keychain(0), Keychain::Other(7));
+        assert_eq!(
+            single.origin(0, 3).to_string(),
+            format!("branch 7 index 3 of {}", single)
+        );
+
+        let unlabeled = parse(&format!("tr({BIP86_XPUB}/*)"));
+        assert_eq!(unlabeled.keychain(0), Keychain::External);
+    }
+
+    #[test]
+    fn reject_network_mismatch() {
+        assert!(matches!(
+            Descriptor::parse(BIP84_ZPUB, Network::Testnet4),
+            Err(DescriptorError::NetworkMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn sortedmulti_ignores_key_order() {
+        let secp = Secp256k1::verification_only();
+        let a = with_checksum(&format!("wsh(sortedmulti(1,{BIP86_XPUB}/0/*,{BIP86_XPUB}/1/*))"));
+        let b = with_checksum(&format!("wsh(sortedmulti(1,{BIP86_XPUB}/1/*,{BIP86_XPUB}/0/*))"));
+        let a = Descriptor::parse(&a, Network::Bitcoin).unwrap();
+        let b = Descriptor::parse(&b, Network::Bitcoin).unwrap();
+
+        assert_eq!(
+            a.derive(&secp, 0, 7, Network::Bitcoin).unwrap(),
+            b.derive(&secp, 0, 7, Network::Bitcoin).unwrap()
+        );
+    }
+
+    #[test]
+    fn extend_past_last_used() {
+        let secp = Secp256k1::verification_only();
+        let descriptor = Descriptor::parse(BIP84_ZPUB, Network::Bitcoin).unwrap();
+        let mut watch = DescriptorWatch::new(descriptor, 5, Network::Bitcoin);
+
+        let derived = watch.extend(&secp).unwrap();
+        assert_eq!(derived.len(), 10);
+        assert!(watch.extend(&secp).unwrap().is_empty());
+
+        let (address, origin) = derived[3].clone();
+        assert_eq!(origin.keychain, Keychain::External);
+        assert_eq!(origin.index, 3);
+
+        watch.mark_used(&address);
+        assert!(watch.is_used(&derived[1].0));
+        assert!(!watch.is_used(&derived[4].0));
+        let derived = watch.extend(&secp).unwrap();
+        assert_eq!(derived.len(), 4);
+        assert!(derived.iter().all(|(_, origin)| origin.keychain == Keychain::External));
+        assert_eq!(derived.last().unwrap().1.index, 8);
+    }
+}
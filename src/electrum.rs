@@ -0,0 +1,946 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcoin::{
+    Address, Amount, BlockHash, OutPoint, ScriptBuf, Transaction, Txid,
+    block::Header,
+    consensus::encode::{FromHexError, deserialize_hex},
+    hashes::{Hash, sha256},
+    hex::DisplayHex,
+};
+use esplora_client::{Tx, TxStatus, Utxo, UtxoStatus};
+use log::{debug, info, warn};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned, pki_types::ServerName};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use thiserror::Error;
+
+use crate::chain::{AddressStatus, ChainError, ChainSource, page_after, tx_with_prevouts};
+use crate::proxy;
+
+/// The Electrum protocol version `smaug` speaks.
+const PROTOCOL_VERSION: &str = "1.4";
+
+/// Errors that happen while talking to an Electrum server.
+#[derive(Debug, Error)]
+pub enum ElectrumError {
+    /// The server URL is not `tcp://host:port` or `ssl://host:port`.
+    #[error("invalid Electrum URL `{0}`, expected `tcp://host:port` or `ssl://host:port`")]
+    InvalidUrl(String),
+
+    /// Error on the connection to the server.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// Error setting up TLS.
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+
+    /// The server closed the connection.
+    #[error("the Electrum server closed the connection")]
+    Closed,
+
+    /// The server did not respond in time.
+    #[error("the Electrum server did not respond in time")]
+    Timeout,
+
+    /// The server answered with an error.
+    #[error("`{method}` failed: {message}")]
+    Server { method: String, message: String },
+
+    /// Error parsing a message from the server.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// Error decoding a raw transaction or block header.
+    #[error(transparent)]
+    Decode(#[from] FromHexError),
+}
+
+/// Electrum server connection parameters.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ElectrumConfig {
+    /// The server URL: `tcp://host:port`, or `ssl://host:port` for TLS.
+    pub(crate) url: String,
+    /// How long to wait for the server to respond, in seconds.
+    #[serde(default = "default_electrum_timeout_sec")]
+    pub(crate) timeout_sec: u64,
+}
+
+fn default_electrum_timeout_sec() -> u64 {
+    30
+}
+
+/// The Electrum script hash of a script: the reversed SHA256 of the script, in hex.
+fn script_hash(script_pubkey: &ScriptBuf) -> String {
+    let mut hash = sha256::Hash::hash(script_pubkey.as_bytes()).to_byte_array();
+    hash.reverse();
+
+    hash.to_lower_hex_string()
+}
+
+/// Where the Electrum server is.
+#[derive(Clone, Debug)]
+struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    timeout: Duration,
+}
+
+impl Endpoint {
+    fn parse(config: &ElectrumConfig) -> Result<Endpoint, ElectrumError> {
+        let invalid_url = || ElectrumError::InvalidUrl(config.url.clone());
+
+        let (scheme, host_port) = config.url.split_once("://").ok_or_else(invalid_url)?;
+        let tls = match scheme {
+            "tcp" => false,
+            "ssl" | "tls" => true,
+            _ => return Err(invalid_url()),
+        };
+        let (host, port) = host_port
+            .trim_end_matches('/')
+            .rsplit_once(':')
+            .ok_or_else(invalid_url)?;
+
+        Ok(Endpoint {
+            tls,
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid_url())?,
+            timeout: Duration::from_secs(config.timeout_sec),
+        })
+    }
+
+    /// Open a connection to the server.
+    fn connect(&self) -> Result<Connection, ElectrumError> {
+        let tcp = proxy::connect(&self.host, self.port, self.timeout)?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+
+        let stream = match self.tls {
+            false => Stream::Tcp(tcp),
+            true => {
+                let roots = RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                    .with_safe_default_protocol_versions()?
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let server_name = ServerName::try_from(self.host.clone())
+                    .map_err(|_| ElectrumError::InvalidUrl(self.host.clone()))?;
+                let connection = ClientConnection::new(Arc::new(config), server_name)?;
+                Stream::Tls(Box::new(StreamOwned::new(connection, tcp)))
+            }
+        };
+
+        Ok(Connection {
+            reader: BufReader::new(stream),
+            line: Vec::new(),
+            next_id: 0,
+            timeout: self.timeout,
+        })
+    }
+}
+
+/// A plain or TLS stream to the server.
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(tcp) => tcp,
+            Stream::Tls(tls) => tls.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// A connection to the server, exchanging newline-delimited JSON-RPC messages.
+struct Connection {
+    reader: BufReader<Stream>,
+    /// The part of the next message read so far.
+    line: Vec<u8>,
+    next_id: u64,
+    timeout: Duration,
+}
+
+impl Connection {
+    /// Read the next message, or `None` if none arrives within `timeout`.
+    fn read(&mut self, timeout: Duration) -> Result<Option<Value>, ElectrumError> {
+        self.reader
+            .get_ref()
+            .tcp()
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+
+        match self.reader.read_until(b'\n', &mut self.line) {
+            Ok(_) if self.line.ends_with(b"\n") => {
+                let message = serde_json::from_slice(&self.line);
+                self.line.clear();
+                Ok(Some(message?))
+            }
+            Ok(_) => Err(ElectrumError::Closed),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Call `method`, collecting the notifications received in the meantime.
+    fn call(&mut self, method: &str, params: Value, notifications: &mut Vec<Value>) -> Result<Value, ElectrumError> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+
+        let stream = self.reader.get_mut();
+        stream.write_all(format!("{request}\n").as_bytes())?;
+        stream.flush()?;
+
+        loop {
+            let Some(mut message) = self.read(self.timeout)? else {
+                return Err(ElectrumError::Timeout);
+            };
+
+            if message["id"] == id {
+                return match message["error"].take() {
+                    Value::Null => Ok(message["result"].take()),
+                    error => Err(ElectrumError::Server {
+                        method: method.to_string(),
+                        message: error["message"]
+                            .as_str()
+                            .map(String::from)
+                            .unwrap_or_else(|| error.to_string()),
+                    }),
+                };
+            }
+            if message["method"].is_string() {
+                notifications.push(message);
+            }
+        }
+    }
+}
+
+/// A block header notification.
+#[derive(Debug, Deserialize)]
+struct HeaderNotification {
+    height: u32,
+    hex: String,
+}
+
+/// An entry of `blockchain.scripthash.listunspent`.
+#[derive(Debug, Deserialize)]
+struct Unspent {
+    tx_hash: Txid,
+    tx_pos: u32,
+    /// 0 (or -1, if it has unconfirmed parents) for mempool transactions.
+    height: i64,
+    value: u64,
+}
+
+/// An entry of `blockchain.scripthash.get_history`.
+#[derive(Debug, Deserialize)]
+struct HistoryEntry {
+    tx_hash: Txid,
+    /// 0 (or -1, if it has unconfirmed parents) for mempool transactions.
+    height: i64,
+}
+
+/// A subscribed script.
+#[derive(Debug, Default)]
+struct Subscription {
+    /// The status of the script's history, `None` if it has none.
+    status: Option<String>,
+    /// The UTXOs of the script, and the status they were fetched at.
+    utxos: Option<(Option<String>, Vec<Utxo>)>,
+    /// The transactions of the script, newest first, and the status they were fetched at.
+    txs: Option<(Option<String>, Vec<Tx>)>,
+}
+
+/// The connection to the server, and what was learnt through it.
+#[derive(Default)]
+struct Session {
+    connection: Option<Connection>,
+    /// The subscribed scripts, by script hash.
+    subscriptions: HashMap<String, Subscription>,
+    /// The height of the chain tip.
+    tip: u32,
+    /// The block headers fetched so far, by height.
+    headers: HashMap<u32, Header>,
+    /// The transactions fetched so far, which never change.
+    transactions: HashMap<Txid, Transaction>,
+    /// Whether the server pushed a change that was not reported yet.
+    changed: bool,
+}
+
+impl Session {
+    /// Connect to the server, and subscribe to new headers and to every script that was subscribed to before.
+    fn connect(&mut self, endpoint: &Endpoint) -> Result<(), ElectrumError> {
+        let mut connection = endpoint.connect()?;
+        let mut notifications = Vec::new();
+
+        let version = concat!("smaug ", env!("CARGO_PKG_VERSION"));
+        connection.call("server.version", json!([version, PROTOCOL_VERSION]), &mut notifications)?;
+        let header = connection.call("blockchain.headers.subscribe", json!([]), &mut notifications)?;
+        self.on_header(serde_json::from_value(header)?)?;
+
+        let script_hashes: Vec<String> = self.subscriptions.keys().cloned().collect();
+        for script_hash in script_hashes {
+            let status = connection.call(
+                "blockchain.scripthash.subscribe",
+                json!([script_hash]),
+                &mut notifications,
+            )?;
+            // Anything that happened while disconnected is a change.
+            self.changed |= self.on_status(&script_hash, status);
+        }
+        info!("Connected to Electrum server {}:{}", endpoint.host, endpoint.port);
+
+        self.connection = Some(connection);
+        self.on_notifications(notifications)
+    }
+
+    /// Call `method`, connecting first if needed.
+    ///
+    /// The connection is dropped on error, and re-established on the next call.
+    fn call(&mut self, endpoint: &Endpoint, method: &str, params: Value) -> Result<Value, ElectrumError> {
+        if self.connection.is_none() {
+            self.connect(endpoint)?;
+        }
+        let Some(connection) = self.connection.as_mut() else {
+            return Err(ElectrumError::Closed);
+        };
+
+        let mut notifications = Vec::new();
+        let result = connection.call(method, params, &mut notifications);
+        if let Err(ElectrumError::Io(_) | ElectrumError::Closed | ElectrumError::Timeout | ElectrumError::Json(_)) =
+            &result
+        {
+            self.connection = None;
+        }
+        self.on_notifications(notifications)?;
+
+        result
+    }
+
+    /// Wait up to `timeout` for a notification, connecting first if needed.
+    fn wait(&mut self, endpoint: &Endpoint, timeout: Duration) -> Result<(), ElectrumError> {
+        if self.connection.is_none() {
+            self.connect(endpoint)?;
+        }
+        let Some(connection) = self.connection.as_mut() else {
+            return Err(ElectrumError::Closed);
+        };
+
+        match connection.read(timeout) {
+            Ok(Some(message)) if message["method"].is_string() => self.on_notifications(vec![message]),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.connection = None;
+                Err(e)
+            }
+        }
+    }
+
+    fn on_notifications(&mut self, notifications: Vec<Value>) -> Result<(), ElectrumError> {
+        for mut notification in notifications {
+            match notification["method"].as_str() {
+                Some("blockchain.headers.subscribe") => {
+                    self.on_header(serde_json::from_value(notification["params"][0].take())?)?;
+                }
+                Some("blockchain.scripthash.subscribe") => {
+                    let Some(script_hash) = notification["params"][0].as_str().map(String::from) else {
+                        continue;
+                    };
+                    debug!("Status of script hash {script_hash} changed");
+                    self.on_status(&script_hash, notification["params"][1].take());
+                }
+                _ => continue,
+            }
+            self.changed = true;
+        }
+
+        Ok(())
+    }
+
+    /// Record a new chain tip, forgetting the cached headers a reorg could have replaced.
+    fn on_header(&mut self, notification: HeaderNotification) -> Result<(), ElectrumError> {
+        let header: Header = deserialize_hex(&notification.hex)?;
+
+        let extends_tip = notification
+            .height
+            .checked_sub(1)
+            .and_then(|height| self.headers.get(&height))
+            .is_none_or(|parent| parent.block_hash() == header.prev_blockhash);
+        match extends_tip {
+            true => self.headers.retain(|height, _| *height < notification.height),
+            false => self.headers.clear(),
+        }
+
+        self.tip = notification.height;
+        self.headers.insert(notification.height, header);
+
+        Ok(())
+    }
+
+    /// Record the status of a script, returning whether it changed.
+    fn on_status(&mut self, script_hash: &str, status: Value) -> bool {
+        let status = status.as_str().map(String::from);
+        let subscription = self.subscriptions.entry(script_hash.to_string()).or_default();
+        let changed = subscription.status != status;
+        subscription.status = status;
+
+        changed
+    }
+
+    /// The header of the block at `height`.
+    fn header(&mut self, endpoint: &Endpoint, height: u32) -> Result<Header, ElectrumError> {
+        if let Some(header) = self.headers.get(&height) {
+            return Ok(*header);
+        }
+
+        let hex = self.call(endpoint, "blockchain.block.header", json!([height]))?;
+        let header: Header = deserialize_hex(hex.as_str().unwrap_or_default())?;
+        self.headers.insert(height, header);
+
+        Ok(header)
+    }
+
+    /// The status of a transaction at an Electrum `height`.
+    fn status(&mut self, endpoint: &Endpoint, height: i64) -> Result<TxStatus, ElectrumError> {
+        let Ok(height) = u32::try_from(height) else {
+            return Ok(TxStatus {
+                confirmed: false,
+                block_height: None,
+                block_hash: None,
+                block_time: None,
+            });
+        };
+        if height == 0 {
+            return self.status(endpoint, -1);
+        }
+
+        let header = self.header(endpoint, height)?;
+        Ok(TxStatus {
+            confirmed: true,
+            block_height: Some(height),
+            block_hash: Some(header.block_hash()),
+            block_time: Some(header.time.into()),
+        })
+    }
+
+    /// A transaction.
+    fn transaction(&mut self, endpoint: &Endpoint, txid: &Txid) -> Result<Transaction, ElectrumError> {
+        if let Some(transaction) = self.transactions.get(txid) {
+            return Ok(transaction.clone());
+        }
+
+        let hex = self.call(endpoint, "blockchain.transaction.get", json!([txid]))?;
+        let transaction: Transaction = deserialize_hex(hex.as_str().unwrap_or_default())?;
+        self.transactions.insert(*txid, transaction.clone());
+
+        Ok(transaction)
+    }
+
+    /// Convert a [`Transaction`] to a [`Tx`], looking up its prevouts.
+    fn esplora_tx(
+        &mut self,
+        endpoint: &Endpoint,
+        transaction: &Transaction,
+        status: TxStatus,
+    ) -> Result<Tx, ElectrumError> {
+        tx_with_prevouts(transaction, status, |txid| self.transaction(endpoint, txid).map(Some))
+    }
+
+    /// The transactions of a script, newest first, served from the cache if its status did not change.
+    fn script_txs(&mut self, endpoint: &Endpoint, script_pubkey: &ScriptBuf) -> Result<Vec<Tx>, ElectrumError> {
+        let script_hash = script_hash(script_pubkey);
+        let status = match self.subscriptions.get(&script_hash) {
+            Some(Subscription {
+                status,
+                txs: Some((fetched_at, txs)),
+                ..
+            }) if status == fetched_at => return Ok(txs.clone()),
+            Some(subscription) => Some(subscription.status.clone()),
+            None => None,
+        };
+
+        let history = self.call(endpoint, "blockchain.scripthash.get_history", json!([script_hash]))?;
+        let mut history: Vec<HistoryEntry> = serde_json::from_value(history)?;
+        history.sort_by_key(|entry| (entry.height > 0, Reverse(entry.height)));
+
+        let mut txs = Vec::new();
+        for entry in history {
+            let transaction = self.transaction(endpoint, &entry.tx_hash)?;
+            let status = self.status(endpoint, entry.height)?;
+            txs.push(self.esplora_tx(endpoint, &transaction, status)?);
+        }
+
+        if let (Some(status), Some(subscription)) = (status, self.subscriptions.get_mut(&script_hash)) {
+            subscription.txs = Some((status, txs.clone()));
+        }
+
+        Ok(txs)
+    }
+}
+
+/// A [`ChainSource`] backed by an Electrum server.
+///
+/// Every watched script is subscribed to, so the server pushes new blocks and changes to the history of the watched
+/// addresses. UTXOs and transactions are only fetched again when the status of their script changed.
+pub(crate) struct ElectrumClient {
+    url: String,
+    endpoint: Endpoint,
+    session: Mutex<Session>,
+}
+
+impl ElectrumClient {
+    /// Build a client from the `[electrum]` section of the configuration.
+    pub(crate) fn new(config: &ElectrumConfig) -> Result<ElectrumClient, ElectrumError> {
+        Ok(ElectrumClient {
+            url: config.url.clone(),
+            endpoint: Endpoint::parse(config)?,
+            session: Mutex::new(Session::default()),
+        })
+    }
+
+    /// The server URL.
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn session(&self) -> MutexGuard<'_, Session> {
+        self.session.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ChainSource for ElectrumClient {
+    fn watch(&self, addresses: &[Address]) -> Result<(), ChainError> {
+        let mut session = self.session();
+
+        for address in addresses {
+            let script_hash = script_hash(&address.script_pubkey());
+            if session.subscriptions.contains_key(&script_hash) {
+                continue;
+            }
+
+            let status = session.call(&self.endpoint, "blockchain.scripthash.subscribe", json!([script_hash]))?;
+            session.on_status(&script_hash, status);
+        }
+
+        Ok(())
+    }
+
+    fn wait_for_changes(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut session = self.session();
+
+        loop {
+            if session.changed {
+                session.changed = false;
+                return true;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            if let Err(e) = session.wait(&self.endpoint, remaining) {
+                warn!("Lost connection to Electrum server {}: {e}", self.url);
+                drop(session);
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                return false;
+            }
+        }
+    }
+
+    fn height(&self) -> Result<u32, ChainError> {
+        let mut session = self.session();
+        let header = session.call(&self.endpoint, "blockchain.headers.subscribe", json!([]))?;
+        session.on_header(serde_json::from_value(header).map_err(ElectrumError::from)?)?;
+
+        Ok(session.tip)
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+        Ok(self.session().header(&self.endpoint, height)?.block_hash())
+    }
+
+    fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        let mut session = self.session();
+        let script_hash = script_hash(&address.script_pubkey());
+        let status = match session.subscriptions.get(&script_hash) {
+            Some(Subscription {
+                status,
+                utxos: Some((fetched_at, utxos)),
+                ..
+            }) if status == fetched_at => return Ok(utxos.clone()),
+            Some(subscription) => Some(subscription.status.clone()),
+            None => None,
+        };
+
+        let unspents = session.call(
+            &self.endpoint,
+            "blockchain.scripthash.listunspent",
+            json!([script_hash]),
+        )?;
+        let unspents: Vec<Unspent> = serde_json::from_value(unspents).map_err(ElectrumError::from)?;
+
+        let mut utxos = Vec::new();
+        for unspent in unspents {
+            let status = session.status(&self.endpoint, unspent.height)?;
+            utxos.push(Utxo {
+                txid: unspent.tx_hash,
+                vout: unspent.tx_pos,
+                status: UtxoStatus {
+                    confirmed: status.confirmed,
+                    block_height: status.block_height,
+                    block_hash: status.block_hash,
+                    block_time: status.block_time,
+                },
+                value: Amount::from_sat(unspent.value),
+            });
+        }
+
+        if let (Some(status), Some(subscription)) = (status, session.subscriptions.get_mut(&script_hash)) {
+            subscription.utxos = Some((status, utxos.clone()));
+        }
+
+        Ok(utxos)
+    }
+
+    fn addresses_status(&self, addresses: &[&Address]) -> Result<Vec<Option<AddressStatus>>, ChainError> {
+        // Kept up to date by the subscriptions, without asking the server.
+        let session = self.session();
+        Ok(addresses
+            .iter()
+            .map(|address| {
+                session
+                    .subscriptions
+                    .get(&script_hash(&address.script_pubkey()))
+                    .map(|subscription| AddressStatus::Electrum(subscription.status.clone()))
+            })
+            .collect())
+    }
+
+    fn address_txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        let txs = self.session().script_txs(&self.endpoint, &address.script_pubkey())?;
+
+        Ok(page_after(txs, last_seen))
+    }
+
+    fn mempool_address_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError> {
+        let mut txs = self.session().script_txs(&self.endpoint, &address.script_pubkey())?;
+        txs.retain(|tx| !tx.status.confirmed);
+
+        Ok(txs)
+    }
+
+    fn spending_txid(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, ChainError> {
+        let mut session = self.session();
+        let Some(output) = session
+            .transaction(&self.endpoint, txid)?
+            .output
+            .get(vout as usize)
+            .cloned()
+        else {
+            return Ok(None);
+        };
+
+        // The spend is in the history of the script the output is locked to. Prefer a confirmed one.
+        let outpoint = OutPoint::new(*txid, vout);
+        let txs = session.script_txs(&self.endpoint, &output.script_pubkey)?;
+        let spends = txs.iter().filter(|tx| {
+            tx.vin
+                .iter()
+                .any(|vin| vin.txid == outpoint.txid && vin.vout == outpoint.vout)
+        });
+
+        Ok(spends.max_by_key(|tx| tx.status.confirmed).map(|tx| tx.txid))
+    }
+
+    fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError> {
+        let mut session = self.session();
+        let transaction = session.transaction(&self.endpoint, txid)?;
+
+        // The height of the transaction is in the history of the scripts it pays to, or spends from.
+        let mut scripts: Vec<ScriptBuf> = transaction
+            .output
+            .iter()
+            .map(|output| output.script_pubkey.clone())
+            .filter(|script_pubkey| !script_pubkey.is_op_return())
+            .collect();
+        for input in transaction
+            .input
+            .iter()
+            .filter(|input| !input.previous_output.is_null())
+        {
+            let previous = session.transaction(&self.endpoint, &input.previous_output.txid)?;
+            if let Some(output) = previous.output.get(input.previous_output.vout as usize) {
+                scripts.push(output.script_pubkey.clone());
+            }
+        }
+
+        for script_pubkey in scripts {
+            let txs = session.script_txs(&self.endpoint, &script_pubkey)?;
+            if let Some(tx) = txs.into_iter().find(|tx| tx.txid == *txid) {
+                return Ok(Some(tx));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        str::FromStr,
+        sync::mpsc::{self, Sender},
+    };
+
+    use bitcoin::{
+        CompactTarget, Network, Sequence, TxIn, TxMerkleNode, TxOut, Witness, absolute::LockTime,
+        block::Version as BlockVersion, consensus::encode::serialize_hex, transaction::Version,
+    };
+
+    use super::*;
+
+    /// What the Electrum stand-in received: the method and parameters of every request.
+    type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// An Electrum server stand-in answering every request with `answer(method, params)`.
+    ///
+    /// Returns its URL, the requests it received, and a sender of notifications to push to the client.
+    fn electrum_stand_in(answer: impl Fn(&str, &Value) -> Value + Send + 'static) -> (String, Requests, Sender<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let (push, notifications) = mpsc::channel::<Value>();
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+
+            let pusher = writer.clone();
+            thread::spawn(move || {
+                for notification in notifications {
+                    writeln!(pusher.lock().unwrap(), "{notification}").unwrap();
+                }
+            });
+
+            for line in BufReader::new(stream).lines() {
+                let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                let method = request["method"].as_str().unwrap().to_string();
+                let result = answer(&method, &request["params"]);
+                received.lock().unwrap().push((method, request["params"].clone()));
+
+                let response = json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+                writeln!(writer.lock().unwrap(), "{response}").unwrap();
+            }
+        });
+
+        (url, requests, push)
+    }
+
+    fn client(url: String) -> ElectrumClient {
+        ElectrumClient::new(&ElectrumConfig { url, timeout_sec: 5 }).unwrap()
+    }
+
+    fn address() -> Address {
+        Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap()
+    }
+
+    fn header(time: u32) -> String {
+        serialize_hex(&Header {
+            version: BlockVersion::TWO,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(0x1d00ffff),
+            nonce: 0,
+        })
+    }
+
+    fn transaction(previous_output: OutPoint, output: TxOut) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![output],
+        }
+    }
+
+    /// A deposit of 100,000 sats to [`address`].
+    fn deposit() -> Transaction {
+        let funding = transaction(
+            OutPoint::null(),
+            TxOut {
+                value: Amount::from_sat(150_000),
+                script_pubkey: ScriptBuf::new_op_return([1u8; 4]),
+            },
+        );
+        transaction(
+            OutPoint::new(funding.compute_txid(), 0),
+            TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: address().script_pubkey(),
+            },
+        )
+    }
+
+    /// A withdrawal sweeping [`deposit`] with a 1,000 sats fee.
+    fn withdrawal() -> Transaction {
+        transaction(
+            OutPoint::new(deposit().compute_txid(), 0),
+            TxOut {
+                value: Amount::from_sat(99_000),
+                script_pubkey: ScriptBuf::new_op_return([0u8; 4]),
+            },
+        )
+    }
+
+    #[test]
+    fn status_changes_are_pushed() {
+        let (url, requests, push) = electrum_stand_in(|method, params| match method {
+            "server.version" => json!(["ElectrumX 1.16.0", "1.4"]),
+            "blockchain.headers.subscribe" => json!({ "height": 101597, "hex": header(1757566235) }),
+            "blockchain.scripthash.subscribe" => json!("status1"),
+            "blockchain.scripthash.listunspent" => {
+                json!([{ "tx_hash": deposit().compute_txid(), "tx_pos": 0, "height": 101595, "value": 100_000 }])
+            }
+            "blockchain.block.header" if params[0] == 101595 => json!(header(1757560000)),
+            _ => Value::Null,
+        });
+        let client = client(url);
+        let script_hash = script_hash(&address().script_pubkey());
+
+        client.watch(&[address()]).unwrap();
+        assert_eq!(client.height().unwrap(), 101597);
+        let utxos = client.address_utxos(&address()).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].status.block_height, Some(101595));
+        assert_eq!(utxos[0].status.block_time, Some(1757560000));
+
+        // Nothing changed, so the UTXOs are served from the cache.
+        assert!(!client.wait_for_changes(Duration::from_millis(50)));
+        client.address_utxos(&address()).unwrap();
+
+        push.send(json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.scripthash.subscribe",
+            "params": [script_hash, "status2"],
+        }))
+        .unwrap();
+        assert!(client.wait_for_changes(Duration::from_secs(5)));
+        client.address_utxos(&address()).unwrap();
+
+        push.send(json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.headers.subscribe",
+            "params": [{ "height": 101598, "hex": header(1757566835) }],
+        }))
+        .unwrap();
+        assert!(client.wait_for_changes(Duration::from_secs(5)));
+
+        let requests = requests.lock().unwrap();
+        let methods: Vec<&str> = requests.iter().map(|(method, _)| method.as_str()).collect();
+        assert_eq!(
+            methods,
+            [
+                "server.version",
+                "blockchain.headers.subscribe",
+                "blockchain.scripthash.subscribe",
+                "blockchain.headers.subscribe",
+                "blockchain.scripthash.listunspent",
+                "blockchain.block.header",
+                "blockchain.scripthash.listunspent",
+            ]
+        );
+        assert_eq!(requests[2].1, json!([script_hash]));
+    }
+
+    #[test]
+    fn spending_transaction_from_history() {
+        let (url, _, _push) = electrum_stand_in(|method, params| match method {
+            "server.version" => json!(["Fulcrum 1.11.1", "1.4"]),
+            "blockchain.headers.subscribe" => json!({ "height": 101597, "hex": header(1757566235) }),
+            "blockchain.scripthash.get_history" => json!([
+                { "tx_hash": deposit().compute_txid(), "height": 101595 },
+                { "tx_hash": withdrawal().compute_txid(), "height": 0, "fee": 1_000 },
+            ]),
+            "blockchain.block.header" => json!(header(1757560000)),
+            "blockchain.transaction.get" => [deposit(), withdrawal()]
+                .iter()
+                .find(|transaction| json!(transaction.compute_txid()) == params[0])
+                .map(|transaction| json!(serialize_hex(transaction)))
+                .unwrap_or(json!(serialize_hex(&transaction(
+                    OutPoint::null(),
+                    TxOut {
+                        value: Amount::from_sat(150_000),
+                        script_pubkey: ScriptBuf::new_op_return([1u8; 4]),
+                    },
+                )))),
+            _ => Value::Null,
+        });
+        let client = client(url);
+        let (deposit, withdrawal) = (deposit().compute_txid(), withdrawal().compute_txid());
+
+        let txs = client.address_txs(&address(), None).unwrap();
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid).collect();
+        assert_eq!(txids, [withdrawal, deposit]);
+        assert!(client.address_txs(&address(), Some(deposit)).unwrap().is_empty());
+        assert_eq!(client.mempool_address_txs(&address()).unwrap().len(), 1);
+
+        assert_eq!(client.spending_txid(&deposit, 0).unwrap(), Some(withdrawal));
+        let sweep = client.tx(&withdrawal).unwrap().unwrap();
+        assert!(!sweep.status.confirmed);
+        assert_eq!(sweep.fee, 1_000);
+        assert_eq!(sweep.vin[0].prevout.as_ref().unwrap().value, 100_000);
+
+        let deposit = client.tx(&deposit).unwrap().unwrap();
+        assert_eq!(deposit.status.block_height, Some(101595));
+        assert_eq!(deposit.fee, 50_000);
+    }
+}
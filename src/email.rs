@@ -1,5 +1,8 @@
+use std::{collections::BTreeSet, io, slice, time::Duration};
+
+use bitcoin::{Address, Network};
 use lettre::{
-    Message, SmtpTransport, Transport,
+    Address as EmailAddress, Message, SmtpTransport, Transport,
     address::AddressError,
     error::Error as LettreError,
     message::{Mailbox, header::ContentType},
@@ -7,14 +10,18 @@ use lettre::{
         self,
         authentication::Credentials,
         client::{Tls, TlsParameters},
+        extension::ClientId,
     },
 };
 use log::{debug, info, warn};
 use thiserror::Error;
 
 use crate::Config;
-use crate::format_with_commas;
-use crate::smaug::Event;
+use crate::heartbeat::ErrorLog;
+use crate::notifier::{Notification, Notifier, NotifierError};
+use crate::proxy;
+use crate::smaug::{Event, EventParams, Movement, Spend};
+use crate::{format_duration, format_with_commas};
 
 /// Errors that happens while sending an email.
 #[derive(Error, Debug)]
@@ -30,128 +37,608 @@ pub enum EmailError {
     /// Email building error.
     #[error(transparent)]
     EmailBuild(#[from] LettreError),
+
+    /// I/O error relaying to the SMTP server through the proxy.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// `recipient_emails` is set, but an SMTP parameter is missing.
+    #[error("`recipient_emails` is set, but `{0}` is missing")]
+    MissingSmtpParameter(&'static str),
 }
 
-/// Create an email message from an [`Event`] to every address in `recipient_emails`.
-pub(crate) fn build_messages(config: &Config, event: &Event) -> Result<Vec<Message>, EmailError> {
-    // The sender's mailbox.
-    let sender_mailbox = Mailbox::new(
-        Some(String::from("Smaug, the UTXO guardian")),
-        config.smtp_username.clone(),
-    );
-
-    // All the recipients we must build messages to.
-    let recipient_mailboxes: Vec<Mailbox> = config
-        .recipient_emails
-        .iter()
-        .map(|email| Mailbox::new(None, email.clone()))
-        .collect();
-    debug!("recipient_mailboxes: {:#?}", recipient_mailboxes);
-
-    let (subject, body) = match event {
-        Event::Subscription(addresses) => {
-            let num_addresses = addresses.len();
-
-            let subject: String = match num_addresses {
-                1 => String::from("You're now subscribed to 1 address"),
-                _ => format!("You're now subscribed to {} addresses", num_addresses),
-            };
+/// Mark the subject of an [`Event`] that was detected after a restart.
+fn with_restart_note(subject: String, after_restart: bool) -> String {
+    match after_restart {
+        true => format!("{subject} (detected after restart)"),
+        false => subject,
+    }
+}
+
+/// Append where the address comes from and whether the [`Event`] was detected after a restart to an email body.
+fn push_details(body: &mut String, event_params: &EventParams) {
+    if let Some(origin) = &event_params.origin {
+        body.push_str(&format!("\n\nThis address was derived at {}", origin));
+    }
+    if event_params.after_restart {
+        body.push_str(&format!(
+            "\n\nThis happened while smaug was not running, and was detected after a restart at height {}",
+            event_params.height
+        ));
+    }
+}
+
+/// Append the fee, replaceability and destinations of a spending transaction to an email body.
+fn push_spend_details(body: &mut String, spend: &Spend, network: Network) {
+    let fee = match (spend.fee, spend.fee_rate()) {
+        (Some(fee), Some(fee_rate)) => format!("{} sats ({:.1} sat/vB)", format_with_commas(fee.to_sat()), fee_rate),
+        _ => String::from("unknown"),
+    };
+    body.push_str(&format!(
+        "\n\nFee: {}, {}",
+        fee,
+        match spend.rbf {
+            true => "signals RBF",
+            false => "does NOT signal RBF",
+        }
+    ));
+
+    body.push_str("\n\nDestinations:");
+    for output in &spend.outputs {
+        let destination = match Address::from_script(&output.script_pubkey, network) {
+            Ok(address) => address.to_string(),
+            Err(_) => format!("script {}", output.script_pubkey),
+        };
+        body.push_str(&format!(
+            "\n- {} sats to {}",
+            format_with_commas(output.value.to_sat()),
+            destination
+        ));
+    }
+}
 
-            let mut body: String = match num_addresses {
-                1 => String::from("You are now subscribed to this address:"),
-                _ => String::from("You're now subscribed to these addresses:"),
+/// Append the errors of `log`, under `title`, to an email body.
+fn push_errors(body: &mut String, title: &str, log: &ErrorLog) {
+    if log.is_empty() {
+        return;
+    }
+
+    body.push_str(&format!("\n\n{title}:"));
+    for error in &log.errors {
+        match error.count {
+            1 => body.push_str(&format!("\n- {}", error.message)),
+            count => body.push_str(&format!("\n- {} ({} times)", error.message, count)),
+        }
+    }
+    if log.others > 0 {
+        body.push_str(&format!("\n- {} other errors", log.others));
+    }
+}
+
+/// Build the subject and body of the notification about an [`Event`].
+pub(crate) fn format_event(network: Network, event: &Event) -> (String, String) {
+    match event {
+        Event::Subscription(subscription) => {
+            let num_addresses = subscription.addresses.len();
+            let num_descriptors = subscription.descriptors.len();
+
+            let subject: String = match (num_addresses, num_descriptors) {
+                (1, 0) => String::from("You're now subscribed to 1 address"),
+                (_, 0) => format!("You're now subscribed to {} addresses", num_addresses),
+                (0, 1) => String::from("You're now subscribed to 1 descriptor"),
+                (0, _) => format!("You're now subscribed to {} descriptors", num_descriptors),
+                _ => format!(
+                    "You're now subscribed to {} addresses and {} descriptors",
+                    num_addresses, num_descriptors
+                ),
             };
-            for address in addresses {
-                body.push_str(&format!("\n- {}", address));
-            }
 
-            debug!("Event::Subscription email:");
-            debug!(" Subject: {subject}");
-            debug!(" Body: {body}");
+            let mut body = String::new();
+            if num_addresses > 0 {
+                body.push_str(match num_addresses {
+                    1 => "You are now subscribed to this address:",
+                    _ => "You're now subscribed to these addresses:",
+                });
+                for address in &subscription.addresses {
+                    body.push_str(&format!("\n- {}", address));
+                }
+            }
+            if num_descriptors > 0 {
+                if !body.is_empty() {
+                    body.push_str("\n\n");
+                }
+                body.push_str(match num_descriptors {
+                    1 => "You are now subscribed to this descriptor:",
+                    _ => "You're now subscribed to these descriptors:",
+                });
+                for (descriptor, derived) in &subscription.descriptors {
+                    body.push_str(&format!("\n- {} ({} addresses derived)", descriptor, derived));
+                }
+            }
 
             (subject, body)
         }
         Event::Deposit(event_params) => {
-            let subject = String::from("Someone deposited to an address you're subscribed to");
+            let subject = with_restart_note(
+                String::from("Someone deposited to an address you're subscribed to"),
+                event_params.after_restart,
+            );
 
-            let body = format!(
+            let mut body = format!(
                 "Someone deposited {} sats to address {}",
                 format_with_commas(event_params.utxo.value.to_sat()),
                 event_params.address
             );
+            match event_params.utxo.status.block_height {
+                Some(block_height) => body.push_str(&format!(
+                    "\n\nTransaction {} confirmed at height {}",
+                    event_params.utxo.txid, block_height
+                )),
+                None => body.push_str(&format!(
+                    "\n\nTransaction {} was seen in the mempool and is not confirmed yet",
+                    event_params.utxo.txid
+                )),
+            }
+            push_details(&mut body, event_params);
+
+            (subject, body)
+        }
+        Event::Confirmed(event_params) => {
+            let subject = with_restart_note(
+                String::from("A deposit to an address you're subscribed to was confirmed"),
+                event_params.after_restart,
+            );
 
-            info!(
-                "Someone deposited {} sats to address {} at height {}",
+            let mut body = format!(
+                "The deposit of {} sats to address {} was confirmed at height {}\n\nTransaction {}",
                 format_with_commas(event_params.utxo.value.to_sat()),
                 event_params.address,
-                event_params.height
+                event_params.height,
+                event_params.utxo.txid
             );
-
-            debug!("Event::Deposit email:");
-            debug!(" Subject: {subject}");
-            debug!(" Body: {body}");
+            push_details(&mut body, event_params);
 
             (subject, body)
         }
         Event::Withdrawal(event_params) => {
-            let subject = String::from("Heads up, someone withdrew from an address you're subscribed to!");
+            let unconfirmed = event_params.spend.as_ref().is_some_and(|spend| !spend.status.confirmed);
+            let subject = with_restart_note(
+                match unconfirmed {
+                    true => String::from(
+                        "Heads up, someone is withdrawing from an address you're subscribed to! (unconfirmed)",
+                    ),
+                    false => String::from("Heads up, someone withdrew from an address you're subscribed to!"),
+                },
+                event_params.after_restart,
+            );
 
-            let body = format!(
+            let mut body = format!(
                 "Someone withdrew {} sats from address {}",
                 format_with_commas(event_params.utxo.value.to_sat()),
                 event_params.address
             );
+            if let Some(spend) = &event_params.spend {
+                match spend.status.block_height {
+                    Some(block_height) => body.push_str(&format!(
+                        "\n\nSpending transaction {} confirmed at height {}",
+                        spend.txid, block_height
+                    )),
+                    None => body.push_str(&format!(
+                        "\n\nSpending transaction {} is in the mempool and is NOT confirmed yet",
+                        spend.txid
+                    )),
+                }
+                push_spend_details(&mut body, spend, network);
+            }
+            push_details(&mut body, event_params);
+
+            (subject, body)
+        }
+        Event::Transaction(tx_params) => {
+            let txid = match tx_params.txid {
+                Some(txid) => txid.to_string(),
+                None => String::from("an unknown transaction"),
+            };
+            let subject = match (tx_params.withdrawals.is_empty(), tx_params.deposits.is_empty()) {
+                (false, _) => String::from("Heads up, someone withdrew from addresses you're subscribed to!"),
+                (true, false) => String::from("Someone deposited to addresses you're subscribed to"),
+                (true, true) => String::from("Deposits to addresses you're subscribed to were confirmed"),
+            };
+            let subject = with_restart_note(subject, tx_params.after_restart);
+
+            let mut body = match &tx_params.status {
+                Some(status) => match status.block_height {
+                    Some(block_height) => format!("Transaction {} confirmed at height {}", txid, block_height),
+                    None => format!("Transaction {} is in the mempool and is NOT confirmed yet", txid),
+                },
+                None => format!("Transaction {}", txid),
+            };
+
+            if !tx_params.withdrawals.is_empty() {
+                body.push_str(&format!(
+                    "\n\nWithdrew {} sats in total:",
+                    format_with_commas(tx_params.total_withdrawn().to_sat())
+                ));
+                for withdrawal in &tx_params.withdrawals {
+                    body.push_str(&format!(
+                        "\n- {} sats from address {} ({}:{})",
+                        format_with_commas(withdrawal.utxo.value.to_sat()),
+                        withdrawal.address,
+                        withdrawal.utxo.txid,
+                        withdrawal.utxo.vout
+                    ));
+                }
+            }
+            if let Some(spend) = tx_params
+                .withdrawals
+                .iter()
+                .find_map(|withdrawal| withdrawal.spend.as_ref())
+            {
+                push_spend_details(&mut body, spend, network);
+            }
+            if !tx_params.deposits.is_empty() {
+                body.push_str(&format!(
+                    "\n\nDeposited {} sats in total:",
+                    format_with_commas(tx_params.total_deposited().to_sat())
+                ));
+                for deposit in &tx_params.deposits {
+                    body.push_str(&format!(
+                        "\n- {} sats to address {} (output {})",
+                        format_with_commas(deposit.utxo.value.to_sat()),
+                        deposit.address,
+                        deposit.utxo.vout
+                    ));
+                }
+            }
+            if !tx_params.confirmations.is_empty() {
+                body.push_str("\n\nConfirmed these deposits:");
+                for confirmation in &tx_params.confirmations {
+                    body.push_str(&format!(
+                        "\n- {} sats to address {} (output {})",
+                        format_with_commas(confirmation.utxo.value.to_sat()),
+                        confirmation.address,
+                        confirmation.utxo.vout
+                    ));
+                }
+            }
+
+            let net_flows = tx_params.net_flows();
+            if !net_flows.is_empty() {
+                body.push_str("\n\nNet flow per address:");
+                for (address, net_flow) in &net_flows {
+                    let sign = if net_flow.is_negative() { "-" } else { "+" };
+                    body.push_str(&format!(
+                        "\n- {}: {}{} sats",
+                        address,
+                        sign,
+                        format_with_commas(net_flow.unsigned_abs().to_sat())
+                    ));
+                }
+            }
+            if tx_params.after_restart {
+                body.push_str(&format!(
+                    "\n\nThis happened while smaug was not running, and was detected after a restart at height {}",
+                    tx_params.height
+                ));
+            }
+
+            (subject, body)
+        }
+        Event::Disagreement(disagreement) => {
+            let subject = String::from("Heads up, your chain source backends disagree!");
+
+            let mut body = match &disagreement.address {
+                Some(address) => format!(
+                    "The Esplora APIs report different confirmed UTXOs for address {} at the same chain tip:",
+                    address
+                ),
+                None => String::from("The Esplora APIs report chain tips too far apart:"),
+            };
+            for answer in &disagreement.answers {
+                let height = match answer.height {
+                    Some(height) => format!("height {}", height),
+                    None => String::from("no answer"),
+                };
+                match (&answer.utxos, answer.balance) {
+                    (Some(utxos), Some(balance)) => body.push_str(&format!(
+                        "\n- {}: {}, {} UTXOs, {} sats",
+                        answer.url,
+                        height,
+                        utxos.len(),
+                        format_with_commas(balance.to_sat())
+                    )),
+                    _ => body.push_str(&format!("\n- {}: {}", answer.url, height)),
+                }
+            }
+            body.push_str("\n\nOne of them may be lagging behind, or lying about the chain.");
+
+            (subject, body)
+        }
+        Event::VerificationFailure(failure) => {
+            let subject = String::from("Heads up, your chain source sent data that failed verification!");
 
-            warn!(
-                "Heads up, someone withdrew {} sats from address {} at height {}!",
+            let mut body = match failure.txid {
+                Some(txid) => format!("Transaction {} failed SPV verification: {}", txid, failure.reason),
+                None => format!("The header chain failed verification: {}", failure.reason),
+            };
+            body.push_str(
+                "\n\nThe chain source may be compromised. The watched addresses are not updated until it serves data \
+                 that checks out.",
+            );
+
+            (subject, body)
+        }
+        Event::Reorg(reorg) => {
+            let subject = format!("Heads up, a reorg of {} blocks happened!", reorg.depth);
+
+            let body = format!(
+                "The chain was reorganized above height {}: block {} was replaced, and the new tip is block {} at \
+                 height {}\n\nEvery movement reported in the {} disconnected blocks was checked again on the new chain.",
+                reorg.fork_height, reorg.old_tip, reorg.new_tip, reorg.height, reorg.depth
+            );
+
+            (subject, body)
+        }
+        Event::DepositReorged(event_params) => {
+            let subject = String::from("Heads up, a deposit to an address you're subscribed to was reorged out!");
+
+            let mut body = format!(
+                "The deposit of {} sats to address {} was confirmed in a block that was reorganized out",
+                format_with_commas(event_params.utxo.value.to_sat()),
+                event_params.address
+            );
+            match event_params.utxo.status.confirmed {
+                false => body.push_str(&format!(
+                    "\n\nTransaction {} is back in the mempool and is NOT confirmed anymore",
+                    event_params.utxo.txid
+                )),
+                true => body.push_str(&format!(
+                    "\n\nTransaction {} is neither in the new chain nor in the mempool, it may have been double-spent",
+                    event_params.utxo.txid
+                )),
+            }
+            push_details(&mut body, event_params);
+
+            (subject, body)
+        }
+        Event::WithdrawalReorged(event_params) => {
+            let subject = String::from("A withdrawal from an address you're subscribed to was reorged out");
+
+            let mut body = format!(
+                "The withdrawal of {} sats from address {} was confirmed in a block that was reorganized out\n\nUTXO \
+                 {}:{} is unspent again",
                 format_with_commas(event_params.utxo.value.to_sat()),
                 event_params.address,
-                event_params.height
+                event_params.utxo.txid,
+                event_params.utxo.vout
+            );
+            push_details(&mut body, event_params);
+
+            (subject, body)
+        }
+        Event::Report(report) => {
+            let subject = match report.has_errors() {
+                false => String::from("smaug status report: all quiet"),
+                true => String::from("smaug status report: something went wrong since the last report"),
+            };
+
+            let last_poll = match report.secs_since_last_poll {
+                Some(secs) => format!("{} ago", format_duration(Duration::from_secs(secs))),
+                None => String::from("never"),
+            };
+            let mut body = format!(
+                "smaug has been guarding {} addresses for {}\n\nTip height: {}\nLast successful poll: {}",
+                report.addresses,
+                format_duration(Duration::from_secs(report.uptime_sec)),
+                report.height,
+                last_poll
             );
 
-            debug!("Event::Withdrawal email:");
-            debug!(" Subject: {subject}");
-            debug!(" Body: {body}");
+            let unconfirmed: u64 = report.balances.iter().map(|balance| balance.unconfirmed.to_sat()).sum();
+            body.push_str(&format!(
+                "\n\nTotal: {} sats ({} sats unconfirmed)",
+                format_with_commas(report.total_balance().to_sat()),
+                format_with_commas(unconfirmed)
+            ));
+            for balance in &report.balances {
+                body.push_str(&format!(
+                    "\n- {}: {} sats",
+                    balance.address,
+                    format_with_commas(balance.balance.to_sat())
+                ));
+            }
+
+            push_errors(
+                &mut body,
+                "Chain source errors since the last report",
+                &report.backend_errors,
+            );
+            push_errors(
+                &mut body,
+                "Delivery errors since the last report",
+                &report.delivery_errors,
+            );
+            if report.dead_letters > 0 {
+                body.push_str(&format!(
+                    "\n\n{} notifications could not be delivered and were given up on, they are kept in the outbox",
+                    report.dead_letters
+                ));
+            }
+            if !report.has_errors() {
+                body.push_str("\n\nNothing went wrong since the last report.");
+            }
 
             (subject, body)
         }
-    };
+        Event::Milestone(milestone) => {
+            let event_params = &milestone.params;
+            let (movement, preposition, txid) = match milestone.movement {
+                Movement::Deposit => ("deposit", "to", Some(event_params.utxo.txid)),
+                Movement::Withdrawal => (
+                    "withdrawal",
+                    "from",
+                    event_params.spend.as_ref().map(|spend| spend.txid),
+                ),
+            };
+            let subject = match milestone.last {
+                true => format!(
+                    "All clear: the {movement} {preposition} an address you're subscribed to has {} confirmations",
+                    milestone.confirmations
+                ),
+                false => format!(
+                    "The {movement} {preposition} an address you're subscribed to has {} confirmations",
+                    milestone.confirmations
+                ),
+            };
+
+            let mut body = format!(
+                "The {} of {} sats {} address {} has {} confirmations",
+                movement,
+                format_with_commas(event_params.utxo.value.to_sat()),
+                preposition,
+                event_params.address,
+                milestone.confirmations
+            );
+            if let Some(txid) = txid {
+                body.push_str(&format!("\n\nTransaction {}", txid));
+            }
+            if milestone.last {
+                body.push_str("\n\nThis is the last notification about it");
+            }
+            push_details(&mut body, event_params);
 
-    let messages: Vec<Message> = recipient_mailboxes
-        .iter()
-        .map(|mailbox| {
-            Message::builder()
-                .from(sender_mailbox.clone())
-                .to(mailbox.clone())
-                .subject(subject.clone())
-                .header(ContentType::TEXT_PLAIN)
-                .body(body.clone())
-                .unwrap()
-        })
-        .collect();
-
-    Ok(messages)
+            (subject, body)
+        }
+    }
+}
+
+/// Delivers [`Event`] notifications by email.
+pub(crate) struct EmailNotifier {
+    /// The SMTP username, also used as the sender's address.
+    sender: EmailAddress,
+    /// The addresses notifications are sent to.
+    recipients: Vec<EmailAddress>,
+    /// The SMTP transport.
+    mailer: SmtpTransport,
 }
 
-/// Send email messages.
-pub(crate) fn send_messages(config: &Config, messages: &Vec<Message>) -> Result<(), EmailError> {
-    let smtp_credentials = Credentials::new(config.smtp_username.to_string(), config.smtp_password.clone());
-    let tls = TlsParameters::new_rustls(config.smtp_server.clone())?;
-    let mailer = SmtpTransport::relay(&config.smtp_server)?
-        .port(config.smtp_port)
+impl EmailNotifier {
+    /// Build an [`EmailNotifier`] from the configuration, if `recipient_emails` is set.
+    pub(crate) fn from_config(config: &Config) -> Result<Option<EmailNotifier>, EmailError> {
+        if config.recipient_emails.is_empty() {
+            return Ok(None);
+        }
+
+        let sender = config
+            .smtp_username
+            .clone()
+            .ok_or(EmailError::MissingSmtpParameter("smtp_username"))?;
+        let password = config
+            .smtp_password
+            .clone()
+            .ok_or(EmailError::MissingSmtpParameter("smtp_password"))?;
+        let server = config
+            .smtp_server
+            .clone()
+            .ok_or(EmailError::MissingSmtpParameter("smtp_server"))?;
+        let port = config.smtp_port.ok_or(EmailError::MissingSmtpParameter("smtp_port"))?;
+
+        let smtp_credentials = Credentials::new(sender.to_string(), password);
+        let tls = TlsParameters::new_rustls(server.clone())?;
+        // Through a proxy, the SMTP server is reached from a local port relaying to it, and its certificate is still
+        // checked against `smtp_server`. The default HELO name would give away our hostname.
+        let mailer = match proxy::forward(&server, port)? {
+            Some(local_address) => SmtpTransport::builder_dangerous(local_address.ip().to_string())
+                .port(local_address.port())
+                .hello_name(ClientId::Domain(String::from("localhost"))),
+            None => SmtpTransport::relay(&server)?.port(port),
+        }
         .credentials(smtp_credentials)
         .tls(Tls::Required(tls))
         .build();
 
-    debug!("Sending {} emails...", messages.len());
-    for message in messages {
-        mailer.send(message)?;
-        if let Some(recipient) = message.envelope().to().first() {
-            info!("Sent email to {}", recipient);
+        Ok(Some(EmailNotifier {
+            sender,
+            recipients: config.recipient_emails.clone(),
+            mailer,
+        }))
+    }
+
+    /// Create an email message from a [`Notification`] to every address in `recipient_emails`.
+    pub(crate) fn build_messages(&self, notification: &Notification) -> Result<Vec<Message>, EmailError> {
+        // The sender's mailbox.
+        let sender_mailbox = Mailbox::new(Some(String::from("Smaug, the UTXO guardian")), self.sender.clone());
+
+        // All the recipients we must build messages to.
+        let recipient_mailboxes: Vec<Mailbox> = self
+            .recipients
+            .iter()
+            .map(|email| Mailbox::new(None, email.clone()))
+            .collect();
+        debug!("recipient_mailboxes: {:#?}", recipient_mailboxes);
+
+        let (subject, body) = (&notification.subject, &notification.body);
+        debug!("Email subject: {subject}");
+        debug!("Email body: {body}");
+
+        let messages: Vec<Message> = recipient_mailboxes
+            .iter()
+            .map(|mailbox| {
+                Message::builder()
+                    .from(sender_mailbox.clone())
+                    .to(mailbox.clone())
+                    .subject(subject.clone())
+                    .header(ContentType::TEXT_PLAIN)
+                    .body(body.clone())
+                    .unwrap()
+            })
+            .collect();
+
+        Ok(messages)
+    }
+
+    /// Send email messages.
+    pub(crate) fn send_messages(&self, messages: &[Message]) -> Result<(), EmailError> {
+        debug!("Sending {} emails...", messages.len());
+        for message in messages {
+            self.mailer.send(message)?;
+            if let Some(recipient) = message.envelope().to().first() {
+                info!("Sent email to {}", recipient);
+            }
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn notify(&self, notification: &Notification, delivered: &mut BTreeSet<String>) -> Result<(), NotifierError> {
+        let messages = self.build_messages(notification)?;
+
+        // Deliver to every recipient not reached yet, even if delivery to one of them fails.
+        let mut result = Ok(());
+        for (recipient, message) in self.recipients.iter().zip(&messages) {
+            let key = recipient.to_string();
+            if delivered.contains(&key) {
+                continue;
+            }
+            match self.send_messages(slice::from_ref(message)) {
+                Ok(()) => {
+                    delivered.insert(key);
+                }
+                Err(e) => {
+                    warn!("Failed to send email to {recipient}: {e}");
+                    result = Err(e);
+                }
+            }
+        }
+
+        Ok(result?)
+    }
 }
 
 #[cfg(test)]
@@ -163,7 +650,6 @@ mod tests {
 
     use super::*;
     use crate::parse_config;
-    use crate::smaug::{Event, EventParams};
 
     #[test]
     fn build_and_send_email() {
@@ -177,7 +663,8 @@ mod tests {
             .unwrap();
 
         let event: Event = Event::Deposit(EventParams {
-            address: address,
+            address,
+            origin: None,
             utxo: Utxo {
                 txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
                 vout: 0,
@@ -190,12 +677,16 @@ mod tests {
                 value: Amount::from_sat(1337),
             },
             height: 900009,
+            after_restart: false,
+            spend: None,
         });
 
-        let messages = build_messages(&config, &event).unwrap();
+        let notifier = EmailNotifier::from_config(&config).unwrap().unwrap();
+        let notification = Notification::new(config.network, &event).unwrap();
+        let messages = notifier.build_messages(&notification).unwrap();
 
         println!("messages: {:#?}", messages);
 
-        let _ = send_messages(&config, &messages).unwrap();
+        notifier.send_messages(&messages).unwrap();
     }
 }
@@ -0,0 +1,293 @@
+use std::{
+    fmt::Display,
+    sync::PoisonError,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bitcoin::Amount;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use crate::outbox::Outbox;
+use crate::proxy;
+use crate::smaug::{AddressBalance, ReportParams};
+use crate::status::{SharedStatus, Status};
+
+/// The default amount of seconds to wait for the dead-man's switch to respond.
+pub(crate) const DEFAULT_PING_TIMEOUT_SEC: u64 = 10;
+
+/// The least amount of seconds between two pings of the dead-man's switch, however often the chain source is polled.
+const MIN_PING_PERIOD_SEC: u64 = 60;
+
+/// How many distinct errors are listed in a report: the others are only counted.
+const MAX_LISTED_ERRORS: usize = 20;
+
+/// Periodic status reports, and the dead-man's switch pinged after successful polls.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct HeartbeatConfig {
+    /// How often to send a status report through the notifiers, in seconds, like 604800 for weekly.
+    /// No report is sent, if left empty.
+    #[serde(default)]
+    pub(crate) report_period_sec: Option<u64>,
+    /// The URL to GET after every successful poll, like a healthchecks.io check, which alerts once `smaug` stops.
+    /// No dead-man's switch is pinged, if left empty.
+    #[serde(default)]
+    pub(crate) ping_url: Option<String>,
+    /// How long to wait for the dead-man's switch to respond, in seconds.
+    #[serde(default = "default_ping_timeout_sec")]
+    pub(crate) ping_timeout_sec: u64,
+}
+
+fn default_ping_timeout_sec() -> u64 {
+    DEFAULT_PING_TIMEOUT_SEC
+}
+
+/// An error, and how many times it happened.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct ErrorCount {
+    pub(crate) message: String,
+    pub(crate) count: u32,
+}
+
+/// Errors grouped by message, in the order they first happened.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct ErrorLog {
+    /// The first `MAX_LISTED_ERRORS` distinct errors.
+    pub(crate) errors: Vec<ErrorCount>,
+    /// How many times errors past those happened.
+    pub(crate) others: u32,
+}
+
+impl ErrorLog {
+    /// Record an error.
+    pub(crate) fn record(&mut self, message: String) {
+        if let Some(error) = self.errors.iter_mut().find(|error| error.message == message) {
+            error.count += 1;
+        } else if self.errors.len() < MAX_LISTED_ERRORS {
+            self.errors.push(ErrorCount { message, count: 1 });
+        } else {
+            self.others += 1;
+        }
+    }
+
+    /// Whether no error was recorded.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.others == 0
+    }
+
+    /// How many errors were recorded.
+    pub(crate) fn count(&self) -> u32 {
+        self.errors.iter().map(|error| error.count).sum::<u32>() + self.others
+    }
+}
+
+/// Tells the world `smaug` is alive: sends the periodic status reports, and pings the dead-man's switch.
+pub(crate) struct Heartbeat {
+    config: Option<HeartbeatConfig>,
+    /// When `smaug` started.
+    started: Instant,
+    /// When the next status report is due, if reports are sent.
+    next_report: Option<Instant>,
+    /// The successful poll the dead-man's switch was last pinged for, and when.
+    last_ping: Option<(SystemTime, Instant)>,
+    /// The errors querying the chain source since the last report.
+    backend_errors: ErrorLog,
+    /// Where pings are sent from, so a slow dead-man's switch never holds back the event loop.
+    runtime: Handle,
+}
+
+impl Heartbeat {
+    /// Start the heartbeat: the first status report is due a `report_period_sec` from now.
+    pub(crate) fn new(config: Option<&HeartbeatConfig>, runtime: &Handle) -> Heartbeat {
+        let started = Instant::now();
+        Heartbeat {
+            config: config.cloned(),
+            started,
+            next_report: config
+                .and_then(|config| config.report_period_sec)
+                .map(|period| started + Duration::from_secs(period)),
+            last_ping: None,
+            backend_errors: ErrorLog::default(),
+            runtime: runtime.clone(),
+        }
+    }
+
+    /// Record that querying the chain source failed to do `what`, for the next report.
+    pub(crate) fn backend_error(&mut self, what: &str, error: &dyn Display) {
+        self.backend_errors.record(format!("failed to {what}: {error}"));
+    }
+
+    /// Ping the dead-man's switch in the background, if the chain source was polled successfully since the last ping.
+    pub(crate) fn ping(&mut self, status: &SharedStatus) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let Some(url) = &config.ping_url else {
+            return;
+        };
+        let Some(last_poll) = status.lock().unwrap_or_else(PoisonError::into_inner).last_poll else {
+            return;
+        };
+        if let Some((pinged_poll, pinged_at)) = self.last_ping
+            && (pinged_poll >= last_poll || pinged_at.elapsed() < Duration::from_secs(MIN_PING_PERIOD_SEC))
+        {
+            return;
+        }
+        self.last_ping = Some((last_poll, Instant::now()));
+
+        let request = proxy::proxied(minreq::get(url)).with_timeout(config.ping_timeout_sec);
+        self.runtime.spawn_blocking(move || match request.send() {
+            Ok(response) if (200..300).contains(&response.status_code) => debug!("Pinged the dead-man's switch"),
+            Ok(response) => warn!(
+                "The dead-man's switch responded with {} {}",
+                response.status_code, response.reason_phrase
+            ),
+            Err(e) => warn!("Failed to ping the dead-man's switch: {e}"),
+        });
+    }
+
+    /// The status report, once it is due, with the errors since the last one.
+    pub(crate) fn report(&mut self, status: &SharedStatus, outbox: &Outbox) -> Option<ReportParams> {
+        let period = self.config.as_ref()?.report_period_sec?;
+        let next_report = self.next_report?;
+        if Instant::now() < next_report {
+            return None;
+        }
+        self.next_report = Some(Instant::now() + Duration::from_secs(period));
+
+        let (delivery_errors, dead_letters) = outbox.take_failures();
+        let report = build_report(
+            &status.lock().unwrap_or_else(PoisonError::into_inner),
+            self.started.elapsed(),
+            std::mem::take(&mut self.backend_errors),
+            delivery_errors,
+            dead_letters,
+        );
+        Some(report)
+    }
+}
+
+/// Build the status report from what `smaug` knew after its last successful poll.
+fn build_report(
+    status: &Status,
+    uptime: Duration,
+    backend_errors: ErrorLog,
+    delivery_errors: ErrorLog,
+    dead_letters: u32,
+) -> ReportParams {
+    let mut balances: Vec<AddressBalance> = status
+        .utxos
+        .iter()
+        .map(|(address, utxos)| AddressBalance {
+            address: address.clone(),
+            balance: utxos.iter().map(|utxo| utxo.value).sum(),
+            unconfirmed: utxos
+                .iter()
+                .filter(|utxo| !utxo.status.confirmed)
+                .map(|utxo| utxo.value)
+                .sum(),
+        })
+        .filter(|balance| balance.balance > Amount::ZERO)
+        .collect();
+    balances.sort_by_key(|balance| balance.address.to_string());
+
+    ReportParams {
+        uptime_sec: uptime.as_secs(),
+        height: status.height,
+        secs_since_last_poll: status
+            .last_poll
+            .map(|last_poll| last_poll.elapsed().unwrap_or_default().as_secs()),
+        addresses: status.utxos.len(),
+        balances,
+        backend_errors,
+        delivery_errors,
+        dead_letters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{Address, BlockHash, Txid, hashes::Hash};
+    use esplora_client::{Utxo, UtxoStatus};
+
+    use super::*;
+    use crate::smaug::UtxoDB;
+
+    fn utxo(vout: u32, value: u64, confirmed: bool) -> Utxo {
+        Utxo {
+            txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            vout,
+            status: UtxoStatus {
+                confirmed,
+                block_height: confirmed.then_some(100),
+                block_hash: confirmed.then(BlockHash::all_zeros),
+                block_time: None,
+            },
+            value: Amount::from_sat(value),
+        }
+    }
+
+    #[test]
+    fn errors_are_grouped_and_capped() {
+        let mut log = ErrorLog::default();
+        assert!(log.is_empty());
+
+        for _ in 0..3 {
+            log.record(String::from("timed out"));
+        }
+        for i in 0..MAX_LISTED_ERRORS + 5 {
+            log.record(format!("error {i}"));
+        }
+
+        assert_eq!(log.errors.len(), MAX_LISTED_ERRORS);
+        assert_eq!(
+            log.errors[0],
+            ErrorCount {
+                message: String::from("timed out"),
+                count: 3
+            }
+        );
+        assert_eq!(log.others, 6);
+        assert_eq!(log.count(), 3 + MAX_LISTED_ERRORS as u32 + 5);
+    }
+
+    #[test]
+    fn report_lists_the_addresses_holding_coins() {
+        let funded = Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .assume_checked();
+        let empty = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .assume_checked();
+        let status = Status {
+            height: 100,
+            last_poll: Some(SystemTime::now() - Duration::from_secs(30)),
+            utxos: UtxoDB::from([
+                (funded.clone(), vec![utxo(0, 1_000, true), utxo(1, 500, false)]),
+                (empty, Vec::new()),
+            ]),
+        };
+        let mut backend_errors = ErrorLog::default();
+        backend_errors.record(String::from("failed to fetch UTXOs: timed out"));
+
+        let report = build_report(
+            &status,
+            Duration::from_secs(3600),
+            backend_errors.clone(),
+            ErrorLog::default(),
+            0,
+        );
+        assert_eq!(report.uptime_sec, 3600);
+        assert_eq!(report.height, 100);
+        assert!(report.secs_since_last_poll.is_some_and(|secs| secs >= 30));
+        assert_eq!(report.addresses, 2);
+        assert_eq!(report.balances.len(), 1);
+        assert_eq!(report.balances[0].address, funded);
+        assert_eq!(report.balances[0].balance, Amount::from_sat(1_500));
+        assert_eq!(report.balances[0].unconfirmed, Amount::from_sat(500));
+        assert_eq!(report.backend_errors, backend_errors);
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{Address, Amount, Txid};
+use esplora_client::{Tx, Utxo, UtxoStatus};
+use log::debug;
+
+use crate::chain::ChainSource;
+use crate::descriptor::AddressOrigin;
+use crate::proxy;
+use crate::smaug::{Event, EventParams, SmaugError, Spend};
+use crate::watchlist::WatchList;
+
+/// The amount of confirmed transactions Esplora returns per page of address history.
+pub(crate) const CHAIN_TXS_PER_PAGE: usize = 25;
+
+/// How many blocks below the polled tip transactions are still looked for, in case the Esplora API that answered, one
+/// of several behind a load balancer or after a failover, indexed them late.
+const LATE_INDEX_MARGIN: u32 = 6;
+
+/// Detects deposits and withdrawals by walking the transaction history of the watched addresses.
+///
+/// Unlike diffing UTXO snapshots, this reports every transaction touching a watched script, including UTXOs that
+/// were created and spent in between two polls.
+#[derive(Debug)]
+pub(crate) struct HistoryWatch {
+    /// Every confirmed transaction at or below this height has been processed.
+    synced_height: u32,
+    /// Confirmed transactions above `synced_height` that were already processed, with their height.
+    processed: HashMap<Txid, u32>,
+    /// Mempool transactions that were already processed.
+    unconfirmed: HashSet<Txid>,
+}
+
+impl HistoryWatch {
+    /// Start walking history from `synced_height`: only transactions confirmed above it, or in the mempool, are new.
+    pub(crate) fn new(synced_height: u32) -> HistoryWatch {
+        HistoryWatch {
+            synced_height,
+            processed: HashMap::new(),
+            unconfirmed: HashSet::new(),
+        }
+    }
+
+    /// Walk the new transactions of every watched address and generate their [`Event`]s.
+    ///
+    /// Returns the events, and the addresses that had new transactions.
+    pub(crate) fn poll(
+        &mut self,
+        chain: &dyn ChainSource,
+        watchlist: &WatchList,
+        height: u32,
+        after_restart: bool,
+    ) -> Result<(Vec<Event>, Vec<Address>), SmaugError> {
+        let mut events = Vec::new();
+        let mut active_addresses = Vec::new();
+        let mut processed = self.processed.clone();
+        let mut unconfirmed = HashSet::new();
+
+        let addresses = proxy::query_order(watchlist.addresses());
+        let first_pages = chain.addresses_txs(&addresses)?;
+        for (address, first_page) in addresses.into_iter().zip(first_pages) {
+            let (new_txs, mempool_txids) = self.fetch_new_txs(chain, address, first_page)?;
+            // Transactions that left the mempool (mined or evicted) are forgotten.
+            unconfirmed.extend(mempool_txids);
+            if new_txs.is_empty() {
+                continue;
+            }
+
+            debug!("{} new transactions on address {address}", new_txs.len());
+            active_addresses.push(address.clone());
+
+            // Oldest first.
+            for tx in new_txs.iter().rev() {
+                let was_unconfirmed = self.unconfirmed.contains(&tx.txid);
+                events.extend(tx_events(
+                    address,
+                    watchlist.origin(address),
+                    tx,
+                    height,
+                    after_restart,
+                    was_unconfirmed,
+                ));
+
+                if let Some(block_height) = tx.status.block_height {
+                    processed.insert(tx.txid, block_height);
+                }
+            }
+        }
+
+        self.advance(height, processed);
+        self.unconfirmed = unconfirmed;
+
+        Ok((events, active_addresses))
+    }
+
+    /// Process a mempool transaction pushed by the chain source, touching `addresses`, and generate its [`Event`]s.
+    ///
+    /// The transaction is remembered as processed, so the next poll only reports its confirmation.
+    pub(crate) fn on_tx(&mut self, tx: &Tx, addresses: &[Address], watchlist: &WatchList, height: u32) -> Vec<Event> {
+        if self.processed.contains_key(&tx.txid) || !self.unconfirmed.insert(tx.txid) {
+            return Vec::new();
+        }
+
+        addresses
+            .iter()
+            .flat_map(|address| tx_events(address, watchlist.origin(address), tx, height, false, false))
+            .collect()
+    }
+
+    /// Record the confirmed transactions `processed` after polling up to `height`.
+    ///
+    /// Only the transactions confirmed more than [`LATE_INDEX_MARGIN`] blocks deep are forgotten: the others are still
+    /// looked for, so one indexed late is reported on a later poll.
+    fn advance(&mut self, height: u32, mut processed: HashMap<Txid, u32>) {
+        self.synced_height = self.synced_height.max(height.saturating_sub(LATE_INDEX_MARGIN));
+        processed.retain(|_, block_height| *block_height > self.synced_height);
+        self.processed = processed;
+    }
+
+    /// Whether `tx` has not been processed yet, or was processed while unconfirmed and is now confirmed.
+    fn is_new(&self, tx: &Tx) -> bool {
+        match tx.status.block_height {
+            Some(block_height) => block_height > self.synced_height && !self.processed.contains_key(&tx.txid),
+            None => !self.unconfirmed.contains(&tx.txid),
+        }
+    }
+
+    /// Fetch the new transactions of `address`, newest first, paging back from `page`, the first page of its history,
+    /// through its confirmed history as needed.
+    ///
+    /// Also returns the txids of all its transactions currently in the mempool.
+    fn fetch_new_txs(
+        &self,
+        chain: &dyn ChainSource,
+        address: &Address,
+        mut page: Vec<Tx>,
+    ) -> Result<(Vec<Tx>, HashSet<Txid>), SmaugError> {
+        let mut new_txs = Vec::new();
+        let mempool_txids = page
+            .iter()
+            .filter(|tx| !tx.status.confirmed)
+            .map(|tx| tx.txid)
+            .collect();
+
+        loop {
+            let confirmed: Vec<&Tx> = page.iter().filter(|tx| tx.status.confirmed).collect();
+            let next_page = match confirmed.last() {
+                Some(oldest) if confirmed.len() >= CHAIN_TXS_PER_PAGE && self.is_new(oldest) => Some(oldest.txid),
+                _ => None,
+            };
+
+            new_txs.extend(page.into_iter().filter(|tx| self.is_new(tx)));
+
+            match next_page {
+                Some(last_seen) => page = chain.address_txs(address, Some(last_seen))?,
+                None => return Ok((new_txs, mempool_txids)),
+            }
+        }
+    }
+}
+
+/// Generate the [`Event`]s of a transaction touching `address`.
+///
+/// Every output paying to `address` is a deposit, and every input spending from it is a withdrawal. A transaction
+/// that was already processed while unconfirmed yields [`Event::Confirmed`]s for its deposits and follow-up
+/// [`Event::Withdrawal`]s for its withdrawals instead.
+pub(crate) fn tx_events(
+    address: &Address,
+    origin: Option<&AddressOrigin>,
+    tx: &Tx,
+    height: u32,
+    after_restart: bool,
+    was_unconfirmed: bool,
+) -> Vec<Event> {
+    let script_pubkey = address.script_pubkey();
+    let mut events = Vec::new();
+
+    for (vout, output) in tx.vout.iter().enumerate() {
+        if output.scriptpubkey != script_pubkey {
+            continue;
+        }
+
+        let event_params = EventParams {
+            address: address.clone(),
+            origin: origin.cloned(),
+            utxo: Utxo {
+                txid: tx.txid,
+                vout: vout as u32,
+                status: UtxoStatus {
+                    confirmed: tx.status.confirmed,
+                    block_height: tx.status.block_height,
+                    block_hash: tx.status.block_hash,
+                    block_time: tx.status.block_time,
+                },
+                value: Amount::from_sat(output.value),
+            },
+            height: tx.status.block_height.unwrap_or(height),
+            after_restart,
+            spend: None,
+        };
+        events.push(match was_unconfirmed {
+            true => Event::Confirmed(event_params),
+            false => Event::Deposit(event_params),
+        });
+    }
+
+    for input in &tx.vin {
+        let Some(prevout) = input
+            .prevout
+            .as_ref()
+            .filter(|prevout| prevout.scriptpubkey == script_pubkey)
+        else {
+            continue;
+        };
+
+        events.push(Event::Withdrawal(EventParams {
+            address: address.clone(),
+            origin: origin.cloned(),
+            // The status of the funding transaction is not known from the spending one.
+            utxo: Utxo {
+                txid: input.txid,
+                vout: input.vout,
+                status: UtxoStatus {
+                    confirmed: true,
+                    block_height: None,
+                    block_hash: None,
+                    block_time: None,
+                },
+                value: Amount::from_sat(prevout.value),
+            },
+            height: tx.status.block_height.unwrap_or(height),
+            after_restart,
+            spend: Some(Spend::from_tx(tx)),
+        }));
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{Network, ScriptBuf};
+    use esplora_client::{PrevOut, TxStatus, Vin, Vout};
+
+    use super::*;
+
+    fn address() -> Address {
+        Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap()
+    }
+
+    /// A transaction that spends a deposit to [`address`] and sends the change back to it.
+    fn sweep(block_height: Option<u32>) -> Tx {
+        let watched = address().script_pubkey();
+        Tx {
+            txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            version: 2,
+            locktime: 0,
+            vin: vec![Vin {
+                txid: Txid::from_str("9e8b5c7b468dab43ae55f66d977819102c3bb129825cc6bddb54f45fafb7ae33").unwrap(),
+                vout: 3,
+                prevout: Some(PrevOut {
+                    value: 100_000,
+                    scriptpubkey: watched.clone(),
+                }),
+                scriptsig: ScriptBuf::new(),
+                witness: vec![],
+                sequence: 0xfffffffd,
+                is_coinbase: false,
+            }],
+            vout: vec![
+                Vout {
+                    value: 90_000,
+                    scriptpubkey: ScriptBuf::new_op_return([0u8; 4]),
+                },
+                Vout {
+                    value: 9_000,
+                    scriptpubkey: watched,
+                },
+            ],
+            size: 200,
+            weight: 800,
+            status: TxStatus {
+                confirmed: block_height.is_some(),
+                block_height,
+                block_hash: None,
+                block_time: None,
+            },
+            fee: 1_000,
+        }
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_of_a_transaction() {
+        let events = tx_events(&address(), None, &sweep(None), 101597, false, false);
+
+        assert_eq!(events.len(), 2);
+        let Event::Deposit(deposit) = &events[0] else {
+            panic!("expected a deposit, got {:?}", events[0]);
+        };
+        assert_eq!(deposit.utxo.vout, 1);
+        assert_eq!(deposit.utxo.value, Amount::from_sat(9_000));
+
+        let Event::Withdrawal(withdrawal) = &events[1] else {
+            panic!("expected a withdrawal, got {:?}", events[1]);
+        };
+        assert_eq!(withdrawal.utxo.vout, 3);
+        assert_eq!(withdrawal.utxo.value, Amount::from_sat(100_000));
+        let spend = withdrawal.spend.as_ref().unwrap();
+        assert_eq!(spend.txid, sweep(None).txid);
+        assert_eq!(spend.outputs.len(), 2);
+        assert_eq!(spend.fee_rate(), Some(5.0));
+        assert!(spend.rbf);
+    }
+
+    #[test]
+    fn confirmation_of_a_processed_transaction() {
+        let events = tx_events(&address(), None, &sweep(Some(101598)), 101598, false, true);
+
+        assert!(matches!(&events[0], Event::Confirmed(confirmed) if confirmed.height == 101598));
+        assert!(matches!(
+            &events[1],
+            Event::Withdrawal(withdrawal) if withdrawal.spend.as_ref().unwrap().status.confirmed
+        ));
+    }
+
+    #[test]
+    fn late_indexed_transactions_are_still_new() {
+        let mut watch = HistoryWatch::new(101597);
+
+        // The tip was polled before the transaction confirmed in it was indexed: it is reported on the next poll.
+        watch.advance(101598, HashMap::new());
+        assert!(watch.is_new(&sweep(Some(101598))));
+        assert!(!watch.is_new(&sweep(Some(101597))));
+
+        watch.advance(101599, HashMap::from([(sweep(None).txid, 101598)]));
+        assert!(!watch.is_new(&sweep(Some(101598))));
+
+        // Once buried deep enough, it is forgotten.
+        watch.advance(101598 + LATE_INDEX_MARGIN, watch.processed.clone());
+        assert!(watch.processed.is_empty());
+        assert!(!watch.is_new(&sweep(Some(101598))));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs, process};
+use std::{fs, process, time::Duration};
 
 use argh::FromArgs;
 use bitcoin::{
@@ -7,12 +7,55 @@ use bitcoin::{
 };
 use lettre::Address as EmailAddress;
 use log::{debug, error, info};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::bitcoind::BitcoindConfig;
+use crate::cbf::CbfConfig;
+use crate::confirmations::ConfirmationsConfig;
+use crate::descriptor::DEFAULT_GAP_LIMIT;
+use crate::electrum::ElectrumConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::nostr::NostrConfig;
+use crate::outbox::OutboxConfig;
+use crate::proxy::ProxyConfig;
+use crate::shutdown::{Shutdown, on_signals};
 use crate::smaug::{SmaugError, smaug};
+use crate::spv::SpvConfig;
+use crate::telegram::TelegramConfig;
+use crate::webhook::WebhookConfig;
+use crate::zmq::ZmqConfig;
 
+mod bitcoind;
+mod cbf;
+mod chain;
+mod confirmations;
+mod descriptor;
+mod electrum;
 mod email;
+mod heartbeat;
+mod history;
+mod mempool;
+mod nostr;
+mod notifier;
+mod outbox;
+mod proxy;
+mod quorum;
+mod reorg;
+mod scan;
+mod shutdown;
 mod smaug;
+mod spv;
+#[cfg(test)]
+mod stand_in;
+mod state;
+mod status;
+mod telegram;
+mod watchlist;
+mod webhook;
+mod zmq;
+
+/// What secrets from the configuration are logged as.
+pub(crate) const REDACTED: &str = "<redacted>";
 
 /// smaug watches your addresses and sends you an email if they move
 #[derive(FromArgs)]
@@ -26,30 +69,165 @@ struct Cli {
     version: bool,
 }
 
+/// How `smaug` detects movements on the watched addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Detection {
+    /// Diff snapshots of the UTXOs locked to every address.
+    #[default]
+    Utxo,
+    /// Walk every new transaction in the history of every address.
+    History,
+    /// Scan every new block for the watched scripts locally, never revealing the addresses to the chain source.
+    Scan,
+}
+
+/// How `smaug` uses multiple Esplora APIs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EsploraStrategy {
+    /// Query the first API that answers, moving on to the next one when it errors.
+    #[default]
+    Failover,
+    /// Query every API and alert when their chain tips or the UTXOs of an address diverge.
+    Quorum,
+}
+
 /// `smaug` configuration parameters.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Config {
     /// The network this program will operate on.
     pub(crate) network: Network,
-    /// The Esplora API URL.
+    /// The Esplora API URL, or a list of them.
     /// A default Esplora API will be used, if left empty.
-    pub(crate) esplora_url: Option<String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub(crate) esplora_url: Vec<String>,
+    /// How to use multiple Esplora APIs: `failover` (default) or `quorum`.
+    #[serde(default)]
+    pub(crate) esplora_strategy: EsploraStrategy,
+    /// How many blocks apart the chain tips of the Esplora APIs may be before they are reported as disagreeing,
+    /// with the `quorum` strategy.
+    #[serde(default = "default_quorum_tolerance")]
+    pub(crate) quorum_tolerance: u32,
+    /// How many addresses to query the Esplora APIs about at once. Ignored when the proxy isolates addresses, which
+    /// queries them one at a time.
+    #[serde(default = "default_esplora_concurrency")]
+    pub(crate) esplora_concurrency: usize,
+    /// Where to keep the header chain the answers of the Esplora APIs are verified against.
+    /// Answers are trusted as is, if left empty.
+    #[serde(default)]
+    pub(crate) spv: Option<SpvConfig>,
+    /// The bitcoind JSON-RPC connection to use instead of an Esplora API.
+    #[serde(default)]
+    pub(crate) bitcoind: Option<BitcoindConfig>,
+    /// The Electrum server to use instead of an Esplora API.
+    #[serde(default)]
+    pub(crate) electrum: Option<ElectrumConfig>,
+    /// The P2P peers to download compact block filters from, instead of using an Esplora API.
+    /// Requires `detection = "scan"`.
+    #[serde(default)]
+    pub(crate) cbf: Option<CbfConfig>,
+    /// The bitcoind ZMQ endpoints to subscribe to, to handle new transactions and blocks as soon as they are pushed.
+    /// The chain source is only polled every `POLLING_PERIOD_SEC`, if left empty.
+    #[serde(default)]
+    pub(crate) zmq: Option<ZmqConfig>,
+    /// The SOCKS5 proxy, like Tor, to route all outbound traffic through.
+    /// Servers are connected to directly, if left empty.
+    #[serde(default)]
+    pub(crate) proxy: Option<ProxyConfig>,
     /// The list of addresses to watch for movement.
+    #[serde(default)]
     pub(crate) addresses: Vec<Address<NetworkUnchecked>>,
+    /// The list of checksummed output descriptors and `xpub`/`ypub`/`zpub` keys to watch for movement.
+    #[serde(default)]
+    pub(crate) descriptors: Vec<String>,
+    /// How many unused addresses to derive past the last used one, for every descriptor.
+    #[serde(default = "default_gap_limit")]
+    pub(crate) gap_limit: u32,
+    /// How to detect movements on the watched addresses: `utxo` (default), `history` or `scan`.
+    #[serde(default)]
+    pub(crate) detection: Detection,
+    /// The height to start scanning blocks from, when `detection` is `scan` and there is no saved state.
+    /// Only blocks mined after startup are scanned, if left empty.
+    #[serde(default)]
+    pub(crate) scan_from_height: Option<u32>,
+    /// How often to poll the mempool activity of the watched addresses, in seconds.
+    /// The mempool is not polled in between blocks, if left empty.
+    #[serde(default)]
+    pub(crate) mempool_polling_period_sec: Option<u64>,
+    /// Where to persist the watched UTXOs and the last processed tip across restarts.
+    /// State is not persisted, if left empty.
+    #[serde(default)]
+    pub(crate) state_file: Option<String>,
     /// Wheter to notify of address subscriptions (this will run once, at startup).
     pub(crate) notify_subscriptions: bool,
     /// Whether to notify of deposits to any of the addresses.
     pub(crate) notify_deposits: bool,
+    /// How many confirmations to notify deposits and withdrawals at, by default and for specific addresses.
+    /// Deposits and withdrawals are notified as soon as they are detected, if left empty.
+    #[serde(default)]
+    pub(crate) confirmations: Option<ConfirmationsConfig>,
     /// Recipient emails for address notifications.
+    /// Notifications are not sent by email, if left empty.
+    #[serde(default)]
     pub(crate) recipient_emails: Vec<EmailAddress>,
-    /// The SMTP username.
-    pub(crate) smtp_username: EmailAddress,
-    /// The SMTP password.
-    pub(crate) smtp_password: String,
-    /// The SMTP server.
-    pub(crate) smtp_server: String,
-    /// The SMTP port.
-    pub(crate) smtp_port: u16,
+    /// The SMTP username. Required if `recipient_emails` is set.
+    #[serde(default)]
+    pub(crate) smtp_username: Option<EmailAddress>,
+    /// The SMTP password. Required if `recipient_emails` is set.
+    #[serde(default)]
+    pub(crate) smtp_password: Option<String>,
+    /// The SMTP server. Required if `recipient_emails` is set.
+    #[serde(default)]
+    pub(crate) smtp_server: Option<String>,
+    /// The SMTP port. Required if `recipient_emails` is set.
+    #[serde(default)]
+    pub(crate) smtp_port: Option<u16>,
+    /// Webhooks to POST the JSON serialization of every notified event to.
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    /// Where to send encrypted Nostr direct messages.
+    /// Notifications are not sent over Nostr, if left empty.
+    #[serde(default)]
+    pub(crate) nostr: Option<NostrConfig>,
+    /// Where to send Telegram messages, and which chats may query the bot.
+    /// Notifications are not sent over Telegram, if left empty.
+    #[serde(default)]
+    pub(crate) telegram: Option<TelegramConfig>,
+    /// Where to persist undelivered notifications, and how to retry them.
+    #[serde(default)]
+    pub(crate) outbox: OutboxConfig,
+    /// How often to send status reports, and the dead-man's switch to ping after every successful poll.
+    /// Neither is sent, if left empty.
+    #[serde(default)]
+    pub(crate) heartbeat: Option<HeartbeatConfig>,
+}
+
+fn default_gap_limit() -> u32 {
+    DEFAULT_GAP_LIMIT
+}
+
+fn default_quorum_tolerance() -> u32 {
+    2
+}
+
+fn default_esplora_concurrency() -> usize {
+    8
+}
+
+/// Deserialize either a single string or a list of strings.
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(string) => vec![string],
+        StringOrList::List(list) => list,
+    })
 }
 
 fn parse_config(config_path: &str) -> Config {
@@ -73,14 +251,35 @@ fn parse_config(config_path: &str) -> Config {
     debug!("[smaug]");
     debug!("network = {}", config.network);
     debug!("esplora_url = {:#?}", config.esplora_url);
+    debug!("esplora_strategy = {:?}", config.esplora_strategy);
+    debug!("quorum_tolerance = {}", config.quorum_tolerance);
+    debug!("esplora_concurrency = {}", config.esplora_concurrency);
+    debug!("spv = {:#?}", config.spv);
+    debug!("bitcoind = {:#?}", config.bitcoind);
+    debug!("electrum = {:#?}", config.electrum);
+    debug!("cbf = {:#?}", config.cbf);
+    debug!("zmq = {:#?}", config.zmq);
+    debug!("proxy = {:#?}", config.proxy);
     debug!("addresses = {:#?}", config.addresses);
+    debug!("descriptors = {:#?}", config.descriptors);
+    debug!("gap_limit = {}", config.gap_limit);
+    debug!("detection = {:?}", config.detection);
+    debug!("scan_from_height = {:#?}", config.scan_from_height);
+    debug!("mempool_polling_period_sec = {:#?}", config.mempool_polling_period_sec);
+    debug!("state_file = {:#?}", config.state_file);
     debug!("notify_subscriptions = {:#?}", config.notify_subscriptions);
     debug!("notify_deposits = {}", config.notify_deposits);
+    debug!("confirmations = {:#?}", config.confirmations);
     debug!("recipient_emails = {:#?}", config.recipient_emails);
-    debug!("smtp_username = {}", config.smtp_username);
-    debug!("smtp_password = {}", config.smtp_password);
-    debug!("smtp_server = {}", config.smtp_server);
-    debug!("smtp_port = {}", config.smtp_port);
+    debug!("smtp_username = {:#?}", config.smtp_username);
+    debug!("smtp_password = {:#?}", config.smtp_password.as_ref().map(|_| REDACTED));
+    debug!("smtp_server = {:#?}", config.smtp_server);
+    debug!("smtp_port = {:#?}", config.smtp_port);
+    debug!("webhooks = {:#?}", config.webhooks);
+    debug!("nostr = {:#?}", config.nostr);
+    debug!("telegram = {:#?}", config.telegram);
+    debug!("outbox = {:#?}", config.outbox);
+    debug!("heartbeat = {:#?}", config.heartbeat);
     debug!("");
 
     config
@@ -115,6 +314,19 @@ fn format_with_commas(num: u64) -> String {
     result.chars().rev().collect()
 }
 
+/// Format a duration as its two most significant units, like `1h 59m`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{seconds}s"),
+        (0, 0, _) => format!("{minutes}m {seconds}s"),
+        (0, _, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
 fn main() -> Result<(), SmaugError> {
     env_logger::Builder::from_default_env()
         .filter_level(log::LevelFilter::Info)
@@ -140,8 +352,21 @@ fn main() -> Result<(), SmaugError> {
     // Parse the TOML config file into [`Config`].
     let config: Config = parse_config(&config_path);
 
+    // Shut down gracefully on SIGINT and SIGTERM. This must happen before the runtime spawns its threads.
+    let shutdown = Shutdown::default();
+    on_signals(&shutdown).map_err(SmaugError::Runtime)?;
+
+    // The runtime the Esplora requests and notification deliveries run on.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(SmaugError::Runtime)?;
+
     // Run the "watchdragon".
-    smaug(&config)?;
+    smaug(&config, runtime.handle(), &shutdown)?;
+
+    // Do not wait for notifications still being delivered past their timeout.
+    runtime.shutdown_background();
 
     Ok(())
 }
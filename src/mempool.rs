@@ -0,0 +1,325 @@
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{Address, OutPoint, Txid};
+use esplora_client::Tx;
+use log::{debug, info, warn};
+
+use crate::chain::ChainSource;
+use crate::history::tx_events;
+use crate::proxy;
+use crate::smaug::{Event, EventParams, SmaugError, Spend, UtxoDB, compute_events, fetch_spend};
+use crate::watchlist::WatchList;
+
+/// Watches the mempool activity of the watched addresses in between blocks.
+///
+/// Whenever a new transaction touching an address shows up in the mempool, the address's UTXOs are re-fetched and
+/// diffed, so an unconfirmed sweep is reported before it is mined. Withdrawals seen this way are then followed until
+/// their spending transaction confirms.
+#[derive(Debug, Default)]
+pub(crate) struct MempoolWatch {
+    /// The mempool transactions already processed, per address.
+    seen: HashMap<Address, HashSet<Txid>>,
+    /// Withdrawals reported while their spending transaction was unconfirmed, by withdrawn outpoint.
+    pending: HashMap<OutPoint, EventParams>,
+}
+
+impl MempoolWatch {
+    /// Poll the mempool transactions of every watched address.
+    ///
+    /// Updates `current_state` for addresses with new mempool activity and returns the resulting [`Event`]s.
+    ///
+    /// Every address is fetched before `current_state` and the seen transactions are updated, so a failed poll changes
+    /// nothing, and the next one reports what it would have.
+    pub(crate) fn poll(
+        &mut self,
+        chain: &dyn ChainSource,
+        watchlist: &WatchList,
+        current_state: &mut UtxoDB,
+        height: u32,
+    ) -> Result<Vec<Event>, SmaugError> {
+        let mut mempool_txs: Vec<Tx> = Vec::new();
+
+        let addresses = proxy::query_order(watchlist.addresses());
+        let txs_per_address = chain.addresses_mempool_txs(&addresses)?;
+        let mut seen = HashMap::new();
+        let mut active_addresses = Vec::new();
+        for (address, txs) in addresses.into_iter().zip(txs_per_address) {
+            let txids: HashSet<Txid> = txs.iter().map(|tx| tx.txid).collect();
+
+            let has_new_txs = match self.seen.get(address) {
+                Some(seen) => !txids.is_subset(seen),
+                None => !txids.is_empty(),
+            };
+            // Transactions that left the mempool (mined or evicted) are forgotten.
+            seen.insert(address.clone(), txids);
+            if !has_new_txs {
+                continue;
+            }
+
+            debug!("New mempool activity on address {address}");
+            active_addresses.push(address);
+            mempool_txs.extend(txs);
+        }
+
+        // Re-fetch the UTXOs of the addresses with new mempool activity, which is only marked as seen once they were.
+        let utxos = chain.addresses_utxos(&active_addresses)?;
+        let active_addresses: Vec<Address> = active_addresses.into_iter().cloned().collect();
+        let mut last_state = UtxoDB::new();
+        for (address, utxos) in active_addresses.iter().zip(utxos) {
+            if let Some(last) = current_state.insert(address.clone(), utxos) {
+                last_state.insert(address.clone(), last);
+            }
+        }
+        self.seen.extend(seen);
+
+        let mut events = compute_events(watchlist, &active_addresses, current_state, &last_state, height, false);
+        for event in &mut events {
+            if let Event::Withdrawal(event_params) = event {
+                let outpoint = OutPoint::new(event_params.utxo.txid, event_params.utxo.vout);
+                event_params.spend = mempool_txs
+                    .iter()
+                    .find(|tx| {
+                        tx.vin
+                            .iter()
+                            .any(|vin| vin.txid == outpoint.txid && vin.vout == outpoint.vout)
+                    })
+                    .map(Spend::from_tx);
+
+                if event_params.spend.is_some() {
+                    self.pending.insert(outpoint, event_params.clone());
+                }
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Process a mempool transaction pushed by the chain source, touching `addresses`.
+    ///
+    /// Updates `current_state` as a poll would, so the transaction is not reported again by the next poll, and
+    /// returns the resulting [`Event`]s.
+    pub(crate) fn on_tx(
+        &mut self,
+        tx: &Tx,
+        addresses: &[Address],
+        watchlist: &WatchList,
+        current_state: &mut UtxoDB,
+        height: u32,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for address in addresses {
+            let utxos = current_state.entry(address.clone()).or_default();
+            let already_known = utxos.iter().any(|utxo| utxo.txid == tx.txid);
+            if !self.seen.entry(address.clone()).or_default().insert(tx.txid) || already_known {
+                continue;
+            }
+
+            let mut address_events = tx_events(address, watchlist.origin(address), tx, height, false, false);
+            for event in &mut address_events {
+                match event {
+                    Event::Deposit(event_params) => utxos.push(event_params.utxo),
+                    Event::Withdrawal(event_params) => {
+                        let outpoint = OutPoint::new(event_params.utxo.txid, event_params.utxo.vout);
+                        if let Some(position) = utxos
+                            .iter()
+                            .position(|utxo| OutPoint::new(utxo.txid, utxo.vout) == outpoint)
+                        {
+                            event_params.utxo = utxos.remove(position);
+                        }
+                        self.pending.insert(outpoint, event_params.clone());
+                    }
+                    _ => {}
+                }
+            }
+            events.extend(address_events);
+        }
+
+        events
+    }
+
+    /// Check whether the spending transactions of pending withdrawals got confirmed or replaced.
+    ///
+    /// Returns a follow-up [`Event::Withdrawal`] for every spend that confirmed or was replaced.
+    pub(crate) fn check_pending(&mut self, chain: &dyn ChainSource, height: u32) -> Result<Vec<Event>, SmaugError> {
+        let mut events = Vec::new();
+
+        for (outpoint, event_params) in self.pending.clone() {
+            let Some(spend) = fetch_spend(chain, &outpoint.txid, outpoint.vout)? else {
+                info!("The spend of {outpoint} left the mempool, no longer following it");
+                self.pending.remove(&outpoint);
+                continue;
+            };
+
+            let previous_spend = event_params.spend.as_ref().map(|spend| spend.txid);
+            if spend.status.confirmed || previous_spend != Some(spend.txid) {
+                if previous_spend != Some(spend.txid) {
+                    warn!("The spend of {outpoint} was replaced by transaction {}", spend.txid);
+                }
+
+                let confirmed = spend.status.confirmed;
+                let follow_up = EventParams {
+                    spend: Some(spend),
+                    height,
+                    ..event_params
+                };
+                match confirmed {
+                    true => {
+                        self.pending.remove(&outpoint);
+                    }
+                    false => {
+                        self.pending.insert(outpoint, follow_up.clone());
+                    }
+                }
+                events.push(Event::Withdrawal(follow_up));
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, str::FromStr};
+
+    use bitcoin::{
+        Amount, BlockHash, Network, ScriptBuf, Transaction, TxIn, TxOut, absolute::LockTime, hashes::Hash, transaction,
+    };
+    use esplora_client::{TxStatus, Utxo, UtxoStatus};
+
+    use super::*;
+    use crate::Config;
+    use crate::chain::{ChainError, tx_from_transaction};
+
+    /// A chain source serving the mempool transactions and UTXOs of addresses, failing the UTXOs of `failing` on
+    /// demand.
+    struct FakeChain {
+        mempool: HashMap<Address, Vec<Tx>>,
+        utxos: UtxoDB,
+        failing: Address,
+        fail: Cell<bool>,
+    }
+
+    impl ChainSource for FakeChain {
+        fn height(&self) -> Result<u32, ChainError> {
+            Ok(101597)
+        }
+
+        fn block_hash(&self, _: u32) -> Result<BlockHash, ChainError> {
+            Ok(BlockHash::all_zeros())
+        }
+
+        fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+            if self.fail.get() && *address == self.failing {
+                return Err(ChainError::MissingBlock(0));
+            }
+            Ok(self.utxos.get(address).cloned().unwrap_or_default())
+        }
+
+        fn address_txs(&self, _: &Address, _: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn mempool_address_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError> {
+            Ok(self.mempool.get(address).cloned().unwrap_or_default())
+        }
+
+        fn spending_txid(&self, _: &Txid, _: u32) -> Result<Option<Txid>, ChainError> {
+            Ok(None)
+        }
+
+        fn tx(&self, _: &Txid) -> Result<Option<Tx>, ChainError> {
+            Ok(None)
+        }
+    }
+
+    fn address(s: &str) -> Address {
+        Address::from_str(s)
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap()
+    }
+
+    /// An unconfirmed transaction spending `previous_output` to `script_pubkey`.
+    fn mempool_tx(previous_output: OutPoint, script_pubkey: ScriptBuf) -> Tx {
+        let transaction = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey,
+            }],
+        };
+        let status = TxStatus {
+            confirmed: false,
+            block_height: None,
+            block_hash: None,
+            block_time: None,
+        };
+        tx_from_transaction(&transaction, vec![None], status)
+    }
+
+    #[test]
+    fn failed_poll_does_not_swallow_a_withdrawal() {
+        let swept = address("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd");
+        let funded = address("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx");
+        let config: Config = toml::from_str(&format!(
+            "network = \"testnet4\"\naddresses = [\"{swept}\", \"{funded}\"]\nnotify_subscriptions = false\nnotify_deposits = true"
+        ))
+        .unwrap();
+        let watchlist = WatchList::from_config(&config).unwrap();
+
+        let utxo = Utxo {
+            txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            vout: 0,
+            status: UtxoStatus {
+                confirmed: true,
+                block_height: Some(101595),
+                block_hash: Some(BlockHash::all_zeros()),
+                block_time: None,
+            },
+            value: Amount::from_sat(1337),
+        };
+        let sweep = mempool_tx(OutPoint::new(utxo.txid, utxo.vout), funded.script_pubkey());
+        let chain = FakeChain {
+            mempool: HashMap::from([
+                (swept.clone(), vec![sweep.clone()]),
+                (funded.clone(), vec![sweep.clone()]),
+            ]),
+            utxos: UtxoDB::new(),
+            failing: funded.clone(),
+            fail: Cell::new(true),
+        };
+        let mut current_state = UtxoDB::from([(swept.clone(), vec![utxo]), (funded.clone(), Vec::new())]);
+        let mut mempool_watch = MempoolWatch::default();
+
+        // Fetching the second address fails: nothing is recorded, not even for the first one.
+        assert!(
+            mempool_watch
+                .poll(&chain, &watchlist, &mut current_state, 101597)
+                .is_err()
+        );
+        assert_eq!(current_state[&swept], vec![utxo]);
+        assert!(mempool_watch.seen.is_empty());
+
+        chain.fail.set(false);
+        let events = mempool_watch
+            .poll(&chain, &watchlist, &mut current_state, 101597)
+            .unwrap();
+        let withdrawal = events
+            .iter()
+            .find_map(|event| match event {
+                Event::Withdrawal(event_params) => Some(event_params),
+                _ => None,
+            })
+            .expect("the sweep is reported");
+        assert_eq!(withdrawal.address, swept);
+        assert_eq!(withdrawal.spend.as_ref().map(|spend| spend.txid), Some(sweep.txid));
+        assert!(current_state[&swept].is_empty());
+    }
+}
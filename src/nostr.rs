@@ -0,0 +1,711 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bitcoin::{
+    bech32::{self, Bech32, Hrp},
+    hashes::{Hash, HashEngine, hmac, sha256},
+    secp256k1::{self, All, Keypair, Message, Parity, Secp256k1, SecretKey, XOnlyPublicKey, ecdh},
+};
+use cbc::cipher::{BlockEncryptMut, KeyIvInit, StreamCipher, block_padding::Pkcs7};
+use chacha20::ChaCha20;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use thiserror::Error;
+use tungstenite::{HandshakeError, Message as WsMessage, http::Uri};
+
+use crate::notifier::{Notification, Notifier, NotifierError};
+use crate::proxy;
+use crate::{Config, REDACTED};
+
+/// The default amount of seconds to wait for a relay to respond.
+pub(crate) const DEFAULT_RELAY_TIMEOUT_SEC: u64 = 10;
+
+/// NIP-04 encrypted direct message.
+const KIND_ENCRYPTED_DM: u16 = 4;
+/// NIP-59 seal.
+const KIND_SEAL: u16 = 13;
+/// NIP-17 private direct message.
+const KIND_PRIVATE_DM: u16 = 14;
+/// NIP-59 gift wrap.
+const KIND_GIFT_WRAP: u16 = 1059;
+
+/// How far in the past the timestamps of seals and gift wraps are randomized to, so they can't be correlated with
+/// the time the message was sent (NIP-59).
+const TIMESTAMP_TWEAK_SEC: u64 = 2 * 24 * 60 * 60;
+
+/// Errors that happen while sending Nostr direct messages.
+#[derive(Debug, Error)]
+pub enum NostrError {
+    /// A key in the configuration is not a valid `nsec` or `npub`.
+    #[error("invalid nostr key `{0}`: {1}")]
+    InvalidKey(String, String),
+
+    /// Error deriving keys or signing events.
+    #[error(transparent)]
+    Secp256k1(#[from] secp256k1::Error),
+
+    /// Error gathering randomness.
+    #[error("failed to gather randomness: {0}")]
+    Random(#[from] getrandom::Error),
+
+    /// Error (de)serializing an event.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The message is too long to be encrypted with NIP-44.
+    #[error("message of {0} bytes can't be encrypted with NIP-44")]
+    MessageLength(usize),
+
+    /// Error talking to a relay.
+    #[error("relay `{0}`: {1}")]
+    Relay(String, #[source] tungstenite::Error),
+
+    /// The relay did not accept the event.
+    #[error("relay `{0}` did not accept the event: {1}")]
+    Rejected(String, String),
+
+    /// No relay accepted the direct message to a recipient.
+    #[error("no relay accepted the direct message to `{0}`")]
+    Undelivered(String),
+}
+
+/// Where and how to send Nostr direct messages.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct NostrConfig {
+    /// The `nsec` direct messages are sent from.
+    pub(crate) nsec: String,
+    /// The `npub`s direct messages are sent to.
+    pub(crate) recipients: Vec<String>,
+    /// The relays direct messages are published to.
+    pub(crate) relays: Vec<String>,
+    /// Whether to send a NIP-04 direct message when no relay accepts the NIP-17 one.
+    #[serde(default = "default_nip04_fallback")]
+    pub(crate) nip04_fallback: bool,
+    /// How long to wait for a relay to respond, in seconds.
+    #[serde(default = "default_relay_timeout_sec")]
+    pub(crate) timeout_sec: u64,
+}
+
+fn default_nip04_fallback() -> bool {
+    true
+}
+
+fn default_relay_timeout_sec() -> u64 {
+    DEFAULT_RELAY_TIMEOUT_SEC
+}
+
+impl fmt::Debug for NostrConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NostrConfig")
+            .field("nsec", &REDACTED)
+            .field("recipients", &self.recipients)
+            .field("relays", &self.relays)
+            .field("nip04_fallback", &self.nip04_fallback)
+            .field("timeout_sec", &self.timeout_sec)
+            .finish()
+    }
+}
+
+/// A Nostr event (NIP-01). Rumors (NIP-59) are events without a signature.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct NostrEvent {
+    id: String,
+    pubkey: String,
+    created_at: u64,
+    kind: u16,
+    tags: Vec<Vec<String>>,
+    content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sig: Option<String>,
+}
+
+impl NostrEvent {
+    /// Create an unsigned event, computing its id.
+    fn new(pubkey: XOnlyPublicKey, created_at: u64, kind: u16, tags: Vec<Vec<String>>, content: String) -> NostrEvent {
+        let pubkey = pubkey.to_string();
+        let serialized = json!([0, pubkey, created_at, kind, tags, content]).to_string();
+
+        NostrEvent {
+            id: sha256::Hash::hash(serialized.as_bytes()).to_string(),
+            pubkey,
+            created_at,
+            kind,
+            tags,
+            content,
+            sig: None,
+        }
+    }
+
+    /// Sign the event with `keypair`.
+    fn sign(mut self, secp: &Secp256k1<All>, keypair: &Keypair) -> Result<NostrEvent, NostrError> {
+        let id: sha256::Hash = self.id.parse().expect("the id is computed by `NostrEvent::new`");
+        let message = Message::from_digest(id.to_byte_array());
+        let signature = secp.sign_schnorr_with_aux_rand(&message, keypair, &random_bytes()?);
+        self.sig = Some(signature.to_string());
+
+        Ok(self)
+    }
+}
+
+/// Gather `N` random bytes.
+fn random_bytes<const N: usize>() -> Result<[u8; N], NostrError> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// The current UNIX timestamp.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A timestamp up to [`TIMESTAMP_TWEAK_SEC`] in the past.
+fn tweaked_now() -> Result<u64, NostrError> {
+    Ok(now() - u64::from_le_bytes(random_bytes()?) % TIMESTAMP_TWEAK_SEC)
+}
+
+/// Decode a bech32-encoded `nsec` or `npub` into its 32 bytes.
+fn decode_key(key: &str, hrp: &str) -> Result<[u8; 32], NostrError> {
+    let (decoded_hrp, data) =
+        bech32::decode(key).map_err(|e| NostrError::InvalidKey(key.to_string(), e.to_string()))?;
+    if decoded_hrp.as_str() != hrp {
+        return Err(NostrError::InvalidKey(
+            key.to_string(),
+            format!("expected an `{hrp}`, found an `{decoded_hrp}`"),
+        ));
+    }
+
+    data.try_into()
+        .map_err(|_| NostrError::InvalidKey(key.to_string(), String::from("expected 32 bytes")))
+}
+
+/// Encode an x-only public key as an `npub`.
+fn npub(pubkey: &XOnlyPublicKey) -> String {
+    bech32::encode::<Bech32>(Hrp::parse_unchecked("npub"), &pubkey.serialize()).unwrap_or_else(|_| pubkey.to_string())
+}
+
+/// The x coordinate of the ECDH shared point between `secret` and `public`.
+fn shared_x(secret: &SecretKey, public: &XOnlyPublicKey) -> [u8; 32] {
+    let point = ecdh::shared_secret_point(&public.public_key(Parity::Even), secret);
+
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&point[..32]);
+    x
+}
+
+/// HMAC-SHA256 over the concatenation of `parts`.
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    for part in parts {
+        engine.input(part);
+    }
+
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+}
+
+/// The NIP-44 conversation key between `secret` and `public`.
+fn nip44_conversation_key(secret: &SecretKey, public: &XOnlyPublicKey) -> [u8; 32] {
+    // HKDF-extract, with the shared x coordinate as the input key material.
+    hmac_sha256(b"nip44-v2", &[&shared_x(secret, public)])
+}
+
+/// The NIP-44 ChaCha20 key, ChaCha20 nonce and HMAC key of a message.
+fn nip44_message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    // HKDF-expand to 76 bytes, with the nonce as info.
+    let t1 = hmac_sha256(conversation_key, &[nonce, &[1]]);
+    let t2 = hmac_sha256(conversation_key, &[&t1, nonce, &[2]]);
+    let t3 = hmac_sha256(conversation_key, &[&t2, nonce, &[3]]);
+
+    let mut chacha_nonce = [0u8; 12];
+    chacha_nonce.copy_from_slice(&t2[..12]);
+    let mut hmac_key = [0u8; 32];
+    hmac_key[..20].copy_from_slice(&t2[12..]);
+    hmac_key[20..].copy_from_slice(&t3[..12]);
+
+    (t1, chacha_nonce, hmac_key)
+}
+
+/// The length a NIP-44 plaintext of `len` bytes is padded to.
+fn nip44_padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32;
+    }
+
+    let next_power = 1 << (usize::BITS - (len - 1).leading_zeros());
+    let chunk = if next_power <= 256 { 32 } else { next_power / 8 };
+    chunk * ((len - 1) / chunk + 1)
+}
+
+/// Encrypt `plaintext` with NIP-44 (version 2).
+fn nip44_encrypt(conversation_key: &[u8; 32], plaintext: &str, nonce: [u8; 32]) -> Result<String, NostrError> {
+    let len = plaintext.len();
+    if !(1..=u16::MAX as usize).contains(&len) {
+        return Err(NostrError::MessageLength(len));
+    }
+
+    let mut padded = Vec::with_capacity(2 + nip44_padded_len(len));
+    padded.extend_from_slice(&(len as u16).to_be_bytes());
+    padded.extend_from_slice(plaintext.as_bytes());
+    padded.resize(2 + nip44_padded_len(len), 0);
+
+    let (chacha_key, chacha_nonce, hmac_key) = nip44_message_keys(conversation_key, &nonce);
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+    let mac = hmac_sha256(&hmac_key, &[&nonce, &padded]);
+
+    let mut payload = vec![2];
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&padded);
+    payload.extend_from_slice(&mac);
+
+    Ok(BASE64.encode(payload))
+}
+
+/// Encrypt `plaintext` with NIP-04.
+fn nip04_encrypt(secret: &SecretKey, public: &XOnlyPublicKey, plaintext: &str, iv: [u8; 16]) -> String {
+    let key = shared_x(secret, public);
+    let ciphertext = cbc::Encryptor::<aes::Aes256>::new(&key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+
+    format!("{}?iv={}", BASE64.encode(ciphertext), BASE64.encode(iv))
+}
+
+/// Publish `event` to the relay at `url` and wait for it to be accepted.
+fn publish_to_relay(url: &str, event: &NostrEvent, timeout: Duration) -> Result<(), NostrError> {
+    let relay_error = |e: tungstenite::Error| NostrError::Relay(url.to_string(), e);
+    let io_error = |e: std::io::Error| NostrError::Relay(url.to_string(), tungstenite::Error::Io(e));
+
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| relay_error(tungstenite::Error::HttpFormat(e.into())))?;
+    let host = uri.host().unwrap_or_default();
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("wss") => 443,
+        _ => 80,
+    });
+
+    let stream = proxy::connect(host, port, timeout).map_err(io_error)?;
+    stream.set_read_timeout(Some(timeout)).map_err(io_error)?;
+    stream.set_write_timeout(Some(timeout)).map_err(io_error)?;
+
+    let (mut socket, _) = tungstenite::client_tls(url, stream).map_err(|e| match e {
+        HandshakeError::Failure(e) => relay_error(e),
+        HandshakeError::Interrupted(_) => io_error(std::io::ErrorKind::WouldBlock.into()),
+    })?;
+    socket
+        .send(WsMessage::text(json!(["EVENT", event]).to_string()))
+        .map_err(relay_error)?;
+
+    let result = loop {
+        let message = match socket.read() {
+            Ok(message) => message,
+            Err(e) => break Err(relay_error(e)),
+        };
+        let text = match message {
+            WsMessage::Text(text) => text,
+            WsMessage::Close(_) => break Err(NostrError::Rejected(url.to_string(), String::from("connection closed"))),
+            _ => continue,
+        };
+
+        let response: Value = serde_json::from_str(&text)?;
+        match response[0].as_str() {
+            Some("OK") if response[1] == event.id.as_str() => match response[2].as_bool() {
+                Some(true) => break Ok(()),
+                _ => {
+                    let reason = response[3].as_str().unwrap_or_default().to_string();
+                    break Err(NostrError::Rejected(url.to_string(), reason));
+                }
+            },
+            Some("NOTICE") => debug!("Relay `{url}` notice: {}", response[1]),
+            _ => continue,
+        }
+    };
+    let _ = socket.close(None);
+
+    result
+}
+
+/// Delivers [`Event`] notifications as encrypted Nostr direct messages.
+pub(crate) struct NostrNotifier {
+    /// The secp256k1 context.
+    secp: Secp256k1<All>,
+    /// The keypair direct messages are sent from.
+    keypair: Keypair,
+    /// The public keys direct messages are sent to.
+    recipients: Vec<XOnlyPublicKey>,
+    /// The relays direct messages are published to.
+    relays: Vec<String>,
+    /// Whether to send a NIP-04 direct message when no relay accepts the NIP-17 one.
+    nip04_fallback: bool,
+    /// How long to wait for a relay to respond.
+    timeout: Duration,
+}
+
+impl NostrNotifier {
+    /// Build a [`NostrNotifier`] from the configuration, if `nostr` is set.
+    pub(crate) fn from_config(config: &Config) -> Result<Option<NostrNotifier>, NostrError> {
+        let Some(nostr) = &config.nostr else {
+            return Ok(None);
+        };
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&decode_key(&nostr.nsec, "nsec")?)?;
+        let keypair = Keypair::from_secret_key(&secp, &secret_key);
+        let recipients = nostr
+            .recipients
+            .iter()
+            .map(|recipient| Ok(XOnlyPublicKey::from_slice(&decode_key(recipient, "npub")?)?))
+            .collect::<Result<Vec<_>, NostrError>>()?;
+        info!(
+            "Sending Nostr direct messages from {}",
+            npub(&keypair.x_only_public_key().0)
+        );
+
+        Ok(Some(NostrNotifier {
+            secp,
+            keypair,
+            recipients,
+            relays: nostr.relays.clone(),
+            nip04_fallback: nostr.nip04_fallback,
+            timeout: Duration::from_secs(nostr.timeout_sec),
+        }))
+    }
+
+    /// Wrap a NIP-17 private direct message to `recipient` in a NIP-59 seal and gift wrap.
+    fn gift_wrap(&self, recipient: &XOnlyPublicKey, subject: &str, body: &str) -> Result<NostrEvent, NostrError> {
+        let (pubkey, _) = self.keypair.x_only_public_key();
+        let rumor = NostrEvent::new(
+            pubkey,
+            now(),
+            KIND_PRIVATE_DM,
+            vec![
+                vec![String::from("p"), recipient.to_string()],
+                vec![String::from("subject"), subject.to_string()],
+            ],
+            body.to_string(),
+        );
+
+        let seal = NostrEvent::new(
+            pubkey,
+            tweaked_now()?,
+            KIND_SEAL,
+            Vec::new(),
+            nip44_encrypt(
+                &nip44_conversation_key(&self.keypair.secret_key(), recipient),
+                &serde_json::to_string(&rumor)?,
+                random_bytes()?,
+            )?,
+        )
+        .sign(&self.secp, &self.keypair)?;
+
+        let ephemeral = Keypair::from_secret_key(&self.secp, &SecretKey::from_slice(&random_bytes::<32>()?)?);
+        NostrEvent::new(
+            ephemeral.x_only_public_key().0,
+            tweaked_now()?,
+            KIND_GIFT_WRAP,
+            vec![vec![String::from("p"), recipient.to_string()]],
+            nip44_encrypt(
+                &nip44_conversation_key(&ephemeral.secret_key(), recipient),
+                &serde_json::to_string(&seal)?,
+                random_bytes()?,
+            )?,
+        )
+        .sign(&self.secp, &ephemeral)
+    }
+
+    /// Create a NIP-04 encrypted direct message to `recipient`.
+    fn nip04_dm(&self, recipient: &XOnlyPublicKey, subject: &str, body: &str) -> Result<NostrEvent, NostrError> {
+        let content = nip04_encrypt(
+            &self.keypair.secret_key(),
+            recipient,
+            &format!("{subject}\n\n{body}"),
+            random_bytes()?,
+        );
+
+        NostrEvent::new(
+            self.keypair.x_only_public_key().0,
+            now(),
+            KIND_ENCRYPTED_DM,
+            vec![vec![String::from("p"), recipient.to_string()]],
+            content,
+        )
+        .sign(&self.secp, &self.keypair)
+    }
+
+    /// Publish `event` to every relay, returning how many accepted it.
+    ///
+    /// Relays are tried independently, so an unreachable relay does not prevent publishing to the others.
+    fn publish(&self, event: &NostrEvent) -> usize {
+        self.relays
+            .iter()
+            .filter(|relay| match publish_to_relay(relay, event, self.timeout) {
+                Ok(()) => {
+                    debug!("Relay `{relay}` accepted event {}", event.id);
+                    true
+                }
+                Err(e) => {
+                    warn!("Failed to publish event {}: {e}", event.id);
+                    false
+                }
+            })
+            .count()
+    }
+
+    /// Send a direct message to `recipient`, falling back to NIP-04 if no relay accepts the NIP-17 one.
+    fn send(&self, recipient: &XOnlyPublicKey, subject: &str, body: &str) -> Result<(), NostrError> {
+        if self.publish(&self.gift_wrap(recipient, subject, body)?) > 0 {
+            info!("Sent Nostr direct message to {}", npub(recipient));
+            return Ok(());
+        }
+
+        if self.nip04_fallback {
+            warn!(
+                "No relay accepted the NIP-17 direct message to {}, falling back to NIP-04",
+                npub(recipient)
+            );
+            if self.publish(&self.nip04_dm(recipient, subject, body)?) > 0 {
+                info!("Sent NIP-04 Nostr direct message to {}", npub(recipient));
+                return Ok(());
+            }
+        }
+
+        Err(NostrError::Undelivered(npub(recipient)))
+    }
+}
+
+impl Notifier for NostrNotifier {
+    fn name(&self) -> &str {
+        "nostr"
+    }
+
+    fn notify(&self, notification: &Notification, delivered: &mut BTreeSet<String>) -> Result<(), NotifierError> {
+        // Deliver to every recipient not reached yet, even if delivery to one of them fails.
+        let mut result = Ok(());
+        for recipient in &self.recipients {
+            let key = recipient.to_string();
+            if delivered.contains(&key) {
+                continue;
+            }
+            match self.send(recipient, &notification.subject, &notification.body) {
+                Ok(()) => {
+                    delivered.insert(key);
+                }
+                Err(e) => {
+                    warn!("{e}");
+                    result = Err(e);
+                }
+            }
+        }
+
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use bitcoin::Network;
+    use cbc::cipher::BlockDecryptMut;
+
+    use super::*;
+    use crate::email::format_event;
+    use crate::smaug::{Event, SubscriptionParams};
+
+    /// Decrypt a NIP-44 payload.
+    fn nip44_decrypt(conversation_key: &[u8; 32], payload: &str) -> String {
+        let payload = BASE64.decode(payload).unwrap();
+        assert_eq!(payload[0], 2);
+        let nonce: [u8; 32] = payload[1..33].try_into().unwrap();
+        let (ciphertext, mac) = payload[33..].split_at(payload.len() - 33 - 32);
+
+        let (chacha_key, chacha_nonce, hmac_key) = nip44_message_keys(conversation_key, &nonce);
+        assert_eq!(hmac_sha256(&hmac_key, &[&nonce, ciphertext]), mac);
+        let mut padded = ciphertext.to_vec();
+        ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+
+        let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+        String::from_utf8(padded[2..2 + len].to_vec()).unwrap()
+    }
+
+    /// Decrypt a NIP-04 payload.
+    fn nip04_decrypt(secret: &SecretKey, public: &XOnlyPublicKey, content: &str) -> String {
+        let (ciphertext, iv) = content.split_once("?iv=").unwrap();
+        let iv: [u8; 16] = BASE64.decode(iv).unwrap().try_into().unwrap();
+        let plaintext = cbc::Decryptor::<aes::Aes256>::new(&shared_x(secret, public).into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&BASE64.decode(ciphertext).unwrap())
+            .unwrap();
+
+        String::from_utf8(plaintext).unwrap()
+    }
+
+    fn secret_key(byte: u8) -> SecretKey {
+        let mut bytes = [0u8; 32];
+        bytes[31] = byte;
+        SecretKey::from_slice(&bytes).unwrap()
+    }
+
+    /// A relay that accepts events of `accepted_kind` and rejects every other, for `connections` connections.
+    ///
+    /// Returns its URL and a handle yielding the events it received.
+    fn relay_stand_in(accepted_kind: u16, connections: usize) -> (String, thread::JoinHandle<Vec<NostrEvent>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut events = Vec::new();
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = tungstenite::accept(stream).unwrap();
+
+                let request: Value = serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+                assert_eq!(request[0], "EVENT");
+                let event: NostrEvent = serde_json::from_value(request[1].clone()).unwrap();
+                let accepted = event.kind == accepted_kind;
+                socket
+                    .send(WsMessage::text(
+                        json!(["OK", event.id, accepted, if accepted { "" } else { "blocked: kind" }]).to_string(),
+                    ))
+                    .unwrap();
+                events.push(event);
+            }
+            events
+        });
+
+        (url, handle)
+    }
+
+    /// The URL of a relay nobody is listening on.
+    fn unreachable_relay() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ws://{}", listener.local_addr().unwrap())
+    }
+
+    fn notifier(relays: Vec<String>, recipient: &SecretKey) -> NostrNotifier {
+        let secp = Secp256k1::new();
+        NostrNotifier {
+            keypair: Keypair::from_secret_key(&secp, &secret_key(1)),
+            recipients: vec![recipient.x_only_public_key(&secp).0],
+            secp,
+            relays,
+            nip04_fallback: true,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn event() -> Event {
+        Event::Subscription(SubscriptionParams {
+            addresses: Vec::new(),
+            descriptors: vec![(String::from("vpub"), 20)],
+        })
+    }
+
+    fn verify(event: &NostrEvent) {
+        let secp = Secp256k1::new();
+        let recomputed = NostrEvent::new(
+            event.pubkey.parse().unwrap(),
+            event.created_at,
+            event.kind,
+            event.tags.clone(),
+            event.content.clone(),
+        );
+        assert_eq!(recomputed.id, event.id);
+
+        let message = Message::from_digest(event.id.parse::<sha256::Hash>().unwrap().to_byte_array());
+        let signature = event.sig.as_ref().unwrap().parse().unwrap();
+        secp.verify_schnorr(&signature, &message, &event.pubkey.parse().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn nip44_test_vector() {
+        let conversation_key =
+            nip44_conversation_key(&secret_key(1), &secret_key(2).x_only_public_key(&Secp256k1::new()).0);
+        assert_eq!(
+            sha256::Hash::from_byte_array(conversation_key).to_string(),
+            "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d"
+        );
+
+        let mut nonce = [0u8; 32];
+        nonce[31] = 1;
+        let payload = nip44_encrypt(&conversation_key, "a", nonce).unwrap();
+        assert_eq!(
+            payload,
+            "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb"
+        );
+        assert_eq!(nip44_decrypt(&conversation_key, &payload), "a");
+        assert_eq!(nip44_padded_len(33), 64);
+        assert_eq!(nip44_padded_len(257), 320);
+    }
+
+    #[test]
+    fn gift_wrapped_direct_message() {
+        let recipient = secret_key(2);
+        let (relay, handle) = relay_stand_in(KIND_GIFT_WRAP, 1);
+        let notifier = notifier(vec![unreachable_relay(), relay], &recipient);
+
+        notifier
+            .notify(
+                &Notification::new(Network::Testnet4, &event()).unwrap(),
+                &mut BTreeSet::new(),
+            )
+            .unwrap();
+
+        let events = handle.join().unwrap();
+        let gift_wrap = &events[0];
+        verify(gift_wrap);
+        assert_eq!(gift_wrap.kind, KIND_GIFT_WRAP);
+
+        let seal: NostrEvent = serde_json::from_str(&nip44_decrypt(
+            &nip44_conversation_key(&recipient, &gift_wrap.pubkey.parse().unwrap()),
+            &gift_wrap.content,
+        ))
+        .unwrap();
+        verify(&seal);
+        assert_eq!(seal.pubkey, notifier.keypair.x_only_public_key().0.to_string());
+
+        let rumor: NostrEvent = serde_json::from_str(&nip44_decrypt(
+            &nip44_conversation_key(&recipient, &seal.pubkey.parse().unwrap()),
+            &seal.content,
+        ))
+        .unwrap();
+        let (subject, body) = format_event(Network::Testnet4, &event());
+        assert_eq!(rumor.kind, KIND_PRIVATE_DM);
+        assert_eq!(rumor.content, body);
+        assert!(rumor.tags.contains(&vec![String::from("subject"), subject]));
+    }
+
+    #[test]
+    fn nip04_fallback() {
+        let recipient = secret_key(2);
+        let (relay, handle) = relay_stand_in(KIND_ENCRYPTED_DM, 2);
+        let notifier = notifier(vec![relay], &recipient);
+
+        notifier
+            .notify(
+                &Notification::new(Network::Testnet4, &event()).unwrap(),
+                &mut BTreeSet::new(),
+            )
+            .unwrap();
+
+        let events = handle.join().unwrap();
+        assert_eq!(events[0].kind, KIND_GIFT_WRAP);
+        let dm = &events[1];
+        verify(dm);
+        assert_eq!(dm.kind, KIND_ENCRYPTED_DM);
+
+        let (subject, body) = format_event(Network::Testnet4, &event());
+        assert_eq!(
+            nip04_decrypt(&recipient, &dm.pubkey.parse().unwrap(), &dm.content),
+            format!("{subject}\n\n{body}")
+        );
+    }
+}
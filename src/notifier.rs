@@ -0,0 +1,111 @@
+use std::collections::BTreeSet;
+
+use bitcoin::Network;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::Config;
+use crate::email::{EmailError, EmailNotifier, format_event};
+use crate::nostr::{NostrError, NostrNotifier};
+use crate::smaug::Event;
+use crate::status::SharedStatus;
+use crate::telegram::{TelegramError, TelegramNotifier};
+use crate::webhook::{WebhookError, WebhookNotifier};
+
+/// Errors that happen while delivering a notification.
+#[derive(Debug, Error)]
+pub enum NotifierError {
+    /// Error sending email notifications.
+    #[error(transparent)]
+    Email(#[from] EmailError),
+
+    /// Error delivering webhook notifications.
+    #[error(transparent)]
+    Webhook(#[from] WebhookError),
+
+    /// Error sending Nostr direct messages.
+    #[error(transparent)]
+    Nostr(#[from] NostrError),
+
+    /// Error sending Telegram messages.
+    #[error(transparent)]
+    Telegram(#[from] TelegramError),
+}
+
+/// A notification about an [`Event`], rendered once for every channel so it can be queued and retried.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Notification {
+    /// The subject of the notification.
+    pub(crate) subject: String,
+    /// The human-readable body of the notification.
+    pub(crate) body: String,
+    /// The JSON serialization of the [`Event`].
+    pub(crate) json: String,
+}
+
+impl Notification {
+    /// Render the notification about an [`Event`] on `network`.
+    pub(crate) fn new(network: Network, event: &Event) -> Result<Notification, serde_json::Error> {
+        let (subject, body) = format_event(network, event);
+
+        Ok(Notification {
+            subject,
+            body,
+            json: serde_json::to_string(event)?,
+        })
+    }
+}
+
+/// A channel [`Event`] notifications are delivered through.
+pub(crate) trait Notifier: Send {
+    /// The name of this channel, for logging and for the outbox.
+    fn name(&self) -> &str;
+
+    /// Deliver a [`Notification`] to every recipient of this channel that is not in `delivered` yet, adding the ones
+    /// it reaches.
+    ///
+    /// Fails if any recipient could not be reached, in which case the retry only goes to the others.
+    fn notify(&self, notification: &Notification, delivered: &mut BTreeSet<String>) -> Result<(), NotifierError>;
+}
+
+/// Build every [`Notifier`] enabled in the configuration.
+///
+/// Interactive notifiers answer queries about `status`.
+pub(crate) fn notifiers_from_config(
+    config: &Config,
+    status: &SharedStatus,
+) -> Result<Vec<Box<dyn Notifier>>, NotifierError> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+
+    if let Some(email) = EmailNotifier::from_config(config)? {
+        notifiers.push(Box::new(email));
+    }
+    if let Some(nostr) = NostrNotifier::from_config(config)? {
+        notifiers.push(Box::new(nostr));
+    }
+    if let Some(telegram) = TelegramNotifier::from_config(config, status) {
+        notifiers.push(Box::new(telegram));
+    }
+    for webhook in &config.webhooks {
+        let webhook = WebhookNotifier::new(webhook.clone());
+        if notifiers.iter().any(|notifier| notifier.name() == webhook.name()) {
+            return Err(WebhookError::DuplicateName(webhook.name().to_string()).into());
+        }
+        notifiers.push(Box::new(webhook));
+    }
+
+    match notifiers.len() {
+        0 => warn!("No notifiers are configured, events will only be logged"),
+        _ => info!(
+            "Delivering notifications via {}",
+            notifiers
+                .iter()
+                .map(|notifier| notifier.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+
+    Ok(notifiers)
+}
@@ -0,0 +1,740 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::Network;
+use futures_util::future::join_all;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    runtime::Handle,
+    sync::Notify,
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::heartbeat::ErrorLog;
+use crate::notifier::{Notification, Notifier};
+use crate::shutdown::Shutdown;
+use crate::smaug::Event;
+use crate::state::write_atomically;
+
+/// The default amount of delivery attempts through a channel before a notification is dead-lettered.
+pub(crate) const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// The default amount of dead letters kept in the outbox.
+pub(crate) const DEFAULT_MAX_DEAD_LETTERS: usize = 100;
+
+/// Errors that happen while loading or persisting the outbox.
+#[derive(Debug, Error)]
+pub enum OutboxError {
+    /// Error reading or writing the outbox file.
+    #[error("outbox file `{0}`: {1}")]
+    Io(PathBuf, #[source] io::Error),
+
+    /// Error (de)serializing the outbox file.
+    #[error("outbox file `{0}`: {1}")]
+    Json(PathBuf, #[source] serde_json::Error),
+}
+
+/// How notifications are queued and retried.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct OutboxConfig {
+    /// Where to persist undelivered notifications across restarts.
+    /// Undelivered notifications are lost on restart, if left empty.
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+    /// How many times to attempt delivery through a channel before giving up on a notification.
+    #[serde(default = "default_max_attempts")]
+    pub(crate) max_attempts: u32,
+    /// `max_attempts` for specific channels: `email`, `nostr`, `telegram` or the name of a webhook.
+    #[serde(default)]
+    pub(crate) max_attempts_per_channel: BTreeMap<String, u32>,
+    /// How long to wait before the first retry, in seconds. Every later retry waits twice as long as the last one.
+    #[serde(default = "default_initial_backoff_sec")]
+    pub(crate) initial_backoff_sec: u64,
+    /// The longest to wait between retries, in seconds.
+    #[serde(default = "default_max_backoff_sec")]
+    pub(crate) max_backoff_sec: u64,
+    /// How many dead letters to keep: the oldest ones are dropped past it.
+    #[serde(default = "default_max_dead_letters")]
+    pub(crate) max_dead_letters: usize,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            path: None,
+            max_attempts: default_max_attempts(),
+            max_attempts_per_channel: BTreeMap::new(),
+            initial_backoff_sec: default_initial_backoff_sec(),
+            max_backoff_sec: default_max_backoff_sec(),
+            max_dead_letters: default_max_dead_letters(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    DEFAULT_MAX_ATTEMPTS
+}
+
+fn default_initial_backoff_sec() -> u64 {
+    30
+}
+
+fn default_max_backoff_sec() -> u64 {
+    3600
+}
+
+fn default_max_dead_letters() -> usize {
+    DEFAULT_MAX_DEAD_LETTERS
+}
+
+/// A [`Notification`] waiting to be delivered through one channel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Delivery {
+    /// Identifies the delivery in the outbox.
+    id: u64,
+    /// The name of the [`Notifier`] to deliver through.
+    channel: String,
+    /// What to deliver.
+    notification: Notification,
+    /// How many delivery attempts failed.
+    attempts: u32,
+    /// When to attempt delivery next, in seconds since the UNIX epoch.
+    next_attempt: u64,
+    /// Why the last attempt failed.
+    last_error: Option<String>,
+    /// The recipients of the channel that were already reached, so a retry does not notify them twice.
+    #[serde(default)]
+    delivered: BTreeSet<String>,
+}
+
+/// The deliveries in the outbox, as stored in the outbox file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Queue {
+    /// The id of the next delivery.
+    next_id: u64,
+    /// The deliveries still to attempt.
+    pending: Vec<Delivery>,
+    /// The deliveries that were given up on, oldest first.
+    dead_letters: Vec<Delivery>,
+    /// Bumped on every change, so an older snapshot of the queue never overwrites a newer one in the outbox file.
+    #[serde(skip)]
+    generation: u64,
+}
+
+impl Queue {
+    /// Drop the oldest dead letters past `max_dead_letters`.
+    fn trim_dead_letters(&mut self, max_dead_letters: usize) {
+        let excess = self.dead_letters.len().saturating_sub(max_dead_letters);
+        if excess > 0 {
+            warn!("Dropping the {excess} oldest dead letters, past `max_dead_letters`");
+            self.dead_letters.drain(..excess);
+        }
+    }
+}
+
+/// The outbox serialized while its queue was locked, to be written once the lock is released.
+struct Snapshot {
+    generation: u64,
+    json: Result<String, serde_json::Error>,
+}
+
+/// The outbox, shared between the event loop and the delivery workers.
+struct Shared {
+    config: OutboxConfig,
+    path: Option<PathBuf>,
+    queue: Mutex<Queue>,
+    /// The generation of the last snapshot written to the outbox file. Held while writing, so writes never interleave.
+    written: Mutex<u64>,
+    /// Signaled whenever a delivery is queued.
+    queued: Notify,
+    /// The failed delivery attempts, and how many deliveries were given up on, since they were last taken.
+    failures: Mutex<(ErrorLog, u32)>,
+}
+
+/// The current time, in seconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record a change to the queue, and serialize it for [`persist`](Shared::persist) if the outbox has a file.
+    fn snapshot(&self, queue: &mut Queue) -> Option<Snapshot> {
+        queue.generation += 1;
+        self.path.as_ref()?;
+
+        Some(Snapshot {
+            generation: queue.generation,
+            json: serde_json::to_string_pretty(queue),
+        })
+    }
+
+    /// Write a snapshot to the outbox file with [`write_atomically`], unless a newer one was written already.
+    ///
+    /// Called once the queue is unlocked, so a slow disk never holds back the workers or the event loop. Failures are
+    /// recorded along with the failed deliveries, for the next status report.
+    fn persist(&self, snapshot: Option<Snapshot>) {
+        let (Some(path), Some(snapshot)) = (&self.path, snapshot) else {
+            return;
+        };
+
+        let mut written = self.written.lock().unwrap_or_else(PoisonError::into_inner);
+        if snapshot.generation <= *written {
+            return;
+        }
+        let result = snapshot
+            .json
+            .map_err(|e| OutboxError::Json(path.clone(), e))
+            .and_then(|json| write_atomically(path, json.as_bytes()).map_err(|e| OutboxError::Io(path.clone(), e)));
+        match result {
+            Ok(()) => *written = snapshot.generation,
+            Err(e) => {
+                error!("Failed to persist the outbox: {e}");
+                let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+                failures.0.record(format!("failed to persist the outbox: {e}"));
+            }
+        }
+    }
+
+    /// Wait until a delivery through `channel` is due, and return it.
+    ///
+    /// Deliveries are attempted in the order they are due, so a notification waiting out its backoff does not hold
+    /// back the ones queued after it.
+    async fn next_due(&self, channel: &str) -> Delivery {
+        loop {
+            // Registered before looking at the queue, so a delivery queued in between is not missed.
+            let queued = self.queued.notified();
+            let timeout = {
+                let queue = self.lock();
+                let next = queue
+                    .pending
+                    .iter()
+                    .filter(|delivery| delivery.channel == channel)
+                    .min_by_key(|delivery| (delivery.next_attempt, delivery.id));
+                match next {
+                    Some(delivery) if delivery.next_attempt <= now() => return delivery.clone(),
+                    Some(delivery) => Some(Duration::from_secs(delivery.next_attempt.saturating_sub(now()))),
+                    None => None,
+                }
+            };
+            match timeout {
+                Some(timeout) => {
+                    let _ = time::timeout(timeout, queued).await;
+                }
+                None => queued.await,
+            }
+        }
+    }
+
+    /// Record the outcome of a delivery attempt: drop it once delivered, schedule a retry with exponential backoff
+    /// if it failed, or move it to the dead letters once it ran out of attempts.
+    fn settle(&self, delivery: &Delivery, result: Result<(), String>) {
+        let mut queue = self.lock();
+        let Some(index) = queue.pending.iter().position(|pending| pending.id == delivery.id) else {
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                queue.pending.remove(index);
+                debug!(
+                    "Delivered \"{}\" via {}",
+                    delivery.notification.subject, delivery.channel
+                );
+            }
+            Err(e) => {
+                let max_attempts = self
+                    .config
+                    .max_attempts_per_channel
+                    .get(&delivery.channel)
+                    .copied()
+                    .unwrap_or(self.config.max_attempts);
+                let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+                failures.0.record(format!("{}: {e}", delivery.channel));
+                let pending = &mut queue.pending[index];
+                pending.attempts += 1;
+                pending.last_error = Some(e.clone());
+                pending.delivered.clone_from(&delivery.delivered);
+
+                match pending.attempts >= max_attempts {
+                    true => {
+                        let dead_letter = queue.pending.remove(index);
+                        error!(
+                            "Giving up on delivering \"{}\" via {} after {} attempts: {e}",
+                            dead_letter.notification.subject, dead_letter.channel, dead_letter.attempts
+                        );
+                        queue.dead_letters.push(dead_letter);
+                        queue.trim_dead_letters(self.config.max_dead_letters);
+                        failures.1 += 1;
+                    }
+                    false => {
+                        let backoff = self
+                            .config
+                            .initial_backoff_sec
+                            .saturating_mul(1 << (pending.attempts - 1).min(32))
+                            .min(self.config.max_backoff_sec);
+                        pending.next_attempt = now() + backoff;
+                        warn!(
+                            "Failed to deliver notification via {} (attempt {}/{max_attempts}): {e}, retrying in {backoff} \
+                             seconds",
+                            pending.channel, pending.attempts
+                        );
+                    }
+                }
+            }
+        }
+        let snapshot = self.snapshot(&mut queue);
+        drop(queue);
+        self.persist(snapshot);
+    }
+}
+
+/// Queues notifications on disk and delivers them in the background, with one worker task per [`Notifier`] on the
+/// async runtime.
+///
+/// A slow or failing channel never holds back detection, nor delivery through the other channels. Failed deliveries
+/// are retried with exponential backoff until they run out of attempts, and then kept as dead letters in the outbox
+/// file.
+pub(crate) struct Outbox {
+    /// The network of the watched addresses, to render notifications for.
+    network: Network,
+    /// The names of the channels every notification is delivered through.
+    channels: Vec<String>,
+    shared: Arc<Shared>,
+    runtime: Handle,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Outbox {
+    /// Load the undelivered notifications of the last run, if persisted, and start a worker for every [`Notifier`] on
+    /// `runtime`, until `shutdown` is requested.
+    ///
+    /// Loaded notifications are retried right away. The ones for channels that are no longer configured are moved to
+    /// the dead letters.
+    pub(crate) fn start(
+        config: &OutboxConfig,
+        network: Network,
+        notifiers: Vec<Box<dyn Notifier>>,
+        runtime: &Handle,
+        shutdown: &Shutdown,
+    ) -> Result<Outbox, OutboxError> {
+        let channels: Vec<String> = notifiers.iter().map(|notifier| notifier.name().to_string()).collect();
+        let path = config.path.as_ref().map(PathBuf::from);
+
+        let mut queue = match &path {
+            Some(path) => load(path)?,
+            None => Queue::default(),
+        };
+        let (pending, orphaned): (Vec<Delivery>, Vec<Delivery>) = std::mem::take(&mut queue.pending)
+            .into_iter()
+            .partition(|delivery| channels.contains(&delivery.channel));
+        queue.pending = pending;
+        for delivery in &mut queue.pending {
+            delivery.next_attempt = now();
+        }
+        for mut delivery in orphaned {
+            warn!(
+                "Dead-lettering \"{}\": {} is no longer configured",
+                delivery.notification.subject, delivery.channel
+            );
+            delivery.last_error = Some(String::from("the channel is no longer configured"));
+            queue.dead_letters.push(delivery);
+        }
+        queue.trim_dead_letters(config.max_dead_letters);
+        if let Some(path) = &path {
+            info!(
+                "Loaded {} undelivered notifications from `{}`",
+                queue.pending.len(),
+                path.display()
+            );
+        }
+        if !queue.dead_letters.is_empty() {
+            warn!(
+                "The outbox holds {} notifications that could not be delivered",
+                queue.dead_letters.len()
+            );
+        }
+
+        let shared = Arc::new(Shared {
+            config: config.clone(),
+            path,
+            queue: Mutex::new(queue),
+            written: Mutex::new(0),
+            queued: Notify::new(),
+            failures: Mutex::default(),
+        });
+        let snapshot = shared.snapshot(&mut shared.lock());
+        shared.persist(snapshot);
+
+        let workers = notifiers
+            .into_iter()
+            .map(|notifier| runtime.spawn(deliver(notifier, shared.clone(), shutdown.clone())))
+            .collect();
+
+        Ok(Outbox {
+            network,
+            channels,
+            shared,
+            runtime: runtime.clone(),
+            workers,
+        })
+    }
+
+    /// Queue a notification about an [`Event`] for delivery through every channel.
+    pub(crate) fn push(&self, event: &Event) {
+        let notification = match Notification::new(self.network, event) {
+            Ok(notification) => notification,
+            Err(e) => {
+                warn!("Failed to render notification: {e}");
+                return;
+            }
+        };
+
+        let mut queue = self.shared.lock();
+        for channel in &self.channels {
+            let id = queue.next_id;
+            queue.next_id += 1;
+            queue.pending.push(Delivery {
+                id,
+                channel: channel.clone(),
+                notification: notification.clone(),
+                attempts: 0,
+                next_attempt: now(),
+                last_error: None,
+                delivered: BTreeSet::new(),
+            });
+        }
+        let snapshot = self.shared.snapshot(&mut queue);
+        drop(queue);
+        self.shared.persist(snapshot);
+        self.shared.queued.notify_waiters();
+    }
+
+    /// The failed delivery attempts, and how many deliveries were given up on, since the last call.
+    pub(crate) fn take_failures(&self) -> (ErrorLog, u32) {
+        std::mem::take(&mut *self.shared.failures.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Wait up to `timeout` for the workers to finish the deliveries in flight, once shutdown was requested.
+    ///
+    /// Whatever is left is delivered after the restart, if the outbox is persisted.
+    pub(crate) fn close(self, timeout: Duration) {
+        let workers = join_all(self.workers);
+        if self
+            .runtime
+            .block_on(async { time::timeout(timeout, workers).await })
+            .is_err()
+        {
+            warn!("Gave up waiting for the notifications being delivered");
+        }
+
+        let pending = self.shared.lock().pending.len();
+        match (pending, &self.shared.path) {
+            (0, _) => {}
+            (_, Some(path)) => info!(
+                "{pending} undelivered notifications are left in `{}` for the next run",
+                path.display()
+            ),
+            (_, None) => warn!("Dropping {pending} undelivered notifications, set `[outbox] path` to keep them"),
+        }
+    }
+}
+
+/// Deliver the notifications queued for `notifier` as they come due, until `shutdown` is requested.
+///
+/// Notifiers do blocking I/O, so every delivery runs on the blocking threads of the runtime.
+async fn deliver(mut notifier: Box<dyn Notifier>, shared: Arc<Shared>, shutdown: Shutdown) {
+    let channel = notifier.name().to_string();
+    loop {
+        let delivery = tokio::select! {
+            delivery = shared.next_due(&channel) => delivery,
+            () = shutdown.wait() => return,
+        };
+
+        let attempt = task::spawn_blocking(move || {
+            let mut delivery = delivery;
+            let result = notifier
+                .notify(&delivery.notification, &mut delivery.delivered)
+                .map_err(|e| e.to_string());
+            (notifier, delivery, result)
+        });
+        let (returned, delivery, result) = match attempt.await {
+            Ok(attempt) => attempt,
+            Err(e) => {
+                error!("Stopped delivering notifications via {channel}: {e}");
+                return;
+            }
+        };
+        notifier = returned;
+        shared.settle(&delivery, result);
+    }
+}
+
+/// Load the outbox file, or an empty outbox if it does not exist.
+fn load(path: &Path) -> Result<Queue, OutboxError> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Queue::default()),
+        Err(e) => return Err(OutboxError::Io(path.to_path_buf(), e)),
+    };
+
+    serde_json::from_str(&json).map_err(|e| OutboxError::Json(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        sync::atomic::{AtomicU32, Ordering},
+        thread,
+        time::Instant,
+    };
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::notifier::NotifierError;
+    use crate::smaug::SubscriptionParams;
+    use crate::webhook::WebhookError;
+
+    /// A channel that fails its first `failures` deliveries, and counts the ones that went through.
+    struct FakeNotifier {
+        failures: u32,
+        attempts: Arc<AtomicU32>,
+        delivered: Arc<AtomicU32>,
+    }
+
+    impl Notifier for FakeNotifier {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn notify(&self, _: &Notification, _: &mut BTreeSet<String>) -> Result<(), NotifierError> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(WebhookError::Status(String::from("fake"), 503, String::from("Unavailable")).into());
+            }
+            self.delivered.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// A channel with two recipients, the second of which fails its first delivery, recording every delivery.
+    struct FlakyRecipient {
+        attempts: AtomicU32,
+        deliveries: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Notifier for FlakyRecipient {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn notify(&self, _: &Notification, delivered: &mut BTreeSet<String>) -> Result<(), NotifierError> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            for recipient in ["alice", "bob"] {
+                if delivered.contains(recipient) {
+                    continue;
+                }
+                if recipient == "bob" && attempt == 0 {
+                    return Err(WebhookError::Status(String::from("bob"), 503, String::from("Unavailable")).into());
+                }
+                self.deliveries.lock().unwrap().push(recipient.to_string());
+                delivered.insert(recipient.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    fn fake(failures: u32) -> (Box<dyn Notifier>, Arc<AtomicU32>) {
+        let delivered = Arc::new(AtomicU32::new(0));
+        let notifier = FakeNotifier {
+            failures,
+            attempts: Arc::new(AtomicU32::new(0)),
+            delivered: delivered.clone(),
+        };
+        (Box::new(notifier), delivered)
+    }
+
+    fn event() -> Event {
+        Event::Subscription(SubscriptionParams {
+            addresses: Vec::new(),
+            descriptors: vec![(String::from("vpub"), 20)],
+        })
+    }
+
+    /// Wait for the workers to settle the outbox.
+    fn wait_until(outbox: &Outbox, settled: impl Fn(&Queue) -> bool) {
+        wait_for(|| settled(&outbox.shared.lock()));
+    }
+
+    /// Wait for `done`, which the workers only get to once the queue is unlocked, like writing the outbox file.
+    fn wait_for(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "the outbox did not settle");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_then_dead_lettered() {
+        let runtime = Runtime::new().unwrap();
+        let shutdown = Shutdown::default();
+        let config = OutboxConfig {
+            max_attempts: 3,
+            initial_backoff_sec: 0,
+            ..OutboxConfig::default()
+        };
+
+        // Delivery goes through on the third attempt.
+        let (notifier, delivered) = fake(2);
+        let outbox = Outbox::start(&config, Network::Testnet4, vec![notifier], runtime.handle(), &shutdown).unwrap();
+        outbox.push(&event());
+        wait_until(&outbox, |queue| queue.pending.is_empty());
+        assert_eq!(delivered.load(Ordering::SeqCst), 1);
+        assert!(outbox.shared.lock().dead_letters.is_empty());
+
+        // Delivery is given up on after the third attempt.
+        let (notifier, delivered) = fake(3);
+        let outbox = Outbox::start(&config, Network::Testnet4, vec![notifier], runtime.handle(), &shutdown).unwrap();
+        outbox.push(&event());
+        wait_until(&outbox, |queue| !queue.dead_letters.is_empty());
+        assert_eq!(delivered.load(Ordering::SeqCst), 0);
+        let dead_letter = outbox.shared.lock().dead_letters[0].clone();
+        assert_eq!(dead_letter.attempts, 3);
+        assert!(dead_letter.last_error.unwrap().contains("503"));
+    }
+
+    #[test]
+    fn only_the_latest_dead_letters_are_kept() {
+        let runtime = Runtime::new().unwrap();
+        let shutdown = Shutdown::default();
+        let config = OutboxConfig {
+            max_attempts: 1,
+            max_dead_letters: 2,
+            ..OutboxConfig::default()
+        };
+
+        let (notifier, _) = fake(u32::MAX);
+        let outbox = Outbox::start(&config, Network::Testnet4, vec![notifier], runtime.handle(), &shutdown).unwrap();
+        for _ in 0..3 {
+            outbox.push(&event());
+        }
+        wait_until(&outbox, |queue| queue.pending.is_empty());
+        let ids: Vec<u64> = outbox
+            .shared
+            .lock()
+            .dead_letters
+            .iter()
+            .map(|delivery| delivery.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn persist_failures_are_reported() {
+        let runtime = Runtime::new().unwrap();
+        let shutdown = Shutdown::default();
+        let path = env::temp_dir()
+            .join(format!("smaug-missing-{}", std::process::id()))
+            .join("outbox.json");
+        let config = OutboxConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..OutboxConfig::default()
+        };
+
+        let (notifier, delivered) = fake(0);
+        let outbox = Outbox::start(&config, Network::Testnet4, vec![notifier], runtime.handle(), &shutdown).unwrap();
+        outbox.push(&event());
+        wait_until(&outbox, |queue| queue.pending.is_empty());
+        assert_eq!(delivered.load(Ordering::SeqCst), 1);
+
+        // Delivery still goes through, and the failures to write the file, on start, once queued and once delivered,
+        // show up in the next report.
+        wait_for(|| outbox.shared.failures.lock().unwrap().0.count() == 3);
+        let (errors, dead_letters) = outbox.take_failures();
+        assert_eq!(dead_letters, 0);
+        assert_eq!(errors.count(), 3);
+        assert!(
+            errors
+                .errors
+                .iter()
+                .all(|error| error.message.starts_with("failed to persist the outbox"))
+        );
+    }
+
+    #[test]
+    fn undelivered_notifications_survive_restarts() {
+        let runtime = Runtime::new().unwrap();
+        let path = env::temp_dir().join(format!("smaug-outbox-{}.json", std::process::id()));
+        let config = OutboxConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            initial_backoff_sec: 3600,
+            ..OutboxConfig::default()
+        };
+
+        // The first attempt fails, and the retry is an hour away: shutting down does not wait for it.
+        let shutdown = Shutdown::default();
+        let (notifier, _) = fake(1);
+        let outbox = Outbox::start(&config, Network::Testnet4, vec![notifier], runtime.handle(), &shutdown).unwrap();
+        outbox.push(&event());
+        wait_until(&outbox, |queue| queue.pending[0].attempts == 1);
+        shutdown.request();
+        let closing = Instant::now();
+        outbox.close(Duration::from_secs(60));
+        assert!(closing.elapsed() < Duration::from_secs(10));
+
+        // After a restart, it is retried right away.
+        let shutdown = Shutdown::default();
+        let (notifier, delivered) = fake(0);
+        let outbox = Outbox::start(&config, Network::Testnet4, vec![notifier], runtime.handle(), &shutdown).unwrap();
+        wait_until(&outbox, |queue| queue.pending.is_empty());
+        assert_eq!(delivered.load(Ordering::SeqCst), 1);
+        wait_for(|| load(&path).unwrap().pending.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn retries_skip_the_recipients_already_reached() {
+        let runtime = Runtime::new().unwrap();
+        let shutdown = Shutdown::default();
+        let config = OutboxConfig {
+            initial_backoff_sec: 0,
+            ..OutboxConfig::default()
+        };
+
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        let notifier = FlakyRecipient {
+            attempts: AtomicU32::new(0),
+            deliveries: deliveries.clone(),
+        };
+        let outbox = Outbox::start(
+            &config,
+            Network::Testnet4,
+            vec![Box::new(notifier)],
+            runtime.handle(),
+            &shutdown,
+        )
+        .unwrap();
+        outbox.push(&event());
+        wait_until(&outbox, |queue| queue.pending.is_empty());
+
+        // Alice was reached by the first attempt, and only Bob by the retry.
+        assert_eq!(*deliveries.lock().unwrap(), vec!["alice", "bob"]);
+    }
+}
@@ -0,0 +1,913 @@
+use std::{
+    collections::HashSet,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use bitcoin::{Address, Amount, Block, BlockHash, OutPoint, ScriptBuf, Txid, block::Header};
+use esplora_client::{AddressStats, AsyncClient, Builder, MerkleProof, OutputStatus, Tx, Utxo, UtxoStatus};
+use futures_util::{StreamExt, TryStreamExt, future::join_all, stream};
+use log::{debug, info, warn};
+use serde::de::DeserializeOwned;
+use tokio::{runtime::Handle, time};
+
+use crate::EsploraStrategy;
+use crate::chain::{AddressStatus, ChainError, ChainSource};
+use crate::history::CHAIN_TXS_PER_PAGE;
+use crate::proxy;
+use crate::smaug::{BackendAnswer, DisagreementParams, Event};
+
+/// The shortest to back off for once an Esplora API rate limits `smaug`, without a `Retry-After`.
+const MIN_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest to back off for once an Esplora API rate limits `smaug`, without a `Retry-After`.
+const MAX_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(600);
+
+/// The longest a request waits for the rate limit of an Esplora API to lift, before failing.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+/// Build a client for the Esplora API at `url`, going through the proxy over the circuit isolating `isolation`, if
+/// one is set up.
+///
+/// The client does not retry on its own: rate limiting is handled by [`EsploraBackends`], across every address.
+pub(crate) fn esplora_client(url: &str, isolation: Option<&str>) -> Result<AsyncClient, esplora_client::Error> {
+    let builder = Builder::new(url).max_retries(0);
+    match proxy::socks5_proxy(url, isolation) {
+        Some(socks5_proxy) => builder.proxy(&socks5_proxy),
+        None => builder,
+    }
+    .build_async()
+}
+
+/// Why a request to an Esplora API failed.
+#[derive(Debug)]
+enum Failure {
+    /// The API answered `429 Too Many Requests`, asking to wait for `Retry-After`, if set.
+    RateLimited(Option<Duration>),
+    /// The API refuses to list the UTXOs or transactions of an address that has too many.
+    Oversized,
+    /// Anything else.
+    Other(esplora_client::Error),
+}
+
+impl From<esplora_client::Error> for Failure {
+    fn from(error: esplora_client::Error) -> Self {
+        match error {
+            esplora_client::Error::HttpResponse { status: 429, .. } => Failure::RateLimited(None),
+            // Like electrs: "Too many unspent transaction outputs (>500). Contact support to raise limits."
+            esplora_client::Error::HttpResponse { message, .. } if message.to_lowercase().contains("too many") => {
+                Failure::Oversized
+            }
+            error => Failure::Other(error),
+        }
+    }
+}
+
+/// GET the JSON at `path` from the API of `client`, reading the `Retry-After` of a `429 Too Many Requests`.
+async fn get_json<T: DeserializeOwned>(client: &AsyncClient, path: &str) -> Result<T, Failure> {
+    let response = client
+        .client()
+        .get(format!("{}{path}", client.url()))
+        .send()
+        .await
+        .map_err(esplora_client::Error::Reqwest)?;
+
+    let status = response.status();
+    if status.as_u16() == 429 {
+        // Only the delay in seconds form is understood, not the HTTP date one.
+        let retry_after = response
+            .headers()
+            .get("retry-after")
+            .and_then(|retry_after| retry_after.to_str().ok())
+            .and_then(|retry_after| retry_after.trim().parse().ok())
+            .map(Duration::from_secs);
+        return Err(Failure::RateLimited(retry_after));
+    }
+    if !status.is_success() {
+        let message = response.text().await.map_err(esplora_client::Error::Reqwest)?;
+        return Err(esplora_client::Error::HttpResponse {
+            status: status.as_u16(),
+            message,
+        }
+        .into());
+    }
+
+    Ok(response.json().await.map_err(esplora_client::Error::Reqwest)?)
+}
+
+/// The rate limit of an Esplora API, shared by every request to it.
+///
+/// Once the API rate limits a request, every request waits until the backoff is over. The backoff doubles every time
+/// the API rate limits again after a backoff, unless it says how long to wait, and halves with every answer.
+#[derive(Debug, Default)]
+struct Throttle(Mutex<ThrottleState>);
+
+#[derive(Debug, Default)]
+struct ThrottleState {
+    /// When requests may be sent again.
+    until: Option<Instant>,
+    /// How long the last backoff was.
+    backoff: Duration,
+}
+
+impl Throttle {
+    fn lock(&self) -> MutexGuard<'_, ThrottleState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// How long requests must wait for the backoff to be over.
+    fn remaining(&self) -> Duration {
+        self.lock()
+            .until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()))
+    }
+
+    /// Back off after a request was rate limited, for `retry_after` if the API said how long, and return how long
+    /// requests must wait.
+    ///
+    /// Requests that were in flight when the backoff started do not lengthen it. A random extra of up to a quarter of
+    /// the backoff spreads the requests waiting for it over time.
+    fn limited(&self, url: &str, retry_after: Option<Duration>) -> Duration {
+        let mut state = self.lock();
+        let now = Instant::now();
+        if state.until.is_none_or(|until| until <= now) {
+            state.backoff = match retry_after {
+                Some(retry_after) => retry_after,
+                None => (state.backoff * 2).clamp(MIN_RATE_LIMIT_BACKOFF, MAX_RATE_LIMIT_BACKOFF),
+            };
+            let max_jitter_ms = state.backoff.as_millis() as u64 / 4;
+            let jitter = Duration::from_millis(proxy::random() % (max_jitter_ms + 1));
+            state.until = Some(now + state.backoff + jitter);
+            warn!(
+                "Esplora API `{url}` is rate limiting requests, backing off for {} seconds",
+                state.backoff.as_secs()
+            );
+        }
+
+        state.until.map_or(Duration::ZERO, |until| until - now)
+    }
+
+    /// Shorten the next backoff after an answer.
+    fn answered(&self) {
+        let mut state = self.lock();
+        state.backoff /= 2;
+    }
+}
+
+/// One or more Esplora APIs used as one [`ChainSource`].
+///
+/// With the `failover` strategy, the APIs are queried in turn until one answers. With the `quorum` strategy, the chain
+/// tip and the UTXOs of every address are fetched from every API and compared, and any divergence is reported as a
+/// disagreement. Everything else fails over.
+///
+/// Requests run on the async runtime: the addresses are queried up to `concurrency` at a time, so a round takes about
+/// as long as the slowest `concurrency`-th of them rather than all of them one after the other. When the proxy
+/// isolates addresses, the queries about every address or transaction go over their own circuit, and the addresses
+/// are queried one at a time whatever `concurrency` is.
+#[derive(Debug)]
+pub(crate) struct EsploraBackends {
+    backends: Vec<(String, AsyncClient)>,
+    /// The rate limit of every API.
+    throttles: Vec<Throttle>,
+    strategy: EsploraStrategy,
+    /// How many blocks apart the chain tips may be.
+    tolerance: u32,
+    /// How many addresses to query at once.
+    concurrency: usize,
+    /// The runtime the requests run on.
+    runtime: Handle,
+    /// The index of the API queried first: the last one that answered.
+    primary: Mutex<usize>,
+    /// The chain tip every API reported on the last poll, with the `quorum` strategy.
+    heights: Mutex<Vec<Option<u32>>>,
+    /// What the APIs currently disagree on: an address, or `None` for the chain tip.
+    /// A disagreement is only reported when it starts.
+    disagreeing: Mutex<HashSet<Option<Address>>>,
+    /// The disagreements not taken yet.
+    disagreements: Mutex<Vec<DisagreementParams>>,
+    /// The addresses whose UTXOs the APIs refuse to list, which are rebuilt from their history instead.
+    oversized: Mutex<HashSet<Address>>,
+}
+
+impl EsploraBackends {
+    /// Build a client for every URL, running its requests on `runtime`.
+    pub(crate) fn new(
+        urls: &[String],
+        strategy: EsploraStrategy,
+        tolerance: u32,
+        concurrency: usize,
+        runtime: Handle,
+    ) -> Result<EsploraBackends, ChainError> {
+        Ok(EsploraBackends {
+            backends: urls
+                .iter()
+                .map(|url| Ok((url.clone(), esplora_client(url, None)?)))
+                .collect::<Result<_, esplora_client::Error>>()?,
+            throttles: urls.iter().map(|_| Throttle::default()).collect(),
+            strategy,
+            tolerance,
+            concurrency: concurrency.max(1),
+            runtime,
+            primary: Mutex::new(0),
+            heights: Mutex::new(vec![None; urls.len()]),
+            disagreeing: Mutex::new(HashSet::new()),
+            disagreements: Mutex::new(Vec::new()),
+            oversized: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Query the API at `index`, waiting for its rate limit to lift first, and again whenever it rate limits the
+    /// request.
+    ///
+    /// Fails right away rather than wait for longer than `MAX_RATE_LIMIT_WAIT`, so the other APIs are tried.
+    async fn query<T>(
+        &self,
+        index: usize,
+        isolation: Option<&str>,
+        request: &impl AsyncFn(&AsyncClient) -> Result<T, Failure>,
+    ) -> Result<T, ChainError> {
+        let (url, client) = &self.backends[index];
+        let throttle = &self.throttles[index];
+        let isolated = isolated_client(url, isolation)?;
+        let client = isolated.as_ref().unwrap_or(client);
+
+        let mut wait = throttle.remaining();
+        loop {
+            if wait > MAX_RATE_LIMIT_WAIT {
+                return Err(ChainError::RateLimited(url.clone(), wait.as_secs()));
+            }
+            time::sleep(wait).await;
+
+            match request(client).await {
+                Ok(answer) => {
+                    throttle.answered();
+                    return Ok(answer);
+                }
+                Err(Failure::RateLimited(retry_after)) => {
+                    wait = throttle.limited(url, retry_after);
+                    debug!("Retrying a rate limited request to `{url}` in {} ms", wait.as_millis());
+                }
+                Err(Failure::Oversized) => return Err(ChainError::Oversized(url.clone())),
+                Err(Failure::Other(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Query the APIs in turn, starting from the primary one, until one answers.
+    async fn failover<T>(
+        &self,
+        isolation: Option<&str>,
+        request: impl AsyncFn(&AsyncClient) -> Result<T, Failure>,
+    ) -> Result<T, ChainError> {
+        let primary = *self.primary.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut last_error = None;
+
+        for offset in 0..self.backends.len() {
+            let index = (primary + offset) % self.backends.len();
+            let url = &self.backends[index].0;
+            match self.query(index, isolation, &request).await {
+                Ok(answer) => {
+                    // Concurrent requests may fail over at the same time: only the first one switches.
+                    let mut primary = self.primary.lock().unwrap_or_else(PoisonError::into_inner);
+                    if index != *primary {
+                        info!("Failing over to Esplora API `{url}`");
+                        *primary = index;
+                    }
+                    return Ok(answer);
+                }
+                Err(e) => {
+                    warn!("Esplora API `{url}` failed: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.expect("there is at least one Esplora API"))
+    }
+
+    /// Query every API at once, returning the answer of each, in order.
+    async fn query_all<T>(
+        &self,
+        isolation: Option<&str>,
+        request: impl AsyncFn(&AsyncClient) -> Result<T, Failure>,
+    ) -> Vec<Option<T>> {
+        let request = &request;
+        join_all(self.backends.iter().enumerate().map(|(index, (url, _))| async move {
+            match self.query(index, isolation, request).await {
+                Ok(answer) => Some(answer),
+                Err(e) => {
+                    warn!("Esplora API `{url}` failed: {e}");
+                    None
+                }
+            }
+        }))
+        .await
+    }
+
+    /// Run `request` for every address, up to `concurrency` at a time, and return the answers in order.
+    ///
+    /// With `isolate_addresses`, the addresses are queried one at a time, every request waiting for its jitter first:
+    /// queried at once, they would reach the API together and could be linked by their timing. The first failure
+    /// cancels the requests still in flight.
+    fn for_each_address<T>(
+        &self,
+        addresses: &[&Address],
+        request: impl AsyncFn(&Address) -> Result<T, ChainError>,
+    ) -> Result<Vec<T>, ChainError> {
+        let request = &request;
+        let concurrency = match proxy::isolates_addresses() {
+            true => 1,
+            false => self.concurrency,
+        };
+        self.runtime.block_on(
+            stream::iter(addresses)
+                .map(|address| async move {
+                    time::sleep(proxy::jitter_delay()).await;
+                    request(address).await
+                })
+                .buffered(concurrency)
+                .try_collect(),
+        )
+    }
+
+    /// Record whether the APIs disagree on `subject`, or leave it as is if they could not be compared.
+    fn record(&self, subject: Option<Address>, disagree: Option<bool>, answers: impl FnOnce() -> Vec<BackendAnswer>) {
+        let mut disagreeing = self.disagreeing.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match disagree {
+            Some(true) if disagreeing.insert(subject.clone()) => {
+                self.disagreements
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(DisagreementParams {
+                        address: subject,
+                        answers: answers(),
+                    });
+            }
+            Some(false) if disagreeing.remove(&subject) => match subject {
+                Some(address) => info!("The Esplora APIs agree again on the UTXOs of address {address}"),
+                None => info!("The Esplora APIs agree again on the chain tip"),
+            },
+            _ => {}
+        }
+    }
+
+    /// The height of the chain tip: the median of the APIs with the `quorum` strategy.
+    async fn tip_height(&self) -> Result<u32, ChainError> {
+        if self.strategy == EsploraStrategy::Failover {
+            return self.failover(None, async |client| Ok(client.get_height().await?)).await;
+        }
+
+        let heights = self
+            .query_all(None, async |client| Ok(client.get_height().await?))
+            .await;
+        let mut answered: Vec<u32> = heights.iter().flatten().copied().collect();
+        if answered.is_empty() {
+            // Every API failed: report the error of the primary one.
+            return self.failover(None, async |client| Ok(client.get_height().await?)).await;
+        }
+        answered.sort_unstable();
+
+        let spread = answered[answered.len() - 1] - answered[0];
+        self.record(None, Some(spread > self.tolerance), || {
+            self.backends
+                .iter()
+                .zip(&heights)
+                .map(|((url, _), height)| BackendAnswer {
+                    url: url.clone(),
+                    height: *height,
+                    utxos: None,
+                    balance: None,
+                })
+                .collect()
+        });
+        *self.heights.lock().unwrap_or_else(PoisonError::into_inner) = heights;
+
+        // The median, so a single API can neither hold the tip back nor push it ahead.
+        Ok(answered[(answered.len() - 1) / 2])
+    }
+
+    /// The stats of `address`, from every API with the `quorum` strategy, so a change on any of them is caught.
+    async fn status(&self, address: &Address) -> Result<Option<AddressStatus>, ChainError> {
+        let isolation = Some(address.to_string());
+        let isolation = isolation.as_deref();
+        let path = format!("/address/{address}");
+        if self.strategy == EsploraStrategy::Failover {
+            let stats = self
+                .failover(isolation, async |client| get_json(client, &path).await)
+                .await?;
+            return Ok(Some(AddressStatus::Esplora(vec![stats])));
+        }
+
+        // Without the stats of every API, the UTXOs are fetched again, so those of the APIs are still compared.
+        let answers = self
+            .query_all(isolation, async |client| get_json::<AddressStats>(client, &path).await)
+            .await;
+        Ok(answers
+            .into_iter()
+            .collect::<Option<Vec<AddressStats>>>()
+            .map(AddressStatus::Esplora))
+    }
+
+    /// The UTXOs locked to `address`, rebuilt from its history if the APIs refuse to list them.
+    async fn utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        if !self
+            .oversized
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(address)
+        {
+            match self.listed_utxos(address).await {
+                Err(ChainError::Oversized(url)) => {
+                    warn!(
+                        "Esplora API `{url}` refuses to list the UTXOs of address {address}, rebuilding them from its \
+                         history from now on"
+                    );
+                    self.oversized
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .insert(address.clone());
+                }
+                listed => return listed,
+            }
+        }
+
+        self.utxos_from_history(address).await
+    }
+
+    /// The UTXOs locked to `address` as listed by the APIs, compared across them with the `quorum` strategy.
+    async fn listed_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        let isolation = Some(address.to_string());
+        let isolation = isolation.as_deref();
+        let path = format!("/address/{address}/utxo");
+        if self.strategy == EsploraStrategy::Failover {
+            return self
+                .failover(isolation, async |client| get_json(client, &path).await)
+                .await;
+        }
+
+        let answers = self
+            .query_all(isolation, async |client| get_json::<Vec<Utxo>>(client, &path).await)
+            .await;
+        let heights = self.heights.lock().unwrap_or_else(PoisonError::into_inner).clone();
+
+        // Mempools legitimately differ, and so do the UTXOs of APIs at different chain tips: only the confirmed UTXOs
+        // reported by APIs at the same chain tip are compared.
+        let confirmed: Vec<Option<Vec<&Utxo>>> = answers
+            .iter()
+            .map(|utxos| {
+                utxos.as_ref().map(|utxos| {
+                    let mut confirmed: Vec<&Utxo> = utxos.iter().filter(|utxo| utxo.status.confirmed).collect();
+                    confirmed.sort_unstable_by_key(|utxo| OutPoint::new(utxo.txid, utxo.vout));
+                    confirmed
+                })
+            })
+            .collect();
+        let comparable: Vec<(u32, &Vec<&Utxo>)> = heights
+            .iter()
+            .zip(&confirmed)
+            .filter_map(|(height, utxos)| Some(((*height)?, utxos.as_ref()?)))
+            .collect();
+        let mut disagree = None;
+        for (i, (height, utxos)) in comparable.iter().enumerate() {
+            for (other_height, other_utxos) in &comparable[i + 1..] {
+                if height == other_height {
+                    let same = utxos.len() == other_utxos.len()
+                        && utxos
+                            .iter()
+                            .zip(other_utxos.iter())
+                            .all(|(utxo, other)| utxo.txid == other.txid && utxo.vout == other.vout);
+                    disagree = Some(disagree.unwrap_or(false) || !same);
+                }
+            }
+        }
+        self.record(Some(address.clone()), disagree, || {
+            self.backends
+                .iter()
+                .zip(heights.iter().zip(&confirmed))
+                .map(|((url, _), (height, utxos))| BackendAnswer {
+                    url: url.clone(),
+                    height: *height,
+                    utxos: utxos
+                        .as_ref()
+                        .map(|utxos| utxos.iter().map(|utxo| OutPoint::new(utxo.txid, utxo.vout)).collect()),
+                    balance: utxos
+                        .as_ref()
+                        .map(|utxos| utxos.iter().map(|utxo| utxo.value).sum::<Amount>()),
+                })
+                .collect()
+        });
+
+        // Answer with the primary API, or the first one that answered.
+        let primary = *self.primary.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut answers = answers;
+        match answers[primary].take() {
+            Some(utxos) => Ok(utxos),
+            None => match answers.into_iter().flatten().next() {
+                Some(utxos) => Ok(utxos),
+                None => {
+                    self.failover(isolation, async |client| get_json(client, &path).await)
+                        .await
+                }
+            },
+        }
+    }
+
+    /// The UTXOs locked to `address`, rebuilt from every transaction touching it.
+    ///
+    /// Slower than listing them, as the history is paged through every time, but APIs like electrs refuse to list the
+    /// UTXOs of an address with too many of them, not its history.
+    async fn utxos_from_history(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        let mut txs = self.mempool_txs(address).await?;
+        let mut last_seen = None;
+        loop {
+            let page = self.chain_txs(address, last_seen).await?;
+            let full = page.len() >= CHAIN_TXS_PER_PAGE;
+            last_seen = page.last().map(|tx| tx.txid);
+            txs.extend(page);
+            if !full {
+                break;
+            }
+        }
+
+        Ok(utxos_from_txs(&address.script_pubkey(), &txs))
+    }
+
+    /// A page of the transactions touching `address`: the mempool ones and the first confirmed ones without
+    /// `last_seen`, or the confirmed ones after `last_seen`.
+    async fn txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        let path = match last_seen {
+            Some(last_seen) => format!("/address/{address}/txs/chain/{last_seen}"),
+            None => format!("/address/{address}/txs"),
+        };
+        self.failover(Some(&address.to_string()), async |client| get_json(client, &path).await)
+            .await
+    }
+
+    /// A page of the confirmed transactions touching `address`, after `last_seen`.
+    async fn chain_txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        let path = match last_seen {
+            Some(last_seen) => format!("/address/{address}/txs/chain/{last_seen}"),
+            None => format!("/address/{address}/txs/chain"),
+        };
+        self.failover(Some(&address.to_string()), async |client| get_json(client, &path).await)
+            .await
+    }
+
+    /// The mempool transactions touching `address`.
+    async fn mempool_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError> {
+        let path = format!("/address/{address}/txs/mempool");
+        self.failover(Some(&address.to_string()), async |client| get_json(client, &path).await)
+            .await
+    }
+}
+
+/// The outputs of `txs` paying to `script_pubkey` that none of `txs` spends, given every transaction touching it.
+fn utxos_from_txs(script_pubkey: &ScriptBuf, txs: &[Tx]) -> Vec<Utxo> {
+    let spent: HashSet<OutPoint> = txs
+        .iter()
+        .flat_map(|tx| &tx.vin)
+        .map(|vin| OutPoint::new(vin.txid, vin.vout))
+        .collect();
+
+    let spent = &spent;
+    let mut seen = HashSet::new();
+    txs.iter()
+        // A transaction confirming while paging through the history may be listed twice.
+        .filter(|tx| seen.insert(tx.txid))
+        .flat_map(|tx| {
+            tx.vout.iter().enumerate().filter_map(move |(vout, output)| {
+                let vout = vout as u32;
+                (output.scriptpubkey == *script_pubkey && !spent.contains(&OutPoint::new(tx.txid, vout))).then(|| {
+                    Utxo {
+                        txid: tx.txid,
+                        vout,
+                        status: UtxoStatus {
+                            confirmed: tx.status.confirmed,
+                            block_height: tx.status.block_height,
+                            block_hash: tx.status.block_hash,
+                            block_time: tx.status.block_time,
+                        },
+                        value: Amount::from_sat(output.value),
+                    }
+                })
+            })
+        })
+        .collect()
+}
+
+/// A client for the API at `url` going over the circuit isolating `isolation`, if the proxy isolates addresses.
+fn isolated_client(url: &str, isolation: Option<&str>) -> Result<Option<AsyncClient>, esplora_client::Error> {
+    isolation
+        .filter(|_| proxy::isolates_addresses())
+        .map(|isolation| esplora_client(url, Some(isolation)))
+        .transpose()
+}
+
+impl ChainSource for EsploraBackends {
+    fn height(&self) -> Result<u32, ChainError> {
+        self.runtime.block_on(self.tip_height())
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+        self.runtime
+            .block_on(self.failover(None, async |client| Ok(client.get_block_hash(height).await?)))
+    }
+
+    fn header(&self, block_hash: &BlockHash) -> Result<Header, ChainError> {
+        self.runtime
+            .block_on(self.failover(None, async |client| Ok(client.get_header_by_hash(block_hash).await?)))
+    }
+
+    fn block(&self, height: u32) -> Result<Block, ChainError> {
+        self.runtime
+            .block_on(self.failover(None, async |client| {
+                let block_hash = client.get_block_hash(height).await?;
+                Ok(client.get_block_by_hash(&block_hash).await?)
+            }))?
+            .ok_or(ChainError::MissingBlock(height))
+    }
+
+    fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        self.runtime.block_on(self.utxos(address))
+    }
+
+    fn addresses_utxos(&self, addresses: &[&Address]) -> Result<Vec<Vec<Utxo>>, ChainError> {
+        self.for_each_address(addresses, async |address| self.utxos(address).await)
+    }
+
+    fn addresses_status(&self, addresses: &[&Address]) -> Result<Vec<Option<AddressStatus>>, ChainError> {
+        self.for_each_address(addresses, async |address| self.status(address).await)
+    }
+
+    fn address_txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        self.runtime.block_on(self.txs(address, last_seen))
+    }
+
+    fn addresses_txs(&self, addresses: &[&Address]) -> Result<Vec<Vec<Tx>>, ChainError> {
+        self.for_each_address(addresses, async |address| self.txs(address, None).await)
+    }
+
+    fn mempool_address_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError> {
+        self.runtime.block_on(self.mempool_txs(address))
+    }
+
+    fn addresses_mempool_txs(&self, addresses: &[&Address]) -> Result<Vec<Vec<Tx>>, ChainError> {
+        self.for_each_address(addresses, async |address| self.mempool_txs(address).await)
+    }
+
+    fn spending_txid(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, ChainError> {
+        let status = self
+            .runtime
+            .block_on(self.failover(Some(&txid.to_string()), async |client| {
+                Ok(client.get_output_status(txid, vout.into()).await?)
+            }))?;
+
+        Ok(match status {
+            Some(OutputStatus {
+                txid: Some(spending_txid),
+                ..
+            }) => Some(spending_txid),
+            _ => None,
+        })
+    }
+
+    fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError> {
+        self.runtime
+            .block_on(self.failover(Some(&txid.to_string()), async |client| {
+                Ok(client.get_tx_info(txid).await?)
+            }))
+    }
+
+    fn merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, ChainError> {
+        self.runtime
+            .block_on(self.failover(Some(&txid.to_string()), async |client| {
+                Ok(client.get_merkle_proof(txid).await?)
+            }))
+    }
+
+    fn take_events(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.disagreements.lock().unwrap_or_else(PoisonError::into_inner))
+            .into_iter()
+            .map(Event::Disagreement)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::stand_in::http_stand_in;
+
+    const ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+    /// A confirmed UTXO, in the JSON format of the Esplora API.
+    fn utxo(txid_byte: char, value: u64) -> String {
+        format!(
+            r#"{{"txid":"{}","vout":0,"status":{{"confirmed":true,"block_height":90}},"value":{}}}"#,
+            txid_byte.to_string().repeat(64),
+            value
+        )
+    }
+
+    /// A transaction paying `outputs` to `ADDRESS` and spending `inputs`, in the JSON format of the Esplora API.
+    fn tx(txid_byte: char, inputs: &[(char, u32)], outputs: &[u64]) -> String {
+        let script_pubkey = Address::from_str(ADDRESS)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey()
+            .to_hex_string();
+        let vin: Vec<String> = inputs
+            .iter()
+            .map(|(txid_byte, vout)| {
+                format!(
+                    r#"{{"txid":"{}","vout":{vout},"prevout":null,"scriptsig":"","sequence":0,"is_coinbase":false}}"#,
+                    txid_byte.to_string().repeat(64)
+                )
+            })
+            .collect();
+        let vout: Vec<String> = outputs
+            .iter()
+            .map(|value| format!(r#"{{"value":{value},"scriptpubkey":"{script_pubkey}"}}"#))
+            .collect();
+        format!(
+            r#"{{"txid":"{}","version":2,"locktime":0,"vin":[{}],"vout":[{}],"size":0,"weight":0,"status":{{"confirmed":true,"block_height":90}},"fee":0}}"#,
+            txid_byte.to_string().repeat(64),
+            vin.join(","),
+            vout.join(",")
+        )
+    }
+
+    /// An Esplora API stand-in at chain tip `height`, answering `utxos` for every address after `delay`.
+    fn esplora_stand_in(height: u32, utxos: Vec<String>, delay: Duration) -> String {
+        let (url, _) = http_stand_in(move |request| match request.path.as_str() {
+            "/blocks/tip/height" => ("200 OK".to_string(), height.to_string()),
+            path if path.ends_with("/utxo") => {
+                thread::sleep(delay);
+                ("200 OK".to_string(), format!("[{}]", utxos.join(",")))
+            }
+            _ => ("404 Not Found".to_string(), String::new()),
+        });
+        url
+    }
+
+    #[test]
+    fn quorum_reports_disagreements_once() {
+        let address = Address::from_str(ADDRESS).unwrap().assume_checked();
+        let urls = [
+            esplora_stand_in(100, vec![utxo('a', 1_000)], Duration::ZERO),
+            // Claims the address holds an extra UTXO.
+            esplora_stand_in(100, vec![utxo('a', 1_000), utxo('b', 5_000)], Duration::ZERO),
+            // Lags 10 blocks behind.
+            esplora_stand_in(90, Vec::new(), Duration::ZERO),
+        ];
+        let runtime = Runtime::new().unwrap();
+        let backends = EsploraBackends::new(&urls, EsploraStrategy::Quorum, 2, 8, runtime.handle().clone()).unwrap();
+
+        assert_eq!(backends.height().unwrap(), 100);
+        let utxos = backends.address_utxos(&address).unwrap();
+        assert_eq!(utxos.len(), 1);
+
+        let disagreements: Vec<DisagreementParams> = backends
+            .take_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Disagreement(disagreement) => Some(disagreement),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(disagreements.len(), 2);
+        assert_eq!(disagreements[0].address, None);
+        assert_eq!(
+            disagreements[0]
+                .answers
+                .iter()
+                .map(|answer| answer.height)
+                .collect::<Vec<_>>(),
+            [Some(100), Some(100), Some(90)]
+        );
+        assert_eq!(disagreements[1].address, Some(address.clone()));
+        assert_eq!(disagreements[1].answers[1].utxos.as_ref().unwrap().len(), 2);
+        assert_eq!(disagreements[1].answers[1].balance, Some(Amount::from_sat(6_000)));
+
+        // The APIs still disagree, but it has been reported already.
+        backends.height().unwrap();
+        backends.address_utxos(&address).unwrap();
+        assert!(backends.take_events().is_empty());
+    }
+
+    #[test]
+    fn failover_moves_to_the_next_api() {
+        // Nothing listens on the first URL once the listener is dropped.
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let urls = [unreachable, esplora_stand_in(100, Vec::new(), Duration::ZERO)];
+        let runtime = Runtime::new().unwrap();
+        let backends = EsploraBackends::new(&urls, EsploraStrategy::Failover, 2, 8, runtime.handle().clone()).unwrap();
+
+        assert_eq!(backends.height().unwrap(), 100);
+        assert_eq!(*backends.primary.lock().unwrap(), 1);
+        assert!(backends.take_events().is_empty());
+    }
+
+    #[test]
+    fn addresses_are_queried_concurrently() {
+        let address = Address::from_str(ADDRESS).unwrap().assume_checked();
+        let addresses = vec![&address; 8];
+        let urls = [esplora_stand_in(
+            100,
+            vec![utxo('a', 1_000)],
+            Duration::from_millis(500),
+        )];
+        let runtime = Runtime::new().unwrap();
+        let backends = EsploraBackends::new(&urls, EsploraStrategy::Failover, 2, 8, runtime.handle().clone()).unwrap();
+
+        // One after the other, the 8 queries would take 4 seconds.
+        let started = Instant::now();
+        let utxos = backends.addresses_utxos(&addresses).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(utxos.len(), 8);
+        assert!(utxos.iter().all(|utxos| utxos.len() == 1));
+    }
+
+    #[test]
+    fn rate_limits_are_waited_out_or_reported() {
+        let address = Address::from_str(ADDRESS).unwrap().assume_checked();
+        let runtime = Runtime::new().unwrap();
+
+        // Rate limits the first request for a second.
+        let requests = AtomicUsize::new(0);
+        let (url, _) = http_stand_in(move |_| match requests.fetch_add(1, Ordering::SeqCst) {
+            0 => ("429 Too Many Requests\r\nRetry-After: 1".to_string(), String::new()),
+            _ => ("200 OK".to_string(), format!("[{}]", utxo('a', 1_000))),
+        });
+        let urls = [url];
+        let backends = EsploraBackends::new(&urls, EsploraStrategy::Failover, 2, 8, runtime.handle().clone()).unwrap();
+        let started = Instant::now();
+        assert_eq!(backends.address_utxos(&address).unwrap().len(), 1);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        // Rate limits every request for an hour.
+        let (url, _) = http_stand_in(|_| ("429 Too Many Requests\r\nRetry-After: 3600".to_string(), String::new()));
+        let urls = [url];
+        let backends = EsploraBackends::new(&urls, EsploraStrategy::Failover, 2, 8, runtime.handle().clone()).unwrap();
+        let started = Instant::now();
+        // Backing off for the hour asked, and up to a quarter more.
+        assert!(matches!(
+            backends.address_utxos(&address),
+            Err(ChainError::RateLimited(_, 3599..=4500))
+        ));
+        assert!(backends.address_utxos(&address).is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn oversized_addresses_are_rebuilt_from_history() {
+        let address = Address::from_str(ADDRESS).unwrap().assume_checked();
+        let runtime = Runtime::new().unwrap();
+        // Pays 2 outputs to the address, the first of which is spent by a mempool transaction.
+        let funding = tx('a', &[('f', 0)], &[1_000, 2_000]);
+        let spending = tx('b', &[('a', 0)], &[]);
+        let (url, requests) = http_stand_in(move |request| match request.path.as_str() {
+            path if path.ends_with("/utxo") => (
+                "400 Bad Request".to_string(),
+                "Too many unspent transaction outputs (>500). Contact support to raise limits.".to_string(),
+            ),
+            path if path.ends_with("/txs/mempool") => ("200 OK".to_string(), format!("[{spending}]")),
+            path if path.ends_with("/txs/chain") => ("200 OK".to_string(), format!("[{funding}]")),
+            _ => ("404 Not Found".to_string(), String::new()),
+        });
+        let urls = [url];
+        let backends = EsploraBackends::new(&urls, EsploraStrategy::Failover, 2, 8, runtime.handle().clone()).unwrap();
+
+        for _ in 0..2 {
+            let utxos = backends.address_utxos(&address).unwrap();
+            assert_eq!(utxos.len(), 1);
+            assert_eq!(utxos[0].txid.to_string(), "a".repeat(64));
+            assert_eq!(utxos[0].vout, 1);
+            assert_eq!(utxos[0].value, Amount::from_sat(2_000));
+            assert!(utxos[0].status.confirmed);
+        }
+        // The UTXOs are not asked for again once refused.
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|request| request.path.ends_with("/utxo"))
+                .count(),
+            1
+        );
+    }
+}
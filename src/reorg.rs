@@ -0,0 +1,421 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::{Address, BlockHash, OutPoint};
+use esplora_client::Utxo;
+use log::warn;
+
+use crate::chain::{ChainError, ChainSource};
+use crate::smaug::{Event, EventParams, ReorgParams, UtxoDB, resolve_spends};
+use crate::watchlist::WatchList;
+
+/// How many recent block hashes are kept: the deepest reorg whose fork point can be found.
+pub(crate) const REORG_WINDOW: usize = 100;
+
+/// The most recent blocks of the chain followed, to tell when the chain source switched to another branch.
+#[derive(Clone, Debug)]
+pub(crate) struct ChainTracker {
+    /// The height of the chain tip.
+    height: u32,
+    /// The hashes of the most recent blocks, oldest first, up to the chain tip.
+    hashes: VecDeque<BlockHash>,
+}
+
+impl ChainTracker {
+    /// Start following the chain from the tip at `height`, given the hashes of the blocks up to it, oldest first.
+    pub(crate) fn new(height: u32, hashes: Vec<BlockHash>) -> ChainTracker {
+        let mut hashes = VecDeque::from(hashes);
+        while hashes.len() > REORG_WINDOW.min(height as usize + 1) {
+            hashes.pop_front();
+        }
+
+        ChainTracker { height, hashes }
+    }
+
+    /// The hash of the block at `height`, if it is recent enough to be known.
+    pub(crate) fn block_hash(&self, height: u32) -> Option<BlockHash> {
+        let offset = self.height.checked_sub(height)? as usize;
+        let index = self.hashes.len().checked_sub(offset + 1)?;
+
+        self.hashes.get(index).copied()
+    }
+
+    /// The known hashes of the blocks up to `height`, oldest first.
+    pub(crate) fn recent_blocks(&self, height: u32) -> Vec<BlockHash> {
+        let excluded = self.height.saturating_sub(height) as usize;
+
+        self.hashes
+            .iter()
+            .take(self.hashes.len().saturating_sub(excluded))
+            .copied()
+            .collect()
+    }
+
+    /// The height of the oldest known block.
+    fn base_height(&self) -> u32 {
+        (self.height + 1).saturating_sub(self.hashes.len() as u32)
+    }
+
+    /// Follow the chain source to its tip `tip_hash` at `height`.
+    ///
+    /// Returns the reorg, if blocks that were followed are no longer in the chain. Nothing changes on error.
+    pub(crate) fn update(
+        &mut self,
+        chain: &dyn ChainSource,
+        height: u32,
+        tip_hash: BlockHash,
+    ) -> Result<Option<ReorgParams>, ChainError> {
+        let hash_at = |block_height: u32| match block_height == height {
+            true => Ok(tip_hash),
+            false => chain.block_hash(block_height),
+        };
+
+        // Walk back from the lowest of both tips to the last block both chains share.
+        let mut fork_height = height.min(self.height);
+        loop {
+            let Some(known_hash) = self.block_hash(fork_height) else {
+                fork_height = self.base_height().saturating_sub(1);
+                warn!(
+                    "The chain forked below the {} blocks followed, around height {fork_height}",
+                    self.hashes.len()
+                );
+                break;
+            };
+            if hash_at(fork_height)? == known_hash || fork_height == 0 {
+                break;
+            }
+            fork_height -= 1;
+        }
+
+        // Fetch the hashes of the new blocks, keeping only the most recent ones.
+        let first_new = (fork_height + 1).max((height + 1).saturating_sub(REORG_WINDOW as u32));
+        let mut new_hashes = Vec::new();
+        for block_height in first_new..=height {
+            new_hashes.push(hash_at(block_height)?);
+        }
+
+        let reorg = (fork_height < self.height).then(|| ReorgParams {
+            fork_height,
+            depth: self.height - fork_height,
+            old_tip: self.block_hash(self.height).expect("the tip is known"),
+            height,
+            new_tip: tip_hash,
+        });
+
+        let kept = match first_new == fork_height + 1 {
+            true => self.recent_blocks(fork_height),
+            false => Vec::new(),
+        };
+        *self = ChainTracker::new(height, kept.into_iter().chain(new_hashes).collect());
+
+        Ok(reorg)
+    }
+}
+
+impl ReorgParams {
+    /// Merge a `later` reorg into this one, which was not handled yet.
+    pub(crate) fn then(self, later: ReorgParams) -> ReorgParams {
+        let fork_height = self.fork_height.min(later.fork_height);
+        ReorgParams {
+            fork_height,
+            depth: self.fork_height + self.depth - fork_height,
+            old_tip: self.old_tip,
+            height: later.height,
+            new_tip: later.new_tip,
+        }
+    }
+}
+
+/// A reorg whose effect on the watched addresses was not reported yet.
+#[derive(Debug)]
+pub(crate) struct PendingReorg {
+    /// The reorg.
+    pub(crate) reorg: ReorgParams,
+    /// The UTXOs locked to every watched address before the reorg.
+    last_state: UtxoDB,
+    /// The events of the rounds that failed since the reorg.
+    events: Vec<Event>,
+}
+
+impl PendingReorg {
+    /// Hold `reorg` until the state of the watched addresses is known on the new chain, given the state before.
+    pub(crate) fn new(reorg: ReorgParams, last_state: UtxoDB) -> PendingReorg {
+        PendingReorg {
+            reorg,
+            last_state,
+            events: Vec::new(),
+        }
+    }
+
+    /// Merge a `later` reorg that happened before this one was handled.
+    pub(crate) fn then(self, later: ReorgParams) -> PendingReorg {
+        PendingReorg {
+            reorg: self.reorg.then(later),
+            ..self
+        }
+    }
+
+    /// Hold the `events` of a round that failed, until the state on the new chain is known.
+    pub(crate) fn defer(&mut self, events: Vec<Event>) {
+        self.events.extend(events);
+    }
+
+    /// Reconcile the `events` found since the reorg with `current_state`, the state on the new chain.
+    ///
+    /// Deposits that were confirmed in a disconnected block and are gone or back in the mempool are reported as
+    /// reorged out, rather than as withdrawals. UTXOs confirmed below the fork that are back are reported as
+    /// withdrawals reorged out, rather than as deposits. Movements that were already reported, and happened again on
+    /// the new chain, are not reported twice.
+    pub(crate) fn finish(
+        self,
+        chain: &dyn ChainSource,
+        watchlist: &WatchList,
+        current_state: &UtxoDB,
+        events: Vec<Event>,
+        after_restart: bool,
+    ) -> Vec<Event> {
+        let PendingReorg {
+            reorg,
+            last_state,
+            events: mut all_events,
+        } = self;
+        all_events.extend(events);
+        resolve_spends(chain, &mut all_events);
+
+        let outpoints = |state: &UtxoDB, address: &Address| -> HashMap<OutPoint, Utxo> {
+            state
+                .get(address)
+                .into_iter()
+                .flatten()
+                .map(|utxo| (OutPoint::new(utxo.txid, utxo.vout), *utxo))
+                .collect()
+        };
+        let event_params = |address: &Address, utxo: Utxo| EventParams {
+            address: address.clone(),
+            origin: watchlist.origin(address).cloned(),
+            utxo,
+            height: reorg.height,
+            after_restart,
+            spend: None,
+        };
+
+        let mut reorged = Vec::new();
+        let mut superseded: Vec<(Address, OutPoint)> = Vec::new();
+        for address in watchlist.addresses() {
+            if !last_state.contains_key(address) {
+                continue;
+            }
+            let last = outpoints(&last_state, address);
+            let current = outpoints(current_state, address);
+
+            for (outpoint, utxo) in &last {
+                if utxo
+                    .status
+                    .block_height
+                    .is_none_or(|height| height <= reorg.fork_height)
+                {
+                    continue;
+                }
+                let spent_on_new_chain = all_events.iter().any(|event| {
+                    matches!(event, Event::Withdrawal(params)
+                        if params.address == *address && OutPoint::new(params.utxo.txid, params.utxo.vout) == *outpoint
+                            && params.spend.is_some())
+                });
+                match current.get(outpoint) {
+                    Some(current_utxo) if !current_utxo.status.confirmed => {
+                        reorged.push(Event::DepositReorged(event_params(address, *current_utxo)));
+                    }
+                    None if !spent_on_new_chain => {
+                        reorged.push(Event::DepositReorged(event_params(address, *utxo)));
+                        superseded.push((address.clone(), *outpoint));
+                    }
+                    _ => {}
+                }
+            }
+
+            for (outpoint, utxo) in &current {
+                if !last.contains_key(outpoint)
+                    && utxo
+                        .status
+                        .block_height
+                        .is_some_and(|height| height <= reorg.fork_height)
+                {
+                    reorged.push(Event::WithdrawalReorged(event_params(address, *utxo)));
+                    superseded.push((address.clone(), *outpoint));
+                }
+            }
+
+            // Movements already reported before the reorg, and found again when rescanning the new chain.
+            for event in &all_events {
+                match event {
+                    Event::Deposit(params) if params.address == *address => {
+                        let outpoint = OutPoint::new(params.utxo.txid, params.utxo.vout);
+                        if last.contains_key(&outpoint) {
+                            superseded.push((address.clone(), outpoint));
+                        }
+                    }
+                    Event::Withdrawal(params) if params.address == *address => {
+                        let outpoint = OutPoint::new(params.utxo.txid, params.utxo.vout);
+                        if !last.contains_key(&outpoint) {
+                            superseded.push((address.clone(), outpoint));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut events = vec![Event::Reorg(reorg)];
+        events.append(&mut reorged);
+        events.extend(all_events.into_iter().filter(|event| match event {
+            Event::Deposit(params) | Event::Withdrawal(params) => !superseded.iter().any(|(address, outpoint)| {
+                params.address == *address && OutPoint::new(params.utxo.txid, params.utxo.vout) == *outpoint
+            }),
+            _ => true,
+        }));
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{Amount, Network, Txid, hashes::Hash};
+    use esplora_client::UtxoStatus;
+
+    use super::*;
+    use crate::Config;
+
+    const WATCHED: &str = "tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd";
+
+    /// A chain source serving `hashes`, the hash of the block at every height.
+    struct FakeChain {
+        hashes: Vec<BlockHash>,
+    }
+
+    impl ChainSource for FakeChain {
+        fn height(&self) -> Result<u32, ChainError> {
+            Ok(self.hashes.len() as u32 - 1)
+        }
+
+        fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+            self.hashes
+                .get(height as usize)
+                .copied()
+                .ok_or(ChainError::MissingBlock(height))
+        }
+
+        fn address_utxos(&self, _: &Address) -> Result<Vec<Utxo>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn address_txs(&self, _: &Address, _: Option<Txid>) -> Result<Vec<esplora_client::Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn mempool_address_txs(&self, _: &Address) -> Result<Vec<esplora_client::Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn spending_txid(&self, _: &Txid, _: u32) -> Result<Option<Txid>, ChainError> {
+            Ok(None)
+        }
+
+        fn tx(&self, _: &Txid) -> Result<Option<esplora_client::Tx>, ChainError> {
+            Ok(None)
+        }
+    }
+
+    fn hash(branch: u8, height: u32) -> BlockHash {
+        let mut bytes = [branch; 32];
+        bytes[..4].copy_from_slice(&height.to_le_bytes());
+        BlockHash::from_byte_array(bytes)
+    }
+
+    fn chain(common: u32, branch: u8, height: u32) -> FakeChain {
+        FakeChain {
+            hashes: (0..=height)
+                .map(|h| match h <= common {
+                    true => hash(0, h),
+                    false => hash(branch, h),
+                })
+                .collect(),
+        }
+    }
+
+    fn utxo(txid_byte: u8, block_height: Option<u32>) -> Utxo {
+        Utxo {
+            txid: Txid::from_byte_array([txid_byte; 32]),
+            vout: 0,
+            status: UtxoStatus {
+                confirmed: block_height.is_some(),
+                block_height,
+                block_hash: None,
+                block_time: None,
+            },
+            value: Amount::from_sat(1337),
+        }
+    }
+
+    #[test]
+    fn same_height_and_deeper_reorgs_are_detected() {
+        let old_chain = chain(200, 1, 200);
+        let mut tracker = ChainTracker::new(200, (101..=200).map(|h| hash(0, h)).collect());
+        assert_eq!(tracker.update(&old_chain, 200, hash(0, 200)).unwrap(), None);
+
+        // The tip is replaced by a block at the same height.
+        let same_height = chain(199, 1, 200);
+        let reorg = tracker.update(&same_height, 200, hash(1, 200)).unwrap().unwrap();
+        assert_eq!((reorg.fork_height, reorg.depth, reorg.old_tip), (199, 1, hash(0, 200)));
+
+        // Three blocks are replaced by a longer branch.
+        let deeper = chain(197, 2, 202);
+        let reorg = tracker.update(&deeper, 202, hash(2, 202)).unwrap().unwrap();
+        assert_eq!((reorg.fork_height, reorg.depth, reorg.height), (197, 3, 202));
+        assert_eq!(tracker.block_hash(198), Some(hash(2, 198)));
+        assert_eq!(tracker.recent_blocks(202).len(), REORG_WINDOW);
+    }
+
+    #[test]
+    fn reorged_movements_are_reported_instead_of_their_reversal() {
+        let config: Config = toml::from_str(&format!(
+            "network = \"testnet4\"\naddresses = [\"{WATCHED}\"]\nnotify_subscriptions = false\nnotify_deposits = true"
+        ))
+        .unwrap();
+        let watchlist = WatchList::from_config(&config).unwrap();
+        let address = Address::from_str(WATCHED)
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap();
+
+        // Before the reorg: a deposit confirmed at height 200, above the fork. The UTXO confirmed at height 150 was
+        // withdrawn at height 200.
+        let last_state = UtxoDB::from([(address.clone(), vec![utxo(1, Some(200))])]);
+        // After it: the deposit is gone, and the withdrawn UTXO is back.
+        let current_state = UtxoDB::from([(address.clone(), vec![utxo(2, Some(150))])]);
+        let events = crate::smaug::compute_events(
+            &watchlist,
+            watchlist.addresses(),
+            &current_state,
+            &last_state,
+            200,
+            false,
+        );
+
+        let reorg = ReorgParams {
+            fork_height: 199,
+            depth: 1,
+            old_tip: hash(0, 200),
+            height: 200,
+            new_tip: hash(1, 200),
+        };
+        let events =
+            PendingReorg::new(reorg, last_state).finish(&chain(199, 1, 200), &watchlist, &current_state, events, false);
+
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Event::Reorg(reorg) if reorg.depth == 1));
+        assert!(matches!(&events[1], Event::DepositReorged(params) if params.utxo.txid == utxo(1, None).txid));
+        assert!(matches!(&events[2], Event::WithdrawalReorged(params) if params.utxo.txid == utxo(2, None).txid));
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::{HashMap, VecDeque};
+
+use bitcoin::{Address, Amount, Block, OutPoint, ScriptBuf, TxOut};
+use esplora_client::{TxStatus, Utxo, UtxoStatus};
+use log::{debug, warn};
+
+use crate::chain::{ChainError, ChainSource, tx_from_transaction};
+use crate::history::tx_events;
+use crate::reorg::REORG_WINDOW;
+use crate::smaug::{Event, SmaugError, UtxoDB};
+use crate::watchlist::WatchList;
+
+/// Detects deposits and withdrawals by scanning every new block for the watched scripts locally.
+///
+/// The chain source only ever serves whole blocks, so it never learns which addresses are watched. Withdrawals are
+/// spotted by matching the outpoints spent in a block against the known UTXOs of the watched addresses, so UTXOs
+/// received before the scan started are only known from the persisted state.
+#[derive(Debug)]
+pub(crate) struct BlockScan {
+    /// Every block at or below this height has been scanned.
+    synced_height: u32,
+    /// The watched scripts, and the address they belong to.
+    scripts: HashMap<ScriptBuf, Address>,
+    /// The known UTXOs of the watched addresses.
+    outpoints: HashMap<OutPoint, (Address, Amount)>,
+    /// The UTXOs spent by the recently scanned blocks, and the height of the block spending them, to restore them if
+    /// the block is reorganized out.
+    spent: VecDeque<(u32, Address, Utxo)>,
+    /// The lowest height whose spent UTXOs are remembered.
+    undo_from: u32,
+}
+
+impl BlockScan {
+    /// Start scanning the blocks above `synced_height`, for the addresses of `watchlist` holding `utxos`.
+    pub(crate) fn new(synced_height: u32, watchlist: &WatchList, utxos: &UtxoDB) -> BlockScan {
+        let mut block_scan = BlockScan {
+            synced_height,
+            scripts: HashMap::new(),
+            outpoints: HashMap::new(),
+            spent: VecDeque::new(),
+            undo_from: synced_height + 1,
+        };
+        block_scan.index(watchlist.addresses());
+        block_scan.index_utxos(utxos);
+
+        block_scan
+    }
+
+    /// Every block at or below this height has been scanned.
+    pub(crate) fn synced_height(&self) -> u32 {
+        self.synced_height
+    }
+
+    fn index(&mut self, addresses: &[Address]) {
+        for address in addresses {
+            self.scripts.insert(address.script_pubkey(), address.clone());
+        }
+    }
+
+    fn index_utxos(&mut self, utxos: &UtxoDB) {
+        for (address, utxos) in utxos {
+            for utxo in utxos {
+                self.outpoints
+                    .insert(OutPoint::new(utxo.txid, utxo.vout), (address.clone(), utxo.value));
+            }
+        }
+    }
+
+    /// Mark every block up to `height` as scanned, forgetting what is needed to undo blocks too deep to be
+    /// reorganized out.
+    fn advance(&mut self, height: u32) {
+        self.synced_height = height;
+
+        let oldest = height.saturating_sub(REORG_WINDOW as u32);
+        while self
+            .spent
+            .front()
+            .is_some_and(|(spent_height, ..)| *spent_height <= oldest)
+        {
+            self.spent.pop_front();
+        }
+        self.undo_from = self.undo_from.max(oldest + 1);
+    }
+
+    /// Undo the blocks scanned above `fork_height`, which were reorganized out, so the new chain is scanned from there.
+    ///
+    /// The UTXOs created by those blocks are dropped from `state`, and the ones they spent are restored, as far as
+    /// they are remembered.
+    pub(crate) fn rewind(&mut self, fork_height: u32, state: &mut UtxoDB) {
+        if fork_height + 1 < self.undo_from {
+            warn!(
+                "Blocks {} to {} were scanned too long ago to be undone, the UTXOs they spent are not restored",
+                fork_height + 1,
+                self.undo_from - 1
+            );
+        }
+
+        for utxos in state.values_mut() {
+            utxos.retain(|utxo| utxo.status.block_height.is_none_or(|height| height <= fork_height));
+        }
+        while let Some((spent_height, ..)) = self.spent.back()
+            && *spent_height > fork_height
+        {
+            let (_, address, utxo) = self.spent.pop_back().expect("checked above");
+            if utxo.status.block_height.is_none_or(|height| height <= fork_height) {
+                state.entry(address).or_default().push(utxo);
+            }
+        }
+
+        self.outpoints.clear();
+        self.index_utxos(state);
+        self.synced_height = self.synced_height.min(fork_height);
+        self.undo_from = self.undo_from.min(fork_height + 1);
+    }
+
+    /// Scan every block up to `height`, updating `state` and collecting the [`Event`]s found into `events`.
+    ///
+    /// Blocks are scanned one at a time: if one fails to download, the ones before it stay scanned.
+    pub(crate) fn scan_to(
+        &mut self,
+        chain: &dyn ChainSource,
+        watchlist: &mut WatchList,
+        state: &mut UtxoDB,
+        height: u32,
+        after_restart: bool,
+        events: &mut Vec<Event>,
+    ) -> Result<(), SmaugError> {
+        while self.synced_height < height {
+            let block_height = self.synced_height + 1;
+
+            // Only download the block if its filter matches one of the watched scripts, when filters are served.
+            // Spent UTXOs are locked to watched scripts too, so their withdrawals match as well.
+            if let Some(filter) = chain.block_filter(block_height)? {
+                let block_hash = chain.block_hash(block_height)?;
+                let scripts = self.scripts.keys().map(|script| script.as_bytes());
+                if !filter.match_any(&block_hash, scripts).map_err(ChainError::from)? {
+                    debug!("Skipping block {block_height} ({block_hash}), its filter does not match");
+                    self.advance(block_height);
+                    continue;
+                }
+            }
+
+            let block = chain.block(block_height)?;
+            debug!("Scanning block {block_height} ({})", block.block_hash());
+
+            events.extend(self.scan_block(&block, block_height, watchlist, state, after_restart)?);
+            self.advance(block_height);
+        }
+
+        Ok(())
+    }
+
+    /// Generate the [`Event`]s of a block, and apply it to `state`.
+    ///
+    /// Addresses derived because of the block are scanned for in the same block too.
+    pub(crate) fn scan_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+        watchlist: &mut WatchList,
+        state: &mut UtxoDB,
+        after_restart: bool,
+    ) -> Result<Vec<Event>, SmaugError> {
+        let mut events = self.scan_txs(block, height, None, watchlist, state, after_restart);
+
+        loop {
+            let new_addresses = watchlist.update()?;
+            if new_addresses.is_empty() {
+                return Ok(events);
+            }
+
+            debug!("Derived {} new addresses", new_addresses.len());
+            self.index(&new_addresses);
+            for address in &new_addresses {
+                state.entry(address.clone()).or_default();
+            }
+            events.extend(self.scan_txs(block, height, Some(&new_addresses), watchlist, state, after_restart));
+        }
+    }
+
+    /// Generate the [`Event`]s of the transactions of a block touching the watched addresses, or only `addresses`.
+    fn scan_txs(
+        &mut self,
+        block: &Block,
+        height: u32,
+        addresses: Option<&[Address]>,
+        watchlist: &mut WatchList,
+        state: &mut UtxoDB,
+        after_restart: bool,
+    ) -> Vec<Event> {
+        let status = TxStatus {
+            confirmed: true,
+            block_height: Some(height),
+            block_hash: Some(block.block_hash()),
+            block_time: Some(block.header.time.into()),
+        };
+        let is_scanned = |address: &Address| addresses.is_none_or(|addresses| addresses.contains(address));
+
+        let mut events = Vec::new();
+        for transaction in &block.txdata {
+            let txid = transaction.compute_txid();
+
+            // Outputs are matched by script, inputs by the outpoint they spend.
+            let spent: Vec<Option<(Address, Amount)>> = transaction
+                .input
+                .iter()
+                .map(|input| {
+                    self.outpoints
+                        .get(&input.previous_output)
+                        .filter(|(address, _)| is_scanned(address))
+                        .cloned()
+                })
+                .collect();
+            let received: Vec<Option<Address>> = transaction
+                .output
+                .iter()
+                .map(|output| {
+                    self.scripts
+                        .get(&output.script_pubkey)
+                        .filter(|address| is_scanned(address))
+                        .cloned()
+                })
+                .collect();
+
+            let mut touched: Vec<Address> = Vec::new();
+            for address in spent
+                .iter()
+                .flatten()
+                .map(|(address, _)| address)
+                .chain(received.iter().flatten())
+            {
+                if !touched.contains(address) {
+                    touched.push(address.clone());
+                }
+            }
+            if touched.is_empty() {
+                continue;
+            }
+
+            let prevouts = spent
+                .iter()
+                .map(|spent| {
+                    spent.as_ref().map(|(address, value)| TxOut {
+                        value: *value,
+                        script_pubkey: address.script_pubkey(),
+                    })
+                })
+                .collect();
+            let tx = tx_from_transaction(transaction, prevouts, status.clone());
+            for address in &touched {
+                events.extend(tx_events(
+                    address,
+                    watchlist.origin(address),
+                    &tx,
+                    height,
+                    after_restart,
+                    false,
+                ));
+                watchlist.mark_used(address);
+            }
+
+            for (input, spent) in transaction.input.iter().zip(spent) {
+                let Some((address, _)) = spent else {
+                    continue;
+                };
+                self.outpoints.remove(&input.previous_output);
+                if let Some(utxos) = state.get_mut(&address)
+                    && let Some(index) = utxos
+                        .iter()
+                        .position(|utxo| OutPoint::new(utxo.txid, utxo.vout) == input.previous_output)
+                {
+                    self.spent.push_back((height, address, utxos.remove(index)));
+                }
+            }
+            for ((vout, output), address) in transaction.output.iter().enumerate().zip(received) {
+                let Some(address) = address else {
+                    continue;
+                };
+                self.outpoints
+                    .insert(OutPoint::new(txid, vout as u32), (address.clone(), output.value));
+                state.entry(address).or_default().push(Utxo {
+                    txid,
+                    vout: vout as u32,
+                    status: UtxoStatus {
+                        confirmed: true,
+                        block_height: status.block_height,
+                        block_hash: status.block_hash,
+                        block_time: status.block_time,
+                    },
+                    value: output.value,
+                });
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        BlockHash, CompactTarget, Network, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, Txid, Witness,
+        absolute::LockTime, block::Header, block::Version, hashes::Hash, transaction,
+    };
+
+    use super::*;
+    use crate::Config;
+    use crate::email::format_event;
+
+    const WATCHED: &str = "tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd";
+
+    fn watchlist() -> WatchList {
+        let config: Config = toml::from_str(&format!(
+            "network = \"testnet4\"\naddresses = [\"{WATCHED}\"]\nnotify_subscriptions = false\nnotify_deposits = true"
+        ))
+        .unwrap();
+
+        WatchList::from_config(&config).unwrap()
+    }
+
+    fn transaction(inputs: &[OutPoint], outputs: &[(ScriptBuf, u64)]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(script_pubkey, value)| TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn block(txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1757566235,
+                bits: CompactTarget::from_consensus(0x1d00ffff),
+                nonce: 0,
+            },
+            txdata,
+        }
+    }
+
+    #[test]
+    fn deposit_spent_in_the_same_block() {
+        let mut watchlist = watchlist();
+        let mut state = UtxoDB::new();
+        let mut block_scan = BlockScan::new(101596, &watchlist, &state);
+
+        let watched = watchlist.addresses()[0].script_pubkey();
+        let elsewhere = ScriptBuf::new_op_return([0x13, 0x37]);
+        let funding_outpoint = OutPoint::new(
+            Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            1,
+        );
+        let deposit = transaction(&[funding_outpoint], &[(watched.clone(), 1337), (elsewhere.clone(), 42)]);
+        let deposit_txid = deposit.compute_txid();
+        let kept = transaction(&[funding_outpoint], &[(watched, 2000)]);
+        let withdrawal = transaction(&[OutPoint::new(deposit_txid, 0)], &[(elsewhere, 1000)]);
+        let block = block(vec![deposit, kept.clone(), withdrawal.clone()]);
+
+        let events = block_scan
+            .scan_block(&block, 101597, &mut watchlist, &mut state, false)
+            .unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Event::Deposit(params) if params.utxo.txid == deposit_txid));
+        assert!(matches!(&events[1], Event::Deposit(params) if params.utxo.value == Amount::from_sat(2000)));
+        let Event::Withdrawal(params) = &events[2] else {
+            panic!("expected a withdrawal, got {:?}", events[2]);
+        };
+        assert_eq!(params.utxo.txid, deposit_txid);
+        let spend = params.spend.as_ref().unwrap();
+        assert_eq!(spend.txid, withdrawal.compute_txid());
+        assert_eq!(spend.status.block_height, Some(101597));
+        assert_eq!(spend.fee, Some(Amount::from_sat(337)));
+
+        // Only the UTXO that was not spent is left.
+        let utxos = &state[&watchlist.addresses()[0]];
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos[0].txid, kept.compute_txid());
+        assert_eq!(utxos[0].status.block_height, Some(101597));
+    }
+
+    #[test]
+    fn withdrawal_of_a_known_utxo() {
+        let mut watchlist = watchlist();
+        let address = watchlist.addresses()[0].clone();
+        let outpoint = OutPoint::new(
+            Txid::from_str("9e8b5c7b468dab43ae55f66d977819102c3bb129825cc6bddb54f45fafb7ae33").unwrap(),
+            0,
+        );
+        let mut state = UtxoDB::from([(
+            address.clone(),
+            vec![Utxo {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                status: UtxoStatus {
+                    confirmed: true,
+                    block_height: Some(101595),
+                    block_hash: None,
+                    block_time: None,
+                },
+                value: Amount::from_sat(5000),
+            }],
+        )]);
+        let mut block_scan = BlockScan::new(101597, &watchlist, &state);
+
+        let unrelated = transaction(
+            &[OutPoint::new(outpoint.txid, 1)],
+            &[(ScriptBuf::new_op_return([0x13, 0x37]), 1000)],
+        );
+        let sweep = transaction(&[outpoint], &[(ScriptBuf::new_op_return([0x42]), 4000)]);
+        let block = block(vec![unrelated, sweep]);
+
+        let events = block_scan
+            .scan_block(&block, 101598, &mut watchlist, &mut state, true)
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        let Event::Withdrawal(params) = &events[0] else {
+            panic!("expected a withdrawal, got {:?}", events[0]);
+        };
+        assert!(params.after_restart);
+        assert_eq!(params.utxo.value, Amount::from_sat(5000));
+        assert_eq!(params.spend.as_ref().unwrap().fee, Some(Amount::from_sat(1000)));
+        assert!(state[&address].is_empty());
+
+        // The block is reorganized out: the withdrawn UTXO is back.
+        block_scan.rewind(101597, &mut state);
+        assert_eq!(state[&address].len(), 1);
+        assert_eq!(state[&address][0].value, Amount::from_sat(5000));
+        assert_eq!(block_scan.synced_height(), 101597);
+    }
+
+    #[test]
+    fn withdrawal_with_unknown_prevouts_has_no_fee() {
+        let mut watchlist = watchlist();
+        let address = watchlist.addresses()[0].clone();
+        let outpoint = OutPoint::new(
+            Txid::from_str("9e8b5c7b468dab43ae55f66d977819102c3bb129825cc6bddb54f45fafb7ae33").unwrap(),
+            0,
+        );
+        let mut state = UtxoDB::from([(
+            address.clone(),
+            vec![Utxo {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                status: UtxoStatus {
+                    confirmed: true,
+                    block_height: Some(101595),
+                    block_hash: None,
+                    block_time: None,
+                },
+                value: Amount::from_sat(5000),
+            }],
+        )]);
+        let mut block_scan = BlockScan::new(101597, &watchlist, &state);
+
+        // Also spends an output that is not watched, whose value is unknown.
+        let unwatched = OutPoint::new(outpoint.txid, 1);
+        let sweep = transaction(&[outpoint, unwatched], &[(ScriptBuf::new_op_return([0x42]), 4000)]);
+        let events = block_scan
+            .scan_block(&block(vec![sweep]), 101598, &mut watchlist, &mut state, false)
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        let Event::Withdrawal(params) = &events[0] else {
+            panic!("expected a withdrawal, got {:?}", events[0]);
+        };
+        let spend = params.spend.as_ref().unwrap();
+        assert_eq!(spend.fee, None);
+        assert_eq!(spend.fee_rate(), None);
+
+        let (_, body) = format_event(Network::Testnet4, &events[0]);
+        assert!(body.contains("Fee: unknown, signals RBF"));
+    }
+}
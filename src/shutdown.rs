@@ -0,0 +1,135 @@
+use std::{
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{runtime::Handle, sync::Notify, time};
+
+/// Requests a graceful shutdown, and lets everything holding a clone wait for it.
+///
+/// Shutdown is cooperative: the event loop and the delivery workers check for it whenever they wait, finish what they
+/// are doing and return.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Shutdown(Arc<ShutdownState>);
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    /// Signaled once shutdown is requested.
+    notify: Notify,
+}
+
+impl Shutdown {
+    /// Request a shutdown, waking everything waiting for it.
+    pub(crate) fn request(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    /// Whether shutdown was requested.
+    pub(crate) fn requested(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// Wait until shutdown is requested.
+    pub(crate) async fn wait(&self) {
+        loop {
+            // Registered before checking, so a request in between is not missed.
+            let notified = self.0.notify.notified();
+            if self.requested() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Sleep for `duration` on `runtime`, or until shutdown is requested.
+    ///
+    /// Returns whether shutdown was requested.
+    pub(crate) fn sleep(&self, runtime: &Handle, duration: Duration) -> bool {
+        runtime
+            .block_on(async { time::timeout(duration, self.wait()).await })
+            .is_ok()
+    }
+}
+
+/// Request a shutdown on the first SIGINT or SIGTERM, and exit right away on the second one.
+///
+/// Must be called before any other thread is spawned: the signals are blocked on the calling thread, and every thread
+/// spawned after inherits that, so they are only ever delivered to the thread waiting for them.
+#[cfg(unix)]
+pub(crate) fn on_signals(shutdown: &Shutdown) -> io::Result<()> {
+    use std::{mem, process, ptr, thread};
+
+    use log::{info, warn};
+
+    // SAFETY: `signals` is initialized by `sigemptyset` before it is used, and only read afterwards.
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) {
+            0 => signals,
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    };
+
+    let shutdown = shutdown.clone();
+    thread::spawn(move || {
+        loop {
+            let mut signal = 0;
+            // SAFETY: `signals` is a valid signal set, and `signal` a valid place to store the signal number.
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                continue;
+            }
+
+            if shutdown.requested() {
+                warn!("Received signal {signal} again, exiting now");
+                process::exit(128 + signal);
+            }
+            info!("Received signal {signal}, shutting down...");
+            shutdown.request();
+        }
+    });
+
+    Ok(())
+}
+
+/// Signals are left to their default handling, outside of UNIX.
+#[cfg(not(unix))]
+pub(crate) fn on_signals(_shutdown: &Shutdown) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn sleeping_is_cut_short_by_a_shutdown_request() {
+        let runtime = Runtime::new().unwrap();
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.sleep(runtime.handle(), Duration::from_millis(10)));
+
+        let requester = shutdown.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            requester.request();
+        });
+        let started = Instant::now();
+        assert!(shutdown.sleep(runtime.handle(), Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // Once requested, waiting returns right away.
+        assert!(shutdown.sleep(runtime.handle(), Duration::from_secs(60)));
+    }
+}
@@ -1,24 +1,50 @@
-use std::{collections::HashMap, process, thread, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use bitcoin::{
-    Network,
+    Amount, BlockHash, OutPoint, Sequence, SignedAmount, TxOut, Txid, Weight,
     address::{Address, NetworkChecked},
 };
-use esplora_client::{BlockingClient, Builder, Utxo};
+use esplora_client::{Tx, TxStatus, Utxo};
 use log::{debug, error, info, warn};
-
+use serde::{Deserialize, Serialize, Serializer};
 use thiserror::Error;
+use tokio::runtime::Handle;
 
-use crate::Config;
-use crate::check_addresses;
-use crate::email::{EmailError, build_messages, send_messages};
+use crate::chain::{AddressStatus, ChainError, ChainSource, chain_source_from_config};
+use crate::confirmations::ConfirmationWatch;
+use crate::descriptor::{AddressOrigin, DescriptorError};
+use crate::heartbeat::{ErrorLog, Heartbeat};
+use crate::history::HistoryWatch;
+use crate::mempool::MempoolWatch;
+use crate::notifier::{NotifierError, notifiers_from_config};
+use crate::outbox::{Outbox, OutboxError};
+use crate::proxy::{self, ProxyError};
+use crate::reorg::{ChainTracker, PendingReorg};
+use crate::scan::BlockScan;
+use crate::shutdown::Shutdown;
+use crate::state::{State, StateError, StoredUtxo};
+use crate::status::{SharedStatus, record_poll};
+use crate::watchlist::WatchList;
+use crate::zmq::{Notification, Zmq, ZmqError, match_tx};
+use crate::{Config, Detection, format_with_commas};
 
 /// The amount of seconds to sleep for between checks.
 pub(crate) const POLLING_PERIOD_SEC: u64 = 30;
 
-/// The amount of seconds to wait before retrying after an Esplora error.
+/// The amount of seconds to wait before retrying after a chain source error.
 pub(crate) const ERROR_RETRY_DELAY_SEC: u64 = 30;
 
+/// The longest the event loop waits for before checking if shutdown was requested, in seconds.
+const SHUTDOWN_CHECK_PERIOD_SEC: u64 = 1;
+
+/// The longest to wait for the notifications being delivered on shutdown, in seconds.
+const SHUTDOWN_DELIVERY_TIMEOUT_SEC: u64 = 10;
+
 /// A [`HashMap`] that maps an address to multiple [`Utxo`]s.
 pub(crate) type UtxoDB = HashMap<Address<NetworkChecked>, Vec<Utxo>>;
 
@@ -29,26 +55,308 @@ pub(crate) const SIGNET_ESPLORA: &str = "https://mempool.space/signet/api";
 /// Testnet4 Mempool.space Esplora API base URL.
 pub(crate) const TESTNET4_ESPLORA: &str = "https://mempool.space/testnet4/api";
 
-/// Parameters of an [`Event`] of kind `Subscription` or `Deposit`.
-#[derive(Clone, Debug)]
+/// Parameters of an [`Event`] of kind `Subscription`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SubscriptionParams {
+    /// The addresses listed in the configuration.
+    pub(crate) addresses: Vec<Address>,
+    /// The descriptors listed in the configuration, with how many addresses were derived from each.
+    pub(crate) descriptors: Vec<(String, usize)>,
+}
+
+/// The transaction spending a withdrawn [`Utxo`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Spend {
+    /// The txid of the spending transaction.
+    pub(crate) txid: Txid,
+    /// The confirmation status of the spending transaction.
+    #[serde(serialize_with = "serialize_tx_status")]
+    pub(crate) status: TxStatus,
+    /// Where the coins went.
+    pub(crate) outputs: Vec<TxOut>,
+    /// The absolute fee paid by the spending transaction, unless the prevout of one of its inputs is unknown.
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub(crate) fee: Option<Amount>,
+    /// The weight of the spending transaction.
+    pub(crate) weight: Weight,
+    /// Whether the spending transaction signals replaceability (BIP125).
+    pub(crate) rbf: bool,
+}
+
+impl Spend {
+    /// Build a [`Spend`] from the spending transaction.
+    pub(crate) fn from_tx(tx: &Tx) -> Spend {
+        Spend {
+            txid: tx.txid,
+            status: tx.status.clone(),
+            outputs: tx
+                .vout
+                .iter()
+                .map(|vout| TxOut {
+                    value: Amount::from_sat(vout.value),
+                    script_pubkey: vout.scriptpubkey.clone(),
+                })
+                .collect(),
+            fee: match tx.vin.iter().all(|vin| vin.prevout.is_some()) {
+                true => Some(Amount::from_sat(tx.fee)),
+                false => None,
+            },
+            weight: Weight::from_wu(tx.weight),
+            rbf: tx.vin.iter().any(|vin| Sequence(vin.sequence).is_rbf()),
+        }
+    }
+
+    /// The fee rate paid by the spending transaction, in sat/vB, if its fee is known.
+    pub(crate) fn fee_rate(&self) -> Option<f64> {
+        self.fee
+            .map(|fee| fee.to_sat() as f64 / self.weight.to_vbytes_ceil().max(1) as f64)
+    }
+}
+
+/// Serialize a [`Utxo`] the same way it is persisted in the state file.
+fn serialize_utxo<S: Serializer>(utxo: &Utxo, serializer: S) -> Result<S::Ok, S::Error> {
+    StoredUtxo::from(utxo).serialize(serializer)
+}
+
+/// A serializable view of a [`TxStatus`].
+#[derive(Serialize)]
+struct TxStatusView<'a> {
+    confirmed: bool,
+    block_height: Option<u32>,
+    block_hash: Option<&'a BlockHash>,
+    block_time: Option<u64>,
+}
+
+impl<'a> From<&'a TxStatus> for TxStatusView<'a> {
+    fn from(status: &'a TxStatus) -> Self {
+        TxStatusView {
+            confirmed: status.confirmed,
+            block_height: status.block_height,
+            block_hash: status.block_hash.as_ref(),
+            block_time: status.block_time,
+        }
+    }
+}
+
+fn serialize_tx_status<S: Serializer>(status: &TxStatus, serializer: S) -> Result<S::Ok, S::Error> {
+    TxStatusView::from(status).serialize(serializer)
+}
+
+fn serialize_optional_tx_status<S: Serializer>(status: &Option<TxStatus>, serializer: S) -> Result<S::Ok, S::Error> {
+    status.as_ref().map(TxStatusView::from).serialize(serializer)
+}
+
+/// Parameters of an [`Event`] of kind `Deposit` or `Withdrawal`.
+#[derive(Clone, Debug, Serialize)]
 pub(crate) struct EventParams {
     /// What address this event refers to.
     pub(crate) address: Address,
+    /// What descriptor and derivation index the address comes from, if it was derived.
+    pub(crate) origin: Option<AddressOrigin>,
     /// What [`UTXO`] this event refers to.
+    #[serde(serialize_with = "serialize_utxo")]
     pub(crate) utxo: Utxo,
     /// What height this event happened at.
     pub(crate) height: u32,
+    /// Whether this event happened while `smaug` was not running, and was detected after a restart.
+    pub(crate) after_restart: bool,
+    /// The transaction spending the [`Utxo`] of a withdrawal, if known.
+    pub(crate) spend: Option<Spend>,
+}
+
+/// Parameters of an [`Event`] of kind `Transaction`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct TxEventParams {
+    /// The txid of the transaction, if known.
+    pub(crate) txid: Option<Txid>,
+    /// The confirmation status of the transaction, if known.
+    #[serde(serialize_with = "serialize_optional_tx_status")]
+    pub(crate) status: Option<TxStatus>,
+    /// The UTXOs this transaction deposited to watched addresses.
+    pub(crate) deposits: Vec<EventParams>,
+    /// The UTXOs this transaction withdrew from watched addresses.
+    pub(crate) withdrawals: Vec<EventParams>,
+    /// The previously unconfirmed deposits of this transaction that got confirmed.
+    pub(crate) confirmations: Vec<EventParams>,
+    /// What height this event happened at.
+    pub(crate) height: u32,
+    /// Whether this event happened while `smaug` was not running, and was detected after a restart.
+    pub(crate) after_restart: bool,
+}
+
+impl TxEventParams {
+    /// The total amount deposited to watched addresses.
+    pub(crate) fn total_deposited(&self) -> Amount {
+        self.deposits.iter().map(|deposit| deposit.utxo.value).sum()
+    }
+
+    /// The total amount withdrawn from watched addresses.
+    pub(crate) fn total_withdrawn(&self) -> Amount {
+        self.withdrawals.iter().map(|withdrawal| withdrawal.utxo.value).sum()
+    }
+
+    /// The net amount that flowed in (positive) or out (negative) of every watched address touched.
+    pub(crate) fn net_flows(&self) -> Vec<(Address, SignedAmount)> {
+        let mut net_flows: Vec<(Address, SignedAmount)> = Vec::new();
+
+        let deposits = self.deposits.iter().map(|deposit| (deposit, 1));
+        let withdrawals = self.withdrawals.iter().map(|withdrawal| (withdrawal, -1));
+        for (event_params, sign) in deposits.chain(withdrawals) {
+            let value = event_params.utxo.value.to_signed().unwrap_or(SignedAmount::MAX) * sign;
+            match net_flows
+                .iter_mut()
+                .find(|(address, _)| *address == event_params.address)
+            {
+                Some((_, net_flow)) => *net_flow += value,
+                None => net_flows.push((event_params.address.clone(), value)),
+            }
+        }
+
+        net_flows
+    }
+}
+
+/// What one backend of the chain source answered, in an [`Event::Disagreement`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct BackendAnswer {
+    /// The URL of the backend.
+    pub(crate) url: String,
+    /// The chain tip height the backend reported, if it answered.
+    pub(crate) height: Option<u32>,
+    /// The confirmed UTXOs the backend reported for the address, for a disagreement on an address.
+    pub(crate) utxos: Option<Vec<OutPoint>>,
+    /// The total value of `utxos`.
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub(crate) balance: Option<Amount>,
+}
+
+/// Parameters of an [`Event`] of kind `Disagreement`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct DisagreementParams {
+    /// The address whose UTXOs the backends disagree on, or `None` if they disagree on the chain tip.
+    pub(crate) address: Option<Address>,
+    /// What every backend answered.
+    pub(crate) answers: Vec<BackendAnswer>,
+}
+
+/// Parameters of an [`Event`] of kind `VerificationFailure`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct VerificationFailureParams {
+    /// The transaction that failed verification, if the failure is about one rather than the header chain.
+    pub(crate) txid: Option<Txid>,
+    /// Why verification failed.
+    pub(crate) reason: String,
+}
+
+/// Parameters of an [`Event`] of kind `Reorg`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct ReorgParams {
+    /// The height of the last block the old and new chains share.
+    pub(crate) fork_height: u32,
+    /// How many blocks were disconnected.
+    pub(crate) depth: u32,
+    /// The chain tip that was reorganized out.
+    pub(crate) old_tip: BlockHash,
+    /// The height of the new chain tip.
+    pub(crate) height: u32,
+    /// The new chain tip.
+    pub(crate) new_tip: BlockHash,
+}
+
+/// Whether a movement is a deposit or a withdrawal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Movement {
+    Deposit,
+    Withdrawal,
+}
+
+/// Parameters of an [`Event`] of kind `Milestone`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct MilestoneParams {
+    /// Whether the movement is a deposit or a withdrawal.
+    pub(crate) movement: Movement,
+    /// How many confirmations the movement has.
+    pub(crate) confirmations: u32,
+    /// Whether this is the last milestone configured for the movement.
+    pub(crate) last: bool,
+    /// The deposit or withdrawal.
+    pub(crate) params: EventParams,
+}
+
+/// The balance of a watched address, in an [`Event::Report`].
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct AddressBalance {
+    pub(crate) address: Address,
+    /// The total value of the UTXOs locked to the address.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub(crate) balance: Amount,
+    /// The part of `balance` that is not confirmed yet.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub(crate) unconfirmed: Amount,
+}
+
+/// Parameters of an [`Event`] of kind `Report`.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ReportParams {
+    /// How long `smaug` has been running, in seconds.
+    pub(crate) uptime_sec: u64,
+    /// The height of the last processed tip.
+    pub(crate) height: u32,
+    /// How long ago the chain source was last polled successfully, in seconds, if it ever was.
+    pub(crate) secs_since_last_poll: Option<u64>,
+    /// How many addresses are watched.
+    pub(crate) addresses: usize,
+    /// The balance of every watched address holding UTXOs.
+    pub(crate) balances: Vec<AddressBalance>,
+    /// The errors querying the chain source since the last report.
+    pub(crate) backend_errors: ErrorLog,
+    /// The errors delivering notifications since the last report.
+    pub(crate) delivery_errors: ErrorLog,
+    /// How many notifications were given up on since the last report.
+    pub(crate) dead_letters: u32,
+}
+
+impl ReportParams {
+    /// The total balance of the watched addresses.
+    pub(crate) fn total_balance(&self) -> Amount {
+        self.balances.iter().map(|balance| balance.balance).sum()
+    }
+
+    /// Whether anything went wrong since the last report.
+    pub(crate) fn has_errors(&self) -> bool {
+        !self.backend_errors.is_empty() || !self.delivery_errors.is_empty() || self.dead_letters > 0
+    }
 }
 
 /// An [`Event`] about a Bitcoin address.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
 pub(crate) enum Event {
-    /// Subscription to a set of addresses.
-    Subscription(Vec<Address>),
+    /// Subscription to a set of addresses and descriptors.
+    Subscription(SubscriptionParams),
     /// A deposit to an address.
     Deposit(EventParams),
     /// A withdrawal from an address.
     Withdrawal(EventParams),
+    /// A previously unconfirmed deposit to an address got confirmed.
+    Confirmed(EventParams),
+    /// A transaction that deposited to, withdrew from, or confirmed multiple UTXOs of the watched addresses.
+    Transaction(TxEventParams),
+    /// The backends of the chain source disagree on the chain tip or on the UTXOs of an address.
+    Disagreement(DisagreementParams),
+    /// The chain source served a header, block or transaction that does not check out against the header chain.
+    VerificationFailure(VerificationFailureParams),
+    /// Blocks that were followed were replaced by another branch.
+    Reorg(ReorgParams),
+    /// A previously reported deposit was confirmed in a block that was reorganized out, and is gone or unconfirmed.
+    DepositReorged(EventParams),
+    /// A previously reported withdrawal was confirmed in a block that was reorganized out, and the UTXO is back.
+    WithdrawalReorged(EventParams),
+    /// A previously notified deposit or withdrawal reached a configured confirmation depth.
+    Milestone(MilestoneParams),
+    /// The periodic status report, telling `smaug` is alive.
+    Report(ReportParams),
 }
 
 #[derive(Debug, Error)]
@@ -57,218 +365,1494 @@ pub(crate) enum SmaugError {
     #[error(transparent)]
     NetworkMismatch(#[from] bitcoin::address::ParseError),
 
-    /// Error creating `EsploraClient`.
+    /// Error parsing or deriving addresses from a descriptor.
+    #[error(transparent)]
+    Descriptor(#[from] DescriptorError),
+
+    /// Error querying the chain source.
+    #[error(transparent)]
+    Chain(#[from] ChainError),
+
+    /// Error loading the persisted state.
+    #[error(transparent)]
+    State(#[from] StateError),
+
+    /// Error setting up notifications.
+    #[error(transparent)]
+    Notifier(#[from] NotifierError),
+
+    /// Error subscribing to bitcoind's ZMQ notifications.
     #[error(transparent)]
-    EsploraClient(#[from] esplora_client::Error),
+    Zmq(#[from] ZmqError),
 
-    /// Error sending email notifications.
+    /// Error setting up the SOCKS5 proxy.
     #[error(transparent)]
-    Email(#[from] EmailError),
+    Proxy(#[from] ProxyError),
+
+    /// Error loading the outbox.
+    #[error(transparent)]
+    Outbox(#[from] OutboxError),
+
+    /// Error starting the async runtime, or listening for shutdown signals.
+    #[error("failed to start the runtime: {0}")]
+    Runtime(#[source] io::Error),
+}
+
+/// The difference in the set of UTXOs locked to an address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct UtxoDiff {
+    /// UTXOs whose outpoint was not locked to the address before.
+    pub(crate) deposited: Vec<Utxo>,
+    /// UTXOs whose outpoint is no longer locked to the address.
+    pub(crate) withdrawn: Vec<Utxo>,
+    /// UTXOs that were unconfirmed before and are confirmed now.
+    pub(crate) confirmed: Vec<Utxo>,
+}
+
+/// The outpoint of a [`Utxo`].
+fn outpoint(utxo: &Utxo) -> OutPoint {
+    OutPoint::new(utxo.txid, utxo.vout)
 }
 
 /// Compute the difference in the set of UTXOs locked to an address.
 ///
-/// Returns two vectors: deposited UTXOs and withdrawn UTXOs.
-pub(crate) fn compute_diff(current_state: &[Utxo], last_state: &[Utxo]) -> (Vec<Utxo>, Vec<Utxo>) {
+/// UTXOs are compared by outpoint, so a status change is never mistaken for a withdrawal followed by a deposit.
+pub(crate) fn compute_diff(current_state: &[Utxo], last_state: &[Utxo]) -> UtxoDiff {
+    // Whether each last UTXO was confirmed, by outpoint.
+    let last: HashMap<OutPoint, bool> = last_state
+        .iter()
+        .map(|utxo| (outpoint(utxo), utxo.status.confirmed))
+        .collect();
+    let current: HashSet<OutPoint> = current_state.iter().map(outpoint).collect();
+
     let deposited: Vec<Utxo> = current_state
         .iter()
-        .filter(|utxo| !last_state.contains(utxo))
+        .filter(|utxo| !last.contains_key(&outpoint(utxo)))
         .cloned()
         .collect();
 
     let withdrawn: Vec<Utxo> = last_state
         .iter()
-        .filter(|utxo| !current_state.contains(utxo))
+        .filter(|utxo| !current.contains(&outpoint(utxo)))
+        .cloned()
+        .collect();
+
+    let confirmed: Vec<Utxo> = current_state
+        .iter()
+        .filter(|utxo| utxo.status.confirmed && last.get(&outpoint(utxo)) == Some(&false))
         .cloned()
         .collect();
 
-    (deposited, withdrawn)
+    UtxoDiff {
+        deposited,
+        withdrawn,
+        confirmed,
+    }
+}
+
+/// Log an [`Event`].
+fn log_event(event: &Event) {
+    match event {
+        Event::Subscription(_) => {}
+        Event::Disagreement(disagreement) => match &disagreement.address {
+            Some(address) => error!("Heads up, the chain source backends disagree on the UTXOs of address {address}!"),
+            None => error!("Heads up, the chain source backends disagree on the chain tip!"),
+        },
+        Event::VerificationFailure(failure) => {
+            error!("Heads up, the chain source failed verification: {}", failure.reason)
+        }
+        Event::Reorg(reorg) => warn!(
+            "Heads up, a reorg of {} blocks replaced the chain above height {}, the new tip is {} at height {}",
+            reorg.depth, reorg.fork_height, reorg.new_tip, reorg.height
+        ),
+        Event::DepositReorged(event_params) => error!(
+            "Heads up, the deposit of {} sats to address {} in transaction {} was reorged out!",
+            format_with_commas(event_params.utxo.value.to_sat()),
+            event_params.address,
+            event_params.utxo.txid
+        ),
+        Event::WithdrawalReorged(event_params) => warn!(
+            "Heads up, the withdrawal of {} sats from address {} was reorged out, {}:{} is unspent again",
+            format_with_commas(event_params.utxo.value.to_sat()),
+            event_params.address,
+            event_params.utxo.txid,
+            event_params.utxo.vout
+        ),
+        Event::Milestone(milestone) => info!(
+            "The {} of {} sats {} address {} has {} confirmations{}",
+            match milestone.movement {
+                Movement::Deposit => "deposit",
+                Movement::Withdrawal => "withdrawal",
+            },
+            format_with_commas(milestone.params.utxo.value.to_sat()),
+            match milestone.movement {
+                Movement::Deposit => "to",
+                Movement::Withdrawal => "from",
+            },
+            milestone.params.address,
+            milestone.confirmations,
+            match milestone.last {
+                true => ", no longer following it",
+                false => "",
+            }
+        ),
+        Event::Report(report) => match report.has_errors() {
+            false => info!("Status report: all quiet at height {}", report.height),
+            true => warn!(
+                "Status report: {} chain source errors, {} delivery errors and {} undelivered notifications since the \
+                 last report",
+                report.backend_errors.count(),
+                report.delivery_errors.count(),
+                report.dead_letters
+            ),
+        },
+        Event::Deposit(event_params) => info!(
+            "Someone deposited {} sats to address {} at height {}",
+            format_with_commas(event_params.utxo.value.to_sat()),
+            event_params.address,
+            event_params.height
+        ),
+        Event::Confirmed(event_params) => info!(
+            "The deposit of {} sats to address {} was confirmed at height {}",
+            format_with_commas(event_params.utxo.value.to_sat()),
+            event_params.address,
+            event_params.height
+        ),
+        Event::Withdrawal(event_params) => match &event_params.spend {
+            Some(spend) if !spend.status.confirmed => warn!(
+                "Heads up, someone is withdrawing {} sats from address {} in unconfirmed transaction {}!",
+                format_with_commas(event_params.utxo.value.to_sat()),
+                event_params.address,
+                spend.txid
+            ),
+            _ => warn!(
+                "Heads up, someone withdrew {} sats from address {} at height {}!",
+                format_with_commas(event_params.utxo.value.to_sat()),
+                event_params.address,
+                event_params.height
+            ),
+        },
+        Event::Transaction(tx_params) => {
+            let txid = match tx_params.txid {
+                Some(txid) => txid.to_string(),
+                None => String::from("an unknown transaction"),
+            };
+            match tx_params.withdrawals.is_empty() {
+                true => info!(
+                    "Transaction {} deposited {} sats to {} addresses at height {}",
+                    txid,
+                    format_with_commas(tx_params.total_deposited().to_sat()),
+                    tx_params.net_flows().len(),
+                    tx_params.height
+                ),
+                false => warn!(
+                    "Heads up, transaction {} withdrew {} sats from {} UTXOs at height {}!",
+                    txid,
+                    format_with_commas(tx_params.total_withdrawn().to_sat()),
+                    tx_params.withdrawals.len(),
+                    tx_params.height
+                ),
+            }
+        }
+    }
 }
 
 /// Handle an [`Event`] according to it's variant.
-pub(crate) fn handle_event(config: &Config, event: &Event) -> Result<(), SmaugError> {
-    let messages = build_messages(config, event)?;
+pub(crate) fn handle_event(config: &Config, outbox: &Outbox, event: &Event) {
+    log_event(event);
 
-    // Send subscription and deposit emails
+    // Notify of subscriptions and deposits
     // iff `notify_subscriptions` and `notify_deposits` are set.
-    match event {
-        Event::Subscription(_) => {
-            if config.notify_subscriptions {
-                send_messages(config, &messages)?;
+    let notify = match event {
+        Event::Subscription(_) => config.notify_subscriptions,
+        Event::Deposit(_) | Event::Confirmed(_) => config.notify_deposits,
+        Event::Withdrawal(_)
+        | Event::Disagreement(_)
+        | Event::VerificationFailure(_)
+        | Event::Reorg(_)
+        | Event::DepositReorged(_)
+        | Event::WithdrawalReorged(_)
+        | Event::Report(_) => true,
+        Event::Transaction(tx_params) => !tx_params.withdrawals.is_empty() || config.notify_deposits,
+        Event::Milestone(milestone) => milestone.movement == Movement::Withdrawal || config.notify_deposits,
+    };
+
+    if notify {
+        outbox.push(event);
+    }
+}
+
+/// Fetch the transaction spending an output, if it is spent.
+pub(crate) fn fetch_spend(chain: &dyn ChainSource, txid: &Txid, vout: u32) -> Result<Option<Spend>, SmaugError> {
+    let Some(spending_txid) = chain.spending_txid(txid, vout)? else {
+        return Ok(None);
+    };
+
+    Ok(chain.tx(&spending_txid)?.as_ref().map(Spend::from_tx))
+}
+
+/// Look up the transaction spending the [`Utxo`] of every withdrawal whose spend is not known yet.
+pub(crate) fn resolve_spends(chain: &dyn ChainSource, events: &mut [Event]) {
+    for event in events {
+        if let Event::Withdrawal(event_params) = event
+            && event_params.spend.is_none()
+        {
+            let (txid, vout) = (event_params.utxo.txid, event_params.utxo.vout);
+            match fetch_spend(chain, &txid, vout) {
+                Ok(Some(spend)) => event_params.spend = Some(spend),
+                Ok(None) => debug!("No spending transaction found for {txid}:{vout}"),
+                Err(e) => warn!("Failed to look up the spending transaction of {txid}:{vout}: {e}"),
             }
         }
-        Event::Deposit(_) => {
-            if config.notify_deposits {
-                send_messages(config, &messages)?;
+    }
+}
+
+/// Aggregate the [`Event`]s of every transaction that touches more than one UTXO into one [`Event::Transaction`].
+///
+/// Deposits and confirmations are grouped by their funding txid, withdrawals by their spending txid. Withdrawals
+/// whose spending transaction is unknown are grouped together.
+pub(crate) fn aggregate_events(events: Vec<Event>) -> Vec<Event> {
+    let mut groups: Vec<(Option<Txid>, Vec<Event>)> = Vec::new();
+    let mut aggregated = Vec::new();
+
+    for event in events {
+        let txid = match &event {
+            Event::Deposit(event_params) | Event::Confirmed(event_params) => Some(event_params.utxo.txid),
+            Event::Withdrawal(event_params) => event_params.spend.as_ref().map(|spend| spend.txid),
+            Event::Subscription(_)
+            | Event::Transaction(_)
+            | Event::Disagreement(_)
+            | Event::VerificationFailure(_)
+            | Event::Reorg(_)
+            | Event::DepositReorged(_)
+            | Event::WithdrawalReorged(_)
+            | Event::Milestone(_)
+            | Event::Report(_) => {
+                aggregated.push(event);
+                continue;
             }
+        };
+        match groups.iter_mut().find(|(group_txid, _)| *group_txid == txid) {
+            Some((_, group)) => group.push(event),
+            None => groups.push((txid, vec![event])),
         }
-        Event::Withdrawal(_) => send_messages(config, &messages)?,
     }
 
-    Ok(())
+    for (txid, mut group) in groups {
+        if group.len() == 1 {
+            aggregated.append(&mut group);
+            continue;
+        }
+
+        let mut tx_params = TxEventParams {
+            txid,
+            status: None,
+            deposits: Vec::new(),
+            withdrawals: Vec::new(),
+            confirmations: Vec::new(),
+            height: 0,
+            after_restart: false,
+        };
+        for event in group {
+            let (Event::Deposit(event_params) | Event::Withdrawal(event_params) | Event::Confirmed(event_params)) =
+                &event
+            else {
+                continue;
+            };
+
+            tx_params.height = tx_params.height.max(event_params.height);
+            tx_params.after_restart |= event_params.after_restart;
+            if tx_params.status.is_none() {
+                tx_params.status = match &event_params.spend {
+                    Some(spend) => Some(spend.status.clone()),
+                    None if txid.is_some() => Some(TxStatus {
+                        confirmed: event_params.utxo.status.confirmed,
+                        block_height: event_params.utxo.status.block_height,
+                        block_hash: event_params.utxo.status.block_hash,
+                        block_time: event_params.utxo.status.block_time,
+                    }),
+                    None => None,
+                };
+            }
+
+            match event {
+                Event::Deposit(event_params) => tx_params.deposits.push(event_params),
+                Event::Withdrawal(event_params) => tx_params.withdrawals.push(event_params),
+                Event::Confirmed(event_params) => tx_params.confirmations.push(event_params),
+                Event::Subscription(_)
+                | Event::Transaction(_)
+                | Event::Disagreement(_)
+                | Event::VerificationFailure(_)
+                | Event::Reorg(_)
+                | Event::DepositReorged(_)
+                | Event::WithdrawalReorged(_)
+                | Event::Milestone(_)
+                | Event::Report(_) => {}
+            }
+        }
+        aggregated.push(Event::Transaction(tx_params));
+    }
+
+    aggregated
+}
+
+/// Handle every [`Event`], logging a warning for the ones that fail.
+///
+/// Withdrawals are first matched to their spending transaction, and the events of every transaction that touches
+/// more than one UTXO are aggregated, so a sweep results in a single notification. The events the chain source raised
+/// about itself meanwhile are handled too.
+fn handle_events(config: &Config, outbox: &Outbox, chain: &dyn ChainSource, mut events: Vec<Event>) {
+    resolve_spends(chain, &mut events);
+    events.extend(chain.take_events());
+
+    for event in aggregate_events(events) {
+        handle_event(config, outbox, &event);
+    }
 }
 
 /// Fetch UTXOs for all addresses with retry logic.
 fn fetch_utxos_with_retry(
-    esplora: &BlockingClient,
+    chain: &dyn ChainSource,
     addresses: &[Address<NetworkChecked>],
 ) -> Result<UtxoDB, SmaugError> {
-    let mut db = UtxoDB::new();
+    chain.watch(addresses)?;
 
-    for address in addresses {
-        let utxos = esplora.get_address_utxos(address)?;
-        db.insert(address.clone(), utxos);
+    let addresses = proxy::query_order(addresses);
+    let utxos = chain.addresses_utxos(&addresses)?;
+
+    Ok(addresses.into_iter().cloned().zip(utxos).collect())
+}
+
+/// Mark the `fetched` addresses that have any history as used, then derive and fetch new addresses from the watched
+/// descriptors until every descriptor has `gap_limit` unused addresses past its last used one.
+fn sync_watchlist(
+    chain: &dyn ChainSource,
+    watchlist: &mut WatchList,
+    db: &mut UtxoDB,
+    fetched: &[Address],
+) -> Result<(), SmaugError> {
+    let mut fetched = fetched.to_vec();
+    loop {
+        mark_used(chain, watchlist, db, &fetched)?;
+        let new_addresses = watchlist.update()?;
+        if new_addresses.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Derived {} new addresses", new_addresses.len());
+        db.extend(fetch_utxos_with_retry(chain, &new_addresses)?);
+        fetched = new_addresses;
+    }
+}
+
+/// Mark the derived `addresses` that have any history as used: the ones holding UTXOs in `db` right away, the others
+/// once the chain source tells they were used, so an address emptied since still counts against the gap limit.
+fn mark_used(
+    chain: &dyn ChainSource,
+    watchlist: &mut WatchList,
+    db: &UtxoDB,
+    addresses: &[Address],
+) -> Result<(), SmaugError> {
+    let candidates: Vec<&Address> = addresses
+        .iter()
+        .filter(|address| watchlist.may_be_unused(address))
+        .collect();
+    let (funded, unknown): (Vec<&Address>, Vec<&Address>) = candidates
+        .into_iter()
+        .partition(|address| db.get(*address).is_some_and(|utxos| !utxos.is_empty()));
+
+    let used = match unknown.is_empty() {
+        true => Vec::new(),
+        false => chain.addresses_used(&unknown)?,
+    };
+    let used = unknown
+        .into_iter()
+        .zip(used)
+        .filter_map(|(address, used)| used.then_some(address));
+    for address in funded.into_iter().chain(used) {
+        watchlist.mark_used(address);
     }
 
+    Ok(())
+}
+
+/// Fetch the UTXOs of the watched addresses whose [`AddressStatus`] changed since it was recorded in `statuses`,
+/// deriving new addresses from the watched descriptors as needed.
+///
+/// Returns the UTXOs of the addresses fetched, new addresses included. `statuses` is only updated once every address
+/// was fetched, so a failed round fetches the same addresses again.
+fn fetch_changes(
+    chain: &dyn ChainSource,
+    watchlist: &mut WatchList,
+    statuses: &mut HashMap<Address, AddressStatus>,
+) -> Result<UtxoDB, SmaugError> {
+    let addresses = proxy::query_order(watchlist.addresses());
+    let current_statuses = chain.addresses_status(&addresses)?;
+    let changed: Vec<(Address, Option<AddressStatus>)> = addresses
+        .into_iter()
+        .zip(current_statuses)
+        .filter(|(address, status)| status.is_none() || statuses.get(*address) != status.as_ref())
+        .map(|(address, status)| (address.clone(), status))
+        .collect();
+    let changed_addresses: Vec<Address> = changed.iter().map(|(address, _)| address.clone()).collect();
+    debug!(
+        "{} of {} addresses changed",
+        changed_addresses.len(),
+        watchlist.addresses().len()
+    );
+
+    let mut db = fetch_utxos_with_retry(chain, &changed_addresses)?;
+    // Addresses used before were marked so already: only the fetched ones may need new addresses derived.
+    sync_watchlist(chain, watchlist, &mut db, &changed_addresses)?;
+
+    for (address, status) in changed {
+        // A status that does not match the UTXOs fetched is not recorded, so they are fetched again next round.
+        match status.filter(|status| status.matches(&db[&address])) {
+            Some(status) => statuses.insert(address, status),
+            None => statuses.remove(&address),
+        };
+    }
+
+    Ok(db)
+}
+
+/// Fetch UTXOs for every watched address, deriving new addresses from the watched descriptors as needed.
+fn fetch_state(chain: &dyn ChainSource, watchlist: &mut WatchList) -> Result<UtxoDB, SmaugError> {
+    let addresses = watchlist.addresses().to_vec();
+    let mut db = fetch_utxos_with_retry(chain, &addresses)?;
+    sync_watchlist(chain, watchlist, &mut db, &addresses)?;
+
     Ok(db)
 }
 
-/// Long-poll the Esplora API, compute address state diffs, and notify the recipients if there is a diff.
-pub(crate) fn smaug(config: &Config) -> Result<(), SmaugError> {
-    let base_url = match &config.esplora_url {
-        Some(url) => {
-            info!("Using configured Esplora API: {url}");
-            url
+/// Re-fetch the UTXOs of `addresses`, which had new transactions, marking them as used and deriving new addresses
+/// from the watched descriptors as needed.
+fn refresh_state(
+    chain: &dyn ChainSource,
+    watchlist: &mut WatchList,
+    db: &mut UtxoDB,
+    addresses: &[Address],
+) -> Result<(), SmaugError> {
+    for address in addresses {
+        watchlist.mark_used(address);
+    }
+    db.extend(fetch_utxos_with_retry(chain, addresses)?);
+
+    sync_watchlist(chain, watchlist, db, &[])
+}
+
+/// Generate [`Event::Deposit`]s, [`Event::Withdrawal`]s and [`Event::Confirmed`]s from the diff between the last and
+/// current states.
+///
+/// Only `addresses` are diffed. Those missing from `last_state` were derived during this round, so all their UTXOs are
+/// deposits. After a restart, however, they are addresses that were not watched before and are skipped.
+pub(crate) fn compute_events(
+    watchlist: &WatchList,
+    addresses: &[Address],
+    current_state: &UtxoDB,
+    last_state: &UtxoDB,
+    height: u32,
+    after_restart: bool,
+) -> Vec<Event> {
+    let mut events: Vec<Event> = Vec::new();
+
+    for address in addresses {
+        if after_restart && !last_state.contains_key(address) {
+            continue;
         }
-        None => match &config.network {
-            Network::Bitcoin => {
-                info!("Using default Bitcoin Esplora API: {BITCOIN_ESPLORA}");
-                BITCOIN_ESPLORA
-            }
-            Network::Signet => {
-                info!("Using default Signet Esplora API: {SIGNET_ESPLORA}");
-                SIGNET_ESPLORA
-            }
-            Network::Testnet4 => {
-                info!("Using default Testnet4 Esplora API: {TESTNET4_ESPLORA}");
-                TESTNET4_ESPLORA
-            }
-            _ => {
-                error!("Other networks are not supported");
-                process::exit(1);
-            }
-        },
+
+        let diff = compute_diff(
+            current_state.get(address).map(Vec::as_slice).unwrap_or_default(),
+            last_state.get(address).map(Vec::as_slice).unwrap_or_default(),
+        );
+
+        // Create [`Event::Deposit`]s based on the `UtxoDBs` diff between the last and current states.
+        for deposit in diff.deposited {
+            let event: Event = Event::Deposit(EventParams {
+                address: address.clone(),
+                origin: watchlist.origin(address).cloned(),
+                utxo: deposit,
+                height,
+                after_restart,
+                spend: None,
+            });
+            events.push(event);
+        }
+
+        // Create [`Event::Withdrawal`]s based on the `UtxoDBs` diff between the last and current states.
+        for withdrawal in diff.withdrawn {
+            let event: Event = Event::Withdrawal(EventParams {
+                address: address.clone(),
+                origin: watchlist.origin(address).cloned(),
+                utxo: withdrawal,
+                height,
+                after_restart,
+                spend: None,
+            });
+            events.push(event);
+        }
+
+        // Create [`Event::Confirmed`]s for deposits that were unconfirmed in the last state.
+        for confirmation in diff.confirmed {
+            let event: Event = Event::Confirmed(EventParams {
+                address: address.clone(),
+                origin: watchlist.origin(address).cloned(),
+                height: confirmation.status.block_height.unwrap_or(height),
+                utxo: confirmation,
+                after_restart,
+                spend: None,
+            });
+            events.push(event);
+        }
+    }
+
+    events
+}
+
+/// Persist the current state at `height` to `path`, along with the recent blocks followed by `chain_tracker`, the
+/// addresses of `watchlist` known to be used and the movements `confirmation_watch` holds back, logging a warning on
+/// failure.
+fn persist_state(
+    chain: &dyn ChainSource,
+    chain_tracker: &ChainTracker,
+    watchlist: &WatchList,
+    confirmation_watch: &ConfirmationWatch,
+    path: &Path,
+    height: u32,
+    utxos: &UtxoDB,
+) {
+    let block_hash = match chain_tracker.block_hash(height) {
+        Some(block_hash) => Ok(block_hash),
+        None => chain.block_hash(height),
     };
+    let result = block_hash.map_err(SmaugError::from).and_then(|block_hash| {
+        let state = State {
+            height,
+            block_hash,
+            recent_blocks: match chain_tracker.block_hash(height) {
+                Some(_) => chain_tracker.recent_blocks(height),
+                None => vec![block_hash],
+            },
+            utxos: utxos.clone(),
+            used: watchlist.used(),
+            tracked: confirmation_watch.stored(),
+        };
+        Ok(state.save(path)?)
+    });
+
+    match result {
+        Ok(()) => debug!("Saved state at height {height} to `{}`", path.display()),
+        Err(e) => warn!("Failed to save state at height {height}: {e}"),
+    }
+}
+
+/// Long-poll the chain source, compute address state diffs, and notify the recipients if there is a diff.
+///
+/// Requests to the Esplora APIs and notification deliveries run on `runtime`. Returns once `shutdown` is requested,
+/// after persisting the state and waiting a bit for the notifications being delivered.
+pub(crate) fn smaug(config: &Config, runtime: &Handle, shutdown: &Shutdown) -> Result<(), SmaugError> {
+    // Route all outbound traffic through the SOCKS5 proxy, if configured.
+    if let Some(proxy) = &config.proxy {
+        proxy::init(proxy)?;
+        info!(
+            "Routing outbound traffic through SOCKS5 proxy {}{}",
+            proxy.socks5,
+            match proxy.isolate_addresses {
+                true => ", isolating every address",
+                false => "",
+            }
+        );
+    }
+
+    // Build the chain source `smaug` will use to make requests.
+    let chain = chain_source_from_config(config, runtime)?;
 
-    // Build the esplora client `smaug` will use to make requests.
-    let esplora = Builder::new(base_url).build_blocking();
+    // Send the periodic status reports and ping the dead-man's switch, if configured.
+    let mut heartbeat = Heartbeat::new(config.heartbeat.as_ref(), runtime);
 
     // Get the current chain tip with retry.
-    let mut current_chain_tip = loop {
-        match esplora.get_height() {
-            Ok(height) => break height,
+    let (mut current_chain_tip, tip_hash) = loop {
+        match chain
+            .height()
+            .and_then(|height| Ok((height, chain.block_hash(height)?)))
+        {
+            Ok(tip) => break tip,
             Err(e) => {
                 error!("Failed to fetch initial chain tip: {e}");
+                heartbeat.backend_error("fetch the initial chain tip", &e);
                 error!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
-                thread::sleep(Duration::from_secs(ERROR_RETRY_DELAY_SEC));
+                if shutdown.sleep(runtime, Duration::from_secs(ERROR_RETRY_DELAY_SEC)) {
+                    return Ok(());
+                }
             }
         }
     };
 
-    // Perform network validation on the provided [`Address`]es against the configured [`Network`].
-    let addresses = check_addresses(&config.addresses, &config.network)?;
+    // Set up every configured notification channel.
+    let status = SharedStatus::default();
+    let notifiers = notifiers_from_config(config, &status)?;
+    // Deliver notifications in the background, so a failing channel never holds back detection.
+    let outbox = Outbox::start(&config.outbox, config.network, notifiers, runtime, shutdown)?;
 
-    // Populate the [`UtxoDB`] with the initial state with retry logic.
-    let mut current_state = loop {
-        match fetch_utxos_with_retry(&esplora, &addresses) {
-            Ok(state) => {
-                for address in &addresses {
-                    info!("Subscribed to address {} at height {}", address, current_chain_tip);
-                }
-                debug!("initial_state = {:#?}", state);
-                break state;
-            }
-            Err(e) => {
-                error!("Failed to fetch initial UTXOs: {e}");
-                error!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
-                thread::sleep(Duration::from_secs(ERROR_RETRY_DELAY_SEC));
+    // Perform network validation on the provided [`Address`]es against the configured [`Network`],
+    // and derive the first addresses of every descriptor.
+    let mut watchlist = WatchList::from_config(config)?;
+
+    // Hold deposits and withdrawals back until they reach their configured confirmation depths.
+    let mut confirmation_watch = ConfirmationWatch::new(config.confirmations.as_ref(), config.network)?;
+
+    // Load the state persisted by the last run, if any.
+    let state_path = config.state_file.as_deref().map(Path::new);
+    let saved_state = match state_path {
+        Some(path) => State::load(path, config.network)?,
+        None => None,
+    };
+    if let (Some(path), Some(saved_state)) = (state_path, &saved_state) {
+        info!(
+            "Loaded state at height {} ({}) from `{}`",
+            saved_state.height,
+            saved_state.block_hash,
+            path.display()
+        );
+        // Keep deriving past the addresses that were already used before the restart, even if they were emptied since.
+        let funded = saved_state.utxos.iter().filter(|(_, utxos)| !utxos.is_empty());
+        for address in saved_state.used.iter().chain(funded.map(|(address, _)| address)) {
+            watchlist.mark_used(address);
+        }
+        watchlist.update()?;
+        // Keep holding back the deposits and withdrawals that had not reached their milestones yet.
+        confirmation_watch.restore(&saved_state.tracked, &watchlist, config.network)?;
+    }
+
+    // Follow the chain from the last processed tip, to find out if it was reorganized while `smaug` was not running.
+    let mut chain_tracker = match &saved_state {
+        Some(saved_state) => ChainTracker::new(saved_state.height, saved_state.recent_blocks.clone()),
+        None => ChainTracker::new(current_chain_tip, vec![tip_hash]),
+    };
+    let mut pending_reorg = match chain_tracker.update(chain.as_ref(), current_chain_tip, tip_hash) {
+        Ok(reorg) => reorg.zip(saved_state.as_ref()).map(|(reorg, saved_state)| {
+            warn!(
+                "Reorg of {} blocks above height {} since the last run",
+                reorg.depth, reorg.fork_height
+            );
+            PendingReorg::new(reorg, saved_state.utxos.clone())
+        }),
+        Err(e) => {
+            warn!("Failed to look for a reorg since the last run: {e}");
+            heartbeat.backend_error("look for a reorg since the last run", &e);
+            None
+        }
+    };
+
+    // Populate the [`UtxoDB`] with the initial state with retry logic. When scanning blocks, the addresses are never
+    // queried: the UTXOs are only known from the saved state, and from the blocks scanned from then on. With UTXO
+    // detection, the status of every address is recorded along, so only the addresses that changed are fetched again.
+    let mut statuses = HashMap::new();
+    let mut current_state = match config.detection {
+        Detection::Scan => {
+            let mut state = saved_state
+                .as_ref()
+                .map(|saved_state| saved_state.utxos.clone())
+                .unwrap_or_default();
+            for address in watchlist.addresses() {
+                state.entry(address.clone()).or_default();
             }
+            state
         }
+        Detection::Utxo | Detection::History => loop {
+            let state = match config.detection {
+                Detection::Utxo => fetch_changes(chain.as_ref(), &mut watchlist, &mut statuses),
+                _ => fetch_state(chain.as_ref(), &mut watchlist),
+            };
+            match state {
+                Ok(state) => break state,
+                Err(e) => {
+                    error!("Failed to fetch initial UTXOs: {e}");
+                    heartbeat.backend_error("fetch initial UTXOs", &e);
+                    error!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
+                    handle_events(config, &outbox, chain.as_ref(), Vec::new());
+                    if shutdown.sleep(runtime, Duration::from_secs(ERROR_RETRY_DELAY_SEC)) {
+                        outbox.close(Duration::from_secs(SHUTDOWN_DELIVERY_TIMEOUT_SEC));
+                        return Ok(());
+                    }
+                }
+            }
+        },
     };
+    for address in watchlist.addresses() {
+        match watchlist.origin(address) {
+            Some(origin) => info!(
+                "Subscribed to address {} ({}) at height {}",
+                address, origin, current_chain_tip
+            ),
+            None => info!("Subscribed to address {} at height {}", address, current_chain_tip),
+        }
+    }
+    debug!("initial_state = {:#?}", current_state);
 
     // Send subscription email iff `config.notify_subscriptions` is set.
     if config.notify_subscriptions {
-        let event = Event::Subscription(addresses.clone());
-        if let Err(e) = handle_event(config, &event) {
-            warn!("Failed to send subscription notification: {e}");
+        let event = Event::Subscription(watchlist.subscription_params());
+        handle_event(config, &outbox, &event);
+    }
+
+    // Report whatever moved while `smaug` was not running.
+    let mut history_watch = HistoryWatch::new(saved_state.as_ref().map_or(current_chain_tip, |state| state.height));
+    let scan_start = match (&saved_state, config.scan_from_height) {
+        (Some(saved_state), _) => saved_state.height,
+        (None, Some(scan_from_height)) => scan_from_height.saturating_sub(1),
+        (None, None) => {
+            if config.detection == Detection::Scan {
+                warn!(
+                    "Without a saved state or `scan_from_height`, UTXOs received before height {current_chain_tip} are unknown"
+                );
+            }
+            current_chain_tip
+        }
+    };
+    let mut block_scan = BlockScan::new(scan_start, &watchlist, &current_state);
+    match config.detection {
+        Detection::Utxo => {
+            if let Some(saved_state) = &saved_state {
+                let mut events = compute_events(
+                    &watchlist,
+                    watchlist.addresses(),
+                    &current_state,
+                    &saved_state.utxos,
+                    current_chain_tip,
+                    true,
+                );
+                if let Some(pending) = pending_reorg.take() {
+                    events = pending.finish(chain.as_ref(), &watchlist, &current_state, events, true);
+                }
+                let events = confirmation_watch.update(chain.as_ref(), &current_state, current_chain_tip, events);
+                debug!("events = {:#?}", events);
+
+                handle_events(config, &outbox, chain.as_ref(), events);
+            }
+        }
+        Detection::History => {
+            match history_watch.poll(chain.as_ref(), &watchlist, current_chain_tip, true) {
+                // Without a saved state, the current history is the baseline.
+                Ok((mut events, active_addresses)) if saved_state.is_some() => {
+                    if let Some(pending) = pending_reorg.take() {
+                        events = pending.finish(chain.as_ref(), &watchlist, &current_state, events, true);
+                    }
+                    if let Err(e) = refresh_state(chain.as_ref(), &mut watchlist, &mut current_state, &active_addresses)
+                    {
+                        warn!("Failed to refresh UTXOs: {e}");
+                        heartbeat.backend_error("refresh UTXOs", &e);
+                    }
+                    let events = confirmation_watch.update(chain.as_ref(), &current_state, current_chain_tip, events);
+                    debug!("events = {:#?}", events);
+                    handle_events(config, &outbox, chain.as_ref(), events);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to walk the transaction history: {e}");
+                    heartbeat.backend_error("walk the transaction history", &e);
+                }
+            }
         }
+        Detection::Scan => {
+            if let Some(pending) = &pending_reorg {
+                block_scan.rewind(pending.reorg.fork_height, &mut current_state);
+            }
+            info!(
+                "Scanning blocks {} to {current_chain_tip}...",
+                block_scan.synced_height() + 1
+            );
+            let mut events = Vec::new();
+            let result = block_scan.scan_to(
+                chain.as_ref(),
+                &mut watchlist,
+                &mut current_state,
+                current_chain_tip,
+                true,
+                &mut events,
+            );
+            if let Err(e) = result {
+                warn!("Failed to scan blocks: {e}");
+                heartbeat.backend_error("scan blocks", &e);
+            }
+            // The state on the new chain is only known once every block was scanned again.
+            match pending_reorg.take() {
+                Some(pending) if block_scan.synced_height() >= current_chain_tip => {
+                    events = pending.finish(chain.as_ref(), &watchlist, &current_state, events, true);
+                }
+                Some(mut pending) => {
+                    pending.defer(std::mem::take(&mut events));
+                    pending_reorg = Some(pending);
+                }
+                None => {}
+            }
+
+            // Without a saved state, the scanned blocks are the baseline.
+            current_chain_tip = block_scan.synced_height();
+            if saved_state.is_some() {
+                let events = confirmation_watch.update(chain.as_ref(), &current_state, current_chain_tip, events);
+                debug!("events = {:#?}", events);
+                handle_events(config, &outbox, chain.as_ref(), events);
+            }
+        }
+    }
+    if let Some(path) = state_path {
+        persist_state(
+            chain.as_ref(),
+            &chain_tracker,
+            &watchlist,
+            &confirmation_watch,
+            path,
+            current_chain_tip,
+            &current_state,
+        );
     }
+    record_poll(&status, current_chain_tip, &current_state);
 
-    // Event Loop.
-    loop {
-        // Fetch the current height.
-        let last_chain_tip = current_chain_tip;
-        current_chain_tip = match esplora.get_height() {
-            Ok(height) => height,
+    let mut mempool_watch = MempoolWatch::default();
+    let mempool_polling_period = match config.detection {
+        Detection::Scan if config.mempool_polling_period_sec.is_some() => {
+            warn!("The mempool is not polled when scanning blocks, ignoring `mempool_polling_period_sec`");
+            None
+        }
+        _ => config.mempool_polling_period_sec.map(Duration::from_secs),
+    };
+    let mut next_block_poll = Instant::now() + Duration::from_secs(POLLING_PERIOD_SEC);
+    let mut next_mempool_poll = Instant::now();
+
+    // Subscribe to bitcoind's ZMQ notifications, so changes are handled as soon as they are pushed.
+    let zmq = config.zmq.as_ref().map(Zmq::subscribe).transpose()?;
+    // Set when ZMQ notifications were missed, so the next round resyncs even if the chain tip did not move.
+    let mut resync = false;
+
+    // Event Loop, until shutdown is requested.
+    while !shutdown.requested() {
+        // Tell the world `smaug` is alive.
+        heartbeat.ping(&status);
+        if let Some(report) = heartbeat.report(&status, &outbox) {
+            handle_event(config, &outbox, &Event::Report(report));
+        }
+
+        // Sleep until the next block or mempool poll is due, or until the chain source pushes a change, checking for
+        // shutdown every `SHUTDOWN_CHECK_PERIOD_SEC`.
+        let next_poll = match mempool_polling_period {
+            Some(_) => next_block_poll.min(next_mempool_poll),
+            None => next_block_poll,
+        };
+        let timeout = next_poll
+            .saturating_duration_since(Instant::now())
+            .min(Duration::from_secs(SHUTDOWN_CHECK_PERIOD_SEC));
+        let notifications = match &zmq {
+            Some(zmq) => zmq.wait(timeout),
+            None => {
+                if chain.wait_for_changes(timeout) {
+                    debug!("The chain source pushed a change, polling now");
+                    next_block_poll = Instant::now();
+                    next_mempool_poll = Instant::now();
+                }
+                Vec::new()
+            }
+        };
+
+        // Match the transactions bitcoind pushed against the watched addresses, and poll right away on new blocks.
+        for notification in notifications {
+            match notification {
+                Notification::Tx(transaction) => {
+                    let Some((tx, addresses)) = match_tx(&transaction, &watchlist, &current_state) else {
+                        continue;
+                    };
+                    debug!(
+                        "Pushed transaction {} touches {} watched addresses",
+                        tx.txid,
+                        addresses.len()
+                    );
+
+                    let events = match config.detection {
+                        Detection::Utxo => {
+                            mempool_watch.on_tx(&tx, &addresses, &watchlist, &mut current_state, current_chain_tip)
+                        }
+                        Detection::History => {
+                            let events = history_watch.on_tx(&tx, &addresses, &watchlist, current_chain_tip);
+                            if let Err(e) =
+                                refresh_state(chain.as_ref(), &mut watchlist, &mut current_state, &addresses)
+                            {
+                                warn!("Failed to refresh UTXOs: {e}");
+                                heartbeat.backend_error("refresh UTXOs", &e);
+                            }
+                            events
+                        }
+                        // The mempool is not watched when scanning blocks.
+                        Detection::Scan => Vec::new(),
+                    };
+                    let events = confirmation_watch.update(chain.as_ref(), &current_state, current_chain_tip, events);
+                    debug!("events = {:#?}", events);
+                    handle_events(config, &outbox, chain.as_ref(), events);
+                    record_poll(&status, current_chain_tip, &current_state);
+                }
+                Notification::Block(block) => {
+                    debug!("bitcoind pushed block {}, polling now", block.block_hash());
+                    next_block_poll = Instant::now();
+                }
+                Notification::Tip => next_block_poll = Instant::now(),
+                Notification::Gap => {
+                    warn!("Missed ZMQ notifications, resyncing through the chain source");
+                    resync = true;
+                    next_block_poll = Instant::now();
+                    next_mempool_poll = Instant::now();
+                }
+            }
+        }
+
+        // Poll the mempool activity of the watched addresses, independently of new blocks.
+        if let Some(period) = mempool_polling_period
+            && Instant::now() >= next_mempool_poll
+        {
+            next_mempool_poll = Instant::now() + period;
+            let events = match config.detection {
+                Detection::Utxo => {
+                    mempool_watch.poll(chain.as_ref(), &watchlist, &mut current_state, current_chain_tip)
+                }
+                Detection::History => history_watch
+                    .poll(chain.as_ref(), &watchlist, current_chain_tip, false)
+                    .map(|(events, active_addresses)| {
+                        if let Err(e) =
+                            refresh_state(chain.as_ref(), &mut watchlist, &mut current_state, &active_addresses)
+                        {
+                            warn!("Failed to refresh UTXOs: {e}");
+                            heartbeat.backend_error("refresh UTXOs", &e);
+                        }
+                        events
+                    }),
+                Detection::Scan => Ok(Vec::new()),
+            };
+            match events {
+                Ok(events) => {
+                    let events = confirmation_watch.update(chain.as_ref(), &current_state, current_chain_tip, events);
+                    debug!("events = {:#?}", events);
+                    handle_events(config, &outbox, chain.as_ref(), events);
+                    record_poll(&status, current_chain_tip, &current_state);
+                }
+                Err(e) => {
+                    warn!("Failed to poll the mempool: {e}");
+                    heartbeat.backend_error("poll the mempool", &e);
+                }
+            }
+        }
+
+        if Instant::now() < next_block_poll {
+            continue;
+        }
+        next_block_poll = Instant::now() + Duration::from_secs(POLLING_PERIOD_SEC);
+
+        // Fetch the current chain tip, and follow the chain to it.
+        let tip = chain.height().and_then(|height| {
+            let tip_hash = chain.block_hash(height)?;
+            Ok((height, chain_tracker.update(chain.as_ref(), height, tip_hash)?))
+        });
+        let new_chain_tip = match tip {
+            Ok((height, reorg)) => {
+                if let Some(reorg) = reorg {
+                    warn!("Reorg of {} blocks above height {}", reorg.depth, reorg.fork_height);
+                    let fork_height = reorg.fork_height;
+                    pending_reorg = Some(match pending_reorg.take() {
+                        Some(pending) => pending.then(reorg),
+                        None => PendingReorg::new(reorg, current_state.clone()),
+                    });
+                    // Undo the blocks that were reorganized out, so they are scanned again on the new chain.
+                    if config.detection == Detection::Scan {
+                        block_scan.rewind(fork_height, &mut current_state);
+                        current_chain_tip = current_chain_tip.min(block_scan.synced_height());
+                    }
+                }
+                height
+            }
             Err(e) => {
-                error!("Failed to fetch initial UTXOs: {e}");
+                error!("Failed to fetch chain tip: {e}");
+                heartbeat.backend_error("fetch the chain tip", &e);
                 error!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
-                thread::sleep(Duration::from_secs(ERROR_RETRY_DELAY_SEC));
+                // Alert about a chain source that failed verification, even though the round failed.
+                handle_events(config, &outbox, chain.as_ref(), Vec::new());
+                next_block_poll = Instant::now() + Duration::from_secs(ERROR_RETRY_DELAY_SEC);
                 continue;
             }
         };
 
-        // Check if the `current_chain_tip` is superior than `last_chain_tip`. If not, skip.
-        if current_chain_tip <= last_chain_tip {
-            thread::sleep(Duration::from_secs(POLLING_PERIOD_SEC));
+        // Check if the `new_chain_tip` is superior than `current_chain_tip`. If not, skip, unless resyncing or handling
+        // a reorg.
+        if new_chain_tip <= current_chain_tip && !resync && pending_reorg.is_none() {
+            handle_events(config, &outbox, chain.as_ref(), Vec::new());
+            record_poll(&status, current_chain_tip, &current_state);
             continue;
         }
 
-        // The initial state becomes the last state.
-        let last_state = current_state.clone();
+        info!("Fetching state at height {}...", new_chain_tip);
 
-        info!("Fetching state at height {}...", current_chain_tip);
+        // The chain tip only advances once its state was fetched, so a failed round is retried.
+        let events = match config.detection {
+            Detection::Utxo => {
+                // A reorg may replace transactions without changing the status of an address: fetch them all again.
+                if pending_reorg.is_some() {
+                    statuses.clear();
+                }
 
-        // Fetch the current state from Esplora with error handling.
-        current_state = match fetch_utxos_with_retry(&esplora, &addresses) {
-            Ok(state) => state,
-            Err(e) => {
-                warn!("Failed to fetch UTXOs: {e}");
-                warn!("Keeping previous state and retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
-                thread::sleep(Duration::from_secs(ERROR_RETRY_DELAY_SEC));
-                continue;
+                // Fetch the UTXOs of the addresses that changed from the chain source with error handling.
+                let changed_state = match fetch_changes(chain.as_ref(), &mut watchlist, &mut statuses) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        warn!("Failed to fetch UTXOs: {e}");
+                        heartbeat.backend_error("fetch UTXOs", &e);
+                        warn!("Keeping previous state and retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
+                        handle_events(config, &outbox, chain.as_ref(), Vec::new());
+                        next_block_poll = Instant::now() + Duration::from_secs(ERROR_RETRY_DELAY_SEC);
+                        continue;
+                    }
+                };
+
+                // The UTXOs they replace become the last state, in watchlist order.
+                let changed: Vec<Address> = watchlist
+                    .addresses()
+                    .iter()
+                    .filter(|address| changed_state.contains_key(*address))
+                    .cloned()
+                    .collect();
+                let mut last_state = UtxoDB::new();
+                for (address, utxos) in changed_state {
+                    if let Some(last) = current_state.insert(address.clone(), utxos) {
+                        last_state.insert(address, last);
+                    }
+                }
+
+                // Compute the difference between states and generate [`Event`]s.
+                let mut events =
+                    compute_events(&watchlist, &changed, &current_state, &last_state, new_chain_tip, false);
+
+                // Follow up on withdrawals that were first seen in the mempool.
+                match mempool_watch.check_pending(chain.as_ref(), new_chain_tip) {
+                    Ok(follow_ups) => events.extend(follow_ups),
+                    Err(e) => {
+                        warn!("Failed to check pending withdrawals: {e}");
+                        heartbeat.backend_error("check pending withdrawals", &e);
+                    }
+                }
+
+                events
             }
-        };
+            Detection::History => {
+                // After a reorg, every address is fetched again, so the deposits that were reorganized out show up.
+                if pending_reorg.is_some() {
+                    match fetch_state(chain.as_ref(), &mut watchlist) {
+                        Ok(state) => current_state = state,
+                        Err(e) => {
+                            warn!("Failed to fetch UTXOs: {e}");
+                            heartbeat.backend_error("fetch UTXOs", &e);
+                            warn!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
+                            handle_events(config, &outbox, chain.as_ref(), Vec::new());
+                            next_block_poll = Instant::now() + Duration::from_secs(ERROR_RETRY_DELAY_SEC);
+                            continue;
+                        }
+                    }
+                }
+
+                // Walk the new transactions of every address and generate [`Event`]s.
+                let (events, active_addresses) =
+                    match history_watch.poll(chain.as_ref(), &watchlist, new_chain_tip, false) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            warn!("Failed to walk the transaction history: {e}");
+                            heartbeat.backend_error("walk the transaction history", &e);
+                            warn!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
+                            handle_events(config, &outbox, chain.as_ref(), Vec::new());
+                            next_block_poll = Instant::now() + Duration::from_secs(ERROR_RETRY_DELAY_SEC);
+                            continue;
+                        }
+                    };
+
+                if let Err(e) = refresh_state(chain.as_ref(), &mut watchlist, &mut current_state, &active_addresses) {
+                    warn!("Failed to refresh UTXOs: {e}");
+                    heartbeat.backend_error("refresh UTXOs", &e);
+                }
 
-        // Compute the difference between states and generate [`Event`]s.
-        let mut events: Vec<Event> = Vec::new();
-        for address in &addresses {
-            let (deposited, withdrawn) =
-                compute_diff(current_state.get(address).unwrap(), last_state.get(address).unwrap());
-
-            // Create [`Event::Deposit`]s based on the `UtxoDBs` diff between the last and current states.
-            for deposit in deposited {
-                let event: Event = Event::Deposit(EventParams {
-                    address: address.clone(),
-                    utxo: deposit,
-                    height: current_chain_tip,
-                });
-                events.push(event);
+                events
             }
+            Detection::Scan => {
+                // Scan every new block, keeping the events of the blocks scanned before a failure.
+                let mut events = Vec::new();
+                let result = block_scan.scan_to(
+                    chain.as_ref(),
+                    &mut watchlist,
+                    &mut current_state,
+                    new_chain_tip,
+                    false,
+                    &mut events,
+                );
+                if let Err(e) = result {
+                    warn!("Failed to scan blocks: {e}");
+                    heartbeat.backend_error("scan blocks", &e);
+                    warn!("Retrying in {ERROR_RETRY_DELAY_SEC} seconds...");
+                    next_block_poll = Instant::now() + Duration::from_secs(ERROR_RETRY_DELAY_SEC);
+                }
 
-            // Create [`Event::Withdrawal`]s based on the `UtxoDBs` diff between the last and current states.
-            for withdrawal in withdrawn {
-                let event: Event = Event::Withdrawal(EventParams {
-                    address: address.clone(),
-                    utxo: withdrawal,
-                    height: current_chain_tip,
-                });
-                events.push(event);
+                events
             }
-        }
-        debug!("events = {:#?}", events);
+        };
+        current_chain_tip = match config.detection {
+            Detection::Scan => block_scan.synced_height(),
+            Detection::Utxo | Detection::History => new_chain_tip,
+        };
+        resync = false;
 
-        for event in &events {
-            match event {
-                Event::Deposit(_) | Event::Withdrawal(_) => {
-                    if let Err(e) = handle_event(config, event) {
-                        warn!("Failed to handle event: {e}");
-                    }
-                }
-                _ => unreachable!(),
+        // Once the state on the new chain is known, report what the reorg changed.
+        let events = match pending_reorg.take() {
+            Some(pending) if current_chain_tip >= new_chain_tip => {
+                pending.finish(chain.as_ref(), &watchlist, &current_state, events, false)
+            }
+            Some(mut pending) => {
+                pending.defer(events);
+                pending_reorg = Some(pending);
+                Vec::new()
             }
+            None => events,
+        };
+        let events = confirmation_watch.update(chain.as_ref(), &current_state, current_chain_tip, events);
+        debug!("events = {:#?}", events);
+
+        handle_events(config, &outbox, chain.as_ref(), events);
+
+        if let Some(path) = state_path {
+            persist_state(
+                chain.as_ref(),
+                &chain_tracker,
+                &watchlist,
+                &confirmation_watch,
+                path,
+                current_chain_tip,
+                &current_state,
+            );
+        }
+        record_poll(&status, current_chain_tip, &current_state);
+    }
+
+    // The mempool activity handled since the last round is kept too.
+    if let Some(path) = state_path {
+        persist_state(
+            chain.as_ref(),
+            &chain_tracker,
+            &watchlist,
+            &confirmation_watch,
+            path,
+            current_chain_tip,
+            &current_state,
+        );
+    }
+    outbox.close(Duration::from_secs(SHUTDOWN_DELIVERY_TIMEOUT_SEC));
+    info!("Shut down");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, slice, str::FromStr};
+
+    use bitcoin::{
+        Amount, BlockHash, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, absolute::LockTime,
+        hashes::Hash, transaction,
+    };
+    use esplora_client::{AddressStats, AddressTxsSummary, UtxoStatus};
+
+    use super::*;
+    use crate::chain::tx_from_transaction;
+
+    fn utxo(vout: u32, block_height: Option<u32>) -> Utxo {
+        Utxo {
+            txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            vout,
+            status: UtxoStatus {
+                confirmed: block_height.is_some(),
+                block_height,
+                block_hash: block_height.map(|_| BlockHash::all_zeros()),
+                block_time: block_height.map(|_| 1757566235),
+            },
+            value: Amount::from_sat(1337),
+        }
+    }
+
+    fn withdrawal(vout: u32, spending_txid: Option<Txid>) -> Event {
+        let address = Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap();
+
+        Event::Withdrawal(EventParams {
+            address,
+            origin: None,
+            utxo: utxo(vout, Some(101595)),
+            height: 101597,
+            after_restart: false,
+            spend: spending_txid.map(|txid| Spend {
+                txid,
+                status: TxStatus {
+                    confirmed: true,
+                    block_height: Some(101597),
+                    block_hash: None,
+                    block_time: None,
+                },
+                outputs: Vec::new(),
+                fee: Some(Amount::from_sat(1000)),
+                weight: Weight::from_wu(800),
+                rbf: true,
+            }),
+        })
+    }
+
+    #[test]
+    fn aggregate_withdrawals_by_spending_transaction() {
+        let sweep = Txid::from_str("9e8b5c7b468dab43ae55f66d977819102c3bb129825cc6bddb54f45fafb7ae33").unwrap();
+        let other = Txid::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        let events = (0..200)
+            .map(|vout| withdrawal(vout, Some(sweep)))
+            .chain([withdrawal(200, Some(other))]);
+
+        let aggregated = aggregate_events(events.collect());
+        assert_eq!(aggregated.len(), 2);
+
+        let Event::Transaction(tx_params) = &aggregated[0] else {
+            panic!("expected a transaction, got {:?}", aggregated[0]);
+        };
+        assert_eq!(tx_params.txid, Some(sweep));
+        assert_eq!(tx_params.withdrawals.len(), 200);
+        assert_eq!(tx_params.total_withdrawn(), Amount::from_sat(200 * 1337));
+        assert_eq!(tx_params.net_flows()[0].1, SignedAmount::from_sat(-200 * 1337));
+        assert!(matches!(&aggregated[1], Event::Withdrawal(_)));
+    }
+
+    #[test]
+    fn confirmation_is_not_a_withdrawal() {
+        let last_state = vec![utxo(0, None), utxo(1, Some(101595))];
+        let current_state = vec![utxo(0, Some(101597)), utxo(1, Some(101595))];
+
+        let diff = compute_diff(&current_state, &last_state);
+        assert!(diff.deposited.is_empty());
+        assert!(diff.withdrawn.is_empty());
+        assert_eq!(diff.confirmed, vec![utxo(0, Some(101597))]);
+    }
+
+    #[test]
+    fn diff_by_outpoint() {
+        let last_state = vec![utxo(0, Some(101595)), utxo(1, Some(101595))];
+        let current_state = vec![utxo(1, Some(101595)), utxo(2, None)];
+
+        let diff = compute_diff(&current_state, &last_state);
+        assert_eq!(diff.deposited, vec![utxo(2, None)]);
+        assert_eq!(diff.withdrawn, vec![utxo(0, Some(101595))]);
+        assert!(diff.confirmed.is_empty());
+    }
+
+    /// A chain source serving the UTXOs and statuses of addresses, and the transactions spending outputs, recording
+    /// the addresses whose UTXOs were fetched.
+    #[derive(Default)]
+    struct FakeChain {
+        utxos: UtxoDB,
+        statuses: HashMap<Address, AddressStatus>,
+        spends: HashMap<OutPoint, Tx>,
+        fetched: RefCell<Vec<Address>>,
+    }
+
+    impl ChainSource for FakeChain {
+        fn height(&self) -> Result<u32, ChainError> {
+            Ok(0)
+        }
+
+        fn block_hash(&self, _: u32) -> Result<BlockHash, ChainError> {
+            Ok(BlockHash::all_zeros())
+        }
+
+        fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+            self.fetched.borrow_mut().push(address.clone());
+            Ok(self.utxos.get(address).cloned().unwrap_or_default())
+        }
+
+        fn addresses_status(&self, addresses: &[&Address]) -> Result<Vec<Option<AddressStatus>>, ChainError> {
+            Ok(addresses
+                .iter()
+                .map(|address| self.statuses.get(*address).cloned())
+                .collect())
+        }
+
+        fn address_txs(&self, _: &Address, _: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn mempool_address_txs(&self, _: &Address) -> Result<Vec<Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn spending_txid(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, ChainError> {
+            Ok(self.spends.get(&OutPoint::new(*txid, vout)).map(|tx| tx.txid))
+        }
+
+        fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError> {
+            Ok(self.spends.values().find(|tx| tx.txid == *txid).cloned())
         }
+    }
+
+    /// The Esplora stats of an address that received `funded` sats, and never spent them.
+    fn stats(address: &Address, funded: u64) -> AddressStatus {
+        let summary = |funded_txo_sum| AddressTxsSummary {
+            funded_txo_count: 1,
+            funded_txo_sum,
+            spent_txo_count: 0,
+            spent_txo_sum: 0,
+            tx_count: 1,
+        };
+        AddressStatus::Esplora(vec![AddressStats {
+            address: address.to_string(),
+            chain_stats: summary(funded),
+            mempool_stats: summary(0),
+        }])
+    }
+
+    #[test]
+    fn only_changed_addresses_are_fetched_again() {
+        let electrum = Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap();
+        let esplora = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap();
+        let config: Config = toml::from_str(&format!(
+            "network = \"testnet4\"\naddresses = [\"{electrum}\", \"{esplora}\"]\nnotify_subscriptions = false\nnotify_deposits = true"
+        ))
+        .unwrap();
+        let mut watchlist = WatchList::from_config(&config).unwrap();
+        let mut chain = FakeChain {
+            utxos: UtxoDB::from([
+                (electrum.clone(), vec![utxo(0, Some(100))]),
+                (esplora.clone(), vec![utxo(1, Some(100))]),
+            ]),
+            statuses: HashMap::from([
+                (electrum.clone(), AddressStatus::Electrum(Some("a".repeat(64)))),
+                (esplora.clone(), stats(&esplora, 1337)),
+            ]),
+            ..FakeChain::default()
+        };
+        let mut statuses = HashMap::new();
+        let mut fetch = |chain: &FakeChain| {
+            chain.fetched.borrow_mut().clear();
+            let db = fetch_changes(chain, &mut watchlist, &mut statuses).unwrap();
+            let mut fetched = chain.fetched.borrow().clone();
+            fetched.sort();
+            assert_eq!(db.keys().count(), fetched.len());
+            fetched
+        };
+
+        assert_eq!(fetch(&chain).len(), 2);
+        assert!(fetch(&chain).is_empty());
+
+        chain
+            .statuses
+            .insert(electrum.clone(), AddressStatus::Electrum(Some("b".repeat(64))));
+        assert_eq!(fetch(&chain), slice::from_ref(&electrum));
+        assert!(fetch(&chain).is_empty());
+
+        // The stats count a deposit the UTXOs were fetched without: they are fetched again until they match.
+        chain.statuses.insert(esplora.clone(), stats(&esplora, 2 * 1337));
+        assert_eq!(fetch(&chain), slice::from_ref(&esplora));
+        assert_eq!(fetch(&chain), slice::from_ref(&esplora));
+        chain
+            .utxos
+            .insert(esplora.clone(), vec![utxo(1, Some(100)), utxo(2, Some(101))]);
+        assert_eq!(fetch(&chain), slice::from_ref(&esplora));
+        assert!(fetch(&chain).is_empty());
+    }
+
+    #[test]
+    fn emptied_addresses_still_count_as_used() {
+        let config: Config = toml::from_str(
+            "network = \"bitcoin\"\ndescriptors = [\"zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs\"]\ngap_limit = 2\nnotify_subscriptions = false\nnotify_deposits = true",
+        )
+        .unwrap();
+        let mut watchlist = WatchList::from_config(&config).unwrap();
+        assert_eq!(watchlist.addresses().len(), 4);
+
+        // The second receive address was used and emptied: it holds no UTXO, but has a history.
+        let emptied = watchlist.addresses()[1].clone();
+        let chain = FakeChain {
+            statuses: HashMap::from([(emptied.clone(), AddressStatus::Electrum(Some("a".repeat(64))))]),
+            ..FakeChain::default()
+        };
+
+        let db = fetch_state(&chain, &mut watchlist).unwrap();
+        assert_eq!(db.len(), 6);
+        assert_eq!(watchlist.addresses().len(), 6);
+        assert_eq!(watchlist.origin(&watchlist.addresses()[5]).unwrap().index, 3);
+        assert_eq!(watchlist.used(), vec![emptied]);
+    }
+
+    #[test]
+    fn withdrawals_are_enriched_with_their_spending_transaction() {
+        let spent = utxo(0, Some(101595));
+        let destination = ScriptBuf::new_op_return([0u8; 4]);
+        let transaction = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(spent.txid, spent.vout),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..TxIn::default()
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(1000),
+                    script_pubkey: destination.clone(),
+                },
+                TxOut {
+                    value: Amount::from_sat(137),
+                    script_pubkey: ScriptBuf::new(),
+                },
+            ],
+        };
+        let prevout = TxOut {
+            value: spent.value,
+            script_pubkey: ScriptBuf::new(),
+        };
+        let status = TxStatus {
+            confirmed: false,
+            block_height: None,
+            block_hash: None,
+            block_time: None,
+        };
+        let sweep = tx_from_transaction(&transaction, vec![Some(prevout)], status);
+        let chain = FakeChain {
+            spends: HashMap::from([(OutPoint::new(spent.txid, spent.vout), sweep.clone())]),
+            ..FakeChain::default()
+        };
+
+        // The first withdrawal is looked up, the second one is not spent as far as the chain source knows, and the
+        // third one already knows its spend.
+        let known = Txid::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        let mut events = vec![withdrawal(0, None), withdrawal(1, None), withdrawal(2, Some(known))];
+        resolve_spends(&chain, &mut events);
 
-        thread::sleep(Duration::from_secs(POLLING_PERIOD_SEC));
+        let spends: Vec<Option<&Spend>> = events
+            .iter()
+            .map(|event| match event {
+                Event::Withdrawal(event_params) => event_params.spend.as_ref(),
+                _ => panic!("expected a withdrawal, got {event:?}"),
+            })
+            .collect();
+        let spend = spends[0].expect("the spending transaction is found");
+        assert_eq!(spend.txid, sweep.txid);
+        assert!(!spend.status.confirmed);
+        assert_eq!(spend.outputs, transaction.output);
+        assert_eq!(spend.outputs[0].script_pubkey, destination);
+        assert_eq!(spend.fee, Some(Amount::from_sat(200)));
+        assert_eq!(spend.weight, transaction.weight());
+        assert_eq!(spend.fee_rate(), Some(200.0 / transaction.vsize() as f64));
+        assert!(spend.rbf);
+        assert!(spends[1].is_none());
+        assert_eq!(spends[2].map(|spend| spend.txid), Some(known));
     }
 }
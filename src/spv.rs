@@ -0,0 +1,980 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use bitcoin::{
+    Address, Block, BlockHash, CompactTarget, Network, OutPoint, Target, TxMerkleNode, TxOut, Txid,
+    bip158::BlockFilter,
+    block::Header,
+    consensus::{
+        Params,
+        encode::{self, deserialize_hex, serialize_hex},
+    },
+    constants::genesis_block,
+    hashes::{Hash, HashEngine, sha256d},
+};
+use esplora_client::{MerkleProof, Tx, Utxo};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::chain::{AddressStatus, ChainError, ChainSource};
+use crate::smaug::{Event, VerificationFailureParams};
+use crate::state::write_atomically;
+
+/// The 4th halving block, where the mainnet header chain starts unless configured otherwise.
+const BITCOIN_CHECKPOINT: (u32, &str) = (
+    840_000,
+    "0000000000000000000320283a032748cef8227873ff4872689bf23f1cda83a5",
+);
+
+/// Errors that happen while verifying the answers of a chain source.
+#[derive(Debug, Error)]
+pub enum SpvError {
+    /// Only one of `checkpoint_height` and `checkpoint_hash` is set.
+    #[error("`checkpoint_height` and `checkpoint_hash` must be set together in `[spv]`")]
+    PartialCheckpoint,
+
+    /// The chain source served another header for the checkpoint.
+    #[error("the chain source served another header for checkpoint {0}")]
+    Checkpoint(BlockHash),
+
+    /// The chain of the chain source does not connect to the header chain above the checkpoint.
+    #[error("the chain of the chain source does not include the checkpoint at height {0}")]
+    Unconnected(u32),
+
+    /// The headers of the chain source do not lead to the tip it claims.
+    #[error("the headers of the chain source do not lead to its tip at height {0}")]
+    InconsistentTip(u32),
+
+    /// A header does not check out.
+    #[error("header {0} has {1}")]
+    InvalidHeader(BlockHash, &'static str),
+
+    /// A block does not check out.
+    #[error("block {0} {1}")]
+    InvalidBlock(u32, &'static str),
+
+    /// A transaction does not check out.
+    #[error("transaction {0} {1}")]
+    InvalidTx(Txid, &'static str),
+
+    /// Error reading or writing the header store.
+    #[error("header store `{0}`: {1}")]
+    StoreIo(PathBuf, #[source] io::Error),
+
+    /// Error (de)serializing the header store.
+    #[error("header store `{0}`: {1}")]
+    StoreJson(PathBuf, #[source] serde_json::Error),
+
+    /// Error decoding a header from the header store.
+    #[error("header store `{0}`: {1}")]
+    StoreHeader(PathBuf, #[source] encode::FromHexError),
+
+    /// The header store was written for a different network.
+    #[error("header store `{0}` was written for network `{1}`")]
+    StoreNetwork(PathBuf, Network),
+
+    /// The header store starts from a different checkpoint.
+    #[error("header store `{0}` starts from another checkpoint, at height {1}")]
+    StoreCheckpoint(PathBuf, u32),
+}
+
+impl SpvError {
+    /// Whether the chain source served something that does not check out, rather than the header store failing.
+    fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            SpvError::Checkpoint(_)
+                | SpvError::Unconnected(_)
+                | SpvError::InconsistentTip(_)
+                | SpvError::InvalidHeader(..)
+                | SpvError::InvalidBlock(..)
+                | SpvError::InvalidTx(..)
+        )
+    }
+}
+
+/// SPV verification parameters.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SpvConfig {
+    /// Where to keep the header chain across restarts.
+    #[serde(default = "default_header_store")]
+    pub(crate) header_store: String,
+    /// The height of the block the header chain starts from.
+    /// The 4th halving block on mainnet and the genesis block elsewhere, if left empty.
+    #[serde(default)]
+    pub(crate) checkpoint_height: Option<u32>,
+    /// The hash of the block at `checkpoint_height`.
+    #[serde(default)]
+    pub(crate) checkpoint_hash: Option<BlockHash>,
+}
+
+fn default_header_store() -> String {
+    String::from("smaug-spv-headers.json")
+}
+
+/// The on-disk representation of [`HeaderChain`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredHeaderChain {
+    network: Network,
+    base_height: u32,
+    /// The consensus encoding of every header, in hex.
+    headers: Vec<String>,
+}
+
+/// The best header chain known from the checkpoint up, with every header checked for proof of work and difficulty.
+#[derive(Debug)]
+struct HeaderChain {
+    params: Params,
+    /// The height of the checkpoint, the first header.
+    base_height: u32,
+    headers: Vec<Header>,
+    heights: HashMap<BlockHash, u32>,
+}
+
+impl HeaderChain {
+    /// Start a header chain from the `checkpoint` header at `base_height`.
+    fn new(network: Network, base_height: u32, checkpoint: Header) -> HeaderChain {
+        HeaderChain {
+            params: Params::new(network),
+            base_height,
+            heights: HashMap::from([(checkpoint.block_hash(), base_height)]),
+            headers: vec![checkpoint],
+        }
+    }
+
+    /// Load the header chain from `path`, if it exists.
+    fn load(path: &Path, network: Network) -> Result<Option<HeaderChain>, SpvError> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(SpvError::StoreIo(path.to_path_buf(), e)),
+        };
+        let stored: StoredHeaderChain =
+            serde_json::from_str(&json).map_err(|e| SpvError::StoreJson(path.to_path_buf(), e))?;
+        if stored.network != network || stored.headers.is_empty() {
+            return Err(SpvError::StoreNetwork(path.to_path_buf(), stored.network));
+        }
+
+        let headers = stored
+            .headers
+            .iter()
+            .map(|header| deserialize_hex(header))
+            .collect::<Result<Vec<Header>, _>>()
+            .map_err(|e| SpvError::StoreHeader(path.to_path_buf(), e))?;
+        let heights = headers
+            .iter()
+            .zip(stored.base_height..)
+            .map(|(header, height)| (header.block_hash(), height))
+            .collect();
+
+        Ok(Some(HeaderChain {
+            params: Params::new(network),
+            base_height: stored.base_height,
+            headers,
+            heights,
+        }))
+    }
+
+    /// Save the header chain to `path`, through a temporary file.
+    fn save(&self, path: &Path) -> Result<(), SpvError> {
+        let stored = StoredHeaderChain {
+            network: self.params.network,
+            base_height: self.base_height,
+            headers: self.headers.iter().map(serialize_hex).collect(),
+        };
+        let json = serde_json::to_string(&stored).map_err(|e| SpvError::StoreJson(path.to_path_buf(), e))?;
+
+        write_atomically(path, json.as_bytes()).map_err(|e| SpvError::StoreIo(path.to_path_buf(), e))
+    }
+
+    fn tip_height(&self) -> u32 {
+        self.base_height + self.headers.len() as u32 - 1
+    }
+
+    fn header(&self, height: u32) -> Option<&Header> {
+        let index = height.checked_sub(self.base_height)?;
+        self.headers.get(index as usize)
+    }
+
+    fn block_hash(&self, height: u32) -> Option<BlockHash> {
+        self.header(height).map(Header::block_hash)
+    }
+
+    /// The bits of the last header that is not a minimum difficulty one, or that starts a difficulty period.
+    fn last_regular_bits(&self) -> CompactTarget {
+        let interval = self.params.difficulty_adjustment_interval() as u32;
+        let min_difficulty = self.params.max_attainable_target.to_compact_lossy();
+
+        self.headers
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(index, header)| {
+                header.bits != min_difficulty || (self.base_height + *index as u32).is_multiple_of(interval)
+            })
+            .map_or(self.headers[0].bits, |(_, header)| header.bits)
+    }
+
+    /// Whether `header`, following the tip, commits to the difficulty target the consensus rules expect.
+    ///
+    /// The exact retarget is only checked once a whole difficulty period is known, and on networks without minimum
+    /// difficulty blocks: otherwise the new target only has to be within a factor of 4 of the previous one.
+    fn has_expected_target(&self, header: &Header) -> bool {
+        let params = &self.params;
+        let prev = &self.headers[self.headers.len() - 1];
+        let height = self.tip_height() + 1;
+        let interval = params.difficulty_adjustment_interval() as u32;
+
+        if params.no_pow_retargeting {
+            return header.bits == prev.bits;
+        }
+
+        if !height.is_multiple_of(interval) {
+            return match params.allow_min_difficulty_blocks {
+                // Test networks allow a minimum difficulty block once twice the target spacing passed.
+                true => {
+                    header.bits == self.last_regular_bits()
+                        || (header.bits == params.max_attainable_target.to_compact_lossy()
+                            && u64::from(header.time) > u64::from(prev.time) + 2 * params.pow_target_spacing)
+                }
+                false => header.bits == prev.bits,
+            };
+        }
+
+        match self.header(height - interval) {
+            Some(first) if !params.allow_min_difficulty_blocks => {
+                header.bits == CompactTarget::from_header_difficulty_adjustment(*first, *prev, params)
+            }
+            _ => [self.last_regular_bits(), prev.bits].into_iter().any(|bits| {
+                let last = Target::from_compact(bits);
+                header.target() <= last.max_transition_threshold(params)
+                    && header.target() >= last.min_transition_threshold()
+            }),
+        }
+    }
+
+    /// Check `header` and append it to the chain.
+    fn push(&mut self, header: Header) -> Result<(), SpvError> {
+        let block_hash = header.block_hash();
+        if header.prev_blockhash != self.headers[self.headers.len() - 1].block_hash() {
+            return Err(SpvError::InvalidHeader(
+                block_hash,
+                "a previous block that is not the tip",
+            ));
+        }
+        if header.target() > self.params.max_attainable_target {
+            return Err(SpvError::InvalidHeader(
+                block_hash,
+                "a target above the proof of work limit",
+            ));
+        }
+        if header.validate_pow(header.target()).is_err() {
+            return Err(SpvError::InvalidHeader(block_hash, "an invalid proof of work"));
+        }
+        if !self.has_expected_target(&header) {
+            return Err(SpvError::InvalidHeader(block_hash, "an unexpected difficulty target"));
+        }
+
+        self.heights.insert(block_hash, self.tip_height() + 1);
+        self.headers.push(header);
+
+        Ok(())
+    }
+
+    /// Replace the headers above `fork_height` with `branch` if it has more work, returning whether it did.
+    fn reorganize(&mut self, fork_height: u32, branch: Vec<Header>) -> Result<bool, SpvError> {
+        let keep = (fork_height - self.base_height + 1) as usize;
+        let work = |headers: &[Header]| headers.iter().map(Header::work).reduce(|total, work| total + work);
+        if work(&branch) <= work(&self.headers[keep..]) {
+            return Ok(false);
+        }
+
+        let replaced = self.headers.split_off(keep);
+        for header in &replaced {
+            self.heights.remove(&header.block_hash());
+        }
+        for header in branch {
+            if let Err(e) = self.push(header) {
+                // Restore the replaced headers, which were checked already.
+                for header in self.headers.drain(keep..) {
+                    self.heights.remove(&header.block_hash());
+                }
+                for (header, height) in replaced.into_iter().zip(fork_height + 1..) {
+                    self.heights.insert(header.block_hash(), height);
+                    self.headers.push(header);
+                }
+                return Err(e);
+            }
+        }
+
+        if !replaced.is_empty() {
+            info!("Reorg of {} blocks above height {fork_height}", replaced.len());
+        }
+
+        Ok(true)
+    }
+}
+
+/// The merkle root `proof` leads to from `txid`, if the proof is well-formed.
+fn merkle_root(txid: Txid, proof: &MerkleProof) -> Option<TxMerkleNode> {
+    if proof.merkle.len() >= usize::BITS as usize || proof.pos >> proof.merkle.len() != 0 {
+        return None;
+    }
+
+    let mut hash = txid.to_raw_hash();
+    for (depth, sibling) in proof.merkle.iter().enumerate() {
+        let mut engine = sha256d::Hash::engine();
+        match (proof.pos >> depth) & 1 {
+            0 => {
+                engine.input(hash.as_byte_array());
+                engine.input(sibling.as_byte_array());
+            }
+            _ => {
+                engine.input(sibling.as_byte_array());
+                engine.input(hash.as_byte_array());
+            }
+        }
+        hash = sha256d::Hash::from_engine(engine);
+    }
+
+    Some(TxMerkleNode::from_raw_hash(hash))
+}
+
+/// What [`SpvVerifier`] keeps across requests.
+#[derive(Debug)]
+struct SpvState {
+    headers: HeaderChain,
+    /// The transactions whose inclusion in a block of the header chain was verified.
+    verified_txs: HashSet<(Txid, BlockHash)>,
+    /// The outputs of the transactions verified to hash to their txid.
+    verified_outputs: HashMap<OutPoint, TxOut>,
+    /// The verification failures notified already.
+    reported: HashSet<String>,
+    /// The events not taken yet.
+    events: Vec<Event>,
+}
+
+impl SpvState {
+    /// Raise an [`Event::VerificationFailure`] the first time `result` fails verification a given way.
+    fn report<T>(&mut self, result: Result<T, ChainError>) -> Result<T, ChainError> {
+        if let Err(ChainError::Spv(e)) = &result
+            && e.is_failure()
+            && self.reported.insert(e.to_string())
+        {
+            self.events.push(Event::VerificationFailure(VerificationFailureParams {
+                txid: match e {
+                    SpvError::InvalidTx(txid, _) => Some(*txid),
+                    _ => None,
+                },
+                reason: e.to_string(),
+            }));
+        }
+
+        result
+    }
+}
+
+/// A [`ChainSource`] checking the answers of another one against a header chain, like an SPV client.
+///
+/// Headers are downloaded from the inner chain source and checked for proof of work and difficulty from a checkpoint
+/// up. Every confirmed UTXO and transaction is then checked against its merkle proof and the header at its height,
+/// and blocks against their header. Anything confirmed below the checkpoint cannot be checked, and is trusted.
+pub(crate) struct SpvVerifier {
+    inner: Box<dyn ChainSource>,
+    header_store: PathBuf,
+    state: Mutex<SpvState>,
+}
+
+impl SpvVerifier {
+    /// Wrap `inner`, resuming from the header store if it exists, or starting from the checkpoint.
+    pub(crate) fn new(
+        inner: Box<dyn ChainSource>,
+        network: Network,
+        config: &SpvConfig,
+    ) -> Result<SpvVerifier, ChainError> {
+        let (checkpoint_height, checkpoint_hash) = match (config.checkpoint_height, config.checkpoint_hash) {
+            (Some(height), Some(block_hash)) => (height, block_hash),
+            (None, None) => match network {
+                Network::Bitcoin => (
+                    BITCOIN_CHECKPOINT.0,
+                    BlockHash::from_str(BITCOIN_CHECKPOINT.1).expect("valid checkpoint hash"),
+                ),
+                _ => (0, genesis_block(network).block_hash()),
+            },
+            _ => return Err(SpvError::PartialCheckpoint.into()),
+        };
+
+        let header_store = PathBuf::from(&config.header_store);
+        let headers = match HeaderChain::load(&header_store, network)? {
+            Some(headers) => {
+                if headers.base_height != checkpoint_height || headers.headers[0].block_hash() != checkpoint_hash {
+                    return Err(SpvError::StoreCheckpoint(header_store, headers.base_height).into());
+                }
+                info!(
+                    "Loaded headers up to height {} from `{}`",
+                    headers.tip_height(),
+                    header_store.display()
+                );
+                headers
+            }
+            None => {
+                let checkpoint = inner.header(&checkpoint_hash)?;
+                if checkpoint.block_hash() != checkpoint_hash {
+                    return Err(SpvError::Checkpoint(checkpoint_hash).into());
+                }
+                HeaderChain::new(network, checkpoint_height, checkpoint)
+            }
+        };
+
+        Ok(SpvVerifier {
+            inner,
+            header_store,
+            state: Mutex::new(SpvState {
+                headers,
+                verified_txs: HashSet::new(),
+                verified_outputs: HashMap::new(),
+                reported: HashSet::new(),
+                events: Vec::new(),
+            }),
+        })
+    }
+
+    /// The height the header chain starts from.
+    pub(crate) fn checkpoint_height(&self) -> u32 {
+        self.lock().headers.base_height
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SpvState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sync the header chain with the chain of the chain source, whose tip is at `height`.
+    ///
+    /// Headers are downloaded back from the tip until they connect to the header chain, and only replace its tip if
+    /// they have more work.
+    fn sync(&self, state: &mut SpvState, height: u32) -> Result<(), ChainError> {
+        let headers = &mut state.headers;
+        let mut block_hash = self.inner.block_hash(height)?;
+        let mut branch = Vec::new();
+
+        let fork_height = loop {
+            if let Some(&fork_height) = headers.heights.get(&block_hash) {
+                break fork_height;
+            }
+            if branch.len() as u32 >= height.saturating_sub(headers.base_height) {
+                return Err(SpvError::Unconnected(headers.base_height).into());
+            }
+
+            let header = self.inner.header(&block_hash)?;
+            if header.block_hash() != block_hash {
+                return Err(SpvError::InvalidHeader(block_hash, "a hash that does not match its content").into());
+            }
+            block_hash = header.prev_blockhash;
+            branch.push(header);
+            if branch.len() % 10_000 == 0 {
+                info!("Downloaded {} headers...", branch.len());
+            }
+        };
+        if fork_height + branch.len() as u32 != height {
+            return Err(SpvError::InconsistentTip(height).into());
+        }
+
+        branch.reverse();
+        if headers.reorganize(fork_height, branch)? {
+            debug!("Synced headers up to height {}", headers.tip_height());
+            if let Err(e) = headers.save(&self.header_store) {
+                warn!("Failed to save headers: {e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The hash of the header chain block at `height`, syncing it first if `height` is above its tip.
+    ///
+    /// Returns `None` below the checkpoint.
+    fn verified_block_hash(&self, state: &mut SpvState, height: u32) -> Result<Option<BlockHash>, ChainError> {
+        if height < state.headers.base_height {
+            return Ok(None);
+        }
+        if height > state.headers.tip_height() {
+            let tip_height = self.inner.height()?;
+            self.sync(state, tip_height)?;
+        }
+
+        Ok(state.headers.block_hash(height))
+    }
+
+    /// Check that the transaction `txid` is in the header chain block at `height`, per its merkle proof.
+    ///
+    /// Returns the hash of that block, or `None` below the checkpoint.
+    fn verify_inclusion(&self, state: &mut SpvState, txid: Txid, height: u32) -> Result<Option<BlockHash>, ChainError> {
+        if height < state.headers.base_height {
+            debug!("Transaction {txid} is confirmed below the checkpoint and cannot be verified");
+            return Ok(None);
+        }
+        let block_hash = self
+            .verified_block_hash(state, height)?
+            .ok_or(SpvError::InvalidTx(txid, "is confirmed above the chain tip"))?;
+        if state.verified_txs.contains(&(txid, block_hash)) {
+            return Ok(Some(block_hash));
+        }
+
+        let proof = self
+            .inner
+            .merkle_proof(&txid)?
+            .ok_or(SpvError::InvalidTx(txid, "has no merkle proof"))?;
+        if proof.block_height != height {
+            return Err(SpvError::InvalidTx(txid, "has a merkle proof for another height").into());
+        }
+        let header = state.headers.header(height).expect("the block hash is known");
+        if merkle_root(txid, &proof) != Some(header.merkle_root) {
+            return Err(SpvError::InvalidTx(txid, "is not in the block at its height").into());
+        }
+
+        state.verified_txs.insert((txid, block_hash));
+        Ok(Some(block_hash))
+    }
+
+    /// Check that `tx` hashes to its txid and, if it is confirmed, that it is in the block it claims.
+    fn verify_tx(&self, state: &mut SpvState, tx: &Tx) -> Result<(), ChainError> {
+        if tx.to_tx().compute_txid() != tx.txid {
+            return Err(SpvError::InvalidTx(tx.txid, "does not hash to its txid").into());
+        }
+        let (true, Some(height)) = (tx.status.confirmed, tx.status.block_height) else {
+            return Ok(());
+        };
+
+        let block_hash = self.verify_inclusion(state, tx.txid, height)?;
+        if let (Some(block_hash), Some(claimed)) = (block_hash, tx.status.block_hash)
+            && block_hash != claimed
+        {
+            return Err(SpvError::InvalidTx(tx.txid, "claims a block that is not in the header chain").into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the confirmed `utxo` is created by a transaction in the block it claims, locked to `address`.
+    fn verify_utxo(&self, state: &mut SpvState, address: &Address, utxo: &Utxo) -> Result<(), ChainError> {
+        let (true, Some(height)) = (utxo.status.confirmed, utxo.status.block_height) else {
+            return Ok(());
+        };
+        let Some(block_hash) = self.verify_inclusion(state, utxo.txid, height)? else {
+            return Ok(());
+        };
+        if utxo.status.block_hash.is_some_and(|claimed| claimed != block_hash) {
+            return Err(SpvError::InvalidTx(utxo.txid, "claims a block that is not in the header chain").into());
+        }
+
+        let outpoint = OutPoint::new(utxo.txid, utxo.vout);
+        let output = match state.verified_outputs.get(&outpoint) {
+            Some(output) => output,
+            None => {
+                let tx = self
+                    .inner
+                    .tx(&utxo.txid)?
+                    .ok_or(SpvError::InvalidTx(utxo.txid, "is not served by the chain source"))?
+                    .to_tx();
+                if tx.compute_txid() != utxo.txid {
+                    return Err(SpvError::InvalidTx(utxo.txid, "does not hash to its txid").into());
+                }
+                let output = tx
+                    .output
+                    .into_iter()
+                    .nth(utxo.vout as usize)
+                    .ok_or(SpvError::InvalidTx(utxo.txid, "does not create the claimed output"))?;
+                state.verified_outputs.entry(outpoint).or_insert(output)
+            }
+        };
+        if output.value != utxo.value || output.script_pubkey != address.script_pubkey() {
+            return Err(SpvError::InvalidTx(utxo.txid, "does not create the claimed output").into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that `block` is the header chain block at `height`, and matches its merkle root.
+    fn verify_block(&self, state: &mut SpvState, height: u32, block: &Block) -> Result<(), ChainError> {
+        if let Some(block_hash) = self.verified_block_hash(state, height)?
+            && block.block_hash() != block_hash
+        {
+            return Err(SpvError::InvalidBlock(height, "is not the header chain block at its height").into());
+        }
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return Err(SpvError::InvalidBlock(height, "does not match its merkle root").into());
+        }
+
+        Ok(())
+    }
+}
+
+impl ChainSource for SpvVerifier {
+    fn watch(&self, addresses: &[Address]) -> Result<(), ChainError> {
+        self.inner.watch(addresses)
+    }
+
+    fn wait_for_changes(&self, timeout: Duration) -> bool {
+        self.inner.wait_for_changes(timeout)
+    }
+
+    fn height(&self) -> Result<u32, ChainError> {
+        let height = self.inner.height()?;
+        let mut state = self.lock();
+        let result = self.sync(&mut state, height);
+        state.report(result)?;
+
+        Ok(state.headers.tip_height())
+    }
+
+    fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+        let mut state = self.lock();
+        let result = self.verified_block_hash(&mut state, height);
+        match state.report(result)? {
+            Some(block_hash) => Ok(block_hash),
+            None => self.inner.block_hash(height),
+        }
+    }
+
+    fn header(&self, block_hash: &BlockHash) -> Result<Header, ChainError> {
+        self.inner.header(block_hash)
+    }
+
+    fn block(&self, height: u32) -> Result<Block, ChainError> {
+        let block = self.inner.block(height)?;
+        let mut state = self.lock();
+        let result = self.verify_block(&mut state, height, &block);
+        state.report(result)?;
+
+        Ok(block)
+    }
+
+    fn block_filter(&self, height: u32) -> Result<Option<BlockFilter>, ChainError> {
+        self.inner.block_filter(height)
+    }
+
+    fn address_utxos(&self, address: &Address) -> Result<Vec<Utxo>, ChainError> {
+        let utxos = self.inner.address_utxos(address)?;
+        let mut state = self.lock();
+        for utxo in &utxos {
+            let result = self.verify_utxo(&mut state, address, utxo);
+            state.report(result)?;
+        }
+
+        Ok(utxos)
+    }
+
+    fn addresses_utxos(&self, addresses: &[&Address]) -> Result<Vec<Vec<Utxo>>, ChainError> {
+        let utxos = self.inner.addresses_utxos(addresses)?;
+        let mut state = self.lock();
+        for (address, utxos) in addresses.iter().zip(&utxos) {
+            for utxo in utxos {
+                let result = self.verify_utxo(&mut state, address, utxo);
+                state.report(result)?;
+            }
+        }
+
+        Ok(utxos)
+    }
+
+    fn address_txs(&self, address: &Address, last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+        let txs = self.inner.address_txs(address, last_seen)?;
+        let mut state = self.lock();
+        for tx in &txs {
+            let result = self.verify_tx(&mut state, tx);
+            state.report(result)?;
+        }
+
+        Ok(txs)
+    }
+
+    fn addresses_txs(&self, addresses: &[&Address]) -> Result<Vec<Vec<Tx>>, ChainError> {
+        let txs = self.inner.addresses_txs(addresses)?;
+        let mut state = self.lock();
+        for tx in txs.iter().flatten() {
+            let result = self.verify_tx(&mut state, tx);
+            state.report(result)?;
+        }
+
+        Ok(txs)
+    }
+
+    fn mempool_address_txs(&self, address: &Address) -> Result<Vec<Tx>, ChainError> {
+        self.inner.mempool_address_txs(address)
+    }
+
+    fn addresses_mempool_txs(&self, addresses: &[&Address]) -> Result<Vec<Vec<Tx>>, ChainError> {
+        self.inner.addresses_mempool_txs(addresses)
+    }
+
+    fn addresses_status(&self, addresses: &[&Address]) -> Result<Vec<Option<AddressStatus>>, ChainError> {
+        // Statuses only decide which UTXOs are fetched again, and those are still verified.
+        self.inner.addresses_status(addresses)
+    }
+
+    fn spending_txid(&self, txid: &Txid, vout: u32) -> Result<Option<Txid>, ChainError> {
+        self.inner.spending_txid(txid, vout)
+    }
+
+    fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError> {
+        let tx = self.inner.tx(txid)?;
+        if let Some(tx) = &tx {
+            let mut state = self.lock();
+            let result = match tx.txid == *txid {
+                true => self.verify_tx(&mut state, tx),
+                false => Err(SpvError::InvalidTx(*txid, "is served as another transaction").into()),
+            };
+            state.report(result)?;
+        }
+
+        Ok(tx)
+    }
+
+    fn merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, ChainError> {
+        self.inner.merkle_proof(txid)
+    }
+
+    fn take_events(&self) -> Vec<Event> {
+        let mut events = self.inner.take_events();
+        events.append(&mut self.lock().events);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        sync::{Arc, Mutex},
+    };
+
+    use bitcoin::{
+        Amount, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, absolute::LockTime, block::Version, transaction,
+    };
+    use esplora_client::{TxStatus, UtxoStatus};
+
+    use super::*;
+    use crate::chain::tx_from_transaction;
+
+    fn transaction(previous_output: OutPoint, tag: u8, script_pubkey: ScriptBuf, value: u64) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::from_bytes(vec![0x01, tag]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey,
+            }],
+        }
+    }
+
+    /// Mine a regtest block on top of `prev`, with `bits` and a coinbase followed by `txs`.
+    fn mine(prev: &Header, bits: CompactTarget, txs: Vec<Transaction>) -> Block {
+        let coinbase = transaction(OutPoint::null(), prev.nonce as u8, ScriptBuf::new(), 50_0000_0000);
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: prev.time + 600,
+                bits,
+                nonce: 0,
+            },
+            txdata: [vec![coinbase], txs].concat(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        block
+    }
+
+    /// A chain source serving `blocks`, and whatever UTXOs are put in `utxos` for every address.
+    struct FakeChain {
+        blocks: Vec<Block>,
+        utxos: Arc<Mutex<Vec<Utxo>>>,
+    }
+
+    impl FakeChain {
+        fn find(&self, txid: &Txid) -> Option<(u32, usize, &Block)> {
+            self.blocks.iter().zip(0..).find_map(|(block, height)| {
+                let pos = block.txdata.iter().position(|tx| tx.compute_txid() == *txid)?;
+                Some((height, pos, block))
+            })
+        }
+    }
+
+    impl ChainSource for FakeChain {
+        fn height(&self) -> Result<u32, ChainError> {
+            Ok(self.blocks.len() as u32 - 1)
+        }
+
+        fn block_hash(&self, height: u32) -> Result<BlockHash, ChainError> {
+            Ok(self.blocks[height as usize].block_hash())
+        }
+
+        fn header(&self, block_hash: &BlockHash) -> Result<Header, ChainError> {
+            let block = self.blocks.iter().find(|block| block.block_hash() == *block_hash);
+            Ok(block.unwrap().header)
+        }
+
+        fn address_utxos(&self, _address: &Address) -> Result<Vec<Utxo>, ChainError> {
+            Ok(self.utxos.lock().unwrap().clone())
+        }
+
+        fn address_txs(&self, _address: &Address, _last_seen: Option<Txid>) -> Result<Vec<Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn mempool_address_txs(&self, _address: &Address) -> Result<Vec<Tx>, ChainError> {
+            Ok(Vec::new())
+        }
+
+        fn spending_txid(&self, _txid: &Txid, _vout: u32) -> Result<Option<Txid>, ChainError> {
+            Ok(None)
+        }
+
+        fn tx(&self, txid: &Txid) -> Result<Option<Tx>, ChainError> {
+            Ok(self.find(txid).map(|(height, pos, block)| {
+                let transaction = &block.txdata[pos];
+                let status = TxStatus {
+                    confirmed: true,
+                    block_height: Some(height),
+                    block_hash: Some(block.block_hash()),
+                    block_time: Some(block.header.time as u64),
+                };
+                tx_from_transaction(transaction, vec![None; transaction.input.len()], status)
+            }))
+        }
+
+        fn merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleProof>, ChainError> {
+            Ok(self.find(txid).map(|(block_height, pos, block)| {
+                let mut level: Vec<sha256d::Hash> =
+                    block.txdata.iter().map(|tx| tx.compute_txid().to_raw_hash()).collect();
+                let (mut index, mut merkle) = (pos, Vec::new());
+                while level.len() > 1 {
+                    if level.len() % 2 == 1 {
+                        level.push(level[level.len() - 1]);
+                    }
+                    merkle.push(Txid::from_raw_hash(level[index ^ 1]));
+                    level = level
+                        .chunks(2)
+                        .map(|pair| sha256d::Hash::hash(&[pair[0].to_byte_array(), pair[1].to_byte_array()].concat()))
+                        .collect();
+                    index /= 2;
+                }
+                MerkleProof {
+                    block_height,
+                    merkle,
+                    pos,
+                }
+            }))
+        }
+    }
+
+    fn config(name: &str) -> SpvConfig {
+        let header_store = env::temp_dir().join(format!("smaug-spv-{name}-{}.json", std::process::id()));
+        let _ = fs::remove_file(&header_store);
+
+        SpvConfig {
+            header_store: header_store.to_string_lossy().into_owned(),
+            checkpoint_height: None,
+            checkpoint_hash: None,
+        }
+    }
+
+    #[test]
+    fn forged_utxos_are_reported_once() {
+        let address = Address::p2wsh(&ScriptBuf::new_op_return([0x13, 0x37]), Network::Regtest);
+        let funding = OutPoint::new(Txid::all_zeros(), 7);
+        let elsewhere = transaction(funding, 1, ScriptBuf::new(), 4_000);
+        let deposit = transaction(funding, 2, address.script_pubkey(), 1_000);
+        let deposit_txid = deposit.compute_txid();
+
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        for txs in [vec![], vec![elsewhere, deposit], vec![]] {
+            let block = mine(&blocks[blocks.len() - 1].header, blocks[0].header.bits, txs);
+            blocks.push(block);
+        }
+        let utxo = Utxo {
+            txid: deposit_txid,
+            vout: 0,
+            status: UtxoStatus {
+                confirmed: true,
+                block_height: Some(2),
+                block_hash: Some(blocks[2].block_hash()),
+                block_time: None,
+            },
+            value: Amount::from_sat(1_000),
+        };
+        let utxos = Arc::new(Mutex::new(vec![utxo]));
+        let chain = FakeChain {
+            blocks,
+            utxos: utxos.clone(),
+        };
+        let verifier = SpvVerifier::new(Box::new(chain), Network::Regtest, &config("forged")).unwrap();
+
+        assert_eq!(verifier.height().unwrap(), 3);
+        assert_eq!(verifier.address_utxos(&address).unwrap(), [utxo]);
+        assert!(verifier.take_events().is_empty());
+
+        // The chain source now claims the deposit is worth more than it is.
+        *utxos.lock().unwrap() = vec![Utxo {
+            value: Amount::from_sat(1_000_000),
+            ..utxo
+        }];
+        assert!(verifier.address_utxos(&address).is_err());
+        let events = verifier.take_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            Event::VerificationFailure(failure) if failure.txid == Some(deposit_txid)
+        ));
+
+        // It is only notified once.
+        assert!(verifier.address_utxos(&address).is_err());
+        assert!(verifier.take_events().is_empty());
+    }
+
+    #[test]
+    fn headers_with_an_unexpected_target_are_rejected() {
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        // Regtest never retargets, so the third block must not change the difficulty.
+        for bits in [0x207fffff, 0x207fffff, 0x207ffffe] {
+            let block = mine(
+                &blocks[blocks.len() - 1].header,
+                CompactTarget::from_consensus(bits),
+                vec![],
+            );
+            blocks.push(block);
+        }
+        let chain = FakeChain {
+            blocks,
+            utxos: Arc::default(),
+        };
+        let verifier = SpvVerifier::new(Box::new(chain), Network::Regtest, &config("target")).unwrap();
+
+        assert!(verifier.height().is_err());
+        let events = verifier.take_events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            Event::VerificationFailure(failure)
+                if failure.txid.is_none() && failure.reason.ends_with("an unexpected difficulty target")
+        ));
+        assert_eq!(verifier.lock().headers.tip_height(), 0);
+    }
+}
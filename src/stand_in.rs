@@ -0,0 +1,102 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use serde_json::Value;
+
+/// A request received by an [`http_stand_in`].
+#[derive(Clone, Debug)]
+pub(crate) struct Request {
+    /// The path of the request, query included.
+    pub(crate) path: String,
+    /// The headers of the request, with lowercase names.
+    pub(crate) headers: Vec<(String, String)>,
+    /// The body of the request.
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the header `name`, if it was sent.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body of the request, parsed as JSON.
+    pub(crate) fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// The requests an [`http_stand_in`] received so far, in the order they came in.
+pub(crate) type Requests = Arc<Mutex<Vec<Request>>>;
+
+/// An HTTP/1.1 server stand-in on a local port, answering every request with `respond`, on a thread of its own so a
+/// slow answer does not hold back the others.
+///
+/// `respond` returns the status, like `200 OK`, followed by any extra header lines, and the body. Every request is
+/// recorded before it is answered.
+///
+/// Returns the base URL of the server, and the requests it received.
+pub(crate) fn http_stand_in(
+    respond: impl Fn(&Request) -> (String, String) + Send + Sync + 'static,
+) -> (String, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Requests::default();
+
+    let respond = Arc::new(respond);
+    let received = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let (respond, received) = (respond.clone(), received.clone());
+            thread::spawn(move || answer(stream, &*respond, &received));
+        }
+    });
+
+    (url, requests)
+}
+
+/// Read the request on `stream`, record it into `received` and answer it with `respond`.
+fn answer(stream: TcpStream, respond: &dyn Fn(&Request) -> (String, String), received: &Requests) {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    let mut request = Request {
+        path: request_line.split_whitespace().nth(1).unwrap().to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    let content_length = request
+        .header("content-length")
+        .map_or(0, |length| length.parse().unwrap());
+    request.body = vec![0; content_length];
+    reader.read_exact(&mut request.body).unwrap();
+
+    received.lock().unwrap().push(request.clone());
+    let (status, body) = respond(&request);
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+}
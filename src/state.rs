@@ -0,0 +1,254 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use bitcoin::{
+    Address, Amount, BlockHash, Network, Txid,
+    address::{NetworkUnchecked, ParseError},
+};
+use esplora_client::{Utxo, UtxoStatus};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::confirmations::StoredTracked;
+use crate::smaug::UtxoDB;
+
+/// Errors that happen while loading or saving the state file.
+#[derive(Debug, Error)]
+pub enum StateError {
+    /// Error reading or writing the state file.
+    #[error("state file `{0}`: {1}")]
+    Io(PathBuf, #[source] io::Error),
+
+    /// Error (de)serializing the state file.
+    #[error("state file `{0}`: {1}")]
+    Json(PathBuf, #[source] serde_json::Error),
+
+    /// The state file was written for a different network.
+    #[error("state file `{0}` holds addresses for another network: {1}")]
+    NetworkMismatch(PathBuf, #[source] ParseError),
+}
+
+/// A [`Utxo`] as stored in the state file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StoredUtxo {
+    txid: Txid,
+    vout: u32,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    value: Amount,
+    confirmed: bool,
+    block_height: Option<u32>,
+    block_hash: Option<BlockHash>,
+    block_time: Option<u64>,
+}
+
+impl From<&Utxo> for StoredUtxo {
+    fn from(utxo: &Utxo) -> Self {
+        StoredUtxo {
+            txid: utxo.txid,
+            vout: utxo.vout,
+            value: utxo.value,
+            confirmed: utxo.status.confirmed,
+            block_height: utxo.status.block_height,
+            block_hash: utxo.status.block_hash,
+            block_time: utxo.status.block_time,
+        }
+    }
+}
+
+impl From<StoredUtxo> for Utxo {
+    fn from(utxo: StoredUtxo) -> Self {
+        Utxo {
+            txid: utxo.txid,
+            vout: utxo.vout,
+            status: UtxoStatus {
+                confirmed: utxo.confirmed,
+                block_height: utxo.block_height,
+                block_hash: utxo.block_hash,
+                block_time: utxo.block_time,
+            },
+            value: utxo.value,
+        }
+    }
+}
+
+/// The UTXOs locked to an address, as stored in the state file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredAddress {
+    address: Address<NetworkUnchecked>,
+    utxos: Vec<StoredUtxo>,
+}
+
+/// The on-disk representation of [`State`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredState {
+    height: u32,
+    block_hash: BlockHash,
+    #[serde(default)]
+    recent_blocks: Vec<BlockHash>,
+    addresses: Vec<StoredAddress>,
+    #[serde(default)]
+    used: Vec<Address<NetworkUnchecked>>,
+    #[serde(default)]
+    tracked: Vec<StoredTracked>,
+}
+
+/// What `smaug` knew about the chain and the watched addresses after the last processed tip.
+#[derive(Clone, Debug)]
+pub(crate) struct State {
+    /// The height of the last processed tip.
+    pub(crate) height: u32,
+    /// The hash of the last processed tip.
+    pub(crate) block_hash: BlockHash,
+    /// The hashes of the most recent blocks up to the last processed tip, oldest first, to find where the chain forked
+    /// if it was reorganized while `smaug` was not running.
+    pub(crate) recent_blocks: Vec<BlockHash>,
+    /// The UTXOs locked to every watched address at the last processed tip.
+    pub(crate) utxos: UtxoDB,
+    /// The last used address of every branch of the watched descriptors, so the addresses emptied since still count
+    /// as used.
+    pub(crate) used: Vec<Address>,
+    /// The deposits and withdrawals held back until their next confirmation milestone, so they are still notified
+    /// after a restart.
+    pub(crate) tracked: Vec<StoredTracked>,
+}
+
+impl State {
+    /// Load the [`State`] from `path`, if it exists.
+    pub(crate) fn load(path: &Path, network: Network) -> Result<Option<State>, StateError> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StateError::Io(path.to_path_buf(), e)),
+        };
+        let stored: StoredState = serde_json::from_str(&json).map_err(|e| StateError::Json(path.to_path_buf(), e))?;
+
+        let mut utxos = UtxoDB::new();
+        for entry in stored.addresses {
+            let address = entry
+                .address
+                .require_network(network)
+                .map_err(|e| StateError::NetworkMismatch(path.to_path_buf(), e))?;
+            utxos.insert(address, entry.utxos.into_iter().map(Utxo::from).collect());
+        }
+        let used = stored
+            .used
+            .into_iter()
+            .map(|address| address.require_network(network))
+            .collect::<Result<_, _>>()
+            .map_err(|e| StateError::NetworkMismatch(path.to_path_buf(), e))?;
+
+        Ok(Some(State {
+            height: stored.height,
+            block_hash: stored.block_hash,
+            recent_blocks: match stored.recent_blocks.is_empty() {
+                true => vec![stored.block_hash],
+                false => stored.recent_blocks,
+            },
+            utxos,
+            used,
+            tracked: stored.tracked,
+        }))
+    }
+
+    /// Save the [`State`] to `path`.
+    ///
+    /// The state is written with [`write_atomically`], so a crash never leaves a truncated state file behind.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), StateError> {
+        let stored = StoredState {
+            height: self.height,
+            block_hash: self.block_hash,
+            recent_blocks: self.recent_blocks.clone(),
+            addresses: self
+                .utxos
+                .iter()
+                .map(|(address, utxos)| StoredAddress {
+                    address: address.as_unchecked().clone(),
+                    utxos: utxos.iter().map(StoredUtxo::from).collect(),
+                })
+                .collect(),
+            used: self.used.iter().map(|address| address.as_unchecked().clone()).collect(),
+            tracked: self.tracked.clone(),
+        };
+        let json = serde_json::to_string_pretty(&stored).map_err(|e| StateError::Json(path.to_path_buf(), e))?;
+
+        write_atomically(path, json.as_bytes()).map_err(|e| StateError::Io(path.to_path_buf(), e))
+    }
+}
+
+/// Write `bytes` to `path` through a temporary file, synced to disk before it is renamed over `path`, with the rename
+/// synced too, so neither a crash nor a power loss mid-write leaves a truncated or empty file behind.
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+
+    // The rename only survives a power loss once the directory holding the file is synced as well.
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, str::FromStr};
+
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    #[test]
+    fn save_and_load_state() {
+        let path = env::temp_dir().join(format!("smaug-state-{}.json", std::process::id()));
+        let address = Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap();
+        let utxo = Utxo {
+            txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            vout: 1,
+            status: UtxoStatus {
+                confirmed: true,
+                block_height: Some(101596),
+                block_hash: Some(BlockHash::all_zeros()),
+                block_time: Some(1757566235),
+            },
+            value: Amount::from_sat(1337),
+        };
+
+        let state = State {
+            height: 101597,
+            block_hash: BlockHash::all_zeros(),
+            recent_blocks: vec![BlockHash::all_zeros()],
+            utxos: UtxoDB::from([(address.clone(), vec![utxo])]),
+            used: vec![address.clone()],
+            tracked: Vec::new(),
+        };
+        state.save(&path).unwrap();
+
+        let loaded = State::load(&path, Network::Testnet4).unwrap().unwrap();
+        assert_eq!(loaded.height, 101597);
+        assert_eq!(loaded.recent_blocks, vec![BlockHash::all_zeros()]);
+        assert_eq!(loaded.utxos.get(&address).unwrap(), &vec![utxo]);
+        assert_eq!(loaded.used, vec![address]);
+        assert!(State::load(&path, Network::Bitcoin).is_err());
+
+        fs::remove_file(&path).unwrap();
+        assert!(State::load(&path, Network::Testnet4).unwrap().is_none());
+    }
+}
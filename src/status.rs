@@ -0,0 +1,28 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use crate::smaug::UtxoDB;
+
+/// What `smaug` knew after its last successful poll, shared with interactive notifiers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Status {
+    /// The height of the last processed tip.
+    pub(crate) height: u32,
+    /// When the chain source was last polled successfully.
+    pub(crate) last_poll: Option<SystemTime>,
+    /// The UTXOs locked to every watched address.
+    pub(crate) utxos: UtxoDB,
+}
+
+/// A [`Status`] shared between the event loop and interactive notifiers.
+pub(crate) type SharedStatus = Arc<Mutex<Status>>;
+
+/// Record a successful poll at `height`.
+pub(crate) fn record_poll(status: &SharedStatus, height: u32, utxos: &UtxoDB) {
+    let mut status = status.lock().unwrap_or_else(PoisonError::into_inner);
+    status.height = height;
+    status.last_poll = Some(SystemTime::now());
+    status.utxos = utxos.clone();
+}
@@ -0,0 +1,523 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use bitcoin::Amount;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use thiserror::Error;
+
+use crate::Config;
+use crate::notifier::{Notification, Notifier, NotifierError};
+use crate::proxy;
+use crate::smaug::ERROR_RETRY_DELAY_SEC;
+use crate::status::{SharedStatus, Status};
+use crate::{REDACTED, format_duration, format_with_commas};
+
+/// The default Telegram Bot API base URL.
+pub(crate) const TELEGRAM_API: &str = "https://api.telegram.org";
+
+/// The default amount of seconds to wait for the Bot API to respond.
+pub(crate) const DEFAULT_TELEGRAM_TIMEOUT_SEC: u64 = 10;
+
+/// How long `getUpdates` waits for new commands, in seconds.
+const LONG_POLL_TIMEOUT_SEC: u64 = 30;
+
+/// The maximum length of a Telegram message, in characters.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// The commands the bot answers.
+const HELP: &str = "/status - tip height, last successful poll and number of watched addresses
+/balance - balance of the watched addresses
+/mute <duration> - mute alerts to this chat, e.g. /mute 2h
+/unmute - unmute alerts to this chat";
+
+/// Errors that happen while talking to the Telegram Bot API.
+#[derive(Debug, Error)]
+pub enum TelegramError {
+    /// Error making the HTTP request.
+    #[error(transparent)]
+    Http(#[from] minreq::Error),
+
+    /// Error (de)serializing a request or response.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The Bot API returned an error.
+    #[error("Telegram Bot API error: {0}")]
+    Api(String),
+}
+
+/// Where and how to send Telegram messages.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct TelegramConfig {
+    /// The token of the bot, as given by @BotFather.
+    pub(crate) bot_token: String,
+    /// The chats alerts are sent to. The bot only answers commands from these chats.
+    pub(crate) chat_ids: Vec<i64>,
+    /// The Bot API base URL.
+    #[serde(default = "default_telegram_api")]
+    pub(crate) api_url: String,
+    /// How long to wait for the Bot API to respond, in seconds.
+    #[serde(default = "default_telegram_timeout_sec")]
+    pub(crate) timeout_sec: u64,
+}
+
+fn default_telegram_api() -> String {
+    TELEGRAM_API.to_string()
+}
+
+fn default_telegram_timeout_sec() -> u64 {
+    DEFAULT_TELEGRAM_TIMEOUT_SEC
+}
+
+impl fmt::Debug for TelegramConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TelegramConfig")
+            .field("bot_token", &REDACTED)
+            .field("chat_ids", &self.chat_ids)
+            .field("api_url", &self.api_url)
+            .field("timeout_sec", &self.timeout_sec)
+            .finish()
+    }
+}
+
+/// The envelope of every Bot API response.
+#[derive(Debug, Deserialize)]
+struct ApiResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+/// An incoming update.
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<IncomingMessage>,
+}
+
+/// An incoming message.
+#[derive(Debug, Deserialize)]
+struct IncomingMessage {
+    chat: Chat,
+    text: Option<String>,
+}
+
+/// The chat a message was sent in.
+#[derive(Debug, Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+/// Parse a duration like `30m`, `2h` or `1d`.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = duration.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(amount.checked_mul(unit_secs)?))
+}
+
+/// Answer a command sent to the bot from `chat_id`.
+fn answer_command(
+    text: &str,
+    chat_id: i64,
+    status: &Status,
+    mutes: &mut HashMap<i64, SystemTime>,
+    now: SystemTime,
+) -> String {
+    let mut words = text.split_whitespace();
+    // Commands in groups are suffixed with the bot's username, like `/status@smaug_bot`.
+    let command = words.next().unwrap_or_default().split('@').next().unwrap_or_default();
+
+    match command {
+        "/status" => {
+            let last_poll = match status.last_poll {
+                Some(last_poll) => format!(
+                    "{} ago",
+                    format_duration(now.duration_since(last_poll).unwrap_or_default())
+                ),
+                None => String::from("never"),
+            };
+            let mut reply = format!(
+                "Tip height: {}\nLast successful poll: {}\nWatched addresses: {}",
+                status.height,
+                last_poll,
+                status.utxos.len()
+            );
+            if let Some(until) = mutes.get(&chat_id)
+                && let Ok(remaining) = until.duration_since(now)
+            {
+                reply.push_str(&format!("\nAlerts muted for {}", format_duration(remaining)));
+            }
+            reply
+        }
+        "/balance" => {
+            let mut balances: Vec<(String, Amount, Amount)> = status
+                .utxos
+                .iter()
+                .map(|(address, utxos)| {
+                    let total: Amount = utxos.iter().map(|utxo| utxo.value).sum();
+                    let unconfirmed: Amount = utxos
+                        .iter()
+                        .filter(|utxo| !utxo.status.confirmed)
+                        .map(|utxo| utxo.value)
+                        .sum();
+                    (address.to_string(), total, unconfirmed)
+                })
+                .filter(|(_, total, _)| *total > Amount::ZERO)
+                .collect();
+            balances.sort();
+
+            let total: Amount = balances.iter().map(|(_, total, _)| *total).sum();
+            let unconfirmed: Amount = balances.iter().map(|(_, _, unconfirmed)| *unconfirmed).sum();
+            let mut reply = format!(
+                "Total: {} sats ({} sats unconfirmed)",
+                format_with_commas(total.to_sat()),
+                format_with_commas(unconfirmed.to_sat())
+            );
+            for (address, total, _) in balances {
+                reply.push_str(&format!("\n- {}: {} sats", address, format_with_commas(total.to_sat())));
+            }
+            reply
+        }
+        // A duration too long to add to the current time is rejected like a malformed one.
+        "/mute" => match words
+            .next()
+            .and_then(parse_duration)
+            .and_then(|duration| Some((duration, now.checked_add(duration)?)))
+        {
+            Some((duration, until)) => {
+                mutes.insert(chat_id, until);
+                format!("Alerts to this chat are muted for {}", format_duration(duration))
+            }
+            None => String::from("Usage: /mute <duration>, e.g. /mute 30m, /mute 2h or /mute 1d"),
+        },
+        "/unmute" => match mutes.remove(&chat_id) {
+            Some(_) => String::from("Alerts to this chat are unmuted"),
+            None => String::from("Alerts to this chat are not muted"),
+        },
+        _ => format!("smaug guards your coins. Commands:\n{HELP}"),
+    }
+}
+
+/// Delivers [`Event`] notifications through a Telegram bot, and answers commands sent to it.
+#[derive(Clone)]
+pub(crate) struct TelegramNotifier {
+    /// The Bot API base URL, including the bot token.
+    bot_url: String,
+    /// How long to wait for the Bot API to respond, in seconds.
+    timeout_sec: u64,
+    /// The chats alerts are sent to.
+    chat_ids: Vec<i64>,
+    /// Until when alerts are muted, per chat.
+    mutes: Arc<Mutex<HashMap<i64, SystemTime>>>,
+    /// What `smaug` knew after its last successful poll.
+    status: SharedStatus,
+}
+
+impl TelegramNotifier {
+    /// Build a [`TelegramNotifier`] from the configuration, if `telegram` is set, and start answering commands.
+    pub(crate) fn from_config(config: &Config, status: &SharedStatus) -> Option<TelegramNotifier> {
+        let telegram = config.telegram.as_ref()?;
+        let notifier = TelegramNotifier::new(telegram, status.clone());
+
+        let commands = notifier.clone();
+        thread::spawn(move || commands.answer_commands());
+
+        Some(notifier)
+    }
+
+    /// Create a [`TelegramNotifier`].
+    fn new(telegram: &TelegramConfig, status: SharedStatus) -> TelegramNotifier {
+        TelegramNotifier {
+            bot_url: format!("{}/bot{}", telegram.api_url.trim_end_matches('/'), telegram.bot_token),
+            timeout_sec: telegram.timeout_sec,
+            chat_ids: telegram.chat_ids.clone(),
+            mutes: Arc::new(Mutex::new(HashMap::new())),
+            status,
+        }
+    }
+
+    /// Call a Bot API method.
+    fn call<T: DeserializeOwned>(&self, method: &str, params: Value, timeout_sec: u64) -> Result<T, TelegramError> {
+        let response = proxy::proxied(minreq::post(format!("{}/{}", self.bot_url, method)))
+            .with_timeout(timeout_sec)
+            .with_header("Content-Type", "application/json")
+            .with_body(serde_json::to_vec(&params)?)
+            .send()?;
+        let response: ApiResponse<T> = serde_json::from_str(response.as_str()?)?;
+
+        match (response.ok, response.result) {
+            (true, Some(result)) => Ok(result),
+            _ => Err(TelegramError::Api(
+                response.description.unwrap_or_else(|| String::from("unknown error")),
+            )),
+        }
+    }
+
+    /// Send a text message to a chat.
+    fn send_message(&self, chat_id: i64, text: &str) -> Result<(), TelegramError> {
+        let text: String = text.chars().take(MAX_MESSAGE_LEN).collect();
+        self.call::<Value>(
+            "sendMessage",
+            json!({ "chat_id": chat_id, "text": text }),
+            self.timeout_sec,
+        )?;
+
+        Ok(())
+    }
+
+    /// Whether alerts to `chat_id` are muted.
+    fn is_muted(&self, chat_id: i64) -> bool {
+        let mutes = self.mutes.lock().unwrap_or_else(PoisonError::into_inner);
+        mutes.get(&chat_id).is_some_and(|until| *until > SystemTime::now())
+    }
+
+    /// Fetch the commands sent after `offset` and answer them, returning the next offset.
+    ///
+    /// Only commands from the configured chats are answered.
+    fn poll_commands(&self, offset: i64) -> Result<i64, TelegramError> {
+        let updates: Vec<Update> = self.call(
+            "getUpdates",
+            json!({ "offset": offset, "timeout": LONG_POLL_TIMEOUT_SEC, "allowed_updates": ["message"] }),
+            LONG_POLL_TIMEOUT_SEC + self.timeout_sec,
+        )?;
+
+        let mut next_offset = offset;
+        for update in updates {
+            next_offset = next_offset.max(update.update_id + 1);
+            let Some(IncomingMessage { chat, text: Some(text) }) = update.message else {
+                continue;
+            };
+            if !self.chat_ids.contains(&chat.id) {
+                debug!("Ignoring Telegram message from unknown chat {}", chat.id);
+                continue;
+            }
+
+            let reply = {
+                let status = self.status.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut mutes = self.mutes.lock().unwrap_or_else(PoisonError::into_inner);
+                answer_command(&text, chat.id, &status, &mut mutes, SystemTime::now())
+            };
+            if let Err(e) = self.send_message(chat.id, &reply) {
+                warn!("Failed to answer Telegram command: {e}");
+            }
+        }
+
+        Ok(next_offset)
+    }
+
+    /// Answer commands sent to the bot, forever.
+    fn answer_commands(&self) {
+        info!("Answering Telegram commands");
+
+        let mut offset = 0;
+        loop {
+            match self.poll_commands(offset) {
+                Ok(next_offset) => offset = next_offset,
+                Err(e) => {
+                    warn!("Failed to fetch Telegram commands: {e}");
+                    thread::sleep(Duration::from_secs(ERROR_RETRY_DELAY_SEC));
+                }
+            }
+        }
+    }
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    fn notify(&self, notification: &Notification, delivered: &mut BTreeSet<String>) -> Result<(), NotifierError> {
+        let text = format!("{}\n\n{}", notification.subject, notification.body);
+
+        // Deliver to every chat not reached yet, even if delivery to one of them fails.
+        let mut result = Ok(());
+        for &chat_id in &self.chat_ids {
+            if delivered.contains(&chat_id.to_string()) {
+                continue;
+            }
+            if self.is_muted(chat_id) {
+                debug!("Alerts to Telegram chat {chat_id} are muted");
+                continue;
+            }
+            match self.send_message(chat_id, &text) {
+                Ok(()) => {
+                    info!("Sent Telegram message to chat {chat_id}");
+                    delivered.insert(chat_id.to_string());
+                }
+                Err(e) => {
+                    warn!("Failed to send Telegram message to chat {chat_id}: {e}");
+                    result = Err(e);
+                }
+            }
+        }
+
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{Address, Network, Txid};
+    use esplora_client::{Utxo, UtxoStatus};
+
+    use super::*;
+    use crate::email::format_event;
+    use crate::smaug::{Event, SubscriptionParams, UtxoDB};
+    use crate::stand_in::{Requests, http_stand_in};
+
+    /// A Bot API stand-in answering `getUpdates` with `updates`, and any other method with an empty object.
+    fn bot_api_stand_in(updates: Value) -> (String, Requests) {
+        http_stand_in(move |request| {
+            let result = match request.path.rsplit('/').next().unwrap() {
+                "getUpdates" => updates.clone(),
+                _ => json!({}),
+            };
+            (
+                String::from("200 OK"),
+                json!({ "ok": true, "result": result }).to_string(),
+            )
+        })
+    }
+
+    fn notifier(api_url: String, status: Status) -> TelegramNotifier {
+        let telegram = TelegramConfig {
+            bot_token: String::from("123:token"),
+            chat_ids: vec![1, 2],
+            api_url,
+            timeout_sec: 5,
+        };
+        TelegramNotifier::new(&telegram, Arc::new(Mutex::new(status)))
+    }
+
+    fn status() -> Status {
+        let address = Address::from_str("tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd")
+            .unwrap()
+            .require_network(Network::Testnet4)
+            .unwrap();
+        let utxo = |vout, confirmed| Utxo {
+            txid: Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            vout,
+            status: UtxoStatus {
+                confirmed,
+                block_height: None,
+                block_hash: None,
+                block_time: None,
+            },
+            value: Amount::from_sat(1_000_000),
+        };
+
+        Status {
+            height: 101597,
+            last_poll: Some(SystemTime::now() - Duration::from_secs(90)),
+            utxos: UtxoDB::from([(address, vec![utxo(0, true), utxo(1, false)])]),
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let now = SystemTime::now();
+        let status = Status {
+            last_poll: Some(now - Duration::from_secs(90)),
+            ..status()
+        };
+        let mut mutes = HashMap::new();
+
+        assert_eq!(
+            answer_command("/status@smaug_bot", 1, &status, &mut mutes, now),
+            "Tip height: 101597\nLast successful poll: 1m 30s ago\nWatched addresses: 1"
+        );
+        assert_eq!(
+            answer_command("/balance", 1, &status, &mut mutes, now),
+            "Total: 2,000,000 sats (1,000,000 sats unconfirmed)\n\
+             - tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd: 2,000,000 sats"
+        );
+
+        assert_eq!(
+            answer_command("/mute 2h", 1, &status, &mut mutes, now),
+            "Alerts to this chat are muted for 2h 0m"
+        );
+        assert_eq!(mutes.get(&1), Some(&(now + Duration::from_secs(7200))));
+        assert!(answer_command("/status", 1, &status, &mut mutes, now).ends_with("Alerts muted for 2h 0m"));
+        assert!(answer_command("/mute forever", 1, &status, &mut mutes, now).starts_with("Usage"));
+        assert!(answer_command("/mute 10000000000000000000s", 1, &status, &mut mutes, now).starts_with("Usage"));
+        assert_eq!(
+            answer_command("/unmute", 1, &status, &mut mutes, now),
+            "Alerts to this chat are unmuted"
+        );
+        assert!(answer_command("/help", 1, &status, &mut mutes, now).contains("/balance"));
+    }
+
+    #[test]
+    fn alerts_skip_muted_chats() {
+        let (url, requests) = bot_api_stand_in(json!([]));
+        let notifier = notifier(url, status());
+        notifier
+            .mutes
+            .lock()
+            .unwrap()
+            .insert(2, SystemTime::now() + Duration::from_secs(60));
+
+        let event = Event::Subscription(SubscriptionParams {
+            addresses: Vec::new(),
+            descriptors: vec![(String::from("vpub"), 20)],
+        });
+        let mut delivered = BTreeSet::new();
+        notifier
+            .notify(&Notification::new(Network::Testnet4, &event).unwrap(), &mut delivered)
+            .unwrap();
+        // Only the chat that was not muted is recorded as reached.
+        assert_eq!(delivered, BTreeSet::from([String::from("1")]));
+
+        let requests = requests.lock().unwrap();
+        let (subject, body) = format_event(Network::Testnet4, &event);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/bot123:token/sendMessage");
+        assert_eq!(requests[0].json()["chat_id"], 1);
+        assert_eq!(requests[0].json()["text"], format!("{subject}\n\n{body}"));
+    }
+
+    #[test]
+    fn answer_commands_from_configured_chats() {
+        let updates = json!([
+            { "update_id": 7, "message": { "chat": { "id": 1 }, "text": "/status" } },
+            { "update_id": 8, "message": { "chat": { "id": 666 }, "text": "/balance" } },
+        ]);
+        let (url, requests) = bot_api_stand_in(updates);
+        let notifier = notifier(url, status());
+
+        assert_eq!(notifier.poll_commands(0).unwrap(), 9);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/bot123:token/getUpdates");
+        assert_eq!(requests[0].json()["offset"], 0);
+        assert_eq!(requests[1].path, "/bot123:token/sendMessage");
+        assert_eq!(requests[1].json()["chat_id"], 1);
+        assert!(
+            requests[1].json()["text"]
+                .as_str()
+                .unwrap()
+                .starts_with("Tip height: 101597")
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{
+    Address,
+    secp256k1::{Secp256k1, VerifyOnly},
+};
+
+use crate::Config;
+use crate::check_addresses;
+use crate::descriptor::{AddressOrigin, Descriptor, DescriptorWatch};
+use crate::smaug::{SmaugError, SubscriptionParams};
+
+/// The set of addresses `smaug` watches: the ones listed in the configuration
+/// plus the ones derived from the configured descriptors.
+#[derive(Debug)]
+pub(crate) struct WatchList {
+    /// Every watched address, in the order they were added.
+    addresses: Vec<Address>,
+    /// The same addresses as `addresses`, to tell whether a derived address is watched already.
+    watched: HashSet<Address>,
+    /// The descriptor origin of every derived address.
+    origins: HashMap<Address, AddressOrigin>,
+    /// The watched descriptors.
+    descriptors: Vec<DescriptorWatch>,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl WatchList {
+    /// Build the [`WatchList`] from the configured addresses and descriptors,
+    /// deriving the first `gap_limit` addresses of every descriptor.
+    pub(crate) fn from_config(config: &Config) -> Result<WatchList, SmaugError> {
+        let addresses = check_addresses(&config.addresses, &config.network)?;
+        let descriptors = config
+            .descriptors
+            .iter()
+            .map(|descriptor| {
+                let descriptor = Descriptor::parse(descriptor, config.network)?;
+                Ok(DescriptorWatch::new(descriptor, config.gap_limit, config.network))
+            })
+            .collect::<Result<Vec<_>, SmaugError>>()?;
+
+        let mut watchlist = WatchList {
+            watched: addresses.iter().cloned().collect(),
+            addresses,
+            origins: HashMap::new(),
+            descriptors,
+            secp: Secp256k1::verification_only(),
+        };
+        watchlist.extend()?;
+
+        Ok(watchlist)
+    }
+
+    /// Every watched address.
+    pub(crate) fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    /// The watched descriptors.
+    pub(crate) fn descriptors(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().map(|watch| watch.descriptor())
+    }
+
+    /// The descriptor origin of `address`, if it was derived from one.
+    pub(crate) fn origin(&self, address: &Address) -> Option<&AddressOrigin> {
+        self.origins.get(address)
+    }
+
+    /// The configured addresses and descriptors, for an [`Event::Subscription`](crate::smaug::Event::Subscription).
+    pub(crate) fn subscription_params(&self) -> SubscriptionParams {
+        let addresses = self
+            .addresses
+            .iter()
+            .filter(|address| !self.origins.contains_key(address))
+            .cloned()
+            .collect();
+        let descriptors = self
+            .descriptors()
+            .map(|descriptor| {
+                let descriptor = descriptor.to_string();
+                let derived = self
+                    .origins
+                    .values()
+                    .filter(|origin| origin.descriptor == descriptor)
+                    .count();
+                (descriptor, derived)
+            })
+            .collect();
+
+        SubscriptionParams { addresses, descriptors }
+    }
+
+    /// Derive new addresses to keep the gap limit past the addresses marked used.
+    ///
+    /// Returns the newly derived addresses, which must be fetched, and marked used if they have any history.
+    pub(crate) fn update(&mut self) -> Result<Vec<Address>, SmaugError> {
+        self.extend()
+    }
+
+    /// Whether `address` was derived from a watched descriptor and is not known to be used yet, so it must be checked
+    /// for history.
+    pub(crate) fn may_be_unused(&self, address: &Address) -> bool {
+        self.origins.contains_key(address) && !self.descriptors.iter().any(|watch| watch.is_used(address))
+    }
+
+    /// The last used address of every branch of the watched descriptors, to persist which addresses were used.
+    pub(crate) fn used(&self) -> Vec<Address> {
+        self.descriptors
+            .iter()
+            .flat_map(|watch| watch.last_used())
+            .cloned()
+            .collect()
+    }
+
+    /// Mark `address` as used, if it was derived from a watched descriptor.
+    ///
+    /// New addresses are only derived on the next call to [`WatchList::update`].
+    pub(crate) fn mark_used(&mut self, address: &Address) {
+        if self.origins.contains_key(address) {
+            for watch in &mut self.descriptors {
+                watch.mark_used(address);
+            }
+        }
+    }
+
+    fn extend(&mut self) -> Result<Vec<Address>, SmaugError> {
+        let mut new_addresses = Vec::new();
+
+        for watch in &mut self.descriptors {
+            for (address, origin) in watch.extend(&self.secp)? {
+                if self.origins.insert(address.clone(), origin).is_none() && self.watched.insert(address.clone()) {
+                    self.addresses.push(address.clone());
+                    new_addresses.push(address);
+                }
+            }
+        }
+
+        Ok(new_addresses)
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use bitcoin::{Address, Amount, Block, OutPoint, Transaction, TxOut, consensus::encode::deserialize};
+use esplora_client::{Tx, TxStatus};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::chain::tx_from_transaction;
+use crate::proxy;
+use crate::smaug::{ERROR_RETRY_DELAY_SEC, UtxoDB};
+use crate::watchlist::WatchList;
+
+/// The ZMTP 3.0 greeting of a client using the NULL security mechanism.
+const GREETING: [u8; 64] = {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xFF;
+    greeting[9] = 0x7F;
+    // Version 3.0.
+    greeting[10] = 3;
+    greeting[12] = b'N';
+    greeting[13] = b'U';
+    greeting[14] = b'L';
+    greeting[15] = b'L';
+    greeting
+};
+/// The flag of a frame that is followed by more frames of the same message.
+const FLAG_MORE: u8 = 0x01;
+/// The flag of a frame whose size is encoded on 8 bytes.
+const FLAG_LONG: u8 = 0x02;
+/// The flag of a command frame.
+const FLAG_COMMAND: u8 = 0x04;
+/// How long to wait for bitcoind to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The largest frame accepted from bitcoind.
+const MAX_FRAME_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Errors that happen while setting up ZMQ subscriptions.
+#[derive(Debug, Error)]
+pub enum ZmqError {
+    /// No endpoint is configured.
+    #[error("at least one of `rawtx`, `rawblock` or `sequence` must be set in `[zmq]`")]
+    NoEndpoints,
+
+    /// An endpoint is not a `tcp://host:port` URL.
+    #[error("invalid ZMQ endpoint `{0}`, expected `tcp://host:port`")]
+    InvalidEndpoint(String),
+}
+
+/// The bitcoind ZMQ endpoints to subscribe to (`-zmqpubrawtx`, `-zmqpubrawblock`, `-zmqpubsequence`).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ZmqConfig {
+    /// The endpoint publishing every transaction entering the mempool or a block.
+    #[serde(default)]
+    pub(crate) rawtx: Option<String>,
+    /// The endpoint publishing every new block.
+    #[serde(default)]
+    pub(crate) rawblock: Option<String>,
+    /// The endpoint publishing blocks connected and disconnected, and transactions added to and removed from the
+    /// mempool.
+    #[serde(default)]
+    pub(crate) sequence: Option<String>,
+}
+
+/// Something bitcoind pushed.
+#[derive(Debug)]
+pub(crate) enum Notification {
+    /// A transaction entered the mempool or a block.
+    Tx(Transaction),
+    /// A block was connected.
+    Block(Block),
+    /// The chain tip changed: a block was connected or disconnected.
+    Tip,
+    /// Notifications were missed, because of a gap in the sequence numbers or a reconnection.
+    Gap,
+}
+
+/// Subscriptions to bitcoind's ZMQ notifications, each endpoint read by its own thread.
+#[derive(Debug)]
+pub(crate) struct Zmq {
+    receiver: Receiver<Notification>,
+}
+
+impl Zmq {
+    /// Subscribe to every configured endpoint.
+    pub(crate) fn subscribe(config: &ZmqConfig) -> Result<Zmq, ZmqError> {
+        let mut topics: HashMap<String, Vec<&'static str>> = HashMap::new();
+        for (topic, endpoint) in [
+            ("rawtx", &config.rawtx),
+            ("rawblock", &config.rawblock),
+            ("sequence", &config.sequence),
+        ] {
+            if let Some(endpoint) = endpoint {
+                let address = endpoint
+                    .strip_prefix("tcp://")
+                    .ok_or_else(|| ZmqError::InvalidEndpoint(endpoint.clone()))?;
+                topics.entry(address.to_string()).or_default().push(topic);
+            }
+        }
+        if topics.is_empty() {
+            return Err(ZmqError::NoEndpoints);
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        for (address, topics) in topics {
+            let sender = sender.clone();
+            thread::spawn(move || subscribe_forever(&address, &topics, &sender));
+        }
+
+        Ok(Zmq { receiver })
+    }
+
+    /// Wait up to `timeout` for notifications, returning every one received.
+    pub(crate) fn wait(&self, timeout: Duration) -> Vec<Notification> {
+        let first = match self.receiver.recv_timeout(timeout) {
+            Ok(notification) => notification,
+            Err(RecvTimeoutError::Timeout) => return Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(timeout);
+                return Vec::new();
+            }
+        };
+
+        let mut notifications = vec![first];
+        notifications.extend(self.receiver.try_iter());
+        notifications
+    }
+}
+
+/// Read the notifications of `topics` from `address`, reconnecting after errors, until the receiver is dropped.
+///
+/// Every reconnection is reported as a [`Notification::Gap`], since messages may have been published meanwhile.
+fn subscribe_forever(address: &str, topics: &[&str], sender: &Sender<Notification>) {
+    let mut connected_before = false;
+
+    loop {
+        let result = Subscriber::connect(address, topics).and_then(|mut subscriber| {
+            info!("Subscribed to ZMQ {} at `tcp://{address}`", topics.join(", "));
+            if connected_before && sender.send(Notification::Gap).is_err() {
+                return Ok(());
+            }
+            connected_before = true;
+
+            let mut sequences: HashMap<Vec<u8>, u32> = HashMap::new();
+            loop {
+                let message = subscriber.receive()?;
+                let [topic, body, sequence] = message.as_slice() else {
+                    debug!("Ignoring a ZMQ message of {} frames", message.len());
+                    continue;
+                };
+                let Ok(sequence) = <[u8; 4]>::try_from(sequence.as_slice()).map(u32::from_le_bytes) else {
+                    continue;
+                };
+
+                let mut notifications = Vec::new();
+                if let Some(last) = sequences.insert(topic.clone(), sequence)
+                    && sequence != last.wrapping_add(1)
+                {
+                    warn!(
+                        "Missed {} ZMQ `{}` notifications",
+                        sequence.wrapping_sub(last).wrapping_sub(1),
+                        String::from_utf8_lossy(topic)
+                    );
+                    notifications.push(Notification::Gap);
+                }
+                notifications.extend(notification(topic, body));
+
+                for notification in notifications {
+                    if sender.send(notification).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        });
+
+        match result {
+            // The receiver was dropped.
+            Ok(()) => return,
+            Err(e) => {
+                warn!("ZMQ subscription to `tcp://{address}` failed: {e}");
+                warn!("Reconnecting in {ERROR_RETRY_DELAY_SEC} seconds...");
+                thread::sleep(Duration::from_secs(ERROR_RETRY_DELAY_SEC));
+            }
+        }
+    }
+}
+
+/// Decode the body of a message published on `topic`.
+fn notification(topic: &[u8], body: &[u8]) -> Option<Notification> {
+    match topic {
+        b"rawtx" => match deserialize(body) {
+            Ok(transaction) => Some(Notification::Tx(transaction)),
+            Err(e) => {
+                warn!("Failed to decode a ZMQ transaction: {e}");
+                None
+            }
+        },
+        b"rawblock" => match deserialize(body) {
+            Ok(block) => Some(Notification::Block(block)),
+            Err(e) => {
+                warn!("Failed to decode a ZMQ block: {e}");
+                Some(Notification::Tip)
+            }
+        },
+        // A 32 byte hash followed by a label: `C`onnected and `D`isconnected blocks, `A`dded and `R`emoved
+        // transactions.
+        b"sequence" => match body.get(32) {
+            Some(b'C' | b'D') => Some(Notification::Tip),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A ZMQ SUB socket speaking ZMTP 3.0 over TCP.
+#[derive(Debug)]
+struct Subscriber {
+    stream: BufReader<TcpStream>,
+}
+
+impl Subscriber {
+    /// Connect to `address`, perform the ZMTP handshake and subscribe to `topics`.
+    fn connect(address: &str, topics: &[&str]) -> io::Result<Subscriber> {
+        let stream = proxy::connect_address(address, CONNECT_TIMEOUT)?;
+        let mut subscriber = Subscriber {
+            stream: BufReader::new(stream),
+        };
+
+        subscriber.write(&GREETING)?;
+        let mut greeting = [0u8; 64];
+        subscriber.stream.read_exact(&mut greeting)?;
+        if greeting[0] != 0xFF || greeting[9] != 0x7F || greeting[10] < 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a ZMTP 3 peer"));
+        }
+
+        let mut ready = vec![5];
+        ready.extend(b"READY");
+        ready.push(11);
+        ready.extend(b"Socket-Type");
+        ready.extend(3u32.to_be_bytes());
+        ready.extend(b"SUB");
+        subscriber.send_frame(FLAG_COMMAND, &ready)?;
+
+        for topic in topics {
+            let mut subscription = vec![1];
+            subscription.extend(topic.as_bytes());
+            subscriber.send_frame(0, &subscription)?;
+        }
+
+        Ok(subscriber)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.get_mut().write_all(bytes)
+    }
+
+    fn send_frame(&mut self, flags: u8, body: &[u8]) -> io::Result<()> {
+        let mut frame = match u8::try_from(body.len()) {
+            Ok(size) => vec![flags, size],
+            Err(_) => {
+                let mut frame = vec![flags | FLAG_LONG];
+                frame.extend((body.len() as u64).to_be_bytes());
+                frame
+            }
+        };
+        frame.extend(body);
+
+        self.write(&frame)
+    }
+
+    /// Read the frames of the next message, skipping commands.
+    fn receive(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+
+        loop {
+            let mut flags = [0u8];
+            self.stream.read_exact(&mut flags)?;
+            let flags = flags[0];
+
+            let size = match flags & FLAG_LONG {
+                0 => {
+                    let mut size = [0u8];
+                    self.stream.read_exact(&mut size)?;
+                    u64::from(size[0])
+                }
+                _ => {
+                    let mut size = [0u8; 8];
+                    self.stream.read_exact(&mut size)?;
+                    u64::from_be_bytes(size)
+                }
+            };
+            if size > MAX_FRAME_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+            }
+            let mut body = vec![0u8; size as usize];
+            self.stream.read_exact(&mut body)?;
+
+            if flags & FLAG_COMMAND != 0 {
+                continue;
+            }
+            frames.push(body);
+            if flags & FLAG_MORE == 0 {
+                return Ok(frames);
+            }
+        }
+    }
+}
+
+/// Match a transaction pushed by bitcoind against the watched addresses.
+///
+/// Returns the transaction, with the prevouts of the watched UTXOs it spends, and the addresses it touches, if any.
+pub(crate) fn match_tx(transaction: &Transaction, watchlist: &WatchList, state: &UtxoDB) -> Option<(Tx, Vec<Address>)> {
+    let outpoints: HashMap<OutPoint, (&Address, Amount)> = state
+        .iter()
+        .flat_map(|(address, utxos)| {
+            utxos
+                .iter()
+                .map(move |utxo| (OutPoint::new(utxo.txid, utxo.vout), (address, utxo.value)))
+        })
+        .collect();
+
+    let mut addresses: Vec<Address> = Vec::new();
+    let prevouts = transaction
+        .input
+        .iter()
+        .map(|input| {
+            let (address, value) = outpoints.get(&input.previous_output)?;
+            if !addresses.contains(address) {
+                addresses.push((*address).clone());
+            }
+            Some(TxOut {
+                value: *value,
+                script_pubkey: address.script_pubkey(),
+            })
+        })
+        .collect();
+    for output in &transaction.output {
+        if let Some(address) = watchlist
+            .addresses()
+            .iter()
+            .find(|address| address.matches_script_pubkey(&output.script_pubkey))
+            && !addresses.contains(address)
+        {
+            addresses.push(address.clone());
+        }
+    }
+    if addresses.is_empty() {
+        return None;
+    }
+
+    let status = TxStatus {
+        confirmed: false,
+        block_height: None,
+        block_hash: None,
+        block_time: None,
+    };
+    Some((tx_from_transaction(transaction, prevouts, status), addresses))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, str::FromStr};
+
+    use bitcoin::{
+        ScriptBuf, Sequence, TxIn, Txid, Witness, absolute::LockTime, consensus::encode::serialize, transaction,
+    };
+    use esplora_client::{Utxo, UtxoStatus};
+
+    use super::*;
+    use crate::Config;
+    use crate::mempool::MempoolWatch;
+    use crate::smaug::{Event, Spend};
+
+    const WATCHED: &str = "tb1pk3su3yelyq4349c23rrmk0xa34dpmxght2t2ssenqj9vz9s4692shkkxxd";
+
+    fn transaction(inputs: &[OutPoint], outputs: &[(ScriptBuf, u64)]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(script_pubkey, value)| TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn gaps_in_sequence_numbers_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let published = transaction(&[OutPoint::null()], &[(ScriptBuf::new_op_return([0x13, 0x37]), 0)]);
+        let body = serialize(&published);
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut publisher = Subscriber {
+                stream: BufReader::new(stream),
+            };
+            publisher.write(&GREETING).unwrap();
+            let mut greeting = [0u8; 64];
+            publisher.stream.read_exact(&mut greeting).unwrap();
+            publisher.send_frame(FLAG_COMMAND, b"\x05READY").unwrap();
+            assert_eq!(publisher.receive().unwrap(), vec![b"\x01rawtx".to_vec()]);
+
+            for sequence in [7u32, 8, 10] {
+                publisher.send_frame(FLAG_MORE, b"rawtx").unwrap();
+                publisher.send_frame(FLAG_MORE, &body).unwrap();
+                publisher.send_frame(0, &sequence.to_le_bytes()).unwrap();
+            }
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let zmq = Zmq::subscribe(&ZmqConfig {
+            rawtx: Some(format!("tcp://{address}")),
+            ..ZmqConfig::default()
+        })
+        .unwrap();
+        let mut notifications = Vec::new();
+        while notifications.len() < 4 {
+            let received = zmq.wait(Duration::from_secs(5));
+            assert!(!received.is_empty(), "got {notifications:?}");
+            notifications.extend(received);
+        }
+
+        assert!(matches!(&notifications[0], Notification::Tx(tx) if *tx == published));
+        assert!(matches!(&notifications[1], Notification::Tx(_)));
+        assert!(matches!(&notifications[2], Notification::Gap));
+        assert!(matches!(&notifications[3], Notification::Tx(_)));
+    }
+
+    #[test]
+    fn pushed_spend_is_reported_once() {
+        let config: Config = toml::from_str(&format!(
+            "network = \"testnet4\"\naddresses = [\"{WATCHED}\"]\nnotify_subscriptions = false\nnotify_deposits = true"
+        ))
+        .unwrap();
+        let watchlist = WatchList::from_config(&config).unwrap();
+        let address = watchlist.addresses()[0].clone();
+        let outpoint = OutPoint::new(
+            Txid::from_str("33aeb7af5ff454dbbdc65c8229b13b2c101978976df655ae43ab8d467b5c8b9e").unwrap(),
+            1,
+        );
+        let mut state = UtxoDB::from([(
+            address.clone(),
+            vec![Utxo {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                status: UtxoStatus {
+                    confirmed: true,
+                    block_height: Some(101595),
+                    block_hash: None,
+                    block_time: None,
+                },
+                value: Amount::from_sat(5000),
+            }],
+        )]);
+
+        let unrelated = transaction(&[OutPoint::null()], &[(ScriptBuf::new_op_return([0x42]), 0)]);
+        assert!(match_tx(&unrelated, &watchlist, &state).is_none());
+
+        // Spend the watched UTXO, sending the change back to the watched address.
+        let spend = transaction(
+            &[outpoint],
+            &[
+                (ScriptBuf::new_op_return([0x13, 0x37]), 3000),
+                (address.script_pubkey(), 1500),
+            ],
+        );
+        let (tx, addresses) = match_tx(&spend, &watchlist, &state).unwrap();
+        assert_eq!(addresses, vec![address.clone()]);
+        assert_eq!(tx.fee, 500);
+
+        let mut mempool_watch = MempoolWatch::default();
+        let events = mempool_watch.on_tx(&tx, &addresses, &watchlist, &mut state, 101597);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], Event::Deposit(params) if params.utxo.value == Amount::from_sat(1500)));
+        let Event::Withdrawal(params) = &events[1] else {
+            panic!("expected a withdrawal, got {:?}", events[1]);
+        };
+        assert_eq!(params.utxo.status.block_height, Some(101595));
+        assert!(!params.spend.as_ref().unwrap().status.confirmed);
+        assert_eq!(params.spend.as_ref().unwrap().fee, Some(Amount::from_sat(500)));
+
+        // The change is now the only UTXO, and the transaction is not reported again.
+        assert_eq!(state[&address].len(), 1);
+        assert!(!state[&address][0].status.confirmed);
+        let (tx, addresses) = match_tx(&spend, &watchlist, &state).unwrap();
+        assert!(
+            mempool_watch
+                .on_tx(&tx, &addresses, &watchlist, &mut state, 101598)
+                .is_empty()
+        );
+
+        // Spending the change along with a coin that is not watched leaves the fee unknown.
+        let change = OutPoint::new(spend.compute_txid(), 1);
+        let sweep = transaction(
+            &[change, OutPoint::new(outpoint.txid, 0)],
+            &[(ScriptBuf::new_op_return([0x13, 0x37]), 3000)],
+        );
+        let (tx, _) = match_tx(&sweep, &watchlist, &state).unwrap();
+        assert_eq!(Spend::from_tx(&tx).fee, None);
+    }
+}